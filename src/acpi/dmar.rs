@@ -0,0 +1,164 @@
+use alloc::vec::Vec;
+use core::{cell::SyncUnsafeCell, mem, ptr};
+
+use super::{find_sdt, sdt::Sdt};
+
+/// DMA Remapping Reporting table, describing the Intel VT-d remapping units
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Dmar {
+    pub header: Sdt,
+    /// Maximum DMA physical address width, minus one
+    pub host_address_width: u8,
+    pub flags: u8,
+    _reserved: [u8; 10],
+}
+
+/// The platform supports interrupt remapping.
+pub const FLAG_INTR_REMAP: u8 = 1 << 0;
+/// Firmware asks the OS not to enable x2APIC mode.
+pub const FLAG_X2APIC_OPT_OUT: u8 = 1 << 1;
+
+/// The unit covers every PCI device on its segment not claimed by another unit.
+pub const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+const STRUCT_DRHD: u16 = 0;
+
+const SCOPE_IOAPIC: u8 = 3;
+const SCOPE_HPET: u8 = 4;
+
+static DMAR: SyncUnsafeCell<Option<&'static Dmar>> = SyncUnsafeCell::new(None);
+
+pub fn dmar() -> Option<&'static Dmar> {
+    // SAFETY: The `DMAR` variable is initialized only once before use.
+    unsafe { *DMAR.get() }
+}
+
+impl Dmar {
+    pub fn init() {
+        let Some(dmar) = find_sdt("DMAR").first().and_then(|sdt| Dmar::new(sdt)) else {
+            return;
+        };
+
+        // SAFETY: Ensuring single initialization before APs start.
+        unsafe { DMAR.get().write(Some(dmar)) };
+
+        log::info!("  DMAR: flags {:#x}, {} remapping unit(s)", { dmar.flags }, dmar.drhds().count());
+    }
+
+    #[inline(always)]
+    pub fn new(sdt: &'static Sdt) -> Option<&'static Dmar> {
+        (sdt.signature == *b"DMAR" && sdt.length as usize >= mem::size_of::<Dmar>())
+            .then(|| unsafe { &*ptr::cast::<_, Dmar>(sdt) })
+    }
+
+    #[inline(always)]
+    pub fn intr_remap(&self) -> bool {
+        self.flags & FLAG_INTR_REMAP != 0
+    }
+
+    #[inline(always)]
+    pub fn x2apic_opt_out(&self) -> bool {
+        self.flags & FLAG_X2APIC_OPT_OUT != 0
+    }
+
+    /// Iterates over the DMA remapping hardware unit definitions.
+    pub fn drhds(&self) -> impl Iterator<Item = Drhd> + '_ {
+        let base = self as *const Self as *const u8;
+        let len = self.header.length as usize;
+        let mut offset = mem::size_of::<Dmar>();
+
+        core::iter::from_fn(move || {
+            while offset + 4 <= len {
+                let struct_type = unsafe { base.add(offset).cast::<u16>().read_unaligned() };
+                let struct_len = unsafe { base.add(offset + 2).cast::<u16>().read_unaligned() } as usize;
+                if struct_len < 4 || offset + struct_len > len {
+                    return None;
+                }
+                let current = offset;
+                offset += struct_len;
+
+                if struct_type == STRUCT_DRHD && struct_len >= mem::size_of::<DrhdHeader>() {
+                    let header = unsafe { base.add(current).cast::<DrhdHeader>().read_unaligned() };
+                    return Some(Drhd {
+                        flags: header.flags,
+                        segment: header.segment,
+                        register_base: header.register_base,
+                        scopes: unsafe { base.add(current + mem::size_of::<DrhdHeader>()) },
+                        scopes_len: struct_len - mem::size_of::<DrhdHeader>(),
+                    });
+                }
+            }
+            None
+        })
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct DrhdHeader {
+    struct_type: u16,
+    length: u16,
+    flags: u8,
+    _size: u8,
+    segment: u16,
+    register_base: u64,
+}
+
+/// A VT-d remapping unit and the devices it covers.
+#[derive(Clone, Copy, Debug)]
+pub struct Drhd {
+    pub flags: u8,
+    pub segment: u16,
+    pub register_base: u64,
+    scopes: *const u8,
+    scopes_len: usize,
+}
+
+/// Device behind a remapping unit that is not enumerated through PCI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceScope {
+    IoApic { id: u8, source_id: u16 },
+    Hpet { id: u8, source_id: u16 },
+}
+
+impl Drhd {
+    #[inline(always)]
+    pub fn include_pci_all(&self) -> bool {
+        self.flags & DRHD_INCLUDE_PCI_ALL != 0
+    }
+
+    /// Returns the I/O APICs and HPETs in this unit's scope, with the PCI requester ID
+    /// their interrupt messages carry.
+    pub fn special_scopes(&self) -> Vec<DeviceScope> {
+        let mut scopes = Vec::new();
+        let mut offset = 0;
+
+        while offset + 6 <= self.scopes_len {
+            let scope = unsafe { self.scopes.add(offset) };
+            let (scope_type, scope_len) = unsafe { (scope.read(), scope.add(1).read() as usize) };
+            if scope_len < 6 || offset + scope_len > self.scopes_len {
+                break;
+            }
+            offset += scope_len;
+
+            let enumeration_id = unsafe { scope.add(4).read() };
+            let start_bus = unsafe { scope.add(5).read() };
+            // The path is a list of (device, function) pairs from the start bus; these
+            // devices sit directly on it
+            if scope_len < 8 {
+                continue;
+            }
+            let (device, function) = unsafe { (scope.add(6).read(), scope.add(7).read()) };
+            let source_id = u16::from(start_bus) << 8 | u16::from(device & 0x1F) << 3 | u16::from(function & 0x7);
+
+            match scope_type {
+                SCOPE_IOAPIC => scopes.push(DeviceScope::IoApic { id: enumeration_id, source_id }),
+                SCOPE_HPET => scopes.push(DeviceScope::Hpet { id: enumeration_id, source_id }),
+                _ => (),
+            }
+        }
+
+        scopes
+    }
+}
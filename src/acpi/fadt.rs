@@ -0,0 +1,173 @@
+use core::{cell::SyncUnsafeCell, mem, ptr};
+
+use super::{find_sdt, sdt::Sdt, GenericAddressStructure};
+
+/// Fixed ACPI Description Table, up to the fields of ACPI 6.
+///
+/// Older tables are shorter; the kernel keeps a zero-padded copy, and fields past the
+/// end of the firmware table read as zero through the accessors.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: Sdt,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    _reserved: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_length: u8,
+    pub gpe1_length: u8,
+    pub gpe1_base: u8,
+    pub c_state_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    _reserved2: u8,
+    pub flags: u32,
+    pub reset_reg: GenericAddressStructure,
+    pub reset_value: u8,
+    pub arm_boot_arch: u16,
+    pub minor_version: u8,
+    pub x_firmware_control: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddressStructure,
+    pub x_pm1b_event_block: GenericAddressStructure,
+    pub x_pm1a_control_block: GenericAddressStructure,
+    pub x_pm1b_control_block: GenericAddressStructure,
+    pub x_pm2_control_block: GenericAddressStructure,
+    pub x_pm_timer_block: GenericAddressStructure,
+    pub x_gpe0_block: GenericAddressStructure,
+    pub x_gpe1_block: GenericAddressStructure,
+    pub sleep_control_reg: GenericAddressStructure,
+    pub sleep_status_reg: GenericAddressStructure,
+    pub hypervisor_vendor_id: u64,
+}
+
+/// The PM timer is 32 bits wide rather than 24.
+pub const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+/// The platform has no legacy devices and relies on the hardware-reduced ACPI model.
+pub const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// ARM boot architecture flag: PSCI is implemented.
+pub const ARM_PSCI_COMPLIANT: u16 = 1;
+/// ARM boot architecture flag: PSCI is called with HVC instead of SMC.
+pub const ARM_PSCI_USE_HVC: u16 = 1 << 1;
+
+/// Length of the ACPI 1.0 table, which has everything up to the boot architecture flags.
+const MIN_LENGTH: usize = 116;
+
+static FADT: SyncUnsafeCell<Option<Fadt>> = SyncUnsafeCell::new(None);
+
+pub fn fadt() -> Option<&'static Fadt> {
+    // SAFETY: The `FADT` variable is initialized only once before use.
+    unsafe { (*FADT.get()).as_ref() }
+}
+
+impl Fadt {
+    pub fn init() {
+        let Some(fadt) = find_sdt("FACP").first().and_then(|sdt| Fadt::new(sdt)) else {
+            log::warn!("Unable to find FADT");
+            return;
+        };
+
+        // SAFETY: Ensuring single initialization before APs start.
+        unsafe { FADT.get().write(Some(fadt)) };
+        let Some(fadt) = self::fadt() else {
+            return;
+        };
+        log::info!(
+            "  FADT: revision {}.{}, flags {:#x}",
+            { fadt.header.revision },
+            fadt.minor_version(),
+            { fadt.flags }
+        );
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        crate::device::pm_timer::init();
+    }
+
+    /// Copies the table at `sdt`, padding whatever an older revision lacks with zeros.
+    pub fn new(sdt: &'static Sdt) -> Option<Fadt> {
+        let length = sdt.length as usize;
+        if sdt.signature != *b"FACP" || length < MIN_LENGTH {
+            return None;
+        }
+        // SAFETY: Every field is plain data, and only the `length` bytes the firmware
+        // table covers are read from it.
+        unsafe {
+            let mut fadt = mem::zeroed::<Fadt>();
+            ptr::copy_nonoverlapping(
+                ptr::from_ref(sdt).cast::<u8>(),
+                ptr::from_mut(&mut fadt).cast::<u8>(),
+                length.min(mem::size_of::<Fadt>()),
+            );
+            Some(fadt)
+        }
+    }
+
+    /// Returns whether the table is long enough to hold a field of `size` bytes at `offset`.
+    #[inline(always)]
+    fn has(&self, offset: usize, size: usize) -> bool {
+        self.header.length as usize >= offset + size
+    }
+
+    pub fn minor_version(&self) -> u8 {
+        if self.has(mem::offset_of!(Fadt, minor_version), 1) {
+            self.minor_version
+        } else {
+            0
+        }
+    }
+
+    /// Returns the ARM boot architecture flags, zero before ACPI 5.1.
+    pub fn arm_boot_arch(&self) -> u16 {
+        if self.has(mem::offset_of!(Fadt, arm_boot_arch), 2) {
+            self.arm_boot_arch
+        } else {
+            0
+        }
+    }
+
+    /// Returns the hypervisor vendor identity, zero when not running under a hypervisor
+    /// or before ACPI 6.0.
+    pub fn hypervisor_vendor_id(&self) -> u64 {
+        if self.has(mem::offset_of!(Fadt, hypervisor_vendor_id), 8) {
+            self.hypervisor_vendor_id
+        } else {
+            0
+        }
+    }
+
+    /// Returns the extended PM timer block, if the table has it and it is set.
+    pub fn x_pm_timer_block(&self) -> Option<GenericAddressStructure> {
+        let offset = mem::offset_of!(Fadt, x_pm_timer_block);
+        if !self.has(offset, mem::size_of::<GenericAddressStructure>()) {
+            return None;
+        }
+        let block = self.x_pm_timer_block;
+        ({ block.address } != 0).then_some(block)
+    }
+}
@@ -1,10 +1,18 @@
 use core::{mem, ptr};
 use super::{find_sdt, sdt::Sdt};
 use crate::{
-    device::generic_timer::GenericTimer,
-    dtb::irqchip::{register_irq, IRQ_CHIP},
+    acpi::fadt::{fadt, ARM_PSCI_USE_HVC},
+    device::generic_timer::{self, TimerPpi, TimerPpis},
+    dtb::irqchip::{Polarity, Trigger, IRQ_CHIP},
 };
 
+/// Timer flag: the interrupt is edge triggered rather than level triggered.
+const TIMER_EDGE: u32 = 1 << 0;
+/// Timer flag: the interrupt is active low rather than active high.
+const TIMER_ACTIVE_LOW: u32 = 1 << 1;
+/// Timer flag: the timer keeps running in every power state.
+const TIMER_ALWAYS_ON: u32 = 1 << 2;
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
 pub struct Gtdt {
@@ -27,17 +35,26 @@ pub struct Gtdt {
 impl Gtdt {
     #[inline(always)]
     pub fn init() {
-        let gtdt = Gtdt::new(find_sdt("GTDT").get(0)?)?;
-        log::info!("generic_timer gsiv = {}", gtdt.non_secure_el1_timer_gsiv);
-
-        let mut timer = GenericTimer {
-            clk_freq: 0,
-            reload_count: 0,
+        let Some(gtdt) = find_sdt("GTDT").first().and_then(|sdt| Gtdt::new(sdt)) else {
+            return;
         };
-        timer.init();
 
-        register_irq(gtdt.non_secure_el1_timer_gsiv, timer);
-        unsafe { IRQ_CHIP.irq_enable(gtdt.non_secure_el1_timer_gsiv) };
+        if generic_timer::frequency() == 0 {
+            match generic_timer::control_frame_frequency(gtdt.cnt_control_base) {
+                Some(frequency) => generic_timer::set_fallback_frequency(frequency),
+                None => log::warn!("generic_timer: CNTFRQ_EL0 is not set and there is no CNTControlBase"),
+            }
+        }
+
+        let hypervisor = fadt().is_some_and(|fadt| {
+            fadt.arm_boot_arch() & ARM_PSCI_USE_HVC != 0 || fadt.hypervisor_vendor_id() != 0
+        });
+        generic_timer::init(TimerPpis {
+            physical: timer_ppi(gtdt.non_secure_el1_timer_gsiv, gtdt.non_secure_el1_timer_flags),
+            virt: timer_ppi(gtdt.virtual_el1_timer_gsiv, gtdt.virtual_el1_timer_flags),
+            hyp: timer_ppi(gtdt.el2_timer_gsiv, gtdt.el2_timer_flags),
+            hypervisor,
+        });
     }
 
     #[inline(always)]
@@ -45,4 +62,23 @@ impl Gtdt {
         (sdt.signature == *b"GTDT" && sdt.length as usize >= mem::size_of::<Gtdt>())
             .then(|| unsafe { &*ptr::cast::<_, Gtdt>(sdt) })
     }
-}
\ No newline at end of file
+}
+
+/// Maps the PPI of a timer from its GSIV and flags; a zero GSIV means there is none.
+fn timer_ppi(gsiv: u32, flags: u32) -> Option<TimerPpi> {
+    if gsiv == 0 {
+        return None;
+    }
+    let trigger = if flags & TIMER_EDGE != 0 { Trigger::Edge } else { Trigger::Level };
+    let polarity = if flags & TIMER_ACTIVE_LOW != 0 { Polarity::Low } else { Polarity::High };
+    match IRQ_CHIP.gsi_to_virq(gsiv, trigger, polarity) {
+        Ok(virq) => Some(TimerPpi {
+            virq,
+            always_on: flags & TIMER_ALWAYS_ON != 0,
+        }),
+        Err(err) => {
+            log::error!("generic_timer gsiv {} has no interrupt domain: {:?}", gsiv, err);
+            None
+        }
+    }
+}
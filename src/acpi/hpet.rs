@@ -1,10 +1,20 @@
 use core::{mem, ptr};
 use core::ptr::{read_volatile, write_volatile};
 
+use alloc::vec::Vec;
+
 use crate::memory::{map_device_memory, PhysicalAddress, PAGE_SIZE};
 
 use super::{find_sdt, sdt::Sdt, GenericAddressStructure, ACPI_TABLE};
 
+pub const CAPABILITY_OFFSET: usize = 0x00;
+pub const GENERAL_CONFIG_OFFSET: usize = 0x10;
+pub const GENERAL_INTERRUPT_STATUS_OFFSET: usize = 0x20;
+pub const MAIN_COUNTER_OFFSET: usize = 0xF0;
+
+pub const ENABLE_CNF: u64 = 1;
+pub const LEG_RT_CNF: u64 = 1 << 1;
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 pub struct Hpet {
@@ -19,23 +29,47 @@ pub struct Hpet {
 }
 
 impl Hpet {
+    /// Maps every HPET block, in enumeration order. Blocks described twice are skipped.
+    ///
+    /// On i686 only the first block is used: blocks are read through the fixed
+    /// `HPET_OFFSET` page rather than the linear mapping, and that page holds one.
     #[inline(always)]
     pub fn init() {
-        let hpet = Hpet::new(find_sdt("HPET").get(0)?)?;
+        let mut tables: Vec<&'static Hpet> = find_sdt("HPET").into_iter().filter_map(Hpet::parse).collect();
+        tables.sort_by_key(|hpet| hpet.hpet_number);
+        tables.dedup_by_key(|hpet| hpet.hpet_number);
+
+        let mut hpets = Vec::with_capacity(tables.len());
+        for hpet in tables {
+            // A single block fits at the fixed HPET_OFFSET
+            if cfg!(target_arch = "x86") && !hpets.is_empty() {
+                log::warn!("  HPET: {:X} ignored, only one block can be mapped", hpet.hpet_number);
+                continue;
+            }
+            if unsafe { hpet.map() }.is_err() {
+                log::warn!("  HPET: {:X} could not be mapped", hpet.hpet_number);
+                continue;
+            }
+            log::info!("  HPET: {:X}", hpet.hpet_number);
+            hpets.push(*hpet);
+        }
 
-        log::info!("  HPET: {:X}", hpet.hpet_number);
-        *ACPI_TABLE.hpet.write() = Some(hpet);
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        crate::device::hpet::init(&hpets);
+        *ACPI_TABLE.hpet.write() = hpets;
     }
 
     #[inline(always)]
     pub fn new(sdt: &'static Sdt) -> Option<&'static Hpet> {
+        Self::parse(sdt).filter(|h| unsafe { h.map() }.is_ok())
+    }
+
+    /// Checks `sdt` is an HPET table with a memory-mapped block, without mapping it.
+    #[inline(always)]
+    fn parse(sdt: &'static Sdt) -> Option<&'static Hpet> {
         (sdt.signature == *b"HPET" && sdt.length as usize >= mem::size_of::<Hpet>())
             .then(|| unsafe { &*ptr::cast::<_, Hpet>(sdt) })
             .filter(|h| h.base_address.address_space == 0)
-            .map(|h| {
-                unsafe { h.map().ok()? };
-                h
-            })
     }
 }
 
@@ -73,7 +107,7 @@ impl Hpet {
     }
 
     #[inline(always)]
-    pub unsafe fn write_u64(&mut self, offset: usize, value: u64) {
+    pub unsafe fn write_u64(&self, offset: usize, value: u64) {
         write_volatile((crate::HPET_OFFSET + offset) as *mut u64, value);
     }
 }
@@ -97,9 +131,10 @@ impl Hpet {
     }
 
     #[inline(always)]
-    pub unsafe fn write_u64(&mut self, offset: usize, value: u64) {
+    pub unsafe fn write_u64(&self, offset: usize, value: u64) {
         write_volatile(
             (self.base_address.address as usize + offset + crate::PHYS_OFFSET) as *mut u64,
+            value,
         );
     }
 }
\ No newline at end of file
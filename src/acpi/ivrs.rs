@@ -0,0 +1,172 @@
+use alloc::vec::Vec;
+use core::{cell::SyncUnsafeCell, mem, ptr};
+
+use super::{find_sdt, sdt::Sdt};
+
+/// I/O Virtualization Reporting Structure, describing the AMD IOMMUs
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Ivrs {
+    pub header: Sdt,
+    pub iv_info: u32,
+    _reserved: u64,
+}
+
+/// IVHD block types; a later type describes the same IOMMU as an earlier one with more detail.
+const IVHD_TYPE_10: u8 = 0x10;
+const IVHD_TYPE_11: u8 = 0x11;
+const IVHD_TYPE_40: u8 = 0x40;
+
+const DEVICE_ENTRY_SPECIAL: u8 = 0x48;
+const DEVICE_ENTRY_ACPI_HID: u8 = 0xF0;
+
+const SPECIAL_IOAPIC: u8 = 1;
+const SPECIAL_HPET: u8 = 2;
+
+static IVRS: SyncUnsafeCell<Option<&'static Ivrs>> = SyncUnsafeCell::new(None);
+
+pub fn ivrs() -> Option<&'static Ivrs> {
+    // SAFETY: The `IVRS` variable is initialized only once before use.
+    unsafe { *IVRS.get() }
+}
+
+impl Ivrs {
+    pub fn init() {
+        let Some(ivrs) = find_sdt("IVRS").first().and_then(|sdt| Ivrs::new(sdt)) else {
+            return;
+        };
+
+        // SAFETY: Ensuring single initialization before APs start.
+        unsafe { IVRS.get().write(Some(ivrs)) };
+
+        log::info!("  IVRS: info {:#x}, {} IOMMU(s)", { ivrs.iv_info }, ivrs.units().len());
+    }
+
+    #[inline(always)]
+    pub fn new(sdt: &'static Sdt) -> Option<&'static Ivrs> {
+        (sdt.signature == *b"IVRS" && sdt.length as usize >= mem::size_of::<Ivrs>())
+            .then(|| unsafe { &*ptr::cast::<_, Ivrs>(sdt) })
+    }
+
+    /// Returns one entry per IOMMU, taken from the most detailed IVHD block type that
+    /// describes it.
+    pub fn units(&self) -> Vec<Ivhd> {
+        let base = self as *const Self as *const u8;
+        let len = self.header.length as usize;
+        let mut offset = mem::size_of::<Ivrs>();
+        let mut units: Vec<Ivhd> = Vec::new();
+
+        while offset + 4 <= len {
+            let block_type = unsafe { base.add(offset).read() };
+            let block_len = unsafe { base.add(offset + 2).cast::<u16>().read_unaligned() } as usize;
+            if block_len < 4 || offset + block_len > len {
+                break;
+            }
+            let current = offset;
+            offset += block_len;
+
+            let entries_offset = match block_type {
+                IVHD_TYPE_10 => 24,
+                IVHD_TYPE_11 | IVHD_TYPE_40 => 40,
+                _ => continue,
+            };
+            if block_len < entries_offset {
+                continue;
+            }
+
+            let header = unsafe { base.add(current).cast::<IvhdHeader>().read_unaligned() };
+            let unit = Ivhd {
+                block_type,
+                flags: header.flags,
+                device_id: header.device_id,
+                capability_offset: header.capability_offset,
+                iommu_base: header.iommu_base,
+                segment: header.segment,
+                entries: unsafe { base.add(current + entries_offset) },
+                entries_len: block_len - entries_offset,
+            };
+
+            match units
+                .iter_mut()
+                .find(|other| other.device_id == unit.device_id && other.segment == unit.segment)
+            {
+                Some(other) if other.block_type < unit.block_type => *other = unit,
+                Some(_) => (),
+                None => units.push(unit),
+            }
+        }
+
+        units
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct IvhdHeader {
+    block_type: u8,
+    flags: u8,
+    length: u16,
+    device_id: u16,
+    capability_offset: u16,
+    iommu_base: u64,
+    segment: u16,
+    iommu_info: u16,
+}
+
+/// An AMD IOMMU and the devices it covers.
+#[derive(Clone, Copy, Debug)]
+pub struct Ivhd {
+    pub block_type: u8,
+    pub flags: u8,
+    /// PCI requester ID of the IOMMU itself.
+    pub device_id: u16,
+    pub capability_offset: u16,
+    pub iommu_base: u64,
+    pub segment: u16,
+    entries: *const u8,
+    entries_len: usize,
+}
+
+/// Device behind an IOMMU that is not enumerated through PCI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialDevice {
+    IoApic { id: u8, device_id: u16 },
+    Hpet { id: u8, device_id: u16 },
+}
+
+impl Ivhd {
+    /// Returns the I/O APICs and HPETs behind this IOMMU, with the requester ID their
+    /// interrupt messages carry.
+    pub fn special_devices(&self) -> Vec<SpecialDevice> {
+        let mut devices = Vec::new();
+        let mut offset = 0;
+
+        while offset < self.entries_len {
+            let entry = unsafe { self.entries.add(offset) };
+            let entry_type = unsafe { entry.read() };
+            let entry_len = match entry_type {
+                0x00..=0x3F => 4,
+                0x40..=0x7F => 8,
+                // Fixed part, then a UID whose length is in byte 21
+                DEVICE_ENTRY_ACPI_HID if offset + 22 <= self.entries_len => 22 + usize::from(unsafe { entry.add(21).read() }),
+                _ => break,
+            };
+            if offset + entry_len > self.entries_len {
+                break;
+            }
+            offset += entry_len;
+
+            if entry_type == DEVICE_ENTRY_SPECIAL {
+                let (id, device_id, variety) =
+                    unsafe { (entry.add(4).read(), entry.add(5).cast::<u16>().read_unaligned(), entry.add(7).read()) };
+                match variety {
+                    SPECIAL_IOAPIC => devices.push(SpecialDevice::IoApic { id, device_id }),
+                    SPECIAL_HPET => devices.push(SpecialDevice::Hpet { id, device_id }),
+                    _ => (),
+                }
+            }
+        }
+
+        devices
+    }
+}
@@ -1,14 +1,75 @@
 use alloc::{boxed::Box, vec::Vec};
-use super::{Madt, MadtEntry};
+use core::{
+    arch::{asm, global_asm},
+    hint, mem, ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Once;
+use super::{Madt, MadtEntry, MadtGicc, GICC_ENABLED};
 use crate::{
-    device::irqchip::{
-        gic::{GenericInterruptController, GicCpuIf, GicDistIf},
-        gicv3::{GicV3, GicV3CpuIf},
+    acpi::gtdt,
+    device::{
+        irqchip::{
+            gic::{GenericInterruptController, GicCpuIf, GicDistIf},
+            gicv3::{GicV3, GicV3CpuIf},
+        },
+        generic_timer, psci,
     },
-    dtb::irqchip::{IrqChipItem, IRQ_CHIP},
-    memory::{map_device_memory, PhysicalAddress, PAGE_SIZE},
+    dtb::irqchip::{IrqChip, IrqChipItem, IrqError, IrqSpec, Parent, IRQ_CHIP},
+    ipi,
+    memory::{allocate_p2frame, deallocate_p2frame, map_device_memory, Frame, KernelMapper, PhysicalAddress, PAGE_SIZE},
+    paging::{RmmA, RmmArch, VirtualAddress},
+    start::{kstart_ap, AP_READY, CPU_COUNT},
 };
 
+/// Aff3, Aff2, Aff1 and Aff0 fields of an MPIDR.
+pub const MPIDR_AFFINITY: u64 = 0xFF_00FF_FFFF;
+
+/// Size of a GICv3 distributor, whose routing registers lie beyond the first page.
+const GICD_SIZE_V3: usize = 0x1_0000;
+/// Byte per INTID holding the mask of CPU interfaces it goes to, before GICv3.
+const GICD_ITARGETSR: usize = 0x800;
+/// Affinity of the CPU each SPI goes to with GICv3, a 64-bit register per INTID.
+const GICD_IROUTER: usize = 0x6000;
+/// INTIDs of shared peripheral interrupts, the only ones that can be routed.
+const GIC_SPI_RANGE: core::ops::Range<u32> = 32..1020;
+
+/// Size of a GICv3 redistributor: the RD_base and SGI_base frames.
+const GICR_SIZE: usize = 0x2_0000;
+/// Size of a GICv4 redistributor, which adds the VLPI_base and reserved frames.
+const GICR_SIZE_VLPI: usize = 0x4_0000;
+const GICR_WAKER: usize = 0x14;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+const GICR_TYPER: usize = 0x8;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+
+/// How each CPU reaches its GIC CPU interface.
+enum GicCpu {
+    /// Memory-mapped CPU interface, banked per CPU at the same address, and the
+    /// mapped distributor.
+    V2 { cpu_if: usize, dist: usize },
+    /// System register CPU interface, behind a redistributor per CPU.
+    V3,
+}
+
+static GIC_CPU: Once<GicCpu> = Once::new();
+
+crate::percpu! {
+    /// Mapped redistributor of the CPU with GICv3, zero if unknown.
+    static GICR: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Where the redistributors of a GICv3 are, as described by firmware.
+pub enum Redistributors<'a> {
+    /// One per CPU, as in MADT GICC entries: CPU ID and physical address.
+    PerCpu(&'a [(usize, u64)]),
+    /// Regions of consecutive redistributors, as in device trees, each claimed by the
+    /// CPU whose MPIDR matches its affinity: physical ranges, then CPU IDs and MPIDRs.
+    Regions(&'a [(u64, u64)], &'a [(usize, u64)]),
+}
+
 /// Initializes the GIC (Generic Interrupt Controller) based on MADT table
 pub(super) fn init(madt: &Madt) {
     let mut gicd_opt = None;
@@ -28,69 +89,686 @@ pub(super) fn init(madt: &Madt) {
         log::warn!("No GICD found, aborting initialization");
         return;
     };
+    let Some(&bsp) = giccs.iter().find(|gicc| is_this_cpu(gicc.mpidr)).or(giccs.first()) else {
+        log::warn!("No GICC found, aborting initialization");
+        return;
+    };
+
+    unsafe { crate::percpu::init(bsp.acpi_processor_uid as usize) };
+    register_ipi_targets(&giccs, gicd.gic_version);
 
+    // Handle GIC versions separately
+    let dist = gicd.physical_base_address;
+    match gicd.gic_version {
+        1 | 2 => init_gic_v1_v2(dist, bsp.physical_base_address, None),
+        3 => {
+            // Redistributors described through GICR entries instead are not handled yet
+            let gicrs: Vec<_> = giccs
+                .iter()
+                .filter(|gicc| gicc.gicr_base_address != 0)
+                .map(|gicc| (gicc.acpi_processor_uid as usize, gicc.gicr_base_address))
+                .collect();
+            init_gic_v3(dist, Redistributors::PerCpu(&gicrs), bsp.acpi_processor_uid as usize, None);
+        }
+        _ => {
+            log::warn!("Unsupported GIC version: {}", gicd.gic_version);
+            return;
+        }
+    }
+
+    // The timer PPIs must be known before the APs run `ap_entry`
+    gtdt::Gtdt::init();
+
+    if cfg!(feature = "multi_core") {
+        let use_psci = psci::init();
+        let targets: Vec<_> = giccs
+            .iter()
+            .filter(|gicc| !ptr::eq(**gicc, bsp) && gicc.flags & GICC_ENABLED != 0)
+            .filter_map(|gicc| {
+                let method = if use_psci {
+                    ApMethod::Psci
+                } else if gicc.parking_protocol_version == 1 && gicc.parked_address != 0 {
+                    ApMethod::Parked {
+                        mailbox: gicc.parked_address,
+                        cpu_interface: gicc.cpu_interface_number,
+                    }
+                } else {
+                    log::error!("AP startup failed: CPU {}: no PSCI or parking protocol", { gicc.acpi_processor_uid });
+                    return None;
+                };
+                Some(ApTarget {
+                    cpu_id: gicc.acpi_processor_uid as usize,
+                    mpidr: gicc.mpidr,
+                    method,
+                })
+            })
+            .collect();
+        start_aps(&targets);
+    }
+}
+
+#[inline(always)]
+fn this_mpidr() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack, preserves_flags)) };
+    mpidr
+}
+
+/// Returns whether `mpidr` names the calling CPU.
+pub fn is_this_cpu(mpidr: u64) -> bool {
+    mpidr & MPIDR_AFFINITY == this_mpidr() & MPIDR_AFFINITY
+}
+
+/// Makes every GICC an IPI target, addressed by CPU interface number before GICv3
+/// and by affinity from then on.
+fn register_ipi_targets(giccs: &[&MadtGicc], gic_version: u8) {
+    for gicc in giccs {
+        let cpu_id = gicc.acpi_processor_uid as usize;
+        let hw_id = if gic_version < 3 {
+            u64::from(gicc.cpu_interface_number)
+        } else {
+            gicc.mpidr
+        };
+        ipi::register_cpu(cpu_id, hw_id);
+        if is_this_cpu(gicc.mpidr) {
+            ipi::set_online(cpu_id, true);
+        }
+    }
+}
+
+/// Maps `size` bytes of the distributor at `phys` and initializes it.
+fn init_gic_dist(phys: u64, size: usize) -> GicDistIf {
     let mut gic_dist_if = GicDistIf::default();
     unsafe {
-        let phys = PhysicalAddress::new(gicd.physical_base_address as usize);
-        let virt = map_device_memory(phys, PAGE_SIZE);
+        let virt = map_device_memory(PhysicalAddress::new(phys as usize), size);
         gic_dist_if.init(virt.data());
     }
     log::info!("Initialized GIC Distributor: {:#x?}", gic_dist_if);
+    gic_dist_if
+}
 
-    // Handle GIC versions separately
-    match gicd.gic_version {
-        1 | 2 => initialize_gic_v1_v2(&giccs, gic_dist_if),
-        3 => initialize_gic_v3(&giccs, gic_dist_if),
-        _ => log::warn!("Unsupported GIC version: {}", gicd.gic_version),
+/// Initializes a GIC version 1 or 2 from the physical addresses of its distributor
+/// and CPU interface, registering it as the root interrupt domain.
+pub fn init_gic_v1_v2(dist: u64, cpu_if: u64, phandle: Option<u32>) {
+    let gic_dist_if = init_gic_dist(dist, PAGE_SIZE);
+    let dist = gic_dist_if.address;
+    ipi::set_gicv2_distributor(dist);
+
+    // Every CPU sees its own banked CPU interface at the same address
+    let cpu_if = unsafe { map_device_memory(PhysicalAddress::new(cpu_if as usize), PAGE_SIZE).data() };
+    GIC_CPU.call_once(|| GicCpu::V2 { cpu_if, dist });
+    unsafe { register_cpu_interface(crate::percpu::cpu_id(), dist) };
+
+    let mut gic_cpu_if = GicCpuIf::default();
+    unsafe { gic_cpu_if.init(cpu_if) };
+    log::info!("Initialized GIC CPU Interface: {:#x?}", gic_cpu_if);
+
+    let gic = GenericInterruptController {
+        gic_dist_if,
+        gic_cpu_if,
+        irq_range: (0, 0),
+    };
+    register_irq_chip(Gic::new(Box::new(gic), dist, 2), phandle);
+}
+
+/// Initializes a GIC version 3 from the physical address of its distributor and its
+/// redistributors, registering it as the root interrupt domain.
+pub fn init_gic_v3(dist: u64, redistributors: Redistributors, bsp_cpu_id: usize, phandle: Option<u32>) {
+    let gic_dist_if = init_gic_dist(dist, GICD_SIZE_V3);
+    let dist = gic_dist_if.address;
+    GIC_CPU.call_once(|| GicCpu::V3);
+
+    match redistributors {
+        Redistributors::PerCpu(gicrs) => {
+            for &(cpu_id, phys) in gicrs {
+                let virt = unsafe { map_device_memory(PhysicalAddress::new(phys as usize), GICR_SIZE) };
+                set_redistributor(cpu_id, virt.data());
+            }
+        }
+        Redistributors::Regions(regions, cpus) => {
+            for &(phys, size) in regions {
+                let base = unsafe { map_device_memory(PhysicalAddress::new(phys as usize), size as usize) }.data();
+                unsafe { claim_redistributors(base, size as usize, cpus) };
+            }
+        }
     }
 
-    unsafe { IRQ_CHIP.init(None) };
+    unsafe { wake_redistributor(bsp_cpu_id) };
+    let mut gic_cpu_if = GicV3CpuIf;
+    unsafe { gic_cpu_if.init() };
+    log::info!("Initialized GICv3 CPU Interface: {:#x?}", gic_cpu_if);
+
+    let gic = GicV3 {
+        gic_dist_if,
+        gic_cpu_if,
+        gicrs: Vec::new(),
+        irq_range: (0, 0),
+    };
+    register_irq_chip(Gic::new(Box::new(gic), dist, 3), phandle);
 }
 
-/// Initializes GIC version 1 and 2
-fn initialize_gic_v1_v2(giccs: &[&MadtGicc], gic_dist_if: GicDistIf) {
-    for &gicc in giccs.iter().take(1) { // Only support the first GICC for now
-        let mut gic_cpu_if = GicCpuIf::default();
-        unsafe {
-            let phys = PhysicalAddress::new(gicc.physical_base_address as usize);
-            let virt = map_device_memory(phys, PAGE_SIZE);
-            gic_cpu_if.init(virt.data());
+fn set_redistributor(cpu_id: usize, virt: usize) {
+    crate::percpu::allocate(cpu_id);
+    if let Some(gicr) = GICR.get_for(cpu_id) {
+        gicr.store(virt, Ordering::Release);
+    }
+}
+
+/// Walks the redistributors of a mapped region, giving each to the CPU with its
+/// affinity, until the one marked last.
+unsafe fn claim_redistributors(base: usize, size: usize, cpus: &[(usize, u64)]) {
+    let mut offset = 0;
+    while offset + GICR_SIZE <= size {
+        let typer = ptr::read_volatile((base + offset + GICR_TYPER) as *const u64);
+        // GICR_TYPER holds Aff3.Aff2.Aff1.Aff0, while MPIDR has Aff3 apart
+        let affinity = typer >> 32;
+        let affinity = (affinity & 0xFF00_0000) << 8 | affinity & 0xFF_FFFF;
+        match cpus.iter().find(|&&(_, mpidr)| mpidr & MPIDR_AFFINITY == affinity) {
+            Some(&(cpu_id, _)) => set_redistributor(cpu_id, base + offset),
+            None => log::debug!("Redistributor for unknown affinity {:#x}", affinity),
         }
-        log::info!("Initialized GIC CPU Interface: {:#x?}", gic_cpu_if);
 
-        let gic = GenericInterruptController {
-            gic_dist_if,
-            gic_cpu_if,
-            irq_range: (0, 0),
-        };
-        register_irq_chip(Box::new(gic));
+        if typer & GICR_TYPER_LAST != 0 {
+            break;
+        }
+        offset += if typer & GICR_TYPER_VLPIS != 0 { GICR_SIZE_VLPI } else { GICR_SIZE };
     }
 }
 
-/// Initializes GIC version 3
-fn initialize_gic_v3(giccs: &[&MadtGicc], gic_dist_if: GicDistIf) {
-    for &gicc in giccs.iter().take(1) {
-        let mut gic_cpu_if = GicV3CpuIf;
-        unsafe { gic_cpu_if.init() };
-        log::info!("Initialized GICv3 CPU Interface: {:#x?}", gic_cpu_if);
+/// Takes the redistributor of `cpu_id` out of sleep so it forwards interrupts.
+unsafe fn wake_redistributor(cpu_id: usize) {
+    let Some(base) = GICR.get_for(cpu_id).map(|gicr| gicr.load(Ordering::Acquire)).filter(|&base| base != 0) else {
+        return;
+    };
+    let waker = (base + GICR_WAKER) as *mut u32;
+    ptr::write_volatile(waker, ptr::read_volatile(waker) & !GICR_WAKER_PROCESSOR_SLEEP);
+    while ptr::read_volatile(waker) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+        hint::spin_loop();
+    }
+}
 
-        let gic = GicV3 {
-            gic_dist_if,
-            gic_cpu_if,
-            gicrs: Vec::new(), // TODO: Implement GIC Redistributors
-            irq_range: (0, 0),
-        };
-        register_irq_chip(Box::new(gic));
+/// Makes the calling CPU's GICv2 CPU interface number its IPI target. The target
+/// bytes of the SGIs are banked and hold the interface of the CPU reading them.
+unsafe fn register_cpu_interface(cpu_id: usize, dist: usize) {
+    let mask = ptr::read_volatile((dist + GICD_ITARGETSR) as *const u8);
+    // Reads as zero on a uniprocessor implementation
+    if mask != 0 {
+        ipi::register_cpu(cpu_id, u64::from(mask.trailing_zeros()));
+    }
+}
+
+/// Initializes the calling AP's GIC CPU interface, which is banked per CPU.
+unsafe fn init_gic_cpu(cpu_id: usize) {
+    match GIC_CPU.get() {
+        Some(&GicCpu::V2 { cpu_if, dist }) => {
+            GicCpuIf::default().init(cpu_if);
+            register_cpu_interface(cpu_id, dist);
+        }
+        Some(GicCpu::V3) => {
+            wake_redistributor(cpu_id);
+            GicV3CpuIf.init();
+        }
+        None => {}
+    }
+}
+
+/// A GIC driver along with SPI routing through its distributor.
+struct Gic {
+    chip: Box<dyn IrqChip>,
+    /// Mapped distributor.
+    dist: usize,
+    version: u8,
+}
+
+impl Gic {
+    fn new(chip: Box<dyn IrqChip>, dist: usize, version: u8) -> Self {
+        Self { chip, dist, version }
     }
 }
 
-/// Registers an IRQ chip in the global IRQ chip list
-fn register_irq_chip(chip: Box<dyn IrqChip>) {
+impl IrqChip for Gic {
+    fn name(&self) -> &str {
+        self.chip.name()
+    }
+
+    fn hwirq_count(&self) -> u32 {
+        self.chip.hwirq_count()
+    }
+
+    fn xlate(&self, spec: &[u32]) -> Result<IrqSpec, IrqError> {
+        self.chip.xlate(spec)
+    }
+
+    fn configure(&self, spec: IrqSpec) -> Result<(), IrqError> {
+        self.chip.configure(spec)
+    }
+
+    fn enable(&self, hwirq: u32) {
+        self.chip.enable(hwirq)
+    }
+
+    fn disable(&self, hwirq: u32) {
+        self.chip.disable(hwirq)
+    }
+
+    fn ack(&self) -> Option<u32> {
+        self.chip.ack()
+    }
+
+    fn eoi(&self, hwirq: u32) {
+        self.chip.eoi(hwirq)
+    }
+
+    fn init_cpu(&self) {
+        self.chip.init_cpu()
+    }
+
+    /// Writes the target of an SPI: a CPU interface mask before GICv3, an affinity
+    /// from then on. SGIs and PPIs belong to their CPU.
+    fn set_affinity(&self, hwirq: u32, cpu_id: usize) -> Result<(), IrqError> {
+        if !GIC_SPI_RANGE.contains(&hwirq) {
+            return Err(IrqError::Unsupported);
+        }
+        let hw_id = ipi::hw_id(cpu_id).ok_or(IrqError::OutOfRange)?;
+        let intid = hwirq as usize;
+        unsafe {
+            if self.version < 3 {
+                if hw_id >= 8 {
+                    return Err(IrqError::OutOfRange);
+                }
+                ptr::write_volatile((self.dist + GICD_ITARGETSR + intid) as *mut u8, 1 << hw_id);
+            } else {
+                ptr::write_volatile((self.dist + GICD_IROUTER + intid * 8) as *mut u64, hw_id & MPIDR_AFFINITY);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registers the GIC as the root interrupt domain, taking IPIs on their SGI. GSIVs
+/// are GIC INTIDs.
+fn register_irq_chip(gic: Gic, phandle: Option<u32>) {
     let irq_chip_item = IrqChipItem {
-        phandle: 0,
-        parents: Vec::new(),
-        children: Vec::new(),
-        ic: chip,
+        phandle,
+        gsi_base: Some(0),
+        parent: Parent::Cpu,
+        ic: Box::new(gic),
+    };
+    match IRQ_CHIP.register(irq_chip_item) {
+        Ok(id) => ipi::init_irq(id, ipi::IPI_SGI as u32),
+        Err(err) => log::error!("Failed to register the GIC: {:?}", err),
+    }
+}
+
+// ---------- Secondary CPUs ----------
+
+const AP_KERNEL_TIMEOUT_US: u64 = 1_000_000;
+
+/// Arguments of a starting AP, read with the MMU off.
+///
+/// `cpu_id` through `stack_end` are handed to `kstart_ap` by pointer and must stay in order.
+#[repr(C, align(64))]
+struct ApArgs {
+    cpu_id: u64,
+    /// Kernel page table, loaded into TTBR1.
+    page_table: u64,
+    stack_start: u64,
+    stack_end: u64,
+    /// Virtual address of `ap_entry`.
+    code: u64,
+    /// Virtual address of these arguments, passed to `code`.
+    this: u64,
+    /// Identity map of the entry code, loaded into TTBR0.
+    idmap: u64,
+    mair: u64,
+    tcr: u64,
+    sctlr: u64,
+}
+
+/// Physical address of the arguments for an AP woken through the parking protocol
+/// or a spin table, which jump to the entry code without any.
+static PARKED_ARGS: AtomicU64 = AtomicU64::new(0);
+
+// Entered with the MMU off at a physical address, with the arguments in x0, or
+// through the parked entry. Drops to EL1 if started at EL2, turns the MMU on with
+// the identity map still covering this code, then jumps to `ap_entry` in the kernel.
+global_asm!(
+    "
+    .pushsection .text.ap_entry, \"ax\"
+    .balign 4096
+    .global __ap_entry_parked
+__ap_entry_parked:
+    adrp x0, {parked_args}
+    ldr x0, [x0, :lo12:{parked_args}]
+    .global __ap_entry
+__ap_entry:
+    msr daifset, #0xf
+    mrs x1, CurrentEL
+    cmp x1, #(2 << 2)
+    b.ne 1f
+    mov x1, #(1 << 31)
+    msr hcr_el2, x1
+    mov x1, #3
+    msr cnthctl_el2, x1
+    msr cntvoff_el2, xzr
+    mov x1, #0x3c5
+    msr spsr_el2, x1
+    adr x1, 1f
+    msr elr_el2, x1
+    eret
+1:
+    ldr x1, [x0, #{mair}]
+    msr mair_el1, x1
+    ldr x1, [x0, #{tcr}]
+    msr tcr_el1, x1
+    ldr x1, [x0, #{idmap}]
+    msr ttbr0_el1, x1
+    ldr x1, [x0, #{page_table}]
+    msr ttbr1_el1, x1
+    isb
+    tlbi vmalle1
+    dsb nsh
+    isb
+    // Only the entry code is identity mapped, the arguments must be read before the
+    // MMU is on
+    ldr x2, [x0, #{stack_end}]
+    ldr x3, [x0, #{code}]
+    ldr x4, [x0, #{this}]
+    ldr x1, [x0, #{sctlr}]
+    msr sctlr_el1, x1
+    isb
+    mov sp, x2
+    mov x0, x4
+    br x3
+    .popsection
+    ",
+    parked_args = sym PARKED_ARGS,
+    mair = const mem::offset_of!(ApArgs, mair),
+    tcr = const mem::offset_of!(ApArgs, tcr),
+    idmap = const mem::offset_of!(ApArgs, idmap),
+    page_table = const mem::offset_of!(ApArgs, page_table),
+    sctlr = const mem::offset_of!(ApArgs, sctlr),
+    stack_end = const mem::offset_of!(ApArgs, stack_end),
+    code = const mem::offset_of!(ApArgs, code),
+    this = const mem::offset_of!(ApArgs, this),
+);
+
+unsafe extern "C" {
+    fn __ap_entry();
+    fn __ap_entry_parked();
+}
+
+/// First Rust code on an AP, running on its own stack with the kernel mapped.
+unsafe extern "C" fn ap_entry(args: &'static ApArgs) -> ! {
+    let cpu_id = args.cpu_id as usize;
+    crate::percpu::init(cpu_id);
+    crate::cpu::init_ap();
+    init_gic_cpu(cpu_id);
+    ipi::init_cpu();
+    generic_timer::setup();
+    kstart_ap((&raw const args.cpu_id).cast())
+}
+
+/// Returns the physical address behind a kernel virtual address.
+fn kernel_phys(virt: usize) -> Option<usize> {
+    let (phys, _) = KernelMapper::lock().translate(VirtualAddress::new(virt))?;
+    Some(phys.data())
+}
+
+/// Cleans `len` bytes at `addr` to the point of coherency, for a CPU reading them
+/// with its caches off.
+unsafe fn clean_to_poc(addr: usize, len: usize) {
+    let ctr: u64;
+    asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags));
+    let line = 4 << (ctr >> 16 & 0xF);
+    for line_addr in (addr & !(line - 1)..addr + len).step_by(line) {
+        asm!("dc cvac, {}", in(reg) line_addr, options(nostack, preserves_flags));
+    }
+    asm!("dsb sy", options(nostack, preserves_flags));
+}
+
+/// Four-level identity map of the 2 MiB block holding the AP entry code, with the
+/// attributes of its kernel mapping.
+struct IdentityMap {
+    frame: Frame,
+}
+
+impl IdentityMap {
+    const ORDER: u32 = 2;
+    const BLOCK_SIZE: usize = 2 * 1024 * 1024;
+    const TABLE: u64 = 0b11;
+    const BLOCK: u64 = 0b01;
+    const ACCESS_FLAG: u64 = 1 << 10;
+    /// AttrIndx, NS, AP and SH, shared by page and block descriptors.
+    const LOWER_ATTRIBUTES: u64 = 0x3FC;
+
+    unsafe fn new(code: usize) -> Option<Self> {
+        let (code_phys, flags) = KernelMapper::lock().translate(VirtualAddress::new(code))?;
+        let frame = allocate_p2frame(Self::ORDER)?;
+        let base = frame.base().data();
+        let table = |i: usize| RmmA::phys_to_virt(PhysicalAddress::new(base + i * PAGE_SIZE)).data() as *mut u64;
+        ptr::write_bytes(table(0) as *mut u8, 0, 3 * PAGE_SIZE);
+
+        let phys = code_phys.data() & !(Self::BLOCK_SIZE - 1);
+        *table(0).add(phys >> 39 & 0x1FF) = (base + PAGE_SIZE) as u64 | Self::TABLE;
+        *table(1).add(phys >> 30 & 0x1FF) = (base + 2 * PAGE_SIZE) as u64 | Self::TABLE;
+        *table(2).add(phys >> 21 & 0x1FF) =
+            phys as u64 | (flags.data() as u64 & Self::LOWER_ATTRIBUTES) | Self::ACCESS_FLAG | Self::BLOCK;
+        clean_to_poc(table(0) as usize, 3 * PAGE_SIZE);
+
+        Some(Self { frame })
+    }
+
+    fn root(&self) -> u64 {
+        self.frame.base().data() as u64
+    }
+}
+
+impl Drop for IdentityMap {
+    fn drop(&mut self) {
+        unsafe { deallocate_p2frame(self.frame, Self::ORDER) };
+    }
+}
+
+/// How an AP is released from firmware.
+#[derive(Clone, Copy, Debug)]
+pub enum ApMethod {
+    /// PSCI `CPU_ON`, after [`psci::init`] or [`psci::init_conduit`].
+    Psci,
+    /// ACPI parking protocol mailbox, version 1.
+    Parked { mailbox: u64, cpu_interface: u32 },
+    /// Device tree `spin-table`: the CPU waits in a loop for an entry point to
+    /// appear at `release_addr`, then jumps to it.
+    SpinTable { release_addr: u64 },
+}
+
+/// A secondary CPU to start.
+#[derive(Clone, Copy, Debug)]
+pub struct ApTarget {
+    pub cpu_id: usize,
+    pub mpidr: u64,
+    pub method: ApMethod,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ApStartError {
+    Psci(psci::PsciError),
+    /// The parking protocol mailbox is still in use.
+    MailboxBusy,
+    /// The CPU did not reach the kernel in time.
+    Timeout,
+}
+
+/// Spins until `done` returns true or `timeout_us` microseconds have elapsed on the
+/// generic timer.
+fn wait_for(timeout_us: u64, mut done: impl FnMut() -> bool) -> bool {
+    let (freq, start): (u64, u64);
+    unsafe {
+        asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nomem, nostack, preserves_flags));
+        asm!("isb", "mrs {}, cntpct_el0", out(reg) start, options(nomem, nostack, preserves_flags));
+    }
+    let ticks = timeout_us * freq / 1_000_000;
+
+    while !done() {
+        let now: u64;
+        unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) now, options(nomem, nostack, preserves_flags)) };
+        if now.wrapping_sub(start) >= ticks {
+            return false;
+        }
+        hint::spin_loop();
+    }
+    true
+}
+
+/// Starts the given secondary CPUs one after the other, whether they were found in
+/// the MADT or a device tree. Each must already be registered with [`ipi`].
+pub fn start_aps(targets: &[ApTarget]) {
+    if targets.is_empty() {
+        return;
+    }
+    let Some(idmap) = (unsafe { IdentityMap::new(__ap_entry as usize) }) else {
+        log::error!("Could not identity map the AP entry code, not starting APs");
+        return;
+    };
+    let mut leaked = false;
+
+    for target in targets {
+        CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+        match start_ap(target, &idmap) {
+            Ok(()) => {
+                ipi::set_online(target.cpu_id, true);
+                if cfg!(debug_assertions) {
+                    println!("    CPU {} (MPIDR {:#x}) started", target.cpu_id, target.mpidr);
+                }
+            }
+            Err(err) => {
+                CPU_COUNT.fetch_sub(1, Ordering::SeqCst);
+                // A CPU that timed out may still come up later through the identity map
+                leaked |= err == ApStartError::Timeout;
+                log::error!("AP startup failed: CPU {} (MPIDR {:#x}): {:?}", target.cpu_id, target.mpidr, err);
+            }
+        }
+    }
+
+    if leaked {
+        mem::forget(idmap);
+    }
+}
+
+fn start_ap(target: &ApTarget, idmap: &IdentityMap) -> Result<(), ApStartError> {
+    let cpu_id = target.cpu_id;
+    let stack_frame = allocate_p2frame(4).expect("no more frames for AP stack");
+    let args_frame = allocate_p2frame(0).expect("no more frames for AP arguments");
+    let stack_start = RmmA::phys_to_virt(stack_frame.base()).data();
+    let args_phys = args_frame.base().data();
+    let args_virt = RmmA::phys_to_virt(args_frame.base()).data();
+    crate::percpu::allocate(cpu_id);
+
+    unsafe {
+        let (mair, tcr, ttbr1, sctlr): (u64, u64, u64, u64);
+        asm!(
+            "mrs {}, mair_el1",
+            "mrs {}, tcr_el1",
+            "mrs {}, ttbr1_el1",
+            "mrs {}, sctlr_el1",
+            out(reg) mair, out(reg) tcr, out(reg) ttbr1, out(reg) sctlr,
+            options(nomem, nostack, preserves_flags)
+        );
+        // TTBR0 walks the identity map: 48-bit, 4 KiB granule, inner shareable write-back
+        let tcr = tcr & !0xFFFF | 16 | 0b01 << 8 | 0b01 << 10 | 0b11 << 12;
+
+        ptr::write(
+            args_virt as *mut ApArgs,
+            ApArgs {
+                cpu_id: cpu_id as u64,
+                page_table: ttbr1,
+                stack_start: stack_start as u64,
+                stack_end: (stack_start + (PAGE_SIZE << 4)) as u64,
+                code: ap_entry as u64,
+                this: args_virt as u64,
+                idmap: idmap.root(),
+                mair,
+                tcr,
+                sctlr,
+            },
+        );
+        clean_to_poc(args_virt, mem::size_of::<ApArgs>());
+    }
+
+    AP_READY.store(false, Ordering::SeqCst);
+    let started = match target.method {
+        ApMethod::Psci => {
+            let entry = kernel_phys(__ap_entry as usize).expect("AP entry code not mapped");
+            psci::cpu_on(target.mpidr, entry as u64, args_phys as u64).map_err(ApStartError::Psci)
+        }
+        ApMethod::Parked { mailbox, cpu_interface } => unsafe { wake_parked(cpu_id, mailbox, cpu_interface, args_phys) },
+        ApMethod::SpinTable { release_addr } => unsafe {
+            release_spin_table(release_addr, args_phys);
+            Ok(())
+        },
     };
-    unsafe { IRQ_CHIP.irq_chip_list.chips.push(irq_chip_item) };
-}
\ No newline at end of file
+
+    let result = started.and_then(|()| {
+        if wait_for(AP_KERNEL_TIMEOUT_US, || AP_READY.load(Ordering::SeqCst)) {
+            Ok(())
+        } else {
+            Err(ApStartError::Timeout)
+        }
+    });
+
+    match result {
+        // The stack is kept, while the arguments were copied out by `kstart_ap`
+        Ok(()) => unsafe { deallocate_p2frame(args_frame, 0) },
+        // The CPU never left the firmware, so nothing of this is in use
+        Err(ApStartError::Psci(_) | ApStartError::MailboxBusy) => unsafe {
+            deallocate_p2frame(args_frame, 0);
+            deallocate_p2frame(stack_frame, 4);
+        },
+        Err(ApStartError::Timeout) => {}
+    }
+    result
+}
+
+/// Hands the arguments to the parked entry, which reads them with the MMU off.
+unsafe fn set_parked_args(args_phys: usize) {
+    PARKED_ARGS.store(args_phys as u64, Ordering::SeqCst);
+    clean_to_poc(&raw const PARKED_ARGS as usize, mem::size_of::<u64>());
+}
+
+/// Mailbox of the ACPI parking protocol, version 1.
+const MAILBOX_CPU_ID: usize = 0x0;
+const MAILBOX_JUMP_ADDRESS: usize = 0x8;
+/// CPU ID the mailbox holds while no wakeup is pending.
+const MAILBOX_CPU_ID_FREE: u32 = !0;
+
+/// Wakes a CPU waiting in its parking protocol mailbox. The firmware clears the jump
+/// address once the CPU has left.
+unsafe fn wake_parked(cpu_id: usize, mailbox: u64, cpu_interface: u32, args_phys: usize) -> Result<(), ApStartError> {
+    let mailbox = map_device_memory(PhysicalAddress::new(mailbox as usize), PAGE_SIZE).data();
+    let mailbox_cpu_id = (mailbox + MAILBOX_CPU_ID) as *mut u32;
+    let jump_address = (mailbox + MAILBOX_JUMP_ADDRESS) as *mut u64;
+    if ptr::read_volatile(mailbox_cpu_id) != MAILBOX_CPU_ID_FREE {
+        return Err(ApStartError::MailboxBusy);
+    }
+
+    set_parked_args(args_phys);
+    let entry = kernel_phys(__ap_entry_parked as usize).expect("AP entry code not mapped");
+    ptr::write_volatile(jump_address, entry as u64);
+    ptr::write_volatile(mailbox_cpu_id, cpu_interface);
+    asm!("dsb sy", options(nostack, preserves_flags));
+
+    // The CPU only wakes from WFI once its SGI is sent, which needs no online check
+    let _ = ipi::wake(cpu_id);
+    Ok(())
+}
+
+/// Releases a CPU spinning on a spin table, which polls `release_addr` with its
+/// caches off and waits for events in between.
+unsafe fn release_spin_table(release_addr: u64, args_phys: usize) {
+    set_parked_args(args_phys);
+    let entry = kernel_phys(__ap_entry_parked as usize).expect("AP entry code not mapped");
+    let release = RmmA::phys_to_virt(PhysicalAddress::new(release_addr as usize)).data();
+    ptr::write_volatile(release as *mut u64, entry as u64);
+    clean_to_poc(release, mem::size_of::<u64>());
+    asm!("sev", options(nomem, nostack, preserves_flags));
+}
@@ -2,6 +2,9 @@ use super::Madt;
 
 // Initialize MADT (Multiple APIC Descriptor Table)
 pub(super) fn init(madt: Madt) {
+    // Other CPUs are not enumerated here, so the boot CPU is CPU 0
+    unsafe { crate::percpu::init(0) };
+
     // Log all MADT entries if debugging is enabled, but avoid unnecessary iteration in production
     #[cfg(debug_assertions)]
     {
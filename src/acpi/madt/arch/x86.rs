@@ -1,19 +1,61 @@
-use core::sync::atomic::{AtomicU8, Ordering};
+use alloc::vec::Vec;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::{Mutex, Once};
 use crate::{
-    device::local_apic::the_local_apic,
-    interrupt,
-    memory::{allocate_p2frame, Frame, KernelMapper},
-    paging::{Page, PageFlags, PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+    affinity,
+    device::{
+        delay, hpet, idt, ioapic, lapic_timer,
+        local_apic::{self, the_local_apic, LocalApic},
+        pic, tsc, x2apic,
+    },
+    interrupt, ipi,
+    memory::{allocate_frame_at, allocate_p2frame, deallocate_p2frame, Frame, KernelMapper},
+    paging::{Page, PageFlags, PhysicalAddress, VirtualAddress, PAGE_SIZE},
+    percpu::{irq_restore, irq_save},
+    softirq,
     start::{kstart_ap, AP_READY, CPU_COUNT},
+    time,
+};
+use super::{
+    Madt, MadtEntry, MadtLocalApic, MadtLocalX2Apic, FLAG_PCAT, LOCAL_APIC_ENABLED, LOCAL_APIC_ONLINE_CAPABLE,
 };
-use super::{Madt, MadtEntry};
 
-const TRAMPOLINE: usize = 0x8000;
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
+/// Real-mode page holding the AP trampoline, chosen from the firmware memory map.
+static TRAMPOLINE: Once<Frame> = Once::new();
+
+/// SIPI vectors can only name pages below 1 MiB, and 0xA0000 up is VGA and ROM space.
+const TRAMPOLINE_LIMIT: usize = 0xA_0000;
+
 pub(super) fn init(madt: Madt) {
+    let pcat_compat = madt.flags & FLAG_PCAT == FLAG_PCAT;
+    if pcat_compat {
+        // Move the 8259s off the exception vectors and silence them before the local APIC takes over
+        unsafe { pic::disable() };
+    }
+
+    // x2APIC has to be chosen, and interrupt remapping set up, before anything is routed
+    let max_apic_id = madt.iter().filter_map(Processor::from_entry).map(|cpu| cpu.apic_id).max().unwrap_or(0);
     let local_apic = unsafe { the_local_apic() };
-    let me = local_apic.id() as u8;
+    local_apic.x2 = unsafe { x2apic::init(max_apic_id) };
+    let me = local_apic.id();
+    // The paging mode is settled before the APs are started into it
+    unsafe { init_paging() };
+
+    // The BSP is logical CPU 0, whatever its APIC ID and processor UID
+    let bsp = madt.iter().filter_map(Processor::from_entry).find(|cpu| cpu.apic_id == me).unwrap_or(Processor {
+        uid: 0,
+        apic_id: me,
+        flags: LOCAL_APIC_ENABLED,
+    });
+    let bsp_id = register_cpu(bsp, true);
+    unsafe { crate::percpu::init(bsp_id) };
+    // IPIs are taken from here on, and APs load the same IDT
+    unsafe { idt::init() };
 
     // Log APIC info (Conditional for debugging)
     if cfg!(debug_assertions) {
@@ -24,136 +66,833 @@ pub(super) fn init(madt: Madt) {
         }
     }
 
+    // Route legacy and PCI interrupts through the I/O APICs, all masked until claimed
+    unsafe { ioapic::init(me) };
+    if ioapic::ioapics().is_empty() && pcat_compat {
+        unsafe { pic::enable_fallback() };
+    }
+    setup_cpu_timers();
+
     if cfg!(feature = "multi_core") {
-        // Map trampoline once and reuse throughout the function
-        let trampoline_frame = Frame::containing(PhysicalAddress::new(TRAMPOLINE));
-        let trampoline_page = Page::containing_address(VirtualAddress::new(TRAMPOLINE));
-        let (result, page_table_physaddr) = unsafe {
-            let mut mapper = KernelMapper::lock();
-            let result = mapper
-                .get_mut()
-                .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                .map_phys(trampoline_page.start_address(), trampoline_frame.base(), PageFlags::new().execute(true).write(true))
-                .expect("failed to map trampoline");
-
-            (result, mapper.table().phys().data())
-        };
-        result.flush();
+        let started = with_trampoline(|page_table_physaddr| start_aps(madt, local_apic, me, page_table_physaddr));
+        if started.is_none() {
+            log::error!("No free page below {:#x} for the AP trampoline, not starting APs", TRAMPOLINE_LIMIT);
+        }
+        // The TSC is only a clocksource if the APs started with it in step
+        tsc::check_sync();
+    }
+}
+
+/// Switches the BSP to 5-level paging on x86_64, or to PAE paging on i686, where
+/// the CPU supports it.
+unsafe fn init_paging() {
+    #[cfg(target_arch = "x86_64")]
+    crate::paging::la57::init();
+    #[cfg(target_arch = "x86")]
+    if let Err(err) = crate::paging::pae::init() {
+        log::warn!("Paging: staying 32-bit, {}", err);
+    }
+}
 
-        // Efficiently write trampoline data without atomic operations
-        unsafe {
-            let trampoline_ptr = TRAMPOLINE as *mut u8;
-            core::ptr::copy_nonoverlapping(TRAMPOLINE_DATA.as_ptr(), trampoline_ptr, TRAMPOLINE_DATA.len());
+/// Sets the trampoline up so APs use the same paging mode as the BSP, returning
+/// the table they should load into CR3.
+fn trampoline_paging(page_table_physaddr: usize) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    let page_table_physaddr = {
+        use crate::paging::la57;
+
+        // APs must use the same paging depth as the BSP
+        if la57::levels() == la57::PagingLevels::Five {
+            unsafe { (*trampoline_header()).cr4 |= la57::CR4_LA57 as u64 };
         }
+        la57::root_table_phys(page_table_physaddr)
+    };
+    #[cfg(target_arch = "x86")]
+    let page_table_physaddr = {
+        use crate::paging::pae;
 
-        // Iterate over MADT entries and handle each APIC
-        for madt_entry in madt.iter() {
+        // APs must use the same paging mode as the BSP
+        if pae::is_enabled() {
+            let header = unsafe { &mut *trampoline_header() };
+            header.cr4 |= pae::CR4_PAE as u64;
+            if pae::nx_enabled() {
+                header.efer |= pae::EFER_NXE as u64;
+            }
+        }
+        pae::root_table_phys(page_table_physaddr)
+    };
+
+    page_table_physaddr
+}
+
+/// Starts every enabled AP listed in the MADT through the installed trampoline.
+fn start_aps(madt: Madt, local_apic: &mut LocalApic, me: u32, page_table_physaddr: usize) {
+    let page_table_physaddr = trampoline_paging(page_table_physaddr);
+
+    // Collect the APs to start, each with its own trampoline slot
+    let mut aps = Vec::new();
+    for madt_entry in madt.iter() {
+        if cfg!(debug_assertions) {
+            println!("      {:x?}", madt_entry);
+        }
+        let Some(processor) = Processor::from_entry(madt_entry) else {
+            continue;
+        };
+        if processor.apic_id == me {
             if cfg!(debug_assertions) {
-                println!("      {:x?}", madt_entry);
+                println!("        This is my local APIC");
             }
-            match madt_entry {
-                MadtEntry::LocalApic(ap_local_apic) => {
-                    if ap_local_apic.id == me {
-                        if cfg!(debug_assertions) {
-                            println!("        This is my local APIC");
-                        }
-                    } else if ap_local_apic.flags & 1 == 1 {
-                        // Enable CPU if not disabled
-                        CPU_COUNT.fetch_add(1, Ordering::SeqCst);
-
-                        // Allocate a stack frame for the new AP
-                        let stack_start = allocate_p2frame(4)
-                            .expect("no more frames for ACPI stack")
-                            .base()
-                            .data()
-                            + crate::PHYS_OFFSET;
-                        let stack_end = stack_start + (PAGE_SIZE << 4);
-
-                        let ap_ready = (TRAMPOLINE + 8) as *mut u64;
-                        let ap_cpu_id = unsafe { ap_ready.add(1) };
-                        let ap_page_table = unsafe { ap_ready.add(2) };
-                        let ap_stack_start = unsafe { ap_ready.add(3) };
-                        let ap_stack_end = unsafe { ap_ready.add(4) };
-                        let ap_code = unsafe { ap_ready.add(5) };
-
-                        // Initialize AP control structures atomically
-                        unsafe {
-                            ap_ready.write(0);
-                            ap_cpu_id.write(ap_local_apic.processor.into());
-                            ap_page_table.write(page_table_physaddr as u64);
-                            ap_stack_start.write(stack_start as u64);
-                            ap_stack_end.write(stack_end as u64);
-                            ap_code.write(kstart_ap as u64);
-
-                            // Optional: Fence or memory barrier
-                            core::arch::asm!("");
-                        }
-                        AP_READY.store(false, Ordering::SeqCst);
-
-                        // Send INIT IPI to the target AP
-                        let mut icr = 0x4500;
-                        if local_apic.x2 {
-                            icr |= (ap_local_apic.id as u64) << 32;
-                        } else {
-                            icr |= (ap_local_apic.id as u64) << 56;
-                        }
-                        if cfg!(debug_assertions) {
-                            print!(" IPI...");
-                        }
-                        local_apic.set_icr(icr);
-
-                        // Send START IPI
-                        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
-                        let mut icr = 0x4600 | ap_segment as u64;
-                        if local_apic.x2 {
-                            icr |= (ap_local_apic.id as u64) << 32;
-                        } else {
-                            icr |= (ap_local_apic.id as u64) << 56;
-                        }
-                        if cfg!(debug_assertions) {
-                            print!(" SIPI...");
-                        }
-                        local_apic.set_icr(icr);
-
-                        // Wait for the AP to be ready
-                        if cfg!(debug_assertions) {
-                            print!(" Wait...");
-                        }
-                        while unsafe { (*ap_ready.cast::<AtomicU8>()).load(Ordering::SeqCst) } == 0 {
-                            interrupt::pause();
-                        }
-
-                        // Ensure the AP trampoline is set up
-                        while !AP_READY.load(Ordering::SeqCst) {
-                            interrupt::pause();
-                        }
-
-                        if cfg!(debug_assertions) {
-                            println!(" Ready");
-                        }
-
-                        // Invalidate RMM (if necessary)
-                        unsafe {
-                            RmmA::invalidate_all();
-                        }
-                    } else {
-                        if cfg!(debug_assertions) {
-                            println!("        CPU Disabled");
-                        }
-                    }
-                }
-                _ => (),
+        } else if processor.apic_id > x2apic::MAX_XAPIC_ID && !local_apic.x2 {
+            log::warn!("CPU {} has APIC ID {}, which needs x2APIC mode", processor.uid, processor.apic_id);
+        } else if processor.flags & LOCAL_APIC_ENABLED != 0 {
+            // Firmware may list a CPU both as local APIC and as local x2APIC
+            if aps.iter().any(|(_, ap): &(usize, Processor)| ap.apic_id == processor.apic_id) {
+                continue;
+            }
+            let cpu_id = register_cpu(processor, false);
+            aps.push((cpu_id, processor));
+        } else if processor.flags & LOCAL_APIC_ONLINE_CAPABLE != 0 {
+            if cfg!(debug_assertions) {
+                println!("        CPU Online Capable");
+            }
+            register_cpu(processor, false);
+        } else {
+            if cfg!(debug_assertions) {
+                println!("        CPU Disabled");
+            }
+        }
+    }
+
+    // One slot per AP, found through the APIC ID at the same index; APs without a
+    // slot park themselves in the trampoline
+    let apic_ids: Vec<u32> = aps.iter().map(|(_, processor)| processor.apic_id).collect();
+    let mut slots: Vec<ApSlot> = aps.iter().map(|_| ApSlot::default()).collect();
+    unsafe { install_slots(&slots, &apic_ids, page_table_physaddr) };
+
+    let stacks: Vec<_> = aps
+        .iter()
+        .zip(&mut slots)
+        .map(|(&(cpu_id, _), slot)| {
+            // Enable CPU if not disabled
+            CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+            slot.prepare(cpu_id as u64, page_table_physaddr as u64)
+        })
+        .collect();
+    let pending: Vec<_> = aps
+        .into_iter()
+        .zip(stacks)
+        .zip(&slots)
+        .map(|(((cpu_id, processor), stack_frame), slot)| ApStart {
+            slot,
+            cpu_id,
+            processor,
+            stack_frame,
+        })
+        .collect();
+
+    let mut failures = Vec::new();
+    let pending = if cfg!(feature = "serial_ap_startup") {
+        pending
+    } else {
+        start_aps_parallel(local_apic, pending, &mut failures)
+    };
+
+    // Whatever did not come up in parallel gets one more, serial, attempt
+    for ap in pending {
+        if let Err(stage) = start_ap(local_apic, &ap) {
+            failures.push(ap.fail(local_apic, stage));
+        }
+    }
+
+    if !failures.is_empty() {
+        log::warn!(
+            "{} AP(s) failed to start, continuing with {} CPU(s)",
+            failures.len(),
+            CPU_COUNT.load(Ordering::SeqCst)
+        );
+    }
+
+    // Flush every CPU now running, as they started on the BSP's page tables
+    ipi::tlb_shootdown_all();
+
+    // Every started AP has copied its arguments out of its slot by now
+    drop(slots);
+    drop(apic_ids);
+}
+
+/// Points the trampoline at the slot array, the APIC ID of each slot's AP and the
+/// page table APs should load.
+///
+/// `slots` and `apic_ids` must outlive every AP started through them.
+unsafe fn install_slots(slots: &[ApSlot], apic_ids: &[u32], page_table_physaddr: usize) {
+    assert_eq!(slots.len(), apic_ids.len());
+    let header = &mut *trampoline_header();
+    header.page_table = page_table_physaddr as u64;
+    header.slots = slots.as_ptr() as u64;
+    header.slot_count = slots.len() as u64;
+    header.apic_ids = apic_ids.as_ptr() as u64;
+}
+
+/// Reserves, maps and fills in the AP trampoline, then runs `f` with the physical
+/// address of the kernel page table. The identity mapping is removed afterwards.
+///
+/// Returns `None` if no page below `TRAMPOLINE_LIMIT` is free.
+pub fn with_trampoline<R>(f: impl FnOnce(usize) -> R) -> Option<R> {
+    let trampoline_frame = reserve_trampoline()?;
+    if cfg!(debug_assertions) {
+        println!("    Trampoline at {:#x}", trampoline_frame.base().data());
+    }
+
+    // Map trampoline once and reuse throughout the function
+    let trampoline_page = Page::containing_address(VirtualAddress::new(trampoline_frame.base().data()));
+    let (result, page_table_physaddr) = unsafe {
+        let mut mapper = KernelMapper::lock();
+        let result = mapper
+            .get_mut()
+            .expect("expected kernel page table not to be recursively locked while mapping the trampoline")
+            .map_phys(trampoline_page.start_address(), trampoline_frame.base(), PageFlags::new().execute(true).write(true))
+            .expect("failed to map trampoline");
+
+        (result, mapper.table().phys().data())
+    };
+    result.flush();
+
+    // Efficiently write trampoline data without atomic operations
+    unsafe {
+        let trampoline_ptr = trampoline() as *mut u8;
+        core::ptr::copy_nonoverlapping(TRAMPOLINE_DATA.as_ptr(), trampoline_ptr, TRAMPOLINE_DATA.len());
+        relocate_trampoline();
+    }
+
+    let ret = f(page_table_physaddr);
+
+    // Unmap trampoline after usage
+    unsafe {
+        let (_frame, _, flush) = KernelMapper::lock()
+            .get_mut()
+            .expect("expected kernel page table not to be recursively locked while unmapping the trampoline")
+            .unmap_phys(trampoline_page.start_address(), true)
+            .expect("failed to unmap trampoline page");
+        // APs started meanwhile may still hold the identity mapping
+        flush.ignore();
+    }
+    let mut shootdown = ipi::TlbShootdown::new();
+    shootdown.add_page(trampoline_page);
+    shootdown.finish();
+
+    Some(ret)
+}
+
+/// Returns the identity-mapped address of the trampoline's paging mode switch routine.
+///
+/// Only valid inside `with_trampoline`.
+pub fn switch_paging_entry() -> usize {
+    unsafe { trampoline() + (*trampoline_header()).switch_paging as usize }
+}
+
+/// A CPU from a local APIC or local x2APIC entry of the MADT.
+#[derive(Clone, Copy, Debug)]
+struct Processor {
+    /// ACPI processor UID.
+    uid: u32,
+    apic_id: u32,
+    flags: u32,
+}
+
+impl Processor {
+    fn from_entry(entry: MadtEntry) -> Option<Self> {
+        match entry {
+            MadtEntry::LocalApic(local_apic) => Some(Self::from(local_apic)),
+            MadtEntry::LocalX2Apic(local_x2apic) => Some(Self::from(local_x2apic)),
+            _ => None,
+        }
+    }
+}
+
+impl From<&MadtLocalApic> for Processor {
+    fn from(local_apic: &MadtLocalApic) -> Self {
+        Self {
+            uid: local_apic.processor.into(),
+            apic_id: local_apic.id.into(),
+            flags: local_apic.flags,
+        }
+    }
+}
+
+impl From<&MadtLocalX2Apic> for Processor {
+    fn from(local_x2apic: &MadtLocalX2Apic) -> Self {
+        Self {
+            uid: local_x2apic.processor_uid,
+            apic_id: local_x2apic.x2apic_id,
+            flags: local_x2apic.flags,
+        }
+    }
+}
+
+/// How long to wait for an AP to reach the trampoline and then the kernel.
+const AP_TRAMPOLINE_TIMEOUT_US: u64 = 100_000;
+const AP_KERNEL_TIMEOUT_US: u64 = 1_000_000;
+
+/// Step of AP bring-up at which a CPU stopped responding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApStartupStage {
+    /// The AP never signalled that it left the real-mode trampoline.
+    Trampoline,
+    /// The AP left the trampoline but never reached `kstart_ap`.
+    Kernel,
+}
+
+/// A CPU listed as enabled in the MADT that could not be brought online.
+#[derive(Clone, Copy, Debug)]
+pub struct ApFailure {
+    pub processor: u32,
+    pub apic_id: u32,
+    pub stage: ApStartupStage,
+}
+
+/// Data shared by every AP going through the trampoline.
+#[repr(C)]
+struct TrampolineHeader {
+    page_table: u64,
+    slots: u64,
+    slot_count: u64,
+    /// Offset of the relocation list: a `u16` count, then `u16` offsets of `u32` fields.
+    relocs: u64,
+    /// Extra CR4 bits set by APs before enabling paging.
+    cr4: u64,
+    /// Offset of the paging mode switch routine, zero if there is none.
+    switch_paging: u64,
+    /// Extra EFER bits set by APs before enabling paging.
+    efer: u64,
+    /// APIC ID of the AP each slot is for, as `u32`s.
+    apic_ids: u64,
+}
+
+/// Per-AP trampoline slot, one cache line each so that APs checking in do not contend.
+///
+/// `cpu_id` through `stack_end` are handed to `kstart_ap` by pointer and must stay in order.
+#[repr(C, align(64))]
+#[derive(Default)]
+struct ApSlot {
+    cpu_id: u64,
+    page_table: u64,
+    stack_start: u64,
+    stack_end: u64,
+    code: u64,
+    /// Set by the AP once it has reached long mode (protected mode on i686).
+    ready: AtomicU64,
+    /// Set by the BSP to let the AP jump to `code`.
+    go: AtomicU64,
+}
+
+impl ApSlot {
+    /// Fills in the slot for an AP and allocates its stack and per-CPU area.
+    fn prepare(&mut self, cpu_id: u64, page_table: u64) -> Frame {
+        let stack_frame = allocate_p2frame(4).expect("no more frames for ACPI stack");
+        let stack_start = stack_frame.base().data() + crate::PHYS_OFFSET;
+        let stack_end = stack_start + (PAGE_SIZE << 4);
+
+        // The AP only has to point its base register at it in `ap_entry`
+        crate::percpu::allocate(cpu_id as usize);
+
+        self.cpu_id = cpu_id;
+        self.page_table = page_table;
+        self.stack_start = stack_start as u64;
+        self.stack_end = stack_end as u64;
+        self.code = ap_entry as u64;
+        *self.ready.get_mut() = 0;
+        *self.go.get_mut() = 0;
+
+        stack_frame
+    }
+}
+
+/// First Rust code on an AP, running on its own stack with the kernel's page tables.
+/// `args` points at `cpu_id` in the AP's slot, followed by what `kstart_ap` takes.
+unsafe extern "C" fn ap_entry(args: *const u64) -> ! {
+    let cpu_id = args.read() as usize;
+    crate::percpu::init(cpu_id);
+    #[cfg(target_arch = "x86_64")]
+    crate::paging::la57::init_ap();
+    crate::cpu::init_ap();
+    idt::load();
+    // The local APIC has to be in the BSP's mode before kstart_ap touches it
+    crate::device::x2apic::init_ap();
+    // Enabled here rather than in kstart_ap, as the timer is set up right away
+    local_apic::init_ap();
+    setup_cpu_timers();
+    kstart_ap(args.cast())
+}
+
+/// Sets the clock-event devices of the calling CPU up, once its local APIC is enabled:
+/// the local APIC timer, and an HPET comparator of its own if that timer stops in
+/// deep C-states. The better rated one is used.
+fn setup_cpu_timers() {
+    lapic_timer::setup();
+    hpet::setup_cpu_timer();
+}
+
+/// Returns the frame reserved for the AP trampoline, which is kept for S3 resume.
+pub fn trampoline_frame() -> Option<Frame> {
+    TRAMPOLINE.get().copied()
+}
+
+#[inline(always)]
+fn trampoline() -> usize {
+    TRAMPOLINE.get().expect("AP trampoline not reserved").base().data()
+}
+
+/// Shared trampoline data is placed right after the initial jump.
+#[inline(always)]
+fn trampoline_header() -> *mut TrampolineHeader {
+    (trampoline() + 8) as *mut TrampolineHeader
+}
+
+/// Takes the first usable page below `TRAMPOLINE_LIMIT` in the boot memory map that
+/// the frame allocator still has, skipping page 0 (real-mode IVT and BIOS data area).
+/// The page is never given back, so nothing else can be put there while it is kept
+/// for S3 resume.
+fn reserve_trampoline() -> Option<Frame> {
+    assert!(TRAMPOLINE_DATA.len() <= PAGE_SIZE, "AP trampoline does not fit in a page");
+
+    // Serializes the first reservation, so a single page is taken
+    static RESERVE_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = RESERVE_LOCK.lock();
+    if let Some(&frame) = TRAMPOLINE.get() {
+        return Some(frame);
+    }
+
+    let frame = crate::memory::areas().iter().find_map(|area| {
+        let start = area.base.data().max(PAGE_SIZE).next_multiple_of(PAGE_SIZE);
+        let end = (area.base.data() + area.size).min(TRAMPOLINE_LIMIT);
+        (start..end.saturating_sub(PAGE_SIZE - 1))
+            .step_by(PAGE_SIZE)
+            .find_map(|base| allocate_frame_at(Frame::containing(PhysicalAddress::new(base))))
+    })?;
+
+    Some(*TRAMPOLINE.call_once(|| frame))
+}
+
+/// Adds the trampoline's load address to every field listed in its relocation table.
+unsafe fn relocate_trampoline() {
+    let base = trampoline();
+    let relocs = (base + (*trampoline_header()).relocs as usize) as *const u16;
+    let count = relocs.read_unaligned();
+
+    for i in 0..usize::from(count) {
+        let offset = usize::from(relocs.add(1 + i).read_unaligned());
+        let field = (base + offset) as *mut u32;
+        field.write_unaligned(field.read_unaligned() + base as u32);
+    }
+}
+
+/// An AP being brought up, with its slot filled in.
+struct ApStart<'a> {
+    slot: &'a ApSlot,
+    /// Logical CPU ID.
+    cpu_id: usize,
+    processor: Processor,
+    stack_frame: Frame,
+}
+
+impl ApStart<'_> {
+    #[inline(always)]
+    fn apic_id(&self) -> u32 {
+        self.processor.apic_id
+    }
+
+    #[inline(always)]
+    fn in_trampoline(&self) -> bool {
+        self.slot.ready.load(Ordering::SeqCst) != 0
+    }
+
+    /// Lets the AP leave the trampoline and waits for it to finish `kstart_ap`.
+    fn release(&self) -> Result<(), ApStartupStage> {
+        AP_READY.store(false, Ordering::SeqCst);
+        self.slot.go.store(1, Ordering::SeqCst);
+        if wait_for(AP_KERNEL_TIMEOUT_US, || AP_READY.load(Ordering::SeqCst)) {
+            with_cpu(self.apic_id(), |cpu| {
+                cpu.state = CpuState::Online;
+                cpu.stack = Some(self.stack_frame);
+            });
+            ipi::set_online(self.cpu_id, true);
+            Ok(())
+        } else {
+            Err(ApStartupStage::Kernel)
+        }
+    }
+
+    /// Parks a CPU that did not start and gives back its resources.
+    fn fail(self, local_apic: &mut LocalApic, stage: ApStartupStage) -> ApFailure {
+        // Put the AP back into wait-for-SIPI so a late start cannot reuse the trampoline
+        // or the stack, which is only safe to free once the AP is held in INIT.
+        local_apic.set_icr(icr_for(local_apic, self.apic_id(), ICR_INIT));
+        delay::udelay(200);
+        unsafe { deallocate_p2frame(self.stack_frame, 4) };
+
+        CPU_COUNT.fetch_sub(1, Ordering::SeqCst);
+        let failure = ApFailure {
+            processor: self.processor.uid,
+            apic_id: self.processor.apic_id,
+            stage,
+        };
+        log::error!("AP startup failed: {:?}", failure);
+        failure
+    }
+}
+
+const ICR_INIT: u64 = 0x4500;
+
+/// STARTUP IPI whose vector is the trampoline's page number.
+#[inline(always)]
+fn icr_startup() -> u64 {
+    0x4600 | ((trampoline() >> 12) & 0xFF) as u64
+}
+
+/// Forms an ICR value addressed to `apic_id`.
+fn icr_for(local_apic: &LocalApic, apic_id: u32, low: u64) -> u64 {
+    if local_apic.x2 {
+        low | (u64::from(apic_id) << 32)
+    } else {
+        assert!(apic_id <= 0xFF, "APIC ID {} needs x2APIC mode", apic_id);
+        low | (u64::from(apic_id) << 56)
+    }
+}
+
+/// Spins until `done` returns true or `timeout_us` microseconds have elapsed.
+fn wait_for(timeout_us: u64, mut done: impl FnMut() -> bool) -> bool {
+    const POLL_US: u64 = 10;
+
+    let mut waited = 0;
+    while !done() {
+        if waited >= timeout_us {
+            return false;
+        }
+        delay::udelay(POLL_US);
+        interrupt::pause();
+        waited += POLL_US;
+    }
+    true
+}
+
+/// Brings up all APs at once: one INIT round, one shared 10 ms wait, then two SIPI
+/// rounds. Returns the APs that never reached the trampoline, for a serial retry.
+fn start_aps_parallel<'a>(
+    local_apic: &mut LocalApic,
+    aps: Vec<ApStart<'a>>,
+    failures: &mut Vec<ApFailure>,
+) -> Vec<ApStart<'a>> {
+    if aps.is_empty() {
+        return aps;
+    }
+
+    if cfg!(debug_assertions) {
+        println!("    Starting {} APs in parallel", aps.len());
+    }
+
+    for ap in &aps {
+        local_apic.set_icr(icr_for(local_apic, ap.apic_id(), ICR_INIT));
+    }
+    delay::mdelay(10);
+
+    for round in 0..2 {
+        for ap in aps.iter().filter(|ap| round == 0 || !ap.in_trampoline()) {
+            local_apic.set_icr(icr_for(local_apic, ap.apic_id(), icr_startup()));
+        }
+        delay::udelay(200);
+    }
+
+    wait_for(AP_TRAMPOLINE_TIMEOUT_US, || aps.iter().all(ApStart::in_trampoline));
+
+    // `kstart_ap` is not reentrant, so APs enter the kernel one at a time
+    let (arrived, missing): (Vec<_>, Vec<_>) = aps.into_iter().partition(ApStart::in_trampoline);
+    for ap in arrived {
+        if let Err(stage) = ap.release() {
+            failures.push(ap.fail(local_apic, stage));
+        }
+    }
+
+    missing
+}
+
+/// Brings up one AP through the trampoline using the MP-spec INIT-SIPI-SIPI sequence.
+fn start_ap(local_apic: &mut LocalApic, ap: &ApStart) -> Result<(), ApStartupStage> {
+    // INIT assert, then give the AP 10 ms to reset
+    if cfg!(debug_assertions) {
+        print!("    AP {}: IPI...", ap.apic_id());
+    }
+    local_apic.set_icr(icr_for(local_apic, ap.apic_id(), ICR_INIT));
+    delay::mdelay(10);
+
+    // Two STARTUP IPIs 200 us apart, as the first may be lost on older parts
+    for _ in 0..2 {
+        if cfg!(debug_assertions) {
+            print!(" SIPI...");
+        }
+        local_apic.set_icr(icr_for(local_apic, ap.apic_id(), icr_startup()));
+        delay::udelay(200);
+        if ap.in_trampoline() {
+            break;
+        }
+    }
+
+    // Wait for the AP to be ready
+    if cfg!(debug_assertions) {
+        print!(" Wait...");
+    }
+    let result = if wait_for(AP_TRAMPOLINE_TIMEOUT_US, || ap.in_trampoline()) {
+        ap.release()
+    } else {
+        Err(ApStartupStage::Trampoline)
+    };
+
+    if cfg!(debug_assertions) {
+        println!("{}", if result.is_ok() { " Ready" } else { " Timeout" });
+    }
+    result
+}
+
+/// Hotplug state of a CPU listed in the MADT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuState {
+    /// Held in INIT, waiting for a STARTUP IPI.
+    Offline,
+    Online,
+    /// Asked to migrate its work away and park.
+    GoingOffline,
+    /// Halted with interrupts disabled, about to be put back into INIT.
+    Parked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HotplugError {
+    /// No enabled or online-capable CPU has this APIC ID or processor UID.
+    UnknownCpu,
+    /// The boot CPU is never taken offline.
+    BootCpu,
+    /// A CPU cannot take itself offline, as it has to be put into INIT by another.
+    CurrentCpu,
+    /// The CPU is not in a state that allows the request.
+    WrongState(CpuState),
+    /// No free page below 1 MiB for the AP trampoline.
+    NoTrampoline,
+    Startup(ApStartupStage),
+    /// The CPU did not park in time and stays online.
+    ParkTimeout,
+}
+
+/// ACPI `Notify` values sent to processor devices.
+pub const NOTIFY_BUS_CHECK: u32 = 0x00;
+pub const NOTIFY_DEVICE_CHECK: u32 = 0x01;
+pub const NOTIFY_EJECT_REQUEST: u32 = 0x03;
+
+/// How long an offlined CPU has to migrate its work and park.
+const AP_PARK_TIMEOUT_US: u64 = 1_000_000;
+
+/// A CPU that is enabled or online capable in the MADT.
+struct HotplugCpu {
+    /// Logical CPU ID: dense, in MADT order after the BSP, which is 0.
+    cpu_id: usize,
+    processor: Processor,
+    state: CpuState,
+    boot_cpu: bool,
+    /// Kernel stack handed to the CPU, freed once it is back in INIT.
+    stack: Option<Frame>,
+}
+
+static CPUS: Mutex<Vec<HotplugCpu>> = Mutex::new(Vec::new());
+/// Serializes onlining and offlining, which share the trampoline and the ICR.
+static HOTPLUG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Gives `processor` the next logical CPU ID, unless it already has one, and returns it.
+fn register_cpu(processor: Processor, boot_cpu: bool) -> usize {
+    let mut cpus = CPUS.lock();
+    if let Some(cpu) = cpus.iter().find(|cpu| cpu.processor.apic_id == processor.apic_id) {
+        return cpu.cpu_id;
+    }
+    // ACPI processor UIDs may be sparse, so they are not used as CPU IDs
+    let cpu_id = cpus.len();
+    ipi::register_cpu(cpu_id, processor.apic_id.into());
+    ipi::set_online(cpu_id, boot_cpu);
+    cpus.push(HotplugCpu {
+        cpu_id,
+        processor,
+        state: if boot_cpu { CpuState::Online } else { CpuState::Offline },
+        boot_cpu,
+        stack: None,
+    });
+    cpu_id
+}
+
+fn with_cpu<R>(apic_id: u32, f: impl FnOnce(&mut HotplugCpu) -> R) -> Option<R> {
+    CPUS.lock().iter_mut().find(|cpu| cpu.processor.apic_id == apic_id).map(f)
+}
+
+pub fn cpu_state(apic_id: u32) -> Option<CpuState> {
+    with_cpu(apic_id, |cpu| cpu.state)
+}
+
+/// Brings an offline CPU up through the trampoline, the same way as at boot.
+pub fn cpu_online(apic_id: u32) -> Result<(), HotplugError> {
+    let _guard = HOTPLUG_LOCK.lock();
+    let (cpu_id, processor) = with_cpu(apic_id, |cpu| match cpu.state {
+        CpuState::Offline => Ok((cpu.cpu_id, cpu.processor)),
+        state => Err(HotplugError::WrongState(state)),
+    })
+    .ok_or(HotplugError::UnknownCpu)??;
+
+    let local_apic = unsafe { the_local_apic() };
+    with_trampoline(|page_table_physaddr| {
+        let page_table_physaddr = trampoline_paging(page_table_physaddr);
+        let apic_ids = [apic_id];
+        let mut slot = ApSlot::default();
+        unsafe { install_slots(core::slice::from_ref(&slot), &apic_ids, page_table_physaddr) };
+
+        CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+        let stack_frame = slot.prepare(cpu_id as u64, page_table_physaddr as u64);
+        let ap = ApStart {
+            slot: &slot,
+            cpu_id,
+            processor,
+            stack_frame,
+        };
+
+        let result = start_ap(local_apic, &ap).map_err(|stage| {
+            ap.fail(local_apic, stage);
+            HotplugError::Startup(stage)
+        });
+        ipi::tlb_shootdown_all();
+        result
+    })
+    .ok_or(HotplugError::NoTrampoline)??;
+
+    log::info!("CPU {} (APIC {}, processor {}) online", cpu_id, apic_id, processor.uid);
+    Ok(())
+}
+
+/// Takes an online CPU offline: it is asked to migrate its work away and park, then
+/// put back into INIT so that `cpu_online` can restart it later.
+pub fn cpu_offline(apic_id: u32) -> Result<(), HotplugError> {
+    let _guard = HOTPLUG_LOCK.lock();
+    let local_apic = unsafe { the_local_apic() };
+    let me = local_apic.id();
+
+    let cpu_id = with_cpu(apic_id, |cpu| match cpu.state {
+        _ if cpu.boot_cpu => Err(HotplugError::BootCpu),
+        _ if apic_id == me => Err(HotplugError::CurrentCpu),
+        CpuState::Online => {
+            cpu.state = CpuState::GoingOffline;
+            Ok(cpu.cpu_id)
+        }
+        state => Err(HotplugError::WrongState(state)),
+    })
+    .ok_or(HotplugError::UnknownCpu)??;
+
+    // Device interrupts follow the requesting CPU unless their affinity says
+    // otherwise; the scheduler on the target moves its own contexts before parking
+    let migration = affinity::migrate_from(cpu_id);
+    if !migration.is_empty() {
+        log::debug!("Moved {} interrupts off APIC {}", migration.len(), apic_id);
+    }
+
+    // An idle CPU only notices the request once something takes it out of `hlt`
+    if let Err(err) = ipi::send(cpu_id, ipi::IpiKind::Wakeup) {
+        log::warn!("Could not wake CPU {} to go offline: {:?}", cpu_id, err);
+    }
+
+    if !wait_for(AP_PARK_TIMEOUT_US, || cpu_state(apic_id) == Some(CpuState::Parked)) {
+        let parked = with_cpu(apic_id, |cpu| {
+            if cpu.state == CpuState::GoingOffline {
+                cpu.state = CpuState::Online;
             }
+            cpu.state == CpuState::Parked
+        });
+        if parked != Some(true) {
+            log::warn!("CPU with APIC {} did not park, leaving it online", apic_id);
+            migration.undo();
+            return Err(HotplugError::ParkTimeout);
         }
+    }
+
+    // Only free the stack once the CPU can no longer run on it
+    local_apic.set_icr(icr_for(local_apic, apic_id, ICR_INIT));
+    delay::udelay(200);
+    let stack = with_cpu(apic_id, |cpu| {
+        cpu.state = CpuState::Offline;
+        cpu.stack.take()
+    })
+    .flatten();
+    if let Some(stack_frame) = stack {
+        unsafe { deallocate_p2frame(stack_frame, 4) };
+    }
+    // Bottom halves and timers left on the CPU still have to run
+    softirq::migrate_from(cpu_id);
+    time::migrate_from(cpu_id);
+    CPU_COUNT.fetch_sub(1, Ordering::SeqCst);
 
-        // Unmap trampoline after usage
-        unsafe {
-            let (_frame, _, flush) = KernelMapper::lock()
-                .get_mut()
-                .expect("expected kernel page table not to be recursively locked while initializing MADT")
-                .unmap_phys(trampoline_page.start_address(), true)
-                .expect("failed to unmap trampoline page");
-            flush.flush();
+    log::info!("CPU with APIC {} offline", apic_id);
+    Ok(())
+}
+
+/// Returns true if the calling CPU has been asked to go offline. The scheduler checks
+/// this, moves its contexts to other CPUs and then calls `park_this_cpu`, carrying on
+/// if that returns.
+pub fn offline_requested() -> bool {
+    let me = unsafe { the_local_apic() }.id();
+    cpu_state(me) == Some(CpuState::GoingOffline)
+}
+
+/// Parks the calling CPU after its work has been migrated. It halts with interrupts
+/// disabled until the CPU that requested the offline sends INIT.
+///
+/// Returns if the request was withdrawn meanwhile, as it is when the requesting CPU
+/// gives up waiting; the CPU then stays online.
+pub unsafe fn park_this_cpu() {
+    let flags = irq_save();
+    let cpu_id = crate::percpu::cpu_id();
+    // Answer whatever was sent before other CPUs stop waiting for us
+    ipi::set_online(cpu_id, false);
+    ipi::handle();
+
+    let me = the_local_apic().id();
+    let parked = with_cpu(me, |cpu| {
+        let parked = cpu.state == CpuState::GoingOffline;
+        if parked {
+            cpu.state = CpuState::Parked;
+        }
+        parked
+    });
+    if parked != Some(true) {
+        ipi::set_online(cpu_id, true);
+        irq_restore(flags);
+        return;
+    }
+
+    loop {
+        asm!("cli", "hlt", options(nomem, nostack));
+    }
+}
+
+/// Handles a `Notify` on the processor device with the given `_UID`, as sent by the
+/// firmware on hot-add (bus or device check) and hot-remove (eject request).
+///
+/// After a successful eject request the caller evaluates the device's `_EJ0`.
+pub fn processor_notify(acpi_processor_uid: u32, event: u32) -> Result<(), HotplugError> {
+    let apic_id = CPUS
+        .lock()
+        .iter()
+        .find(|cpu| cpu.processor.uid == acpi_processor_uid)
+        .map(|cpu| cpu.processor.apic_id)
+        .ok_or(HotplugError::UnknownCpu)?;
+
+    match event {
+        NOTIFY_BUS_CHECK | NOTIFY_DEVICE_CHECK if cpu_state(apic_id) == Some(CpuState::Offline) => {
+            cpu_online(apic_id)
+        }
+        NOTIFY_EJECT_REQUEST => cpu_offline(apic_id),
+        _ => {
+            log::debug!("Ignoring notify {:#x} for processor {}", event, acpi_processor_uid);
+            Ok(())
         }
     }
-}
\ No newline at end of file
+}
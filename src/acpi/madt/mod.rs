@@ -21,6 +21,18 @@ mod arch;
 #[path = "arch/other.rs"]
 mod arch;
 
+#[cfg(target_arch = "aarch64")]
+pub use self::arch::{
+    init_gic_v1_v2, init_gic_v3, is_this_cpu, start_aps, ApMethod, ApTarget, Redistributors, MPIDR_AFFINITY,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::arch::{switch_paging_entry, trampoline_frame, with_trampoline};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::arch::{
+    cpu_offline, cpu_online, cpu_state, offline_requested, park_this_cpu, processor_notify, CpuState, HotplugError,
+    NOTIFY_BUS_CHECK, NOTIFY_DEVICE_CHECK, NOTIFY_EJECT_REQUEST,
+};
+
 static MADT: SyncUnsafeCell<Option<Madt>> = SyncUnsafeCell::new(None);
 
 pub fn madt() -> Option<&'static Madt> {
@@ -30,6 +42,14 @@ pub fn madt() -> Option<&'static Madt> {
 
 pub const FLAG_PCAT: u32 = 1;
 
+/// GICC flag: the processor is usable at boot.
+pub const GICC_ENABLED: u32 = 1;
+
+/// Local APIC flag: the processor is usable at boot.
+pub const LOCAL_APIC_ENABLED: u32 = 1;
+/// Local APIC flag (ACPI 6.3): a disabled processor can be brought online later.
+pub const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
 impl Madt {
     pub fn init() {
         if let Some(madt_sdt) = find_sdt("APIC").first() {
@@ -40,12 +60,24 @@ impl Madt {
                 arch::init(madt);
             } else {
                 println!("Invalid MADT structure.");
+                Self::init_without_apic();
             }
         } else {
             println!("Unable to find MADT");
+            Self::init_without_apic();
         }
     }
 
+    /// Falls back to the legacy interrupt controller when there is no usable APIC.
+    fn init_without_apic() {
+        // The boot CPU is the only one known
+        unsafe { crate::percpu::init(0) };
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        unsafe {
+            crate::device::pic::enable_fallback()
+        };
+    }
+
     pub fn new(sdt: &'static Sdt) -> Option<Madt> {
         if sdt.signature == *b"APIC" && sdt.data_len() >= 8 {
             let data_ptr = sdt.data_address() as *const u32;
@@ -90,6 +122,8 @@ impl Iterator for MadtIter {
                 MadtEntry::IoApic(unsafe { &*(base_ptr.add(self.i + 2) as *const MadtIoApic) }),
             0x2 if entry_len == mem::size_of::<MadtIntSrcOverride>() + 2 =>
                 MadtEntry::IntSrcOverride(unsafe { &*(base_ptr.add(self.i + 2) as *const MadtIntSrcOverride) }),
+            0x9 if entry_len == mem::size_of::<MadtLocalX2Apic>() + 2 =>
+                MadtEntry::LocalX2Apic(unsafe { &*(base_ptr.add(self.i + 2) as *const MadtLocalX2Apic) }),
             0xB if entry_len >= mem::size_of::<MadtGicc>() + 2 =>
                 MadtEntry::Gicc(unsafe { &*(base_ptr.add(self.i + 2) as *const MadtGicc) }),
             0xC if entry_len >= mem::size_of::<MadtGicd>() + 2 =>
@@ -108,6 +142,7 @@ pub enum MadtEntry {
     LocalApic(&'static MadtLocalApic),
     IoApic(&'static MadtIoApic),
     IntSrcOverride(&'static MadtIntSrcOverride),
+    LocalX2Apic(&'static MadtLocalX2Apic),
     Gicc(&'static MadtGicc),
     Gicd(&'static MadtGicd),
     Unknown(u8),
@@ -140,6 +175,15 @@ pub struct MadtIntSrcOverride {
     pub flags: u16,
 }
 
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct MadtLocalX2Apic {
+    _reserved: u16,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub processor_uid: u32,
+}
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 pub struct MadtGicc {
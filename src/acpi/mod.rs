@@ -13,12 +13,19 @@ use crate::{
     paging::{PageFlags, PhysicalAddress, RmmA, RmmArch},
 };
 
-use self::{hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt};
+use self::{fadt::Fadt, hpet::Hpet, madt::Madt, rsdp::RSDP, rsdt::Rsdt, rxsdt::Rxsdt, sdt::Sdt, xsdt::Xsdt};
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod dmar;
+pub mod fadt;
 #[cfg(target_arch = "aarch64")]
 mod gtdt;
 pub mod hpet;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod ivrs;
 pub mod madt;
+#[cfg(target_arch = "riscv64")]
+pub mod rhct;
 mod rsdp;
 mod rsdt;
 mod rxsdt;
@@ -90,13 +97,26 @@ pub static RXSDT_ENUM: Once<RxsdtEnum> = Once::new();
 
 /// Parses the ACPI tables to gather CPU, interrupt, and timer information.
 pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
+    // Drivers set up from here query the boot CPU's features, and the text has to be
+    // patched before any AP runs it
+    crate::cpu::init();
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64"))]
+    crate::cpu::alternative::apply();
+
     SDT_POINTERS.write().replace(HashMap::new());
 
     let rsdp_opt = RSDP::get_rsdp(&mut KernelMapper::lock(), already_supplied_rsdp);
     let rsdp = match rsdp_opt {
         Some(r) => r,
         None => {
-            println!("NO RSDP FOUND");
+            // Without ACPI tables the bootloader hands over a device tree instead
+            if already_supplied_rsdp.is_some_and(|dtb| crate::dtb::init(dtb)) {
+                // The counter frequency comes from the device tree
+                crate::time::init();
+                start_balancing();
+            } else {
+                println!("NO RSDP FOUND");
+            }
             return;
         }
     };
@@ -122,20 +142,47 @@ pub unsafe fn init(already_supplied_rsdp: Option<*const u8>) {
     for sdt_addr in rx_enum.iter() {
         if let Some(sdt) = get_sdt(sdt_addr, &mut KernelMapper::lock()) {
             let signature = get_sdt_signature(sdt);
-            SDT_POINTERS.write().as_mut().unwrap().insert(signature, sdt);
+            // Tables like the HPET come once per block, all with the same OEM IDs
+            SDT_POINTERS.write().as_mut().unwrap().entry(signature).or_default().push(sdt);
         }
     }
 
+    // The FADT's boot architecture flags decide how APs are started
+    Fadt::init();
     #[cfg(target_arch = "aarch64")]
     spcr::Spcr::init();
-    Madt::init();
+    // The HPET is needed as a delay source while starting APs from the MADT
     Hpet::init();
-    #[cfg(target_arch = "aarch64")]
-    gtdt::Gtdt::init();
+    // The TSC is calibrated against the HPET or the PM timer, and its frequency is
+    // needed to set the local APIC timers up from the MADT
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    crate::time::init();
+    // Interrupt remapping units have to be known before the MADT decides on x2APIC
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        dmar::Dmar::init();
+        ivrs::Ivrs::init();
+    }
+    // On aarch64 the GTDT is read from the MADT, between GIC and AP startup
+    Madt::init();
+    #[cfg(target_arch = "riscv64")]
+    rhct::Rhct::init();
+    // The counter frequency comes from the GTDT or the RHCT
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    crate::time::init();
+    start_balancing();
+}
+
+/// Spreads device interrupts over the CPUs started so far.
+fn start_balancing() {
+    if cfg!(feature = "multi_core") {
+        crate::affinity::set_balancing(true);
+    }
 }
 
 pub type SdtSignature = (String, [u8; 6], [u8; 8]);
-pub static SDT_POINTERS: RwLock<Option<HashMap<SdtSignature, &'static Sdt>>> = RwLock::new(None);
+/// Every SDT listed in the RSDT or XSDT, in order, by signature.
+pub static SDT_POINTERS: RwLock<Option<HashMap<SdtSignature, Vec<&'static Sdt>>>> = RwLock::new(None);
 
 /// Finds an SDT by name, returning a vector of matching tables.
 pub fn find_sdt(name: &str) -> Vec<&'static Sdt> {
@@ -144,7 +191,8 @@ pub fn find_sdt(name: &str) -> Vec<&'static Sdt> {
         .as_ref()
         .map_or_else(Vec::new, |ptrs| {
             ptrs.iter()
-                .filter_map(|(signature, sdt)| (signature.0 == name).then_some(*sdt))
+                .filter(|(signature, _)| signature.0 == name)
+                .flat_map(|(_, sdts)| sdts.iter().copied())
                 .collect()
         })
 }
@@ -156,11 +204,12 @@ pub fn get_sdt_signature(sdt: &'static Sdt) -> SdtSignature {
 }
 
 pub struct Acpi {
-    pub hpet: RwLock<Option<Hpet>>,
+    /// Every HPET block, in enumeration order.
+    pub hpet: RwLock<Vec<Hpet>>,
     pub next_ctx: RwLock<u64>,
 }
 
 pub static ACPI_TABLE: Acpi = Acpi {
-    hpet: RwLock::new(None),
+    hpet: RwLock::new(Vec::new()),
     next_ctx: RwLock::new(0),
 };
\ No newline at end of file
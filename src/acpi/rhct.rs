@@ -0,0 +1,316 @@
+use core::{cell::SyncUnsafeCell, mem, ptr, slice, str};
+
+use super::{find_sdt, sdt::Sdt};
+
+/// RISC-V Hart Capabilities Table
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct Rhct {
+    pub header: Sdt,
+    pub flags: u32,
+    pub time_base_frequency: u64,
+    pub node_count: u32,
+    pub node_offset: u32,
+}
+
+/// The platform timer cannot wake a hart from suspend.
+pub const FLAG_TIMER_CANNOT_WAKE_CPU: u32 = 1;
+
+const NODE_ISA_STRING: u16 = 0;
+const NODE_CMO: u16 = 1;
+const NODE_MMU: u16 = 2;
+const NODE_HART_INFO: u16 = 0xFFFF;
+
+static RHCT: SyncUnsafeCell<Option<&'static Rhct>> = SyncUnsafeCell::new(None);
+
+pub fn rhct() -> Option<&'static Rhct> {
+    // SAFETY: The `RHCT` variable is initialized only once before use.
+    unsafe { *RHCT.get() }
+}
+
+impl Rhct {
+    pub fn init() {
+        let Some(rhct) = find_sdt("RHCT").first().and_then(|sdt| Rhct::new(sdt)) else {
+            log::warn!("Unable to find RHCT");
+            return;
+        };
+
+        // SAFETY: Ensuring single initialization before APs start.
+        unsafe { RHCT.get().write(Some(rhct)) };
+
+        let time_base_frequency = rhct.time_base_frequency;
+        log::info!("  RHCT: timebase {} Hz, flags {:#x}", time_base_frequency, { rhct.flags });
+
+        for node in rhct.iter() {
+            if let RhctNode::HartInfo(hart) = node {
+                log::debug!(
+                    "    hart {}: isa {:?} mmu {:?} cmo {:?}",
+                    hart.acpi_processor_uid(),
+                    rhct.hart_isa(&hart).map(|isa| isa.as_str()),
+                    rhct.hart_mmu(&hart).map(|mmu| mmu.mmu_type()),
+                    rhct.hart_cmo(&hart),
+                );
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn new(sdt: &'static Sdt) -> Option<&'static Rhct> {
+        (sdt.signature == *b"RHCT" && sdt.length as usize >= mem::size_of::<Rhct>())
+            .then(|| unsafe { &*ptr::cast::<_, Rhct>(sdt) })
+    }
+
+    /// Returns the frequency of the `time` CSR in Hz.
+    #[inline(always)]
+    pub fn time_base_frequency(&self) -> u64 {
+        self.time_base_frequency
+    }
+
+    pub fn iter(&self) -> RhctIter {
+        RhctIter {
+            rhct: self,
+            offset: self.node_offset as usize,
+            remaining: self.node_count,
+        }
+    }
+
+    /// Decodes the node located `offset` bytes from the start of the table.
+    pub fn node_at(&self, offset: usize) -> Option<RhctNode> {
+        let table_len = self.header.length as usize;
+        if offset + mem::size_of::<RhctNodeHeader>() > table_len {
+            return None;
+        }
+
+        let base = self as *const Self as *const u8;
+        let header = unsafe { base.add(offset).cast::<RhctNodeHeader>().read_unaligned() };
+        let node_len = header.length as usize;
+        if node_len < mem::size_of::<RhctNodeHeader>() || offset + node_len > table_len {
+            return None;
+        }
+
+        let body = unsafe { base.add(offset + mem::size_of::<RhctNodeHeader>()) };
+        let body_len = node_len - mem::size_of::<RhctNodeHeader>();
+
+        let node = match header.node_type {
+            NODE_ISA_STRING if body_len >= 2 => {
+                let isa_len = unsafe { body.cast::<u16>().read_unaligned() } as usize;
+                if 2 + isa_len > body_len {
+                    return None;
+                }
+                let bytes = unsafe { slice::from_raw_parts(body.add(2), isa_len) };
+                // The string is NUL terminated within its declared length.
+                let bytes = bytes.split(|&b| b == 0).next().unwrap_or(bytes);
+                RhctNode::IsaString(IsaString(str::from_utf8(bytes).ok()?))
+            }
+            NODE_CMO if body_len >= mem::size_of::<RhctCmo>() => {
+                RhctNode::Cmo(unsafe { &*body.cast::<RhctCmo>() })
+            }
+            NODE_MMU if body_len >= mem::size_of::<RhctMmu>() => {
+                RhctNode::Mmu(unsafe { &*body.cast::<RhctMmu>() })
+            }
+            NODE_HART_INFO if body_len >= 6 => {
+                let offset_count = unsafe { body.cast::<u16>().read_unaligned() } as usize;
+                let acpi_processor_uid = unsafe { body.add(2).cast::<u32>().read_unaligned() };
+                if 6 + offset_count * mem::size_of::<u32>() > body_len {
+                    return None;
+                }
+                RhctNode::HartInfo(RhctHartInfo {
+                    acpi_processor_uid,
+                    offsets: unsafe { body.add(6).cast::<u32>() },
+                    offset_count,
+                })
+            }
+            other => RhctNode::Unknown(other),
+        };
+
+        Some(node)
+    }
+
+    /// Finds the hart info node for the hart with the given ACPI processor UID.
+    pub fn hart(&self, acpi_processor_uid: u32) -> Option<RhctHartInfo> {
+        self.iter().find_map(|node| match node {
+            RhctNode::HartInfo(hart) if hart.acpi_processor_uid() == acpi_processor_uid => Some(hart),
+            _ => None,
+        })
+    }
+
+    /// Returns the ISA string referenced by a hart info node.
+    pub fn hart_isa(&self, hart: &RhctHartInfo) -> Option<IsaString> {
+        hart.offsets().find_map(|offset| match self.node_at(offset as usize)? {
+            RhctNode::IsaString(isa) => Some(isa),
+            _ => None,
+        })
+    }
+
+    /// Returns the cache management operation node referenced by a hart info node.
+    pub fn hart_cmo(&self, hart: &RhctHartInfo) -> Option<&'static RhctCmo> {
+        hart.offsets().find_map(|offset| match self.node_at(offset as usize)? {
+            RhctNode::Cmo(cmo) => Some(cmo),
+            _ => None,
+        })
+    }
+
+    /// Returns the MMU node referenced by a hart info node.
+    pub fn hart_mmu(&self, hart: &RhctHartInfo) -> Option<&'static RhctMmu> {
+        hart.offsets().find_map(|offset| match self.node_at(offset as usize)? {
+            RhctNode::Mmu(mmu) => Some(mmu),
+            _ => None,
+        })
+    }
+}
+
+/// RHCT Iteration Structure
+pub struct RhctIter<'a> {
+    rhct: &'a Rhct,
+    offset: usize,
+    remaining: u32,
+}
+
+impl Iterator for RhctIter<'_> {
+    type Item = RhctNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 || self.offset + mem::size_of::<RhctNodeHeader>() > self.rhct.header.length as usize {
+            return None;
+        }
+
+        let base = self.rhct as *const Rhct as *const u8;
+        let header = unsafe { base.add(self.offset).cast::<RhctNodeHeader>().read_unaligned() };
+        if (header.length as usize) < mem::size_of::<RhctNodeHeader>() {
+            return None;
+        }
+
+        let node = self.rhct.node_at(self.offset)?;
+        self.offset += header.length as usize;
+        self.remaining -= 1;
+        Some(node)
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct RhctNodeHeader {
+    node_type: u16,
+    length: u16,
+    _revision: u16,
+}
+
+/// RHCT Node Variants
+#[derive(Debug)]
+pub enum RhctNode {
+    IsaString(IsaString),
+    Cmo(&'static RhctCmo),
+    Mmu(&'static RhctMmu),
+    HartInfo(RhctHartInfo),
+    Unknown(u16),
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct RhctCmo {
+    _reserved: u8,
+    /// log2 of the Zicbom cache block size
+    pub cbom_block_size: u8,
+    /// log2 of the Zicbop cache block size
+    pub cbop_block_size: u8,
+    /// log2 of the Zicboz cache block size
+    pub cboz_block_size: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct RhctMmu {
+    _reserved: u8,
+    pub mmu_type: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmuType {
+    Sv39,
+    Sv48,
+    Sv57,
+    Unknown(u8),
+}
+
+impl RhctMmu {
+    pub fn mmu_type(&self) -> MmuType {
+        match self.mmu_type {
+            0 => MmuType::Sv39,
+            1 => MmuType::Sv48,
+            2 => MmuType::Sv57,
+            other => MmuType::Unknown(other),
+        }
+    }
+}
+
+/// Per-hart node listing the offsets of the nodes that apply to it.
+#[derive(Clone, Copy, Debug)]
+pub struct RhctHartInfo {
+    acpi_processor_uid: u32,
+    offsets: *const u32,
+    offset_count: usize,
+}
+
+impl RhctHartInfo {
+    #[inline(always)]
+    pub fn acpi_processor_uid(&self) -> u32 {
+        self.acpi_processor_uid
+    }
+
+    /// Iterates over the table offsets of the nodes describing this hart.
+    pub fn offsets(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.offset_count).map(|i| unsafe { self.offsets.add(i).read_unaligned() })
+    }
+}
+
+/// A RISC-V ISA string such as `rv64imafdc_zicbom_sstc`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsaString(&'static str);
+
+impl IsaString {
+    #[inline(always)]
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    /// Checks for a single-letter or multi-letter extension, case-insensitively.
+    pub fn has_extension(&self, name: &str) -> bool {
+        let mut parts = self.0.split('_');
+        let Some(base) = parts.next() else {
+            return false;
+        };
+
+        if let [letter] = name.as_bytes() {
+            let letter = letter.to_ascii_lowercase();
+            let Some(letters) = base
+                .get(..4)
+                .filter(|prefix| prefix.eq_ignore_ascii_case("rv32") || prefix.eq_ignore_ascii_case("rv64"))
+                .and_then(|_| base.get(4..))
+            else {
+                return false;
+            };
+            let letters = letters.as_bytes();
+            // `g` is shorthand for `imafd_zicsr_zifencei`.
+            let expands_g = b"imafd".contains(&letter) && letters.iter().any(|c| c.eq_ignore_ascii_case(&b'g'));
+            return expands_g || letters.iter().any(|c| c.to_ascii_lowercase() == letter);
+        }
+
+        parts.any(|ext| ext.eq_ignore_ascii_case(name))
+    }
+}
+
+// ---------- TESTS ----------
+#[test]
+fn test_isa_string_extensions() {
+    let isa = IsaString("rv64imafdc_zicbom_Sstc");
+    assert!(isa.has_extension("a"));
+    assert!(isa.has_extension("C"));
+    assert!(!isa.has_extension("v"));
+    assert!(isa.has_extension("zicbom"));
+    assert!(isa.has_extension("sstc"));
+    assert!(!isa.has_extension("zicboz"));
+
+    let isa = IsaString("rv64gc");
+    assert!(isa.has_extension("f"));
+    assert!(!isa.has_extension("h"));
+}
@@ -0,0 +1,449 @@
+//! # Interrupt affinity
+//! Device interrupts are registered here with the [`IrqRoute`] that moves them: an
+//! I/O APIC redirection entry, an MSI or MSI-X message, or a GIC SPI. Each has a
+//! mask of the CPUs it may be delivered to, set by its driver or at runtime.
+//!
+//! When balancing is on, [`balance`] spreads movable interrupts over the online
+//! CPUs by the rate they fired at since the last call, counted by [`Counted`]
+//! handlers. It runs as a softirq every [`BALANCE_INTERVAL_NS`], queued by a timer
+//! on the CPU that turned balancing on. Interrupts leave a CPU going offline
+//! through [`migrate_from`].
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::{
+    cmp::Reverse,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    dtb::irqchip::{InterruptHandler, IrqReturn},
+    ipi, percpu,
+    softirq::{self, Deferral, Work},
+    time,
+};
+
+/// Busiest CPU's interrupt count between two [`balance`] calls below which nothing moves.
+const BALANCE_MIN_COUNT: u64 = 1000;
+
+/// Time between two periodic [`balance`] calls.
+pub const BALANCE_INTERVAL_NS: u64 = time::NSEC_PER_SEC;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AffinityError {
+    /// No interrupt is registered with the ID.
+    UnknownIrq,
+    /// The mask holds no online CPU.
+    NoOnlineCpu,
+    /// The interrupt cannot be moved, like a per-CPU interrupt or a multiple-message MSI.
+    Unsupported,
+    /// The target CPU has no vector or remapping entry left for the interrupt.
+    NoResources,
+    /// The interrupt cannot be delivered to the target CPU, like an APIC ID above 255
+    /// without interrupt remapping.
+    Unreachable,
+}
+
+/// A set of CPU IDs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuMask(Vec<u64>);
+
+impl CpuMask {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn single(cpu_id: usize) -> Self {
+        let mut mask = Self::new();
+        mask.insert(cpu_id);
+        mask
+    }
+
+    /// Every CPU with a per-CPU area.
+    pub fn all() -> Self {
+        percpu::cpus().into_iter().collect()
+    }
+
+    pub fn insert(&mut self, cpu_id: usize) {
+        if self.0.len() <= cpu_id / 64 {
+            self.0.resize(cpu_id / 64 + 1, 0);
+        }
+        self.0[cpu_id / 64] |= 1 << (cpu_id % 64);
+    }
+
+    pub fn remove(&mut self, cpu_id: usize) {
+        if let Some(word) = self.0.get_mut(cpu_id / 64) {
+            *word &= !(1 << (cpu_id % 64));
+        }
+    }
+
+    pub fn contains(&self, cpu_id: usize) -> bool {
+        self.0.get(cpu_id / 64).is_some_and(|word| word & (1 << (cpu_id % 64)) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.0.len() * 64).filter(|&cpu_id| self.contains(cpu_id))
+    }
+}
+
+impl FromIterator<usize> for CpuMask {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut mask = Self::new();
+        for cpu_id in iter {
+            mask.insert(cpu_id);
+        }
+        mask
+    }
+}
+
+/// Changes which CPU an interrupt is delivered to.
+pub trait IrqRoute: Send {
+    /// Delivers the interrupt to `cpu_id` from now on. Its handler must follow it
+    /// if handlers are per CPU.
+    fn set_target(&mut self, cpu_id: usize) -> Result<(), AffinityError>;
+}
+
+/// Index of a registered interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrqId(pub usize);
+
+struct Entry {
+    name: &'static str,
+    route: Box<dyn IrqRoute>,
+    mask: CpuMask,
+    cpu_id: usize,
+    /// Whether [`balance`] may move it within its mask.
+    balanced: bool,
+    count: Arc<AtomicU64>,
+    /// `count` at the last [`balance`].
+    last_count: u64,
+}
+
+static IRQS: Mutex<Vec<Option<Entry>>> = Mutex::new(Vec::new());
+static BALANCING: AtomicBool = AtomicBool::new(false);
+/// Set while a balancing timer or softirq is pending.
+static TICKING: AtomicBool = AtomicBool::new(false);
+static BALANCE_WORK: Once<Arc<Work>> = Once::new();
+
+/// Registers an interrupt currently delivered to `cpu_id`, allowed on every CPU
+/// and balanced.
+pub fn register(name: &'static str, route: Box<dyn IrqRoute>, cpu_id: usize) -> IrqId {
+    let entry = Entry {
+        name,
+        route,
+        mask: CpuMask::all(),
+        cpu_id,
+        balanced: true,
+        count: Arc::new(AtomicU64::new(0)),
+        last_count: 0,
+    };
+    let mut irqs = IRQS.lock();
+    let index = match irqs.iter().position(Option::is_none) {
+        Some(index) => {
+            irqs[index] = Some(entry);
+            index
+        }
+        None => {
+            irqs.push(Some(entry));
+            irqs.len() - 1
+        }
+    };
+    IrqId(index)
+}
+
+/// Forgets an interrupt, returning its route so the driver can tear it down.
+pub fn unregister(id: IrqId) -> Option<Box<dyn IrqRoute>> {
+    IRQS.lock().get_mut(id.0)?.take().map(|entry| entry.route)
+}
+
+fn with_entry<R>(id: IrqId, f: impl FnOnce(&mut Entry) -> R) -> Result<R, AffinityError> {
+    let mut irqs = IRQS.lock();
+    let entry = irqs.get_mut(id.0).and_then(Option::as_mut).ok_or(AffinityError::UnknownIrq)?;
+    Ok(f(entry))
+}
+
+/// Moves `entry` to `cpu_id`, unless it is there already.
+fn move_to(entry: &mut Entry, cpu_id: usize) -> Result<(), AffinityError> {
+    if entry.cpu_id == cpu_id {
+        return Ok(());
+    }
+    entry.route.set_target(cpu_id)?;
+    log::debug!("IRQ {}: CPU {} -> {}", entry.name, entry.cpu_id, cpu_id);
+    entry.cpu_id = cpu_id;
+    Ok(())
+}
+
+/// Restricts an interrupt to the CPUs in `mask`, moving it to the first online one
+/// if its current CPU is not in it.
+pub fn set_affinity(id: IrqId, mask: CpuMask) -> Result<(), AffinityError> {
+    with_entry(id, |entry| {
+        if !mask.contains(entry.cpu_id) || !ipi::is_online(entry.cpu_id) {
+            let target = mask.iter().find(|&cpu_id| ipi::is_online(cpu_id)).ok_or(AffinityError::NoOnlineCpu)?;
+            move_to(entry, target)?;
+        }
+        entry.mask = mask;
+        Ok(())
+    })?
+}
+
+pub fn affinity(id: IrqId) -> Option<CpuMask> {
+    with_entry(id, |entry| entry.mask.clone()).ok()
+}
+
+/// Returns the CPU an interrupt is delivered to.
+pub fn target(id: IrqId) -> Option<usize> {
+    with_entry(id, |entry| entry.cpu_id).ok()
+}
+
+/// Lets [`balance`] move an interrupt, or pins it to its current CPU.
+pub fn set_balanced(id: IrqId, balanced: bool) -> Result<(), AffinityError> {
+    with_entry(id, |entry| entry.balanced = balanced)
+}
+
+/// Handler wrapper counting the interrupts of a registered IRQ for [`balance`].
+pub struct Counted<H> {
+    count: Arc<AtomicU64>,
+    handler: H,
+}
+
+impl<H: InterruptHandler> InterruptHandler for Counted<H> {
+    fn irq_handler(&mut self, irq: usize) -> IrqReturn {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.handler.irq_handler(irq)
+    }
+}
+
+/// Wraps the handler of `id` so that its rate is known to [`balance`].
+pub fn counted<H: InterruptHandler>(id: IrqId, handler: H) -> Result<Counted<H>, AffinityError> {
+    let count = with_entry(id, |entry| entry.count.clone())?;
+    Ok(Counted { count, handler })
+}
+
+/// Turns periodic balancing on or off. Turning it on starts the balancing timer on
+/// the calling CPU, which needs a clock-event device.
+pub fn set_balancing(enabled: bool) {
+    BALANCING.store(enabled, Ordering::Relaxed);
+    if enabled && !TICKING.swap(true, Ordering::AcqRel) {
+        arm_balance();
+    }
+}
+
+/// Queues the balancing softirq [`BALANCE_INTERVAL_NS`] from now.
+fn arm_balance() {
+    let work = BALANCE_WORK.call_once(|| Work::new(Deferral::Softirq, balance_tick));
+    if let Err(err) = time::add_timer_after(BALANCE_INTERVAL_NS, move || {
+        softirq::schedule(work);
+    }) {
+        log::warn!("Interrupt balancing not started: {:?}", err);
+        TICKING.store(false, Ordering::Release);
+    }
+}
+
+/// Balances and arms the next round, or stops once balancing was turned off.
+fn balance_tick() {
+    if !BALANCING.load(Ordering::Relaxed) {
+        TICKING.store(false, Ordering::Release);
+        return;
+    }
+    balance();
+    arm_balance();
+}
+
+/// Interrupt as seen by [`plan`]: its CPU, its count since the last balance and the
+/// CPUs it may move to, empty if pinned.
+struct Load<'a> {
+    cpu_id: usize,
+    count: u64,
+    allowed: Option<&'a CpuMask>,
+}
+
+/// Assigns interrupts to `cpus`, busiest first, each to the least loaded CPU it is
+/// allowed on, preferring the one it is on. Returns the new CPU of each interrupt
+/// and the highest load before and after.
+fn plan(irqs: &[Load], cpus: &[usize]) -> (Vec<usize>, u64, u64) {
+    let mut before = vec![0u64; cpus.len()];
+    for irq in irqs {
+        if let Some(slot) = cpus.iter().position(|&cpu_id| cpu_id == irq.cpu_id) {
+            before[slot] += irq.count;
+        }
+    }
+
+    let mut order: Vec<usize> = (0..irqs.len()).collect();
+    // Pinned interrupts first, as they leave no choice
+    order.sort_by_key(|&i| (irqs[i].allowed.is_some(), Reverse(irqs[i].count)));
+
+    let mut after = vec![0u64; cpus.len()];
+    let mut targets: Vec<usize> = irqs.iter().map(|irq| irq.cpu_id).collect();
+    for i in order {
+        let irq = &irqs[i];
+        let best = match irq.allowed {
+            Some(allowed) => cpus
+                .iter()
+                .enumerate()
+                .filter(|&(_, &cpu_id)| allowed.contains(cpu_id))
+                .min_by_key(|&(slot, &cpu_id)| (after[slot], cpu_id != irq.cpu_id))
+                .map(|(slot, _)| slot),
+            None => cpus.iter().position(|&cpu_id| cpu_id == irq.cpu_id),
+        };
+        if let Some(slot) = best {
+            after[slot] += irq.count;
+            targets[i] = cpus[slot];
+        }
+    }
+
+    let max = |loads: &[u64]| loads.iter().copied().max().unwrap_or(0);
+    (targets, max(&before), max(&after))
+}
+
+/// Spreads balanced interrupts over the online CPUs by their rate since the last
+/// call, if balancing is on. Called every [`BALANCE_INTERVAL_NS`]; nothing moves
+/// unless the busiest CPU's load drops by a fifth.
+pub fn balance() {
+    if !BALANCING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut cpus = percpu::cpus();
+    cpus.retain(|&cpu_id| ipi::is_online(cpu_id));
+
+    let mut irqs = IRQS.lock();
+    let mut entries: Vec<&mut Entry> = irqs.iter_mut().flatten().collect();
+    let loads: Vec<Load> = entries
+        .iter_mut()
+        .map(|entry| {
+            let count = entry.count.load(Ordering::Relaxed);
+            let delta = count.wrapping_sub(entry.last_count);
+            entry.last_count = count;
+            Load {
+                cpu_id: entry.cpu_id,
+                count: delta,
+                allowed: entry.balanced.then_some(&entry.mask),
+            }
+        })
+        .collect();
+
+    let (targets, before, after) = plan(&loads, &cpus);
+    drop(loads);
+    if before < BALANCE_MIN_COUNT || after * 5 > before * 4 {
+        return;
+    }
+    for (entry, target) in entries.iter_mut().zip(targets) {
+        if let Err(err) = move_to(entry, target) {
+            log::warn!("Failed to move IRQ {} to CPU {}: {:?}", entry.name, target, err);
+        }
+    }
+}
+
+/// Interrupts moved off a CPU by [`migrate_from`].
+pub struct Migration {
+    cpu_id: usize,
+    irqs: Vec<IrqId>,
+    /// I/O APIC entries set up without registering, by GSI.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    gsis: Vec<u32>,
+}
+
+impl Migration {
+    /// Returns how many interrupts were moved.
+    pub fn len(&self) -> usize {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let gsis = self.gsis.len();
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        let gsis = 0;
+        self.irqs.len() + gsis
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Moves the interrupts back, for a CPU that stays online after all.
+    pub fn undo(self) {
+        for id in self.irqs {
+            match with_entry(id, |entry| move_to(entry, self.cpu_id)) {
+                Ok(Ok(())) | Err(AffinityError::UnknownIrq) => (),
+                Ok(Err(err)) | Err(err) => log::warn!("Failed to move IRQ {} back to CPU {}: {:?}", id.0, self.cpu_id, err),
+            }
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(apic_id) = ipi::hw_id(self.cpu_id) {
+            for gsi in self.gsis {
+                let _ = crate::device::ioapic::route_gsi_to_cpu(gsi, apic_id as u32);
+            }
+        }
+    }
+}
+
+/// Moves every registered interrupt off `cpu_id`, which is being taken offline, to
+/// another online CPU in its mask, preferring the calling one, or to the calling
+/// CPU if the mask has none left. Returns what was moved, to undo it if the CPU
+/// does not go offline.
+///
+/// On x86 I/O APIC entries set up without registering follow the calling CPU too.
+pub fn migrate_from(cpu_id: usize) -> Migration {
+    let mut migration = Migration {
+        cpu_id,
+        irqs: Vec::new(),
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        gsis: Vec::new(),
+    };
+    let me = percpu::cpu_id();
+    let mut online: Vec<usize> = percpu::cpus()
+        .into_iter()
+        .filter(|&other| other != cpu_id && ipi::is_online(other))
+        .collect();
+    online.sort_by_key(|&other| other != me);
+    let Some(&fallback) = online.first() else {
+        log::error!("No CPU left to take the interrupts of CPU {}", cpu_id);
+        return migration;
+    };
+
+    for (index, entry) in IRQS.lock().iter_mut().enumerate() {
+        let Some(entry) = entry.as_mut().filter(|entry| entry.cpu_id == cpu_id) else {
+            continue;
+        };
+        let target = online.iter().copied().find(|&other| entry.mask.contains(other)).unwrap_or_else(|| {
+            log::warn!("IRQ {}: no online CPU in its mask, breaking affinity", entry.name);
+            fallback
+        });
+        match move_to(entry, target) {
+            Ok(()) => migration.irqs.push(IrqId(index)),
+            Err(err) => log::error!("Failed to move IRQ {} off CPU {}: {:?}", entry.name, cpu_id, err),
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if let (Some(from), Some(to)) = (ipi::hw_id(cpu_id), ipi::hw_id(fallback)) {
+        migration.gsis = crate::device::ioapic::retarget_from_cpu(from as u32, to as u32);
+    }
+    migration
+}
+
+// ---------- TESTS ----------
+
+#[test]
+fn plan_spreads_busy_irqs_and_keeps_pinned_ones() {
+    let all: CpuMask = [0, 1].into_iter().collect();
+    let only_0 = CpuMask::single(0);
+    let irqs = [
+        Load { cpu_id: 0, count: 500, allowed: None },
+        Load { cpu_id: 0, count: 400, allowed: Some(&all) },
+        Load { cpu_id: 0, count: 300, allowed: Some(&all) },
+        Load { cpu_id: 0, count: 10, allowed: Some(&only_0) },
+    ];
+    let (targets, before, after) = plan(&irqs, &[0, 1]);
+    assert_eq!(targets, [0, 1, 1, 0]);
+    assert_eq!((before, after), (1210, 700));
+
+    // A balanced assignment is left alone
+    let irqs = [
+        Load { cpu_id: 0, count: 100, allowed: Some(&all) },
+        Load { cpu_id: 1, count: 100, allowed: Some(&all) },
+    ];
+    assert_eq!(plan(&irqs, &[0, 1]).0, [0, 1]);
+}
@@ -28,17 +28,25 @@ unsafe fn map_heap(mapper: &mut KernelMapper, offset: usize, size: usize) {
         let heap_start_page = Page::containing_address(VirtualAddress::new(offset));
         let heap_end_page = Page::containing_address(VirtualAddress::new(offset + size - 1));
 
+        // Only new mappings are added, which no other CPU can have cached as present, so
+        // a local flush suffices. A shootdown here could also deadlock, as the heap lock
+        // is held and other CPUs may wait for it with interrupts disabled.
+        let mut flush_all = PageFlushAll::new();
         for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-            if let Err(e) = mapper.map(
+            match mapper.map(
                 page.start_address(),
                 PageFlags::new()
                     .write(true)
                     .global(cfg!(not(feature = "pti"))),
             ) {
-                log::error!("Failed to map kernel heap: {:?}", e);
-                return;
+                Ok(flush) => flush_all.consume(flush),
+                Err(e) => {
+                    log::error!("Failed to map kernel heap: {:?}", e);
+                    break;
+                }
             }
         }
+        flush_all.flush();
     } else {
         log::error!("Failed to obtain exclusive access to KernelMapper while extending heap");
     }
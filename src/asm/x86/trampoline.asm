@@ -1,32 +1,71 @@
 ; trampoline for bringing up APs
 ; compiled with nasm by build.rs, and included in src/acpi/madt.rs
+; position independent: the kernel copies it to any free page below 1 MiB and
+; applies the relocations listed at `relocs` before sending SIPIs
 
-ORG 0x8000
+ORG 0
 SECTION .text
 USE16
 
 trampoline:
     jmp short startup_ap
     times 8 - ($ - trampoline) nop
-    .ready: dq 0
-    .cpu_id: dq 0
     .page_table: dq 0
-    .stack_start: dq 0
-    .stack_end: dq 0
-    .code: dq 0
+    .slots: dq 0
+    .slot_count: dq 0
+    .relocs: dq relocs
+    .cr4: dq 0
+    .switch_paging: dq switch_paging
+    .efer: dq 0
+    .apic_ids: dq 0
+
+; per-AP slot, at the index of the AP's APIC ID in the apic_ids array (dwords);
+; cpu_id through stack_end are passed to the kernel
+struc APSlot
+    .cpu_id resq 1
+    .page_table resq 1
+    .stack_start resq 1
+    .stack_end resq 1
+    .code resq 1
+    .ready resq 1
+    .go resq 1
+    .padding resq 1
+endstruc
 
 startup_ap:
     cli
 
-    xor ax, ax
+    ; the SIPI vector leaves us at base:0, so CS selects our data
+    mov ax, cs
     mov ds, ax
     mov es, ax
     mov ss, ax
 
+    ; ebp holds our linear base for addressing data after the mode switch
+    movzx ebp, ax
+    shl ebp, 4
+
     ; initialize stack to invalid value
     mov sp, 0
 
-    ; cr3 holds pointer to PML4
+    ; find our APIC ID, which selects our slot once paging is enabled
+    xor eax, eax
+    cpuid
+    cmp eax, 0xB
+    jb .initial_apic_id
+    mov eax, 0xB
+    xor ecx, ecx
+    cpuid
+    mov esi, edx
+    jmp .have_apic_id
+.initial_apic_id:
+    mov eax, 1
+    cpuid
+    shr ebx, 24
+    mov esi, ebx
+.have_apic_id:
+
+    ; cr3 holds pointer to the page directory, or the PDPT with PAE
     mov edi, [trampoline.page_table]
     mov cr3, edi
 
@@ -41,8 +80,21 @@ startup_ap:
     ; 4: Page Size Extension
     mov eax, cr4
     or eax, 1 << 9 | 1 << 7 | 1 << 4
+    ; extra bits requested by the kernel, such as 5: Page Address Extension
+    or eax, [trampoline.cr4]
     mov cr4, eax
 
+    ; extra EFER bits requested by the kernel, such as 11: No-Execute Enable
+    ; EFER only exists on CPUs with NX or long mode, so skip it when unused
+    mov ebx, [trampoline.efer]
+    test ebx, ebx
+    jz .efer_done
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, ebx
+    wrmsr
+.efer_done:
+
     ; initialize floating point registers
     fninit
 
@@ -58,7 +110,8 @@ startup_ap:
     mov cr0, ebx
 
     ; far jump to enable Protected Mode and load CS with 32 bit segment
-    jmp gdt.kernel_code:protected_mode_ap
+.far_jump:
+    jmp dword gdt.kernel_code:protected_mode_ap
 
 USE32
 protected_mode_ap:
@@ -69,20 +122,83 @@ protected_mode_ap:
     mov gs, eax
     mov ss, eax
 
-    mov eax, [trampoline.stack_end]
+    ; esi = our slot, or park if the kernel did not prepare one
+    mov edi, [ebp + trampoline.apic_ids]
+    mov ecx, [ebp + trampoline.slot_count]
+    xor ebx, ebx
+.find_slot:
+    cmp ebx, ecx
+    jae .halt
+    cmp [edi + ebx * 4], esi
+    je .found_slot
+    inc ebx
+    jmp .find_slot
+.found_slot:
+    mov esi, ebx
+    shl esi, 6
+    add esi, [ebp + trampoline.slots]
+
+    mov dword [esi + APSlot.ready], 1
+
+    ; wait for the BSP to let us into the kernel
+.wait_go:
+    pause
+    cmp dword [esi + APSlot.go], 0
+    je .wait_go
+
+    mov eax, [esi + APSlot.stack_end]
     lea esp, [eax - 256]
 
-    mov eax, trampoline.cpu_id
+    lea eax, [esi + APSlot.cpu_id]
     push eax
 
-    mov eax, [trampoline.code]
-    mov dword [trampoline.ready], 1
+    mov eax, [esi + APSlot.code]
     call eax
 .halt:
     cli
     hlt
     jmp .halt
 
+; switches the calling CPU to new page tables whose format depends on CR4.PAE, which
+; can only change with paging disabled, so it runs identity mapped in both tables
+; cdecl: switch_paging(root_table, cr4_set, efer_set)
+; root_table: new CR3, the PDPT with PAE
+; interrupts must be disabled
+switch_paging:
+    push ebx
+    push esi
+    push edi
+    mov edi, [esp + 16]
+    mov esi, [esp + 20]
+    mov ebx, [esp + 24]
+
+    ; the stack is not touched again until paging is back on
+    mov eax, cr0
+    and eax, ~(1 << 31)
+    mov cr0, eax
+
+    mov eax, cr4
+    or eax, esi
+    mov cr4, eax
+
+    test ebx, ebx
+    jz .efer_done
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, ebx
+    wrmsr
+.efer_done:
+    mov cr3, edi
+
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+
+    pop edi
+    pop esi
+    pop ebx
+    ret
+
 struc GDTEntry
     .limitl resw 1
     .basel resw 1
@@ -141,6 +257,7 @@ flags:
 
 gdtr:
     dw gdt.end + 1  ; size
+.base:
     dq gdt          ; offset
 
 gdt:
@@ -167,4 +284,11 @@ istruc GDTEntry
     at GDTEntry.baseh, db 0
 iend
 
-.end equ $ - gdt
\ No newline at end of file
+.end equ $ - gdt
+
+; dword fields that hold trampoline offsets and need the load address added
+relocs:
+    dw (.end - $ - 2) / 2
+    dw gdtr.base
+    dw startup_ap.far_jump + 2
+.end:
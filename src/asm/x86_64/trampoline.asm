@@ -1,31 +1,70 @@
 ; trampoline for bringing up APs
 ; compiled with nasm by build.rs, and included in src/acpi/madt.rs
+; position independent: the kernel copies it to any free page below 1 MiB and
+; applies the relocations listed at `relocs` before sending SIPIs
 
-ORG 0x8000
+ORG 0
 SECTION .text
 USE16
 
 trampoline:
     jmp short startup_ap
     times 8 - ($ - trampoline) nop
-    .ready: dq 0
-    .cpu_id: dq 0
     .page_table: dq 0
-    .stack_start: dq 0
-    .stack_end: dq 0
-    .code: dq 0
+    .slots: dq 0
+    .slot_count: dq 0
+    .relocs: dq relocs
+    .cr4: dq 0
+    .switch_paging: dq switch_paging
+    .efer: dq 0
+    .apic_ids: dq 0
+
+; per-AP slot, at the index of the AP's APIC ID in the apic_ids array (dwords);
+; cpu_id through stack_end are passed to the kernel
+struc APSlot
+    .cpu_id resq 1
+    .page_table resq 1
+    .stack_start resq 1
+    .stack_end resq 1
+    .code resq 1
+    .ready resq 1
+    .go resq 1
+    .padding resq 1
+endstruc
 
 startup_ap:
     cli
 
-    xor ax, ax
+    ; the SIPI vector leaves us at base:0, so CS selects our data
+    mov ax, cs
     mov ds, ax
     mov es, ax
     mov ss, ax
 
+    ; ebp holds our linear base for addressing data after the mode switch
+    movzx ebp, ax
+    shl ebp, 4
+
     ; initialize stack to invalid value
     mov sp, 0
 
+    ; find our APIC ID, which selects our slot once paging is enabled
+    xor eax, eax
+    cpuid
+    cmp eax, 0xB
+    jb .initial_apic_id
+    mov eax, 0xB
+    xor ecx, ecx
+    cpuid
+    mov esi, edx
+    jmp .have_apic_id
+.initial_apic_id:
+    mov eax, 1
+    cpuid
+    shr ebx, 24
+    mov esi, ebx
+.have_apic_id:
+
     ; cr3 holds pointer to PML4
     mov edi, [trampoline.page_table]
     mov cr3, edi
@@ -42,6 +81,8 @@ startup_ap:
     ; 4: Page Size Extension
     mov eax, cr4
     or eax, 1 << 9 | 1 << 7 | 1 << 5 | 1 << 4
+    ; extra bits requested by the kernel, such as 12: 5-level paging
+    or eax, [trampoline.cr4]
     mov cr4, eax
 
     ; initialize floating point registers
@@ -54,6 +95,7 @@ startup_ap:
     mov ecx, 0xC0000080               ; Read from the EFER MSR.
     rdmsr
     or eax, 1 << 11 | 1 << 8          ; Set the Long-Mode-Enable and NXE bit.
+    or eax, [trampoline.efer]
     wrmsr
 
     ; enabling paging and protection simultaneously
@@ -65,7 +107,8 @@ startup_ap:
     mov cr0, ebx
 
     ; far jump to enable Long Mode and load CS with 64 bit segment
-    jmp gdt.kernel_code:long_mode_ap
+.far_jump:
+    jmp dword gdt.kernel_code:long_mode_ap
 
 USE64
 long_mode_ap:
@@ -76,15 +119,145 @@ long_mode_ap:
     mov gs, rax
     mov ss, rax
 
-    mov rcx, [trampoline.stack_end]
+    ; rsi = our slot, or park if the kernel did not prepare one
+    mov ebp, ebp
+    mov rdi, [rbp + trampoline.apic_ids]
+    mov rcx, [rbp + trampoline.slot_count]
+    xor ebx, ebx
+.find_slot:
+    cmp rbx, rcx
+    jae .park
+    cmp [rdi + rbx * 4], esi
+    je .found_slot
+    inc rbx
+    jmp .find_slot
+.found_slot:
+    mov rsi, rbx
+    shl rsi, 6
+    add rsi, [rbp + trampoline.slots]
+
+    mov qword [rsi + APSlot.ready], 1
+
+    ; wait for the BSP to let us into the kernel
+.wait_go:
+    pause
+    cmp qword [rsi + APSlot.go], 0
+    je .wait_go
+
+    mov rcx, [rsi + APSlot.stack_end]
     lea rsp, [rcx - 256]
 
-    mov rdi, trampoline.cpu_id
+    lea rdi, [rsi + APSlot.cpu_id]
 
-    mov rax, [trampoline.code]
-    mov qword [trampoline.ready], 1
+    mov rax, [rsi + APSlot.code]
     jmp rax
 
+.park:
+    cli
+    hlt
+    jmp .park
+
+; switches the calling CPU between 4- and 5-level paging, which is only possible with
+; paging disabled, so it runs identity mapped in both the old and new page tables
+; rdi: new root page table, below 4 GiB
+; rsi: nonzero to enable 5-level paging
+; interrupts must be disabled
+switch_paging:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    lea rbp, [rel trampoline]
+    mov [rbp + switch_paging_state.rsp], rsp
+    sgdt [rbp + switch_paging_state.gdtr]
+    mov ax, cs
+    mov [rbp + switch_paging_state.cs], ax
+    mov ax, ds
+    mov [rbp + switch_paging_state.ds], ax
+    mov ax, es
+    mov [rbp + switch_paging_state.es], ax
+    mov ax, ss
+    mov [rbp + switch_paging_state.ss], ax
+
+    ; fs and gs are left alone so their bases survive
+    lgdt [rbp + gdtr]
+    mov ax, gdt.compat_data
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; drop to compatibility mode
+    jmp far [rbp + switch_paging_state.compat_ptr]
+
+USE32
+.compat:
+    ; 31: Paging, clearing it leaves long mode
+    mov eax, cr0
+    and eax, ~(1 << 31)
+    mov cr0, eax
+
+    ; 12: 5-level paging, only writable while paging is off
+    mov eax, cr4
+    and eax, ~(1 << 12)
+    test esi, esi
+    jz .set_cr4
+    or eax, 1 << 12
+.set_cr4:
+    mov cr4, eax
+    mov cr3, edi
+
+    ; EFER.LME is still set, so this re-enters long mode
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+
+    jmp far [ebp + switch_paging_state.long_ptr]
+
+USE64
+.long:
+    lea rbp, [rel trampoline]
+    lgdt [rbp + switch_paging_state.gdtr]
+    mov ax, [rbp + switch_paging_state.ds]
+    mov ds, ax
+    mov ax, [rbp + switch_paging_state.es]
+    mov es, ax
+    mov ax, [rbp + switch_paging_state.ss]
+    mov ss, ax
+    mov rsp, [rbp + switch_paging_state.rsp]
+
+    ; reload CS from the kernel GDT
+    movzx eax, word [rbp + switch_paging_state.cs]
+    push rax
+    lea rax, [rel .done]
+    push rax
+    o64 retf
+
+.done:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+
+switch_paging_state:
+    .rsp: dq 0
+    .gdtr: times 10 db 0
+    .cs: dw 0
+    .ds: dw 0
+    .es: dw 0
+    .ss: dw 0
+    .compat_ptr:
+        dd switch_paging.compat
+        dw gdt.compat_code
+    .long_ptr:
+        dd switch_paging.long
+        dw gdt.kernel_code
+
 struc GDTEntry
     .limitl resw 1
     .basel resw 1
@@ -143,6 +316,7 @@ flags:
 
 gdtr:
     dw gdt.end + 1  ; size
+.base:
     dq gdt          ; offset
 
 gdt:
@@ -170,4 +344,34 @@ istruc GDTEntry
     at GDTEntry.baseh, db 0
 iend
 
-.end equ $ - gdt
\ No newline at end of file
+; flat 32-bit segments used by switch_paging while in compatibility mode
+.compat_code equ $ - gdt
+istruc GDTEntry
+    at GDTEntry.limitl, dw 0xFFFF
+    at GDTEntry.basel, dw 0
+    at GDTEntry.basem, db 0
+    at GDTEntry.attribute, db attrib.present | attrib.user | attrib.code | attrib.readable
+    at GDTEntry.flags__limith, db 0xF | flags.granularity | flags.default_operand_size
+    at GDTEntry.baseh, db 0
+iend
+
+.compat_data equ $ - gdt
+istruc GDTEntry
+    at GDTEntry.limitl, dw 0xFFFF
+    at GDTEntry.basel, dw 0
+    at GDTEntry.basem, db 0
+    at GDTEntry.attribute, db attrib.present | attrib.user | attrib.writable
+    at GDTEntry.flags__limith, db 0xF | flags.granularity | flags.default_operand_size
+    at GDTEntry.baseh, db 0
+iend
+
+.end equ $ - gdt
+
+; dword fields that hold trampoline offsets and need the load address added
+relocs:
+    dw (.end - $ - 2) / 2
+    dw gdtr.base
+    dw startup_ap.far_jump + 2
+    dw switch_paging_state.compat_ptr
+    dw switch_paging_state.long_ptr
+.end:
@@ -0,0 +1,256 @@
+//! # Alternatives
+//! Instruction sequences that are rewritten once at boot depending on the CPU's
+//! features, instead of branching on them every time.
+//!
+//! [`alternative!`](crate::alternative) emits the default sequence in place, padded
+//! to the length of the alternative, which goes to `.altcode`. The feature name goes
+//! to `.altfeatures` and a record tying the three together to `.altrelocs`.
+//! [`apply`] copies the alternative over the default for every present feature,
+//! skipping records whose name or alternative lie outside of their sections.
+//!
+//! Alternatives are copied, so they must not contain relative branches or
+//! references leaving the sequence.
+
+use core::{
+    ptr, slice, str,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    memory::KernelMapper,
+    paging::{RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+};
+
+use super::{features, CpuFeatures, Feature};
+
+unsafe extern "C" {
+    static __altcode_start: u8;
+    static __altcode_end: u8;
+    static __altrelocs_start: u8;
+    static __altrelocs_end: u8;
+    static __altfeatures_start: u8;
+    static __altfeatures_end: u8;
+}
+
+/// Emits an asm template running `default`, or `then` on CPUs with `feature`.
+///
+/// ```ignore
+/// asm!(alternative!(feature: "smap", then: ["clac"], default: []));
+/// ```
+#[macro_export]
+macro_rules! alternative {
+    (feature: $feature:literal, then: [$($then:literal),* $(,)?], default: [$($default:literal),* $(,)?]) => {
+        concat!(
+            "
+            .pushsection .altcode, \"a\"
+            7730:
+            ", $($then, "\n",)* "
+            7731:
+            .popsection
+            .pushsection .altfeatures, \"a\"
+            7732:
+            .ascii \"", $feature, "\"
+            7733:
+            .popsection
+            7710:
+            ", $($default, "\n",)* "
+            7711:
+            ", $crate::__alternative_padding!("((7731b - 7730b) - (7711b - 7710b))"), "
+            7712:
+            .pushsection .altrelocs, \"a\"
+            .balign ", $crate::__alternative_word!(size), "
+            ", $crate::__alternative_word!(directive), " 7732b, 7733b - 7732b, 7710b, 7711b - 7710b, 7712b - 7710b, 7730b, 7731b - 7730b
+            .popsection
+            "
+        )
+    };
+}
+
+// A comparison is -1 or 1 when true depending on the assembler, so it is squared to
+// pad by the difference only when the alternative is longer.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __alternative_padding {
+    ($diff:literal) => {
+        concat!(".skip (", $diff, " > 0) * (", $diff, " > 0) * ", $diff, ", 0x90")
+    };
+}
+
+#[cfg(target_arch = "aarch64")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __alternative_padding {
+    ($diff:literal) => {
+        concat!(".fill (", $diff, " > 0) * (", $diff, " > 0) * ", $diff, " / 4, 4, 0xd503201f")
+    };
+}
+
+#[cfg(target_pointer_width = "64")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __alternative_word {
+    (size) => {
+        "8"
+    };
+    (directive) => {
+        ".quad"
+    };
+}
+
+#[cfg(target_pointer_width = "32")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __alternative_word {
+    (size) => {
+        "4"
+    };
+    (directive) => {
+        ".long"
+    };
+}
+
+/// One record in `.altrelocs`, as emitted by `alternative!`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct AltReloc {
+    name: *const u8,
+    name_len: usize,
+    /// Default sequence in `.text`, followed by its padding.
+    code: *mut u8,
+    code_len: usize,
+    padded_len: usize,
+    altcode: *const u8,
+    altcode_len: usize,
+}
+
+/// Returns whether the `len` bytes at `ptr` lie between the linker symbols `start` and `end`.
+fn within(ptr: *const u8, len: usize, start: *const u8, end: *const u8) -> bool {
+    let (ptr, start, end) = (ptr as usize, start as usize, end as usize);
+    ptr >= start && ptr.checked_add(len).is_some_and(|ptr_end| ptr_end <= end)
+}
+
+impl AltReloc {
+    /// Checks that the name and the alternative are where `alternative!` puts them,
+    /// and that the alternative fits, before anything is read or copied.
+    fn is_valid(&self) -> bool {
+        unsafe {
+            within(self.name, self.name_len, &raw const __altfeatures_start, &raw const __altfeatures_end)
+                && within(self.altcode, self.altcode_len, &raw const __altcode_start, &raw const __altcode_end)
+                && self.altcode_len <= self.padded_len
+        }
+    }
+
+    fn feature_name(&self) -> &'static str {
+        let name = unsafe { slice::from_raw_parts(self.name, self.name_len) };
+        str::from_utf8(name).unwrap_or("<invalid>")
+    }
+}
+
+static APPLIED: AtomicBool = AtomicBool::new(false);
+/// Features whose alternatives were patched in.
+static PATCHED: AtomicU64 = AtomicU64::new(0);
+
+fn relocs() -> &'static [AltReloc] {
+    unsafe {
+        let start = &raw const __altrelocs_start as *const AltReloc;
+        let end = &raw const __altrelocs_end as *const AltReloc;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Returns the features whose alternatives are in use.
+pub fn patched() -> CpuFeatures {
+    CpuFeatures(PATCHED.load(Ordering::Acquire))
+}
+
+/// Patches every alternative whose feature the boot CPU has. Runs once, after
+/// [`super::init`] and before any AP is started, as no other CPU may execute the
+/// code being rewritten.
+pub unsafe fn apply() {
+    if APPLIED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let features = features();
+    let mut patched = CpuFeatures::empty();
+    let mut count = 0;
+    for reloc in relocs() {
+        if !reloc.is_valid() {
+            log::error!("Malformed alternative record for {:p}, not applied", reloc.code);
+            continue;
+        }
+        let name = reloc.feature_name();
+        let Some(feature) = Feature::from_name(name) else {
+            log::warn!("Alternative at {:p} tagged with unknown feature {:?}", reloc.code, name);
+            continue;
+        };
+        if !features.contains(feature) {
+            continue;
+        }
+
+        let mut code = [0u8; 64];
+        assert!(reloc.padded_len <= code.len(), "alternative at {:p} is too long", reloc.code);
+        ptr::copy_nonoverlapping(reloc.altcode, code.as_mut_ptr(), reloc.altcode_len);
+        fill_nops(&mut code[reloc.altcode_len..reloc.padded_len]);
+        write_text(reloc.code as usize, &code[..reloc.padded_len]);
+
+        patched.insert(feature);
+        count += 1;
+    }
+
+    PATCHED.store(patched.0, Ordering::Release);
+    log::info!("Applied {} of {} alternatives", count, relocs().len());
+}
+
+/// Fills the part of a patch after a shorter alternative.
+fn fill_nops(bytes: &mut [u8]) {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    bytes.fill(0x90);
+    #[cfg(target_arch = "aarch64")]
+    for nop in bytes.chunks_exact_mut(4) {
+        nop.copy_from_slice(&0xd503201fu32.to_le_bytes());
+    }
+}
+
+/// Writes `bytes` over read-only kernel text at `addr`, through the linear mapping
+/// of the physical pages behind it.
+unsafe fn write_text(addr: usize, bytes: &[u8]) {
+    for (i, &byte) in bytes.iter().enumerate() {
+        // Sequences may cross a page boundary, so every byte is translated
+        ptr::write_volatile(text_alias(addr + i) as *mut u8, byte);
+    }
+    sync_text(addr, bytes.len());
+}
+
+/// Returns the linear mapping address of the kernel text byte at `virt`.
+unsafe fn text_alias(virt: usize) -> usize {
+    let (phys, _) = KernelMapper::lock()
+        .translate(VirtualAddress::new(virt & !(PAGE_SIZE - 1)))
+        .expect("alternative in unmapped kernel text");
+    RmmA::phys_to_virt(phys).data() + (virt & (PAGE_SIZE - 1))
+}
+
+/// Makes rewritten instructions visible to instruction fetch on this CPU.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn sync_text(_addr: usize, _len: usize) {
+    // Self-modifying code is coherent on x86; CPUID serializes the pipeline
+    #[cfg(target_arch = "x86")]
+    core::arch::x86::__cpuid(0);
+    #[cfg(target_arch = "x86_64")]
+    core::arch::x86_64::__cpuid(0);
+}
+
+/// Makes rewritten instructions visible to instruction fetch on this CPU.
+#[cfg(target_arch = "aarch64")]
+unsafe fn sync_text(addr: usize, len: usize) {
+    use core::arch::asm;
+
+    for virt in (addr & !3..addr + len).step_by(4) {
+        let alias = text_alias(virt);
+        // Clean the data side through the alias written, invalidate the instruction side by the text address
+        asm!("dc cvau, {}", "dsb ish", "ic ivau, {}", in(reg) alias, in(reg) virt, options(nostack, preserves_flags));
+    }
+    asm!("dsb ish", "isb", options(nostack, preserves_flags));
+}
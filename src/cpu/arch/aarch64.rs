@@ -0,0 +1,122 @@
+//! Features from the ID registers, which EL1 can always read.
+
+use core::arch::asm;
+
+use super::{features, CpuFeatures};
+
+features! {
+    Fp = "fp",
+    Asimd = "asimd",
+    Aes = "aes",
+    Pmull = "pmull",
+    Sha1 = "sha1",
+    Sha256 = "sha256",
+    Sha512 = "sha512",
+    Sha3 = "sha3",
+    Crc32 = "crc32",
+    /// Large System Extensions atomics.
+    Atomics = "atomics",
+    Rdm = "rdm",
+    DotProd = "dotprod",
+    FlagM = "flagm",
+    /// Outer shareable TLB maintenance.
+    TlbiOs = "tlbi_os",
+    /// TLB maintenance by address range.
+    TlbiRange = "tlbi_range",
+    Rng = "rng",
+    /// `dc cvap`.
+    Dcpop = "dcpop",
+    /// `dc cvadp`.
+    Dcpodp = "dcpodp",
+    PointerAuth = "pauth",
+    Jscvt = "jscvt",
+    Fcma = "fcma",
+    Lrcpc = "lrcpc",
+    /// Speculation barrier.
+    Sb = "sb",
+    Sve = "sve",
+    Dit = "dit",
+    Bti = "bti",
+    Ssbs = "ssbs",
+    Mte = "mte",
+    /// Hardware update of the access flag.
+    HwAccessFlag = "hw_af",
+    /// Hardware update of the dirty state.
+    HwDirtyBit = "hw_dbm",
+    Vhe = "vhe",
+    Pan = "pan",
+    Cnp = "cnp",
+    Uao = "uao",
+    E0pd = "e0pd",
+}
+
+macro_rules! read_id {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $reg), out(reg) value, options(nomem, nostack, preserves_flags)) };
+        value
+    }};
+}
+
+/// Returns the unsigned 4-bit ID field at `shift`.
+#[inline(always)]
+fn field(reg: u64, shift: u32) -> u64 {
+    reg >> shift & 0xF
+}
+
+pub fn detect() -> CpuFeatures {
+    let mut features = CpuFeatures::empty();
+    let isar0 = read_id!("id_aa64isar0_el1");
+    let isar1 = read_id!("id_aa64isar1_el1");
+    let pfr0 = read_id!("id_aa64pfr0_el1");
+    let pfr1 = read_id!("id_aa64pfr1_el1");
+    let mmfr1 = read_id!("id_aa64mmfr1_el1");
+    let mmfr2 = read_id!("id_aa64mmfr2_el1");
+
+    // FP and AdvSIMD are signed fields, 0xF meaning absent
+    features.set(Feature::Fp, field(pfr0, 16) != 0xF);
+    features.set(Feature::Asimd, field(pfr0, 20) != 0xF);
+    features.set(Feature::Sve, field(pfr0, 32) >= 1);
+    features.set(Feature::Dit, field(pfr0, 48) >= 1);
+
+    features.set(Feature::Aes, field(isar0, 4) >= 1);
+    features.set(Feature::Pmull, field(isar0, 4) >= 2);
+    features.set(Feature::Sha1, field(isar0, 8) >= 1);
+    features.set(Feature::Sha256, field(isar0, 12) >= 1);
+    features.set(Feature::Sha512, field(isar0, 12) >= 2);
+    features.set(Feature::Crc32, field(isar0, 16) >= 1);
+    features.set(Feature::Atomics, field(isar0, 20) >= 2);
+    features.set(Feature::Rdm, field(isar0, 28) >= 1);
+    features.set(Feature::Sha3, field(isar0, 32) >= 1);
+    features.set(Feature::DotProd, field(isar0, 44) >= 1);
+    features.set(Feature::FlagM, field(isar0, 52) >= 1);
+    features.set(Feature::TlbiOs, field(isar0, 56) >= 1);
+    features.set(Feature::TlbiRange, field(isar0, 56) >= 2);
+    features.set(Feature::Rng, field(isar0, 60) >= 1);
+
+    features.set(Feature::Dcpop, field(isar1, 0) >= 1);
+    features.set(Feature::Dcpodp, field(isar1, 0) >= 2);
+    features.set(
+        Feature::PointerAuth,
+        field(isar1, 4) != 0 || field(isar1, 8) != 0 || field(isar1, 24) != 0 || field(isar1, 28) != 0,
+    );
+    features.set(Feature::Jscvt, field(isar1, 12) >= 1);
+    features.set(Feature::Fcma, field(isar1, 16) >= 1);
+    features.set(Feature::Lrcpc, field(isar1, 20) >= 1);
+    features.set(Feature::Sb, field(isar1, 36) >= 1);
+
+    features.set(Feature::Bti, field(pfr1, 0) >= 1);
+    features.set(Feature::Ssbs, field(pfr1, 4) >= 1);
+    features.set(Feature::Mte, field(pfr1, 8) >= 1);
+
+    features.set(Feature::HwAccessFlag, field(mmfr1, 0) >= 1);
+    features.set(Feature::HwDirtyBit, field(mmfr1, 0) >= 2);
+    features.set(Feature::Vhe, field(mmfr1, 8) >= 1);
+    features.set(Feature::Pan, field(mmfr1, 20) >= 1);
+
+    features.set(Feature::Cnp, field(mmfr2, 0) >= 1);
+    features.set(Feature::Uao, field(mmfr2, 4) >= 1);
+    features.set(Feature::E0pd, field(mmfr2, 60) >= 1);
+
+    features
+}
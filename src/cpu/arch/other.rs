@@ -0,0 +1,9 @@
+//! No feature detection on this architecture yet.
+
+use super::{features, CpuFeatures};
+
+features! {}
+
+pub fn detect() -> CpuFeatures {
+    CpuFeatures::empty()
+}
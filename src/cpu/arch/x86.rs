@@ -0,0 +1,175 @@
+//! Features from CPUID, shared by i686 and x86_64.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid_count, CpuidResult};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+
+use super::{features, CpuFeatures};
+
+features! {
+    Fpu = "fpu",
+    Tsc = "tsc",
+    Msr = "msr",
+    Pae = "pae",
+    Apic = "apic",
+    Pge = "pge",
+    Pat = "pat",
+    Clflush = "clflush",
+    Fxsr = "fxsr",
+    Sse = "sse",
+    Sse2 = "sse2",
+    Sse3 = "sse3",
+    Pclmulqdq = "pclmulqdq",
+    Ssse3 = "ssse3",
+    Fma = "fma",
+    Cx16 = "cx16",
+    Pcid = "pcid",
+    Sse41 = "sse4.1",
+    Sse42 = "sse4.2",
+    X2Apic = "x2apic",
+    Movbe = "movbe",
+    Popcnt = "popcnt",
+    TscDeadline = "tsc_deadline",
+    Aes = "aes",
+    Xsave = "xsave",
+    Avx = "avx",
+    F16c = "f16c",
+    Rdrand = "rdrand",
+    /// Running under a hypervisor.
+    Hypervisor = "hypervisor",
+    /// The local APIC timer keeps running in deep C-states.
+    Arat = "arat",
+    Fsgsbase = "fsgsbase",
+    Bmi1 = "bmi1",
+    Avx2 = "avx2",
+    Smep = "smep",
+    Bmi2 = "bmi2",
+    /// Enhanced `rep movsb` and `rep stosb`.
+    Erms = "erms",
+    Invpcid = "invpcid",
+    Avx512f = "avx512f",
+    Rdseed = "rdseed",
+    Adx = "adx",
+    Smap = "smap",
+    Clflushopt = "clflushopt",
+    Clwb = "clwb",
+    Sha = "sha",
+    Umip = "umip",
+    Pku = "pku",
+    La57 = "la57",
+    Rdpid = "rdpid",
+    /// Fast short `rep movsb`.
+    Fsrm = "fsrm",
+    Xsaveopt = "xsaveopt",
+    Xsavec = "xsavec",
+    Xsaves = "xsaves",
+    Nx = "nx",
+    Pdpe1gb = "pdpe1gb",
+    Rdtscp = "rdtscp",
+    LongMode = "lm",
+    /// The TSC runs at a constant rate in every P-, C- and T-state.
+    InvariantTsc = "invariant_tsc",
+}
+
+#[inline(always)]
+fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+#[inline(always)]
+fn bit(reg: u32, bit: u32) -> bool {
+    reg & (1 << bit) != 0
+}
+
+pub fn detect() -> CpuFeatures {
+    let mut features = CpuFeatures::empty();
+    let max_leaf = cpuid(0, 0).eax;
+    let max_ext_leaf = cpuid(0x8000_0000, 0).eax;
+
+    let leaf1 = cpuid(1, 0);
+    for (feature, reg, index) in [
+        (Feature::Fpu, leaf1.edx, 0),
+        (Feature::Tsc, leaf1.edx, 4),
+        (Feature::Msr, leaf1.edx, 5),
+        (Feature::Pae, leaf1.edx, 6),
+        (Feature::Apic, leaf1.edx, 9),
+        (Feature::Pge, leaf1.edx, 13),
+        (Feature::Pat, leaf1.edx, 16),
+        (Feature::Clflush, leaf1.edx, 19),
+        (Feature::Fxsr, leaf1.edx, 24),
+        (Feature::Sse, leaf1.edx, 25),
+        (Feature::Sse2, leaf1.edx, 26),
+        (Feature::Sse3, leaf1.ecx, 0),
+        (Feature::Pclmulqdq, leaf1.ecx, 1),
+        (Feature::Ssse3, leaf1.ecx, 9),
+        (Feature::Fma, leaf1.ecx, 12),
+        (Feature::Cx16, leaf1.ecx, 13),
+        (Feature::Pcid, leaf1.ecx, 17),
+        (Feature::Sse41, leaf1.ecx, 19),
+        (Feature::Sse42, leaf1.ecx, 20),
+        (Feature::X2Apic, leaf1.ecx, 21),
+        (Feature::Movbe, leaf1.ecx, 22),
+        (Feature::Popcnt, leaf1.ecx, 23),
+        (Feature::TscDeadline, leaf1.ecx, 24),
+        (Feature::Aes, leaf1.ecx, 25),
+        (Feature::Xsave, leaf1.ecx, 26),
+        (Feature::Avx, leaf1.ecx, 28),
+        (Feature::F16c, leaf1.ecx, 29),
+        (Feature::Rdrand, leaf1.ecx, 30),
+        (Feature::Hypervisor, leaf1.ecx, 31),
+    ] {
+        features.set(feature, bit(reg, index));
+    }
+
+    if max_leaf >= 6 {
+        features.set(Feature::Arat, bit(cpuid(6, 0).eax, 2));
+    }
+
+    if max_leaf >= 7 {
+        let leaf7 = cpuid(7, 0);
+        for (feature, reg, index) in [
+            (Feature::Fsgsbase, leaf7.ebx, 0),
+            (Feature::Bmi1, leaf7.ebx, 3),
+            (Feature::Avx2, leaf7.ebx, 5),
+            (Feature::Smep, leaf7.ebx, 7),
+            (Feature::Bmi2, leaf7.ebx, 8),
+            (Feature::Erms, leaf7.ebx, 9),
+            (Feature::Invpcid, leaf7.ebx, 10),
+            (Feature::Avx512f, leaf7.ebx, 16),
+            (Feature::Rdseed, leaf7.ebx, 18),
+            (Feature::Adx, leaf7.ebx, 19),
+            (Feature::Smap, leaf7.ebx, 20),
+            (Feature::Clflushopt, leaf7.ebx, 23),
+            (Feature::Clwb, leaf7.ebx, 24),
+            (Feature::Sha, leaf7.ebx, 29),
+            (Feature::Umip, leaf7.ecx, 2),
+            (Feature::Pku, leaf7.ecx, 3),
+            (Feature::La57, leaf7.ecx, 16),
+            (Feature::Rdpid, leaf7.ecx, 22),
+            (Feature::Fsrm, leaf7.edx, 4),
+        ] {
+            features.set(feature, bit(reg, index));
+        }
+    }
+
+    if max_leaf >= 0xD {
+        let xsave = cpuid(0xD, 1);
+        features.set(Feature::Xsaveopt, bit(xsave.eax, 0));
+        features.set(Feature::Xsavec, bit(xsave.eax, 1));
+        features.set(Feature::Xsaves, bit(xsave.eax, 3));
+    }
+
+    if max_ext_leaf >= 0x8000_0001 {
+        let ext = cpuid(0x8000_0001, 0);
+        features.set(Feature::Nx, bit(ext.edx, 20));
+        features.set(Feature::Pdpe1gb, bit(ext.edx, 26));
+        features.set(Feature::Rdtscp, bit(ext.edx, 27));
+        features.set(Feature::LongMode, bit(ext.edx, 29));
+    }
+    if max_ext_leaf >= 0x8000_0007 {
+        features.set(Feature::InvariantTsc, bit(cpuid(0x8000_0007, 0).edx, 8));
+    }
+
+    features
+}
@@ -0,0 +1,134 @@
+//! # CPU features
+//! Detects what the boot CPU supports, from CPUID on x86 and the ID registers on
+//! aarch64, so that drivers and the `alternative` patcher can query it.
+//!
+//! The kernel runs with the features of the boot CPU; APs only check that they
+//! have everything it patched in.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64"))]
+pub mod alternative;
+
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64.rs"]
+mod arch;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[path = "arch/x86.rs"]
+mod arch;
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64")))]
+#[path = "arch/other.rs"]
+mod arch;
+
+pub use self::arch::Feature;
+
+/// Defines the [`Feature`] enum of an architecture along with the names used to
+/// tag alternatives.
+macro_rules! features {
+    ($($(#[$attr:meta])* $variant:ident = $name:literal,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Feature {
+            $($(#[$attr])* $variant,)*
+        }
+
+        impl Feature {
+            pub const ALL: &'static [Feature] = &[$(Feature::$variant,)*];
+
+            /// Name used in `alternative!` tags and logs.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(Feature::$variant => $name,)*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(Feature::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+
+        const _: () = assert!(Feature::ALL.len() <= 64, "too many CPU features for the set");
+    };
+}
+use features;
+
+/// A set of [`Feature`]s.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures(u64);
+
+impl CpuFeatures {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    #[inline(always)]
+    pub const fn contains(self, feature: Feature) -> bool {
+        self.0 & (1 << feature as u8) != 0
+    }
+
+    #[inline(always)]
+    pub fn insert(&mut self, feature: Feature) {
+        self.0 |= 1 << feature as u8;
+    }
+
+    /// Inserts `feature` if `present`.
+    #[inline(always)]
+    pub fn set(&mut self, feature: Feature, present: bool) {
+        if present {
+            self.insert(feature);
+        }
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Feature> {
+        Feature::ALL.iter().copied().filter(move |&feature| self.contains(feature))
+    }
+}
+
+/// Features of the boot CPU, valid after [`init`].
+static FEATURES: AtomicU64 = AtomicU64::new(0);
+
+/// Detects the boot CPU's features. Must run before anything queries them, and
+/// before [`alternative::apply`].
+pub fn init() {
+    let features = arch::detect();
+    FEATURES.store(features.0, Ordering::Release);
+
+    if cfg!(debug_assertions) {
+        print!("CPU features:");
+        for feature in features.iter() {
+            print!(" {}", feature.name());
+        }
+        println!();
+    }
+}
+
+/// Checks that an AP has every feature the kernel was patched for, returning the
+/// missing ones. The AP is still started, but code depending on them may fault.
+pub fn init_ap() -> CpuFeatures {
+    #[cfg(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64"))]
+    let required = alternative::patched();
+    #[cfg(not(any(target_arch = "aarch64", target_arch = "x86", target_arch = "x86_64")))]
+    let required = CpuFeatures::empty();
+
+    let missing = CpuFeatures(required.0 & !arch::detect().0);
+    for feature in missing.iter() {
+        log::error!("AP lacks CPU feature {} that the kernel was patched for", feature.name());
+    }
+    missing
+}
+
+/// Returns the boot CPU's features.
+#[inline(always)]
+pub fn features() -> CpuFeatures {
+    CpuFeatures(FEATURES.load(Ordering::Acquire))
+}
+
+/// Returns whether the boot CPU has `feature`.
+#[inline(always)]
+pub fn has(feature: Feature) -> bool {
+    features().contains(feature)
+}
@@ -0,0 +1,204 @@
+//! # RISC-V APLIC
+//! The advanced platform-level interrupt controller turns wired interrupts into
+//! MSIs to the IMSIC of a hart when its domain is in MSI delivery mode, which is
+//! the mode driven here: each source is stacked on an IMSIC identity of its own,
+//! picked when it is first mapped, and is claimed and acknowledged at the IMSIC.
+//!
+//! The MSI address of every hart is set up by the firmware owning the root domain;
+//! targets only name the hart index, which is the hart's interrupt file in the IMSIC.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    dtb::{
+        allocate_imsic_identity,
+        fdt::{Fdt, Node},
+        imsic_hart_index,
+        irqchip::{IrqChip, IrqError, IrqSpec, Polarity, Trigger},
+    },
+    memory::{map_device_memory, PhysicalAddress},
+    percpu,
+};
+
+pub const COMPATIBLE: &str = "riscv,aplic";
+
+const DOMAINCFG: usize = 0x0000;
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM_MSI: u32 = 1 << 2;
+/// Configuration of source `i` at `SOURCECFG + (i - 1) * 4`.
+const SOURCECFG: usize = 0x0004;
+const SOURCECFG_DELEGATE: u32 = 1 << 10;
+const SOURCECFG_SM_INACTIVE: u32 = 0;
+const SOURCECFG_SM_EDGE_RISE: u32 = 4;
+const SOURCECFG_SM_EDGE_FALL: u32 = 5;
+const SOURCECFG_SM_LEVEL_HIGH: u32 = 6;
+const SOURCECFG_SM_LEVEL_LOW: u32 = 7;
+/// Rectified input values, a bit per source.
+const IN_CLRIP: usize = 0x1D00;
+const SETIENUM: usize = 0x1EDC;
+const CLRIENUM: usize = 0x1FDC;
+const SETIPNUM_LE: usize = 0x2000;
+/// Target of source `i` at `TARGET + (i - 1) * 4`: hart index and identity.
+const TARGET: usize = 0x3004;
+const TARGET_HART_SHIFT: u32 = 18;
+
+/// Flags of the second specifier cell, as in Linux's `IRQ_TYPE_*`.
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+const IRQ_TYPE_EDGE_FALLING: u32 = 2;
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+const IRQ_TYPE_LEVEL_LOW: u32 = 8;
+
+pub struct Aplic {
+    /// Mapped registers.
+    base: usize,
+    /// Highest source; zero means no interrupt.
+    sources: u32,
+    /// IMSIC identity of each source, zero until it is mapped.
+    identities: Vec<AtomicU32>,
+    /// Source mode of each source, from the last [`IrqChip::configure`].
+    modes: Vec<AtomicU32>,
+}
+
+/// Creates the driver of an APLIC node delivering MSIs. Domains in direct mode,
+/// without an `msi-parent`, are left alone.
+pub fn probe(fdt: &Fdt, node: &Node) -> Option<Box<dyn IrqChip>> {
+    if node.property("msi-parent").is_none() {
+        log::warn!("APLIC {} in direct mode is not supported", node.name);
+        return None;
+    }
+    let (base, size) = fdt.reg(node).next()?;
+    let sources = node.property("riscv,num-sources").and_then(|prop| prop.as_u32())?;
+
+    let base = unsafe { map_device_memory(PhysicalAddress::new(base as usize), size as usize) }.data();
+    let aplic = Aplic {
+        base,
+        sources,
+        identities: (0..=sources).map(|_| AtomicU32::new(0)).collect(),
+        modes: (0..=sources).map(|_| AtomicU32::new(SOURCECFG_SM_INACTIVE)).collect(),
+    };
+    unsafe {
+        ptr::write_volatile(aplic.reg(DOMAINCFG), 0);
+        for hwirq in 1..=sources {
+            ptr::write_volatile(aplic.reg(CLRIENUM), hwirq);
+            ptr::write_volatile(aplic.reg(SOURCECFG + (hwirq as usize - 1) * 4), SOURCECFG_SM_INACTIVE);
+        }
+        ptr::write_volatile(aplic.reg(DOMAINCFG), DOMAINCFG_IE | DOMAINCFG_DM_MSI);
+    }
+    log::info!("  APLIC {} with {} sources", node.name, sources);
+    Some(Box::new(aplic))
+}
+
+impl Aplic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    /// Points `hwirq` at its identity in the interrupt file of `cpu_id`.
+    fn write_target(&self, hwirq: u32, identity: u32, cpu_id: usize) -> Result<(), IrqError> {
+        let hart = imsic_hart_index(cpu_id).ok_or(IrqError::OutOfRange)?;
+        unsafe {
+            ptr::write_volatile(self.reg(TARGET + (hwirq as usize - 1) * 4), hart << TARGET_HART_SHIFT | identity)
+        };
+        Ok(())
+    }
+}
+
+impl IrqChip for Aplic {
+    fn name(&self) -> &str {
+        "aplic"
+    }
+
+    fn hwirq_count(&self) -> u32 {
+        self.sources + 1
+    }
+
+    /// Decodes the source number and its `IRQ_TYPE_*` flags.
+    fn xlate(&self, spec: &[u32]) -> Result<IrqSpec, IrqError> {
+        let (&hwirq, &flags) = spec.first().zip(spec.get(1)).ok_or(IrqError::InvalidSpecifier)?;
+        if hwirq == 0 || hwirq > self.sources {
+            return Err(IrqError::InvalidSpecifier);
+        }
+        let (trigger, polarity) = match flags {
+            IRQ_TYPE_EDGE_RISING => (Trigger::Edge, Polarity::High),
+            IRQ_TYPE_EDGE_FALLING => (Trigger::Edge, Polarity::Low),
+            IRQ_TYPE_LEVEL_HIGH => (Trigger::Level, Polarity::High),
+            IRQ_TYPE_LEVEL_LOW => (Trigger::Level, Polarity::Low),
+            _ => return Err(IrqError::InvalidSpecifier),
+        };
+        Ok(IrqSpec::new(hwirq, trigger, polarity))
+    }
+
+    fn configure(&self, spec: IrqSpec) -> Result<(), IrqError> {
+        let mode = match (spec.trigger, spec.polarity) {
+            (Trigger::Edge, Polarity::High) => SOURCECFG_SM_EDGE_RISE,
+            (Trigger::Edge, Polarity::Low) => SOURCECFG_SM_EDGE_FALL,
+            (Trigger::Level, Polarity::High) => SOURCECFG_SM_LEVEL_HIGH,
+            (Trigger::Level, Polarity::Low) => SOURCECFG_SM_LEVEL_LOW,
+        };
+        let reg = self.reg(SOURCECFG + (spec.hwirq as usize - 1) * 4);
+        unsafe {
+            // Sources delegated to a child domain belong to it
+            if ptr::read_volatile(reg) & SOURCECFG_DELEGATE != 0 {
+                return Err(IrqError::Unsupported);
+            }
+            ptr::write_volatile(reg, mode);
+        }
+        self.modes[spec.hwirq as usize].store(mode, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn enable(&self, hwirq: u32) {
+        unsafe { ptr::write_volatile(self.reg(SETIENUM), hwirq) };
+    }
+
+    fn disable(&self, hwirq: u32) {
+        unsafe { ptr::write_volatile(self.reg(CLRIENUM), hwirq) };
+    }
+
+    /// Claimed at the IMSIC instead.
+    fn ack(&self) -> Option<u32> {
+        None
+    }
+
+    /// Makes a level-triggered source that is still asserted pending again, as MSI
+    /// mode forwards it only once.
+    fn eoi(&self, hwirq: u32) {
+        let mode = self.modes.get(hwirq as usize).map_or(SOURCECFG_SM_INACTIVE, |mode| mode.load(Ordering::Relaxed));
+        if mode != SOURCECFG_SM_LEVEL_HIGH && mode != SOURCECFG_SM_LEVEL_LOW {
+            return;
+        }
+        unsafe {
+            let input = ptr::read_volatile(self.reg(IN_CLRIP + hwirq as usize / 32 * 4));
+            if input & (1 << (hwirq % 32)) != 0 {
+                ptr::write_volatile(self.reg(SETIPNUM_LE), hwirq);
+            }
+        }
+    }
+
+    /// Gives `hwirq` an IMSIC identity of its own the first time, delivered to the
+    /// calling CPU.
+    fn parent_hwirq(&self, hwirq: u32) -> Option<u32> {
+        let slot = self.identities.get(hwirq as usize)?;
+        let identity = match slot.load(Ordering::Relaxed) {
+            0 => {
+                let identity = allocate_imsic_identity()?;
+                slot.store(identity, Ordering::Relaxed);
+                identity
+            }
+            identity => identity,
+        };
+        self.write_target(hwirq, identity, percpu::cpu_id()).ok()?;
+        Some(identity)
+    }
+
+    fn set_affinity(&self, hwirq: u32, cpu_id: usize) -> Result<(), IrqError> {
+        match self.identities.get(hwirq as usize).map(|slot| slot.load(Ordering::Relaxed)) {
+            Some(0) | None => Err(IrqError::OutOfRange),
+            Some(identity) => self.write_target(hwirq, identity, cpu_id),
+        }
+    }
+}
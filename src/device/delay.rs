@@ -0,0 +1,73 @@
+use core::hint::spin_loop;
+
+use super::{
+    hpet,
+    pio::{inb, outb},
+};
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2 gate (bit 0), speaker enable (bit 1) and channel 2 output (bit 5).
+const PIT_GATE_PORT: u16 = 0x61;
+
+/// Busy-waits for at least `us` microseconds.
+///
+/// Uses the main counter of the first HPET block once the driver has taken it over,
+/// and PIT channel 2 otherwise, so it is usable before any timer has been calibrated.
+pub fn udelay(us: u64) {
+    if !hpet_udelay(us) {
+        pit_udelay(us);
+    }
+}
+
+/// Busy-waits for at least `ms` milliseconds.
+pub fn mdelay(ms: u64) {
+    udelay(ms * 1000);
+}
+
+fn hpet_udelay(us: u64) -> bool {
+    let Some(block) = hpet::get() else {
+        return false;
+    };
+
+    let ticks = block.caps.ticks(us.saturating_mul(1000));
+    let mask = block.counter_mask();
+    let mut last = block.counter();
+    let mut elapsed = 0u64;
+    while elapsed < ticks {
+        spin_loop();
+        let now = block.counter();
+        // Masked to the counter width, so a 32-bit counter wrapping still counts forward
+        elapsed += now.wrapping_sub(last) & mask;
+        last = now;
+    }
+
+    true
+}
+
+fn pit_udelay(us: u64) {
+    let mut remaining = (us * PIT_FREQUENCY).div_ceil(1_000_000);
+
+    while remaining > 0 {
+        let count = remaining.min(0xFFFF);
+        remaining -= count;
+
+        unsafe {
+            // Gate low and speaker off while the count is loaded.
+            let gate = inb(PIT_GATE_PORT) & !0b11;
+            outb(PIT_GATE_PORT, gate);
+
+            // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary.
+            outb(PIT_COMMAND, 0b1011_0000);
+            outb(PIT_CHANNEL2, count as u8);
+            outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+            outb(PIT_GATE_PORT, gate | 1);
+            while inb(PIT_GATE_PORT) & (1 << 5) == 0 {
+                spin_loop();
+            }
+            outb(PIT_GATE_PORT, gate);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! # Arm generic timer
+//! Every CPU has its own set of timers, compared against the system counter and
+//! raising a PPI when they expire. The kernel uses one of them per CPU as its
+//! clock-event device:
+//! - the EL2 physical timer when the CPU runs at EL2,
+//! - the virtual timer at EL1 under a hypervisor, which may trap the physical one,
+//! - the EL1 physical timer otherwise.
+//!
+//! Firmware describes the PPIs in the GTDT or the device tree; [`init`] takes them
+//! and [`setup`] makes the calling CPU use its timer.
+
+use alloc::boxed::Box;
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Once;
+
+use crate::{
+    dtb::irqchip::{register_irq, InterruptHandler, IrqReturn, IRQ_CHIP},
+    memory::{map_device_memory, PhysicalAddress, PAGE_SIZE},
+    percpu,
+    time::{self, ClockEvent, TimeError},
+};
+
+/// Timer control register: the timer is enabled.
+const CTL_ENABLE: u64 = 1 << 0;
+/// Timer control register: its interrupt is masked.
+const CTL_IMASK: u64 = 1 << 1;
+
+/// Base frequency register of the memory-mapped counter control frame, `CNTFID0`.
+const CNTFID0: usize = 0x20;
+
+/// Shortest delta worth programming, in counter ticks.
+const MIN_DELTA_TICKS: u64 = 16;
+
+/// Frequency found by firmware other than in `CNTFRQ_EL0`, zero if none.
+static FALLBACK_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+static PPIS: Once<TimerPpis> = Once::new();
+
+/// One of the per-CPU timers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerKind {
+    /// EL1 physical timer, `CNTP_*`.
+    Physical,
+    /// EL1 virtual timer, `CNTV_*`.
+    Virtual,
+    /// EL2 physical timer, `CNTHP_*`.
+    Hyp,
+}
+
+/// The PPI of one timer, as described by firmware.
+#[derive(Clone, Copy, Debug)]
+pub struct TimerPpi {
+    /// Virq of the PPI, mapped with its trigger mode and polarity.
+    pub virq: usize,
+    /// The timer keeps running in every power state.
+    pub always_on: bool,
+}
+
+/// The PPIs of the timers firmware describes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimerPpis {
+    pub physical: Option<TimerPpi>,
+    pub virt: Option<TimerPpi>,
+    pub hyp: Option<TimerPpi>,
+    /// The kernel runs under a hypervisor.
+    pub hypervisor: bool,
+}
+
+impl TimerPpis {
+    fn get(&self, kind: TimerKind) -> Option<TimerPpi> {
+        match kind {
+            TimerKind::Physical => self.physical,
+            TimerKind::Virtual => self.virt,
+            TimerKind::Hyp => self.hyp,
+        }
+    }
+}
+
+/// Returns the exception level the calling CPU runs at.
+fn current_el() -> u64 {
+    let el: u64;
+    unsafe { asm!("mrs {}, CurrentEL", out(reg) el, options(nomem, nostack, preserves_flags)) };
+    (el >> 2) & 0b11
+}
+
+/// Returns the counter frequency from `CNTFRQ_EL0`, or else as found by
+/// [`set_fallback_frequency`]. Zero if unknown.
+pub fn frequency() -> u64 {
+    let frequency: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack, preserves_flags)) };
+    if frequency != 0 {
+        frequency
+    } else {
+        FALLBACK_FREQUENCY.load(Ordering::Relaxed)
+    }
+}
+
+/// Uses `frequency` if firmware left `CNTFRQ_EL0` unset.
+pub fn set_fallback_frequency(frequency: u64) {
+    FALLBACK_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Reads the base frequency from the counter control frame at `phys`.
+pub fn control_frame_frequency(phys: u64) -> Option<u64> {
+    if phys == 0 || phys == u64::MAX {
+        return None;
+    }
+    let virt = unsafe { map_device_memory(PhysicalAddress::new(phys as usize), PAGE_SIZE) }.data();
+    let frequency = unsafe { ptr::read_volatile((virt + CNTFID0) as *const u32) };
+    (frequency != 0).then_some(u64::from(frequency))
+}
+
+/// Writes the control register of the calling CPU's `kind` timer.
+unsafe fn write_ctl(kind: TimerKind, value: u64) {
+    match kind {
+        TimerKind::Physical => asm!("msr cntp_ctl_el0, {}", "isb", in(reg) value, options(nostack)),
+        TimerKind::Virtual => asm!("msr cntv_ctl_el0, {}", "isb", in(reg) value, options(nostack)),
+        TimerKind::Hyp => asm!("msr cnthp_ctl_el2, {}", "isb", in(reg) value, options(nostack)),
+    }
+}
+
+/// Writes the timer value register of the calling CPU's `kind` timer, which fires
+/// once the counter advanced by `ticks`.
+unsafe fn write_tval(kind: TimerKind, ticks: u64) {
+    match kind {
+        TimerKind::Physical => asm!("msr cntp_tval_el0, {}", in(reg) ticks, options(nostack)),
+        TimerKind::Virtual => asm!("msr cntv_tval_el0, {}", in(reg) ticks, options(nostack)),
+        TimerKind::Hyp => asm!("msr cnthp_tval_el2, {}", in(reg) ticks, options(nostack)),
+    }
+}
+
+/// The timer of one CPU. Programmed through the relative timer value, so that it does
+/// not depend on the offset of the virtual counter.
+pub struct GenericTimer {
+    kind: TimerKind,
+    frequency: u64,
+    always_on: bool,
+}
+
+impl ClockEvent for GenericTimer {
+    fn name(&self) -> &'static str {
+        match self.kind {
+            TimerKind::Physical => "arch_timer_phys",
+            TimerKind::Virtual => "arch_timer_virt",
+            TimerKind::Hyp => "arch_timer_hyp",
+        }
+    }
+
+    fn rating(&self) -> u32 {
+        if self.always_on { 450 } else { 400 }
+    }
+
+    fn min_delta_ns(&self) -> u64 {
+        time::cycles_to_ns(MIN_DELTA_TICKS, self.frequency)
+    }
+
+    fn max_delta_ns(&self) -> u64 {
+        // The timer value is a signed 32-bit count
+        time::cycles_to_ns(i32::MAX as u64, self.frequency)
+    }
+
+    fn set_next_event(&mut self, delta_ns: u64) -> Result<(), TimeError> {
+        let ticks = time::ns_to_cycles(delta_ns, self.frequency).clamp(1, i32::MAX as u64);
+        unsafe {
+            write_tval(self.kind, ticks);
+            write_ctl(self.kind, CTL_ENABLE);
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        unsafe { write_ctl(self.kind, 0) };
+    }
+}
+
+/// Handler of a timer PPI, on whichever CPU it fired.
+struct TimerHandler(TimerKind);
+
+impl InterruptHandler for TimerHandler {
+    fn irq_handler(&mut self, _irq: usize) -> IrqReturn {
+        // The line stays asserted until the timer is programmed again, or masked
+        unsafe { write_ctl(self.0, CTL_ENABLE | CTL_IMASK) };
+        time::handle_event();
+        IrqReturn::Handled
+    }
+}
+
+/// Takes the timer PPIs firmware describes and sets the timer of the calling CPU up.
+/// Runs on the BSP before any AP is started; every AP calls [`setup`] on its way in.
+pub fn init(ppis: TimerPpis) {
+    if frequency() == 0 {
+        log::error!("generic_timer: unknown counter frequency");
+        return;
+    }
+    let ppis = PPIS.call_once(|| ppis);
+    for kind in [TimerKind::Physical, TimerKind::Virtual, TimerKind::Hyp] {
+        if let Some(ppi) = ppis.get(kind) {
+            log::info!("generic_timer: {:?} timer irq = {}, always-on {}", kind, ppi.virq, ppi.always_on);
+            if let Err(err) = register_irq(ppi.virq, TimerHandler(kind)) {
+                log::error!("generic_timer: irq {} unavailable: {:?}", ppi.virq, err);
+            }
+        }
+    }
+    setup();
+}
+
+/// Makes the calling CPU use the timer suited to its exception level and enables its
+/// PPI, which is banked per CPU. Returns whether the timer is in use.
+pub fn setup() -> bool {
+    let Some(ppis) = PPIS.get() else {
+        return false;
+    };
+    let candidates = match current_el() {
+        2 => [TimerKind::Hyp, TimerKind::Physical],
+        _ if ppis.hypervisor => [TimerKind::Virtual, TimerKind::Physical],
+        _ => [TimerKind::Physical, TimerKind::Virtual],
+    };
+    let Some((kind, ppi)) = candidates.into_iter().find_map(|kind| Some((kind, ppis.get(kind)?))) else {
+        log::warn!("CPU {}: no usable generic timer", percpu::cpu_id());
+        return false;
+    };
+
+    let timer = GenericTimer {
+        kind,
+        frequency: frequency(),
+        always_on: ppi.always_on,
+    };
+    if !time::register_clock_event(Box::new(timer)) {
+        return false;
+    }
+    let _ = IRQ_CHIP.irq_enable(ppi.virq);
+    true
+}
@@ -0,0 +1,619 @@
+//! # HPET
+//! The high precision event timer block described by the ACPI HPET table: a main
+//! counter running at a fixed rate, which serves as a clocksource, and up to 32
+//! comparators, each raising an interrupt once or periodically when the counter
+//! reaches its value.
+//!
+//! A comparator is delivered through one of the I/O APIC inputs it advertises, as an
+//! FSB message that works like MSI, or, for the first two, through the legacy
+//! replacement route, which takes over ISA IRQ 0 and IRQ 8 from the PIT and RTC.
+//!
+//! Firmware may describe several blocks. The first one provides the clocksource; the
+//! FSB capable comparators of all of them can be handed out to CPUs as their own
+//! clock-event devices, for when the local APIC timer stops in deep C-states. CPUs
+//! left without one have to be woken by another CPU's timer. On i686 only the first
+//! block is mapped, see [`Hpet::init`](crate::acpi::hpet::Hpet::init).
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::{Mutex, Once};
+
+use super::{
+    intremap::{MsiMessage, Source},
+    msi::{self, MsiError, MsiIrq},
+};
+use crate::{
+    acpi::hpet::{
+        Hpet, CAPABILITY_OFFSET, ENABLE_CNF, GENERAL_CONFIG_OFFSET, GENERAL_INTERRUPT_STATUS_OFFSET, LEG_RT_CNF,
+        MAIN_COUNTER_OFFSET,
+    },
+    cpu::{self, Feature},
+    dtb::irqchip::InterruptHandler,
+    percpu,
+    time::{self, ClockEvent, Clocksource, EventHandler, TimeError},
+};
+
+pub const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+/// Longest counter period the specification allows, 100 ns.
+const MAX_PERIOD_FS: u32 = 100_000_000;
+
+const NUM_TIM_CAP_SHIFT: u64 = 8;
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const LEG_RT_CAP: u64 = 1 << 15;
+
+const TIMER_CONFIG_OFFSET: usize = 0x100;
+const TIMER_COMPARATOR_OFFSET: usize = 0x108;
+const TIMER_FSB_ROUTE_OFFSET: usize = 0x110;
+const TIMER_STRIDE: usize = 0x20;
+
+const TN_INT_TYPE_LEVEL: u64 = 1 << 1;
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_PERIODIC: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_SIZE_CAP: u64 = 1 << 5;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+const TN_32MODE_CNF: u64 = 1 << 8;
+const TN_INT_ROUTE_SHIFT: u64 = 9;
+const TN_INT_ROUTE_MASK: u64 = 0x1F << TN_INT_ROUTE_SHIFT;
+const TN_FSB_EN_CNF: u64 = 1 << 14;
+const TN_FSB_INT_DEL_CAP: u64 = 1 << 15;
+const TN_INT_ROUTE_CAP_SHIFT: u64 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HpetError {
+    /// There is no comparator with that number.
+    InvalidComparator,
+    /// The comparator cannot run periodically, or the block has no legacy replacement route.
+    Unsupported,
+    /// The comparator cannot be delivered that way.
+    RouteUnavailable,
+    /// The period does not fit the comparator.
+    OutOfRange,
+    /// The one-shot deadline had passed by the time it was set.
+    Expired,
+    /// Every suitable comparator is claimed already.
+    NoComparator,
+    Msi(MsiError),
+}
+
+impl From<MsiError> for HpetError {
+    fn from(err: MsiError) -> Self {
+        Self::Msi(err)
+    }
+}
+
+/// Contents of the general capabilities register.
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    pub rev_id: u8,
+    /// Number of comparators.
+    pub comparators: u8,
+    /// The main counter is 64 bits wide rather than 32.
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    /// Main counter period in femtoseconds.
+    pub period_fs: u32,
+}
+
+impl Capabilities {
+    fn from_raw(raw: u64) -> Self {
+        Self {
+            rev_id: raw as u8,
+            comparators: ((raw >> NUM_TIM_CAP_SHIFT) & 0x1F) as u8 + 1,
+            counter_64bit: raw & COUNT_SIZE_CAP != 0,
+            legacy_replacement: raw & LEG_RT_CAP != 0,
+            vendor_id: (raw >> 16) as u16,
+            period_fs: (raw >> 32) as u32,
+        }
+    }
+
+    /// Main counter frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SEC / u64::from(self.period_fs)
+    }
+
+    /// Converts nanoseconds to counter ticks, rounding up.
+    pub fn ticks(&self, ns: u64) -> u64 {
+        (u128::from(ns) * 1_000_000).div_ceil(u128::from(self.period_fs)) as u64
+    }
+
+    /// Converts counter ticks to nanoseconds, rounding down.
+    pub fn nanos(&self, ticks: u64) -> u64 {
+        (u128::from(ticks) * u128::from(self.period_fs) / 1_000_000) as u64
+    }
+}
+
+/// What a comparator supports, from its configuration register.
+#[derive(Clone, Copy, Debug)]
+pub struct ComparatorCaps {
+    pub periodic: bool,
+    /// The comparator is 64 bits wide rather than 32.
+    pub wide: bool,
+    pub fsb: bool,
+    /// I/O APIC inputs the comparator can be routed to, one bit each.
+    pub routes: u32,
+}
+
+impl ComparatorCaps {
+    fn from_raw(raw: u64) -> Self {
+        Self {
+            periodic: raw & TN_PER_INT_CAP != 0,
+            wide: raw & TN_SIZE_CAP != 0,
+            fsb: raw & TN_FSB_INT_DEL_CAP != 0,
+            routes: (raw >> TN_INT_ROUTE_CAP_SHIFT) as u32,
+        }
+    }
+}
+
+/// How a comparator raises its interrupt.
+#[derive(Clone, Copy, Debug)]
+pub enum Delivery {
+    /// On an I/O APIC input, which the caller maps. Level triggered interrupts stay
+    /// asserted until [`HpetBlock::ack`].
+    IoApic { input: u8, level: bool },
+    /// On ISA IRQ 0 for comparator 0 and IRQ 8 for comparator 1, with the legacy
+    /// replacement route enabled for the whole block.
+    Legacy,
+    /// As a message written to the local APIC, see [`HpetBlock::allocate_fsb`].
+    Fsb(MsiMessage),
+}
+
+/// An HPET block, mapped and with its main counter running.
+pub struct HpetBlock {
+    regs: Hpet,
+    pub caps: Capabilities,
+    /// Serializes read-modify-write of the configuration registers.
+    lock: Mutex<()>,
+    /// Comparators in use, one bit each.
+    claimed: AtomicU32,
+}
+
+#[inline(always)]
+fn timer_reg(base: usize, n: u8) -> usize {
+    base + TIMER_STRIDE * usize::from(n)
+}
+
+impl HpetBlock {
+    /// Takes over the block described by `regs`, which must be mapped: comparators
+    /// are stopped, the legacy replacement route is turned off and the main counter
+    /// is started. Returns `None` if the capabilities make no sense.
+    pub fn new(regs: Hpet) -> Option<Self> {
+        let caps = Capabilities::from_raw(unsafe { regs.read_u64(CAPABILITY_OFFSET) });
+        if caps.period_fs == 0 || caps.period_fs > MAX_PERIOD_FS {
+            log::warn!("HPET: invalid counter period {} fs", caps.period_fs);
+            return None;
+        }
+
+        let block = Self {
+            regs,
+            caps,
+            lock: Mutex::new(()),
+            claimed: AtomicU32::new(0),
+        };
+        unsafe {
+            for n in 0..caps.comparators {
+                let config = block.config(n);
+                block.set_config(n, config & !(TN_INT_ENB_CNF | TN_FSB_EN_CNF | TN_TYPE_PERIODIC));
+            }
+            let general = block.regs.read_u64(GENERAL_CONFIG_OFFSET);
+            block.regs.write_u64(GENERAL_CONFIG_OFFSET, (general & !LEG_RT_CNF) | ENABLE_CNF);
+        }
+        Some(block)
+    }
+
+    #[inline(always)]
+    unsafe fn config(&self, n: u8) -> u64 {
+        self.regs.read_u64(timer_reg(TIMER_CONFIG_OFFSET, n))
+    }
+
+    #[inline(always)]
+    unsafe fn set_config(&self, n: u8, value: u64) {
+        self.regs.write_u64(timer_reg(TIMER_CONFIG_OFFSET, n), value);
+    }
+
+    #[inline(always)]
+    unsafe fn set_comparator(&self, n: u8, value: u64) {
+        self.regs.write_u64(timer_reg(TIMER_COMPARATOR_OFFSET, n), value);
+    }
+
+    fn check(&self, n: u8) -> Result<(), HpetError> {
+        if n < self.caps.comparators {
+            Ok(())
+        } else {
+            Err(HpetError::InvalidComparator)
+        }
+    }
+
+    /// Enumeration ID of the block, which names it to interrupt remapping.
+    pub fn number(&self) -> u8 {
+        self.regs.hpet_number
+    }
+
+    /// Reads the main counter. Only the low 32 bits count on a 32-bit counter.
+    pub fn counter(&self) -> u64 {
+        unsafe { self.regs.read_u64(MAIN_COUNTER_OFFSET) & self.counter_mask() }
+    }
+
+    /// Mask of the valid main counter bits, at which it wraps.
+    pub fn counter_mask(&self) -> u64 {
+        if self.caps.counter_64bit {
+            u64::MAX
+        } else {
+            u64::from(u32::MAX)
+        }
+    }
+
+    pub fn comparator_caps(&self, n: u8) -> Result<ComparatorCaps, HpetError> {
+        self.check(n)?;
+        Ok(ComparatorCaps::from_raw(unsafe { self.config(n) }))
+    }
+
+    /// Reserves comparator `n` for the caller. Returns false if it was claimed already.
+    pub fn claim(&self, n: u8) -> Result<bool, HpetError> {
+        self.check(n)?;
+        Ok(self.claimed.fetch_or(1 << n, Ordering::AcqRel) & (1 << n) == 0)
+    }
+
+    /// Stops comparator `n` and gives it back.
+    pub fn release(&self, n: u8) -> Result<(), HpetError> {
+        self.stop(n)?;
+        self.claimed.fetch_and(!(1 << n), Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Allocates a vector on `cpu_id` for FSB delivery of this block's comparators,
+    /// through interrupt remapping when it is on.
+    pub fn allocate_fsb(&self, cpu_id: usize) -> Result<MsiIrq, HpetError> {
+        Ok(msi::allocate_irq(Source::Hpet(self.number()), cpu_id)?)
+    }
+
+    /// Routes comparator `n`, leaving it stopped. Comparator 0 and 1 can only use the
+    /// legacy route while it is enabled, and turning it off moves them to `delivery`.
+    pub fn set_delivery(&self, n: u8, delivery: Delivery) -> Result<(), HpetError> {
+        let caps = self.comparator_caps(n)?;
+        let _guard = self.lock.lock();
+        unsafe {
+            let general = self.regs.read_u64(GENERAL_CONFIG_OFFSET);
+            let mut config = self.config(n) & !(TN_INT_ENB_CNF | TN_TYPE_PERIODIC);
+            config &= !(TN_INT_ROUTE_MASK | TN_FSB_EN_CNF | TN_INT_TYPE_LEVEL);
+
+            match delivery {
+                Delivery::IoApic { input, level } => {
+                    if input >= 32 || caps.routes & (1 << input) == 0 {
+                        return Err(HpetError::RouteUnavailable);
+                    }
+                    config |= u64::from(input) << TN_INT_ROUTE_SHIFT;
+                    if level {
+                        config |= TN_INT_TYPE_LEVEL;
+                    }
+                }
+                Delivery::Legacy => {
+                    if !self.caps.legacy_replacement {
+                        return Err(HpetError::Unsupported);
+                    }
+                    if n >= 2 {
+                        return Err(HpetError::RouteUnavailable);
+                    }
+                }
+                Delivery::Fsb(message) => {
+                    if !caps.fsb {
+                        return Err(HpetError::RouteUnavailable);
+                    }
+                    self.regs.write_u64(
+                        timer_reg(TIMER_FSB_ROUTE_OFFSET, n),
+                        message.address << 32 | u64::from(message.data),
+                    );
+                    config |= TN_FSB_EN_CNF;
+                }
+            }
+
+            let legacy = matches!(delivery, Delivery::Legacy);
+            if n < 2 && legacy != (general & LEG_RT_CNF != 0) {
+                // The route applies to both comparators; the other one must agree
+                let other = self.config(n ^ 1);
+                if other & TN_INT_ENB_CNF != 0 {
+                    return Err(HpetError::RouteUnavailable);
+                }
+                self.regs.write_u64(GENERAL_CONFIG_OFFSET, general ^ LEG_RT_CNF);
+            }
+            self.set_config(n, config);
+        }
+        Ok(())
+    }
+
+    /// Fires comparator `n` once, when the main counter reaches `deadline`. Returns
+    /// [`HpetError::Expired`] if the counter was past it once set, in which case the
+    /// interrupt may or may not arrive.
+    pub fn set_oneshot(&self, n: u8, deadline: u64) -> Result<(), HpetError> {
+        let caps = self.comparator_caps(n)?;
+        let _guard = self.lock.lock();
+        unsafe {
+            let config = self.config(n) & !TN_TYPE_PERIODIC;
+            let wide = caps.wide && self.caps.counter_64bit && config & TN_32MODE_CNF == 0;
+            self.set_config(n, config | TN_INT_ENB_CNF);
+            self.set_comparator(n, deadline);
+
+            // Compared in the comparator's width, the counter may have moved past it
+            let now = self.counter();
+            let passed = if wide {
+                (now.wrapping_sub(deadline) as i64) > 0
+            } else {
+                (now.wrapping_sub(deadline) as i32) > 0
+            };
+            if passed {
+                return Err(HpetError::Expired);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fires comparator `n` every `period` ticks, starting one period from now.
+    pub fn set_periodic(&self, n: u8, period: u64) -> Result<(), HpetError> {
+        let caps = self.comparator_caps(n)?;
+        if !caps.periodic {
+            return Err(HpetError::Unsupported);
+        }
+        let _guard = self.lock.lock();
+        unsafe {
+            let config = self.config(n);
+            let wide = caps.wide && self.caps.counter_64bit && config & TN_32MODE_CNF == 0;
+            if period == 0 || (!wide && period > u64::from(u32::MAX)) {
+                return Err(HpetError::OutOfRange);
+            }
+
+            // With VAL_SET the first write sets the comparator and the second one
+            // the period added to it on every match
+            self.set_config(n, config | TN_TYPE_PERIODIC | TN_VAL_SET_CNF | TN_INT_ENB_CNF);
+            self.set_comparator(n, self.counter().wrapping_add(period));
+            self.set_comparator(n, period);
+        }
+        Ok(())
+    }
+
+    /// Stops comparator `n` from raising interrupts.
+    pub fn stop(&self, n: u8) -> Result<(), HpetError> {
+        self.check(n)?;
+        let _guard = self.lock.lock();
+        unsafe {
+            let config = self.config(n);
+            self.set_config(n, config & !(TN_INT_ENB_CNF | TN_TYPE_PERIODIC));
+        }
+        Ok(())
+    }
+
+    /// Clears the interrupt status of a level triggered comparator, deasserting its line.
+    pub fn ack(&self, n: u8) -> Result<(), HpetError> {
+        self.check(n)?;
+        unsafe { self.regs.write_u64(GENERAL_INTERRUPT_STATUS_OFFSET, 1 << n) };
+        Ok(())
+    }
+}
+
+static HPETS: Once<Vec<HpetBlock>> = Once::new();
+
+/// Takes over the blocks described by the ACPI HPET tables, already mapped, in
+/// enumeration order.
+pub fn init(tables: &[Hpet]) {
+    let blocks = tables
+        .iter()
+        .filter_map(|&regs| HpetBlock::new(regs))
+        .inspect(|block| {
+            log::info!(
+                "HPET {}: {} comparators, {} Hz, {}-bit counter{}",
+                block.number(),
+                block.caps.comparators,
+                block.caps.frequency(),
+                if block.caps.counter_64bit { 64 } else { 32 },
+                if block.caps.legacy_replacement { ", legacy replacement" } else { "" },
+            )
+        })
+        .collect();
+    HPETS.call_once(|| blocks);
+    if let Some(block) = get() {
+        time::register_clocksource(Arc::new(HpetClocksource(block)));
+    }
+}
+
+/// Returns every HPET block, in enumeration order.
+pub fn blocks() -> &'static [HpetBlock] {
+    HPETS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Returns the block with the given enumeration ID.
+pub fn find(number: u8) -> Option<&'static HpetBlock> {
+    blocks().iter().find(|block| block.number() == number)
+}
+
+/// Returns the first HPET block, which provides the clocksource.
+pub fn get() -> Option<&'static HpetBlock> {
+    blocks().first()
+}
+
+/// Reads the main counter, for use as a clocksource running at [`frequency`] and
+/// wrapping at [`counter_mask`].
+pub fn counter() -> Option<u64> {
+    get().map(HpetBlock::counter)
+}
+
+/// Main counter frequency in Hz.
+pub fn frequency() -> Option<u64> {
+    get().map(|hpet| hpet.caps.frequency())
+}
+
+/// Mask of the valid main counter bits.
+pub fn counter_mask() -> Option<u64> {
+    get().map(HpetBlock::counter_mask)
+}
+
+/// Returns whether CPUs need a clock-event device besides their local APIC timer,
+/// which stops in deep C-states unless it is always running.
+pub fn needs_cpu_timers() -> bool {
+    !cpu::has(Feature::Arat)
+}
+
+/// Fewest ticks a one-shot comparator is set ahead, so the counter cannot pass the
+/// value before it is written.
+const MIN_DELTA_TICKS: u64 = 192;
+
+/// A comparator delivering to a single CPU by FSB, used as that CPU's clock-event
+/// device. Dropping it gives the comparator and the vector back.
+pub struct CpuTimer {
+    block: &'static HpetBlock,
+    comparator: u8,
+    cpu_id: usize,
+    irq: Option<MsiIrq>,
+}
+
+impl CpuTimer {
+    pub fn block(&self) -> &'static HpetBlock {
+        self.block
+    }
+
+    pub fn comparator(&self) -> u8 {
+        self.comparator
+    }
+
+    pub fn cpu_id(&self) -> usize {
+        self.cpu_id
+    }
+
+    /// Fires once when the block's main counter reaches `deadline`.
+    pub fn set_deadline(&self, deadline: u64) -> Result<(), HpetError> {
+        self.block.set_oneshot(self.comparator, deadline)
+    }
+
+    pub fn stop(&self) -> Result<(), HpetError> {
+        self.block.stop(self.comparator)
+    }
+}
+
+impl Drop for CpuTimer {
+    fn drop(&mut self) {
+        let _ = self.block.release(self.comparator);
+        if let Some(irq) = self.irq.take() {
+            irq.free();
+        }
+    }
+}
+
+impl ClockEvent for CpuTimer {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    /// Above local APIC timers that stop in deep C-states.
+    fn rating(&self) -> u32 {
+        110
+    }
+
+    fn min_delta_ns(&self) -> u64 {
+        self.block.caps.nanos(MIN_DELTA_TICKS)
+    }
+
+    /// Comparators may be 32 bits wide, and a delta past half their range reads as expired.
+    fn max_delta_ns(&self) -> u64 {
+        self.block.caps.nanos(u64::from(i32::MAX as u32))
+    }
+
+    fn set_next_event(&mut self, delta_ns: u64) -> Result<(), TimeError> {
+        let ticks = self.block.caps.ticks(delta_ns).max(MIN_DELTA_TICKS);
+        let deadline = self.block.counter().wrapping_add(ticks) & self.block.counter_mask();
+        self.set_deadline(deadline).map_err(|_| TimeError::Expired)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Hands a free FSB capable comparator of any block to `cpu_id`, running `handler`
+/// there when it fires. The comparator starts stopped.
+pub fn assign_cpu_timer(cpu_id: usize, handler: impl InterruptHandler + 'static) -> Result<CpuTimer, HpetError> {
+    let (block, comparator) = blocks()
+        .iter()
+        .flat_map(|block| (0..block.caps.comparators).map(move |n| (block, n)))
+        .find(|&(block, n)| block.comparator_caps(n).is_ok_and(|caps| caps.fsb) && block.claim(n) == Ok(true))
+        .ok_or(HpetError::NoComparator)?;
+
+    let irq = match block.allocate_fsb(cpu_id) {
+        Ok(irq) => irq,
+        Err(err) => {
+            let _ = block.release(comparator);
+            return Err(err);
+        }
+    };
+    let routed = irq
+        .set_handler(handler)
+        .and_then(|()| irq.message())
+        .map_err(HpetError::from)
+        .and_then(|message| block.set_delivery(comparator, Delivery::Fsb(message)));
+    // Dropped on error, which frees both
+    let timer = CpuTimer {
+        block,
+        comparator,
+        cpu_id,
+        irq: Some(irq),
+    };
+    routed.map(|()| timer)
+}
+
+/// Gives the calling CPU an HPET comparator as its clock-event device if its local
+/// APIC timer stops in deep C-states. Returns whether it got one.
+pub fn setup_cpu_timer() -> bool {
+    if !needs_cpu_timers() {
+        return false;
+    }
+    let cpu_id = percpu::cpu_id();
+    match assign_cpu_timer(cpu_id, EventHandler) {
+        Ok(timer) => time::register_clock_event(Box::new(timer)),
+        Err(err) => {
+            log::debug!("CPU {}: no HPET comparator: {:?}", cpu_id, err);
+            false
+        }
+    }
+}
+
+/// The main counter of the first block.
+struct HpetClocksource(&'static HpetBlock);
+
+impl Clocksource for HpetClocksource {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn frequency(&self) -> u64 {
+        self.0.caps.frequency()
+    }
+
+    fn mask(&self) -> u64 {
+        self.0.counter_mask()
+    }
+
+    fn read(&self) -> u64 {
+        self.0.counter()
+    }
+}
+
+// ---------- TESTS ----------
+#[test]
+fn test_capabilities() {
+    // QEMU: 3 comparators, 64-bit counter, legacy replacement, 10 ns period
+    let caps = Capabilities::from_raw(0x0098_9680_8086_A201);
+    assert_eq!(caps.rev_id, 1);
+    assert_eq!(caps.comparators, 3);
+    assert!(caps.counter_64bit);
+    assert!(caps.legacy_replacement);
+    assert_eq!(caps.vendor_id, 0x8086);
+    assert_eq!(caps.period_fs, 10_000_000);
+    assert_eq!(caps.frequency(), 100_000_000);
+    assert_eq!(caps.ticks(1_000), 100);
+    assert_eq!(caps.ticks(1_005), 101);
+    assert_eq!(caps.nanos(101), 1_010);
+}
@@ -0,0 +1,225 @@
+//! # IDT gates
+//! Gates of the vectors handled in this crate rather than by the kernel's own stubs:
+//! the IPI, the local APIC timer and every device vector of [`vector`].
+//! Every vector from [`DEVICE_VECTOR_BASE`] has a stub that pushes its number and
+//! jumps to a common entry, which saves the scratch registers, switches to the
+//! kernel's per-CPU base when coming from user mode and calls [`dispatch`]. Softirqs
+//! run at the end of the outermost one.
+//!
+//! The BSP copies the IDT the kernel loaded, points the gates of the handled vectors
+//! at their stubs and loads the copy in [`init`]; every AP loads the same copy in
+//! [`load`] on its way in.
+
+use alloc::vec;
+use core::{
+    arch::{asm, global_asm},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+    lapic_timer::{self, TIMER_VECTOR},
+    local_apic::the_local_apic,
+    vector::{self, DEVICE_VECTOR_BASE, SYSTEM_VECTOR_BASE},
+};
+use crate::{
+    ipi::{self, IPI_VECTOR},
+    softirq,
+};
+
+/// Bytes from one stub to the next.
+const STUB_SIZE: usize = 16;
+const STUB_COUNT: usize = 256 - DEVICE_VECTOR_BASE as usize;
+
+#[cfg(target_arch = "x86_64")]
+const GATE_SIZE: usize = 16;
+#[cfg(target_arch = "x86")]
+const GATE_SIZE: usize = 8;
+
+/// Present, DPL 0 interrupt gate, which enters with interrupts disabled.
+const GATE_INTERRUPT: u64 = 0x8E;
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: usize,
+}
+
+/// Address of the IDT copy, zero until the BSP made it.
+static IDT: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(target_arch = "x86_64")]
+global_asm!(
+    "
+    .pushsection .text.vector_stubs, \"ax\"
+    .p2align 4
+    .global __vector_stubs
+__vector_stubs:
+    .set __stub_vector, {base}
+    .rept {count}
+    .p2align 4
+    pushq $__stub_vector
+    jmp .Lvector_common
+    .set __stub_vector, __stub_vector + 1
+    .endr
+
+.Lvector_common:
+    testb $3, 16(%rsp)
+    jz 1f
+    swapgs
+1:
+    push %rax
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    mov 72(%rsp), %rdi
+    cld
+    // The vector and nine registers leave the stack 8 bytes off the 16 the call needs
+    sub $8, %rsp
+    call {dispatch}
+    add $8, %rsp
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rax
+    testb $3, 16(%rsp)
+    jz 1f
+    swapgs
+1:
+    add $8, %rsp
+    iretq
+    .popsection
+    ",
+    base = const DEVICE_VECTOR_BASE,
+    count = const STUB_COUNT,
+    dispatch = sym dispatch,
+    options(att_syntax)
+);
+
+// FS is saved here and loaded with the per-CPU selector by `dispatch`
+#[cfg(target_arch = "x86")]
+global_asm!(
+    "
+    .pushsection .text.vector_stubs, \"ax\"
+    .p2align 4
+    .global __vector_stubs
+__vector_stubs:
+    .set __stub_vector, {base}
+    .rept {count}
+    .p2align 4
+    pushl $__stub_vector
+    jmp .Lvector_common
+    .set __stub_vector, __stub_vector + 1
+    .endr
+
+.Lvector_common:
+    push %eax
+    push %ecx
+    push %edx
+    push %fs
+    cld
+    pushl 16(%esp)
+    call {dispatch}
+    add $4, %esp
+    pop %fs
+    pop %edx
+    pop %ecx
+    pop %eax
+    add $4, %esp
+    iret
+    .popsection
+    ",
+    base = const DEVICE_VECTOR_BASE,
+    count = const STUB_COUNT,
+    dispatch = sym dispatch,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    fn __vector_stubs();
+}
+
+/// Returns whether [`dispatch`] handles `vector`, leaving every other gate to the kernel.
+fn is_handled(vector: u8) -> bool {
+    matches!(vector, IPI_VECTOR | TIMER_VECTOR) || (DEVICE_VECTOR_BASE..SYSTEM_VECTOR_BASE).contains(&vector)
+}
+
+/// Writes an interrupt gate to the stub of `vector` into `idt`.
+unsafe fn write_gate(idt: *mut u64, vector: u8, cs: u16) {
+    let stub = (__vector_stubs as *const () as usize + usize::from(vector - DEVICE_VECTOR_BASE) * STUB_SIZE) as u64;
+    let low = stub & 0xFFFF | u64::from(cs) << 16 | GATE_INTERRUPT << 40 | (stub >> 16 & 0xFFFF) << 48;
+    let gate = idt.add(usize::from(vector) * GATE_SIZE / 8);
+    ptr::write_volatile(gate, low);
+    #[cfg(target_arch = "x86_64")]
+    ptr::write_volatile(gate.add(1), stub >> 32);
+}
+
+/// Copies the IDT loaded on the BSP, points the handled vectors at their stubs and
+/// loads the copy. Called once the kernel has set its IDT up, before any AP starts.
+pub unsafe fn init() {
+    let mut idtr = DescriptorTablePointer { limit: 0, base: 0 };
+    asm!("sidt [{}]", in(reg) &mut idtr, options(nostack, preserves_flags));
+
+    let copy = vec![0u64; 256 * GATE_SIZE / 8].leak();
+    let len = (usize::from(idtr.limit) + 1).min(256 * GATE_SIZE);
+    ptr::copy_nonoverlapping(idtr.base as *const u8, copy.as_mut_ptr().cast::<u8>(), len);
+
+    let cs: u16;
+    asm!("mov {:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+    for vector in DEVICE_VECTOR_BASE..=u8::MAX {
+        if is_handled(vector) {
+            write_gate(copy.as_mut_ptr(), vector, cs);
+        }
+    }
+
+    IDT.store(copy.as_mut_ptr() as usize, Ordering::Release);
+    load();
+}
+
+/// Loads the IDT made by [`init`] on the calling CPU. Does nothing before it.
+pub unsafe fn load() {
+    let base = IDT.load(Ordering::Acquire);
+    if base == 0 {
+        return;
+    }
+    let idtr = DescriptorTablePointer {
+        limit: (256 * GATE_SIZE - 1) as u16,
+        base,
+    };
+    asm!("lidt [{}]", in(reg) &idtr, options(readonly, nostack, preserves_flags));
+}
+
+/// Handles `vector`, called by the common stub with interrupts disabled, and runs
+/// the softirqs it queued once the local APIC was acknowledged.
+extern "C" fn dispatch(vector: usize) {
+    #[cfg(target_arch = "x86")]
+    unsafe { crate::percpu::load_selector() };
+
+    softirq::irq_enter();
+    match vector as u8 {
+        IPI_VECTOR => {
+            unsafe { the_local_apic().eoi() };
+            ipi::handle();
+        }
+        TIMER_VECTOR => lapic_timer::handle(),
+        vector @ DEVICE_VECTOR_BASE..SYSTEM_VECTOR_BASE => {
+            vector::handle(vector);
+            unsafe { the_local_apic().eoi() };
+        }
+        vector => {
+            log::warn!("Unexpected interrupt on vector {:#x}", vector);
+            unsafe { the_local_apic().eoi() };
+        }
+    }
+    softirq::irq_exit();
+}
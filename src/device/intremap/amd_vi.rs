@@ -0,0 +1,340 @@
+//! AMD-Vi interrupt remapping. Every source device gets a device table entry pointing
+//! at the shared remapping table; DMA from those devices is passed through untranslated.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+
+use crate::{
+    acpi::ivrs::{ivrs, SpecialDevice},
+    memory::{allocate_p2frame, map_device_memory, Frame},
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+};
+
+use super::{
+    super::ioapic::{DeliveryMode, DestinationMode},
+    write_entry128, Handle, MsiMessage, RemapEntry, Source, Table, TABLE_ORDER,
+};
+
+const REG_DEVICE_TABLE: usize = 0x0000;
+const REG_COMMAND_BUFFER: usize = 0x0008;
+const REG_CONTROL: usize = 0x0018;
+const REG_EXT_FEATURES: usize = 0x0030;
+const REG_COMMAND_HEAD: usize = 0x2000;
+const REG_COMMAND_TAIL: usize = 0x2008;
+
+const CONTROL_IOMMU_EN: u64 = 1 << 0;
+const CONTROL_CMD_BUF_EN: u64 = 1 << 12;
+const CONTROL_GA_EN: u64 = 1 << 17;
+const CONTROL_XT_EN: u64 = 1 << 50;
+
+const EFR_XT_SUP: u64 = 1 << 2;
+const EFR_GA_SUP: u64 = 1 << 7;
+
+/// One device table entry for each of the 65536 requester IDs, 32 bytes each.
+const DEVICE_TABLE_ORDER: u32 = 9;
+const DEVICE_TABLE_SIZE: usize = PAGE_SIZE << DEVICE_TABLE_ORDER;
+
+const DTE_VALID: u64 = 1 << 0;
+const DTE_INT_VALID: u64 = 1 << 0;
+const DTE_INT_TABLE_MASK: u64 = 0x000F_FFFF_FFFF_FFC0;
+const DTE_INT_REMAPPED: u64 = 0b10 << 60;
+/// INIT, ExtInt, NMI, LINT0 and LINT1 are passed through unremapped.
+const DTE_INT_PASS_LEGACY: u64 = 1 << 56 | 1 << 57 | 1 << 58 | 1 << 62 | 1 << 63;
+
+const CMD_COMPLETION_WAIT: u32 = 0x1;
+const CMD_INVALIDATE_DEVTAB_ENTRY: u32 = 0x2;
+const CMD_INVALIDATE_INTERRUPT_TABLE: u32 = 0x5;
+const COMMAND_ENTRIES: usize = PAGE_SIZE / 16;
+
+const IRTE_REMAP_EN: u64 = 1 << 0;
+const IRTE_DEST_LOGICAL: u64 = 1 << 6;
+
+/// Command ring of one IOMMU, with a completion word in the page after it.
+struct CommandBuffer {
+    frame: Frame,
+    tail: usize,
+}
+
+impl CommandBuffer {
+    #[inline(always)]
+    fn virt(&self) -> usize {
+        RmmA::phys_to_virt(PhysicalAddress::new(self.frame.base().data())).data()
+    }
+
+    #[inline(always)]
+    fn completion_phys(&self) -> u64 {
+        (self.frame.base().data() + PAGE_SIZE) as u64
+    }
+
+    #[inline(always)]
+    fn completion_ptr(&self) -> *mut u64 {
+        (self.virt() + PAGE_SIZE) as *mut u64
+    }
+}
+
+struct Iommu {
+    regs: usize,
+    devices: Vec<SpecialDevice>,
+    commands: Mutex<CommandBuffer>,
+}
+
+impl Iommu {
+    #[inline(always)]
+    unsafe fn read(&self, reg: usize) -> u64 {
+        read_volatile((self.regs + reg) as *const u64)
+    }
+
+    #[inline(always)]
+    unsafe fn write(&self, reg: usize, value: u64) {
+        write_volatile((self.regs + reg) as *mut u64, value)
+    }
+
+    /// Queues `commands` followed by a completion wait and spins until it is reached.
+    unsafe fn submit(&self, commands: &[[u32; 4]]) {
+        let mut buffer = self.commands.lock();
+        let completion = buffer.completion_ptr();
+        write_volatile(completion, 0);
+
+        let address = buffer.completion_phys();
+        let wait = [
+            address as u32 | 1,
+            (address >> 32) as u32 | CMD_COMPLETION_WAIT << 28,
+            1,
+            0,
+        ];
+        for command in commands.iter().chain(Some(&wait)) {
+            let slot = (buffer.virt() + buffer.tail * 16) as *mut u32;
+            for (i, &dword) in command.iter().enumerate() {
+                write_volatile(slot.add(i), dword);
+            }
+            buffer.tail = (buffer.tail + 1) % COMMAND_ENTRIES;
+        }
+        self.write(REG_COMMAND_TAIL, (buffer.tail as u64) << 4);
+
+        while read_volatile(completion) == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn invalidate_device(&self, requester_id: u16) {
+        let device_id = u32::from(requester_id);
+        self.submit(&[
+            [device_id, CMD_INVALIDATE_DEVTAB_ENTRY << 28, 0, 0],
+            [device_id, CMD_INVALIDATE_INTERRUPT_TABLE << 28, 0, 0],
+        ]);
+    }
+}
+
+pub struct AmdVi {
+    iommus: Vec<Iommu>,
+    device_table: Frame,
+    /// Requester IDs whose device table entry points at the remapping table.
+    attached: Mutex<Vec<u16>>,
+    /// 128-bit entries with 32-bit destinations, rather than the legacy 32-bit format.
+    ga: bool,
+    x2apic: bool,
+}
+
+impl AmdVi {
+    /// Maps every IOMMU from the IVRS, returning `None` unless all of them can remap
+    /// interrupts to 32-bit destinations when `x2apic` is set.
+    pub unsafe fn probe(x2apic: bool) -> Option<Self> {
+        let units = ivrs()?.units();
+        let mut iommus = Vec::new();
+        let mut ga = true;
+
+        for unit in units {
+            let regs = map_device_memory(PhysicalAddress::new(unit.iommu_base as usize), 4 * PAGE_SIZE).data();
+            let efr = read_volatile((regs + REG_EXT_FEATURES) as *const u64);
+            ga &= efr & EFR_GA_SUP != 0;
+            if x2apic && (efr & EFR_XT_SUP == 0 || efr & EFR_GA_SUP == 0) {
+                log::warn!("  IR: IOMMU at {:#x} cannot remap to x2APIC IDs (efr {:#x})", { unit.iommu_base }, efr);
+                return None;
+            }
+
+            let frame = allocate_p2frame(1)?;
+            let commands = CommandBuffer { frame, tail: 0 };
+            core::ptr::write_bytes(commands.virt() as *mut u8, 0, 2 * PAGE_SIZE);
+
+            iommus.push(Iommu {
+                regs,
+                devices: unit.special_devices(),
+                commands: Mutex::new(commands),
+            });
+        }
+        if iommus.is_empty() {
+            return None;
+        }
+
+        let device_table = allocate_p2frame(DEVICE_TABLE_ORDER)?;
+        core::ptr::write_bytes(
+            RmmA::phys_to_virt(PhysicalAddress::new(device_table.base().data())).data() as *mut u8,
+            0,
+            DEVICE_TABLE_SIZE,
+        );
+
+        Some(Self {
+            iommus,
+            device_table,
+            attached: Mutex::new(Vec::new()),
+            ga,
+            x2apic,
+        })
+    }
+
+    #[inline(always)]
+    pub fn entry_size(&self) -> usize {
+        if self.ga { 16 } else { 4 }
+    }
+
+    #[inline(always)]
+    pub fn extended_ids(&self) -> bool {
+        self.x2apic
+    }
+
+    pub fn requester_id(&self, source: Source) -> Option<u16> {
+        let mut devices = self.iommus.iter().flat_map(|iommu| iommu.devices.iter());
+        match source {
+            Source::Pci(requester_id) => Some(requester_id),
+            Source::IoApic(id) => devices.find_map(|device| match *device {
+                SpecialDevice::IoApic { id: device_id, device_id: requester_id } if device_id == id => {
+                    Some(requester_id)
+                }
+                _ => None,
+            }),
+            Source::Hpet(id) => devices.find_map(|device| match *device {
+                SpecialDevice::Hpet { id: device_id, device_id: requester_id } if device_id == id => {
+                    Some(requester_id)
+                }
+                _ => None,
+            }),
+        }
+    }
+
+    pub unsafe fn enable(&self, table: &Table) -> Result<(), &'static str> {
+        for iommu in &self.iommus {
+            let mut control = iommu.read(REG_CONTROL);
+            iommu.write(REG_CONTROL, control & !(CONTROL_IOMMU_EN | CONTROL_CMD_BUF_EN));
+
+            iommu.write(
+                REG_DEVICE_TABLE,
+                self.device_table.base().data() as u64 | (DEVICE_TABLE_SIZE / PAGE_SIZE - 1) as u64,
+            );
+
+            let commands = iommu.commands.lock().frame.base().data() as u64;
+            iommu.write(REG_COMMAND_BUFFER, commands | (COMMAND_ENTRIES.trailing_zeros() as u64) << 56);
+            iommu.write(REG_COMMAND_HEAD, 0);
+            iommu.write(REG_COMMAND_TAIL, 0);
+            iommu.commands.lock().tail = 0;
+
+            control |= CONTROL_IOMMU_EN | CONTROL_CMD_BUF_EN;
+            if self.ga {
+                control |= CONTROL_GA_EN;
+            }
+            if self.x2apic {
+                control |= CONTROL_XT_EN;
+            }
+            iommu.write(REG_CONTROL, control);
+        }
+
+        // I/O APICs and HPETs use the table from the start rather than from their first
+        // entry, so none of their interrupts bypass it
+        let special: Vec<u16> = self
+            .iommus
+            .iter()
+            .flat_map(|iommu| iommu.devices.iter())
+            .map(|device| match *device {
+                SpecialDevice::IoApic { device_id, .. } | SpecialDevice::Hpet { device_id, .. } => device_id,
+            })
+            .collect();
+        for requester_id in special {
+            self.attach(table, requester_id);
+            for iommu in &self.iommus {
+                iommu.invalidate_device(requester_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the device table entry of `requester_id` at the remapping table.
+    unsafe fn attach(&self, table: &Table, requester_id: u16) {
+        let mut attached = self.attached.lock();
+        if attached.contains(&requester_id) {
+            return;
+        }
+
+        let dte = (RmmA::phys_to_virt(PhysicalAddress::new(self.device_table.base().data())).data()
+            + usize::from(requester_id) * 32) as *mut u64;
+        let is_ioapic = self
+            .iommus
+            .iter()
+            .flat_map(|iommu| iommu.devices.iter())
+            .any(|device| matches!(*device, SpecialDevice::IoApic { device_id, .. } if device_id == requester_id));
+
+        let mut interrupts = DTE_INT_VALID
+            | u64::from(TABLE_ORDER) << 1
+            | (table.phys() as u64 & DTE_INT_TABLE_MASK)
+            | DTE_INT_REMAPPED;
+        if is_ioapic {
+            interrupts |= DTE_INT_PASS_LEGACY;
+        }
+        write_volatile(dte.add(2), interrupts);
+        // Valid without translation: DMA keeps working untranslated
+        write_volatile(dte, read_volatile(dte) | DTE_VALID);
+
+        attached.push(requester_id);
+    }
+
+    pub unsafe fn write_entry(&self, table: &Table, handle: Handle, requester_id: u16, entry: Option<&RemapEntry>) {
+        if entry.is_some() {
+            self.attach(table, requester_id);
+        }
+
+        let irte = table.entry_ptr(handle.index());
+        match entry {
+            Some(entry) if self.ga => {
+                let mut low = IRTE_REMAP_EN | (entry.delivery_mode as u64) << 2 | u64::from(entry.dest & 0xFF_FFFF) << 8;
+                if entry.dest_mode == DestinationMode::Logical {
+                    low |= IRTE_DEST_LOGICAL;
+                }
+                let high = u64::from(entry.vector) | u64::from(entry.dest >> 24) << 56;
+                write_entry128(irte.cast::<u64>(), low, high, IRTE_REMAP_EN);
+            }
+            Some(entry) => {
+                let mut irte_value = IRTE_REMAP_EN as u32
+                    | (entry.delivery_mode as u32) << 2
+                    | (entry.dest & 0xFF) << 8
+                    | u32::from(entry.vector) << 16;
+                if entry.dest_mode == DestinationMode::Logical {
+                    irte_value |= IRTE_DEST_LOGICAL as u32;
+                }
+                write_volatile(irte.cast::<u32>(), irte_value);
+            }
+            None if self.ga => {
+                write_volatile(irte.cast::<u64>(), 0);
+                write_volatile(irte.cast::<u64>().add(1), 0);
+            }
+            None => write_volatile(irte.cast::<u32>(), 0),
+        }
+
+        for iommu in &self.iommus {
+            iommu.invalidate_device(requester_id);
+        }
+    }
+}
+
+/// I/O APIC redirection entry whose vector field carries the table index, with fixed
+/// delivery and destination zero; the table entry holds the real vector and destination.
+pub fn ioapic_rte(handle: Handle, _entry: &RemapEntry) -> u64 {
+    (handle.index() as u64 & 0xFF) | (DeliveryMode::Fixed as u64) << 8
+}
+
+/// MSI whose data word carries the table index.
+pub fn msi_message(handle: Handle) -> MsiMessage {
+    MsiMessage {
+        address: 0xFEE0_0000,
+        data: handle.index() as u32 & 0x7FF,
+    }
+}
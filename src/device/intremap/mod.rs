@@ -0,0 +1,336 @@
+//! # Interrupt remapping
+//! Interrupts from I/O APICs, HPETs and MSIs are routed through a table owned by the
+//! IOMMU (Intel VT-d or AMD-Vi) instead of carrying their destination in the message.
+//! This is the only way to reach APIC IDs above 255 from devices in x2APIC mode.
+//!
+//! All remapping units share a single table, so a handle means the same thing no
+//! matter which unit the interrupt passes through.
+
+use alloc::{vec, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    memory::{allocate_p2frame, Frame},
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+};
+
+use super::ioapic::{DeliveryMode, DestinationMode, Polarity, TriggerMode};
+
+mod amd_vi;
+mod vtd;
+
+/// log2 of the number of remapping table entries.
+const TABLE_ORDER: u32 = 11;
+const TABLE_ENTRIES: usize = 1 << TABLE_ORDER;
+
+/// Replaces a 16-byte table entry that the hardware may read at any time. With
+/// `cmpxchg16b` both halves change at once; otherwise `enable`, a bit of the low half
+/// that makes the hardware use the entry, is cleared while the halves disagree.
+unsafe fn write_entry128(entry: *mut u64, low: u64, high: u64, enable: u64) {
+    #[cfg(target_arch = "x86_64")]
+    if crate::cpu::has(crate::cpu::Feature::Cx16) {
+        write_entry128_atomic(entry, low, high);
+        return;
+    }
+
+    let current = read_volatile(entry);
+    if current & enable != 0 {
+        write_volatile(entry, current & !enable);
+    }
+    // The low half is written last, as it holds `enable`
+    write_volatile(entry.add(1), high);
+    write_volatile(entry, low);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "cmpxchg16b")]
+unsafe fn write_entry128_atomic(entry: *mut u64, low: u64, high: u64) {
+    use core::{arch::x86_64::cmpxchg16b, sync::atomic::Ordering};
+
+    let entry = entry.cast::<u128>();
+    let new = u128::from(high) << 64 | u128::from(low);
+    let mut current = read_volatile(entry);
+    loop {
+        let previous = cmpxchg16b(entry, current, new, Ordering::SeqCst, Ordering::SeqCst);
+        if previous == current {
+            break;
+        }
+        current = previous;
+    }
+}
+
+/// Device that raises a remapped interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// I/O APIC, by MADT ID.
+    IoApic(u8),
+    /// HPET block, by ACPI enumeration ID.
+    Hpet(u8),
+    /// PCI function sending MSI or MSI-X, by requester ID (bus, device, function).
+    Pci(u16),
+}
+
+/// Where and how a remapped interrupt is delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemapEntry {
+    pub vector: u8,
+    pub dest: u32,
+    pub trigger_mode: TriggerMode,
+    pub dest_mode: DestinationMode,
+    pub delivery_mode: DeliveryMode,
+}
+
+/// Index of an entry in the interrupt remapping table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Handle(u16);
+
+impl Handle {
+    #[inline(always)]
+    pub fn index(self) -> usize {
+        usize::from(self.0)
+    }
+}
+
+/// MSI address and data words for a remapped interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemapError {
+    /// Interrupt remapping is not enabled.
+    Disabled,
+    /// No remapping unit covers the source.
+    UnknownSource,
+    TableFull,
+    /// The entry cannot be expressed for this source, such as an AMD-Vi I/O APIC
+    /// entry beyond the 8-bit vector field.
+    Unsupported,
+}
+
+enum Backend {
+    Vtd(vtd::Vtd),
+    AmdVi(amd_vi::AmdVi),
+}
+
+impl Backend {
+    fn requester_id(&self, source: Source) -> Option<u16> {
+        match self {
+            Self::Vtd(vtd) => vtd.requester_id(source),
+            Self::AmdVi(amd_vi) => amd_vi.requester_id(source),
+        }
+    }
+
+    unsafe fn write_entry(&self, table: &Table, handle: Handle, requester_id: u16, entry: Option<&RemapEntry>) {
+        match self {
+            Self::Vtd(vtd) => vtd.write_entry(table, handle, requester_id, entry),
+            Self::AmdVi(amd_vi) => amd_vi.write_entry(table, handle, requester_id, entry),
+        }
+    }
+}
+
+/// The remapping table in memory shared by all units.
+struct Table {
+    frame: Frame,
+    /// Size of one table entry in bytes.
+    entry_size: usize,
+}
+
+impl Table {
+    unsafe fn new(entry_size: usize) -> Option<Self> {
+        let size = TABLE_ENTRIES * entry_size;
+        let order = (size / PAGE_SIZE).next_power_of_two().trailing_zeros();
+        let frame = allocate_p2frame(order)?;
+        let table = Self { frame, entry_size };
+        core::ptr::write_bytes(table.entry_ptr(0), 0, size);
+        Some(table)
+    }
+
+    #[inline(always)]
+    fn phys(&self) -> usize {
+        self.frame.base().data()
+    }
+
+    #[inline(always)]
+    fn entry_ptr(&self, index: usize) -> *mut u8 {
+        let virt = RmmA::phys_to_virt(PhysicalAddress::new(self.phys())).data();
+        (virt + index * self.entry_size) as *mut u8
+    }
+}
+
+/// Bookkeeping for an allocated handle.
+#[derive(Clone, Copy, Debug)]
+struct Allocation {
+    requester_id: u16,
+    entry: RemapEntry,
+}
+
+struct Remapper {
+    backend: Backend,
+    table: Table,
+    allocations: Mutex<Vec<Option<Allocation>>>,
+}
+
+static REMAPPER: Once<Remapper> = Once::new();
+
+/// Returns whether interrupts are remapped.
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    REMAPPER.get().is_some()
+}
+
+/// Returns whether remapped entries can name 32-bit x2APIC destinations.
+pub fn supports_x2apic() -> bool {
+    match REMAPPER.get().map(|remapper| &remapper.backend) {
+        Some(Backend::Vtd(vtd)) => vtd.extended_ids(),
+        Some(Backend::AmdVi(amd_vi)) => amd_vi.extended_ids(),
+        None => false,
+    }
+}
+
+/// Enables interrupt remapping on every VT-d or AMD-Vi unit described by ACPI.
+/// With `x2apic`, entries use 32-bit destination IDs, which every unit has to support.
+///
+/// Returns false, leaving remapping off, if there is no usable unit.
+pub unsafe fn init(x2apic: bool) -> bool {
+    if is_enabled() {
+        return true;
+    }
+
+    let setup = vtd::Vtd::probe(x2apic)
+        .map(|vtd| (Backend::Vtd(vtd), vtd::ENTRY_SIZE))
+        .or_else(|| amd_vi::AmdVi::probe(x2apic).map(|amd_vi| (Backend::AmdVi(amd_vi), amd_vi.entry_size())));
+    let Some((backend, entry_size)) = setup else {
+        return false;
+    };
+
+    let Some(table) = Table::new(entry_size) else {
+        log::error!("  IR: out of memory for the remapping table");
+        return false;
+    };
+
+    let enabled = match &backend {
+        Backend::Vtd(vtd) => vtd.enable(&table),
+        Backend::AmdVi(amd_vi) => amd_vi.enable(&table),
+    };
+    if let Err(err) = enabled {
+        log::error!("  IR: could not enable interrupt remapping: {}", err);
+        return false;
+    }
+
+    log::info!(
+        "  IR: {} interrupt remapping, {} entries{}",
+        match backend {
+            Backend::Vtd(_) => "VT-d",
+            Backend::AmdVi(_) => "AMD-Vi",
+        },
+        TABLE_ENTRIES,
+        if x2apic { ", x2APIC destinations" } else { "" }
+    );
+
+    REMAPPER.call_once(|| Remapper {
+        backend,
+        table,
+        allocations: Mutex::new(vec![None; TABLE_ENTRIES]),
+    });
+    true
+}
+
+/// Allocates a remapping table entry for `source` and fills it in.
+pub fn allocate(source: Source, entry: RemapEntry) -> Result<Handle, RemapError> {
+    let remapper = REMAPPER.get().ok_or(RemapError::Disabled)?;
+    let requester_id = remapper.backend.requester_id(source).ok_or(RemapError::UnknownSource)?;
+
+    let mut allocations = remapper.allocations.lock();
+    let index = allocations.iter().position(Option::is_none).ok_or(RemapError::TableFull)?;
+    // AMD-Vi takes the index of an I/O APIC interrupt from its 8-bit vector field
+    if matches!(remapper.backend, Backend::AmdVi(_)) && matches!(source, Source::IoApic(_)) && index > 0xFF {
+        return Err(RemapError::Unsupported);
+    }
+
+    let handle = Handle(index as u16);
+    allocations[index] = Some(Allocation { requester_id, entry });
+    unsafe { remapper.backend.write_entry(&remapper.table, handle, requester_id, Some(&entry)) };
+    Ok(handle)
+}
+
+/// Returns the delivery settings of an allocated handle.
+pub fn entry(handle: Handle) -> Option<RemapEntry> {
+    let remapper = REMAPPER.get()?;
+    remapper.allocations.lock()[handle.index()].map(|allocation| allocation.entry)
+}
+
+/// Rewrites an allocated entry, for example to move the interrupt to another CPU.
+pub fn update(handle: Handle, f: impl FnOnce(&mut RemapEntry)) -> Result<RemapEntry, RemapError> {
+    let remapper = REMAPPER.get().ok_or(RemapError::Disabled)?;
+    let mut allocations = remapper.allocations.lock();
+    let allocation = allocations[handle.index()].as_mut().ok_or(RemapError::UnknownSource)?;
+
+    f(&mut allocation.entry);
+    unsafe { remapper.backend.write_entry(&remapper.table, handle, allocation.requester_id, Some(&allocation.entry)) };
+    Ok(allocation.entry)
+}
+
+/// Clears and releases an entry; interrupts still using it are blocked.
+pub fn free(handle: Handle) {
+    let Some(remapper) = REMAPPER.get() else {
+        return;
+    };
+    let mut allocations = remapper.allocations.lock();
+    if let Some(allocation) = allocations[handle.index()].take() {
+        unsafe { remapper.backend.write_entry(&remapper.table, handle, allocation.requester_id, None) };
+    }
+}
+
+/// Builds the I/O APIC redirection entry that raises the interrupt behind `handle`.
+/// The trigger mode is repeated in the entry, as the I/O APIC needs it for level interrupts.
+pub fn ioapic_rte(handle: Handle, polarity: Polarity, mask: bool) -> Result<u64, RemapError> {
+    let remapper = REMAPPER.get().ok_or(RemapError::Disabled)?;
+    let entry = entry(handle).ok_or(RemapError::UnknownSource)?;
+
+    let mut rte = match remapper.backend {
+        Backend::Vtd(_) => vtd::ioapic_rte(handle, &entry),
+        Backend::AmdVi(_) => amd_vi::ioapic_rte(handle, &entry),
+    };
+    if entry.trigger_mode == TriggerMode::Level {
+        rte |= 1 << 15;
+    }
+    if mask {
+        rte |= 1 << 16;
+    }
+    if polarity == Polarity::ActiveLow {
+        rte |= 1 << 13;
+    }
+    Ok(rte)
+}
+
+/// Returns the MSI address and data that raise the interrupt behind `handle`.
+pub fn msi_message(handle: Handle) -> Result<MsiMessage, RemapError> {
+    let remapper = REMAPPER.get().ok_or(RemapError::Disabled)?;
+    Ok(match remapper.backend {
+        Backend::Vtd(_) => vtd::msi_message(handle),
+        Backend::AmdVi(_) => amd_vi::msi_message(handle),
+    })
+}
+
+/// Iterates over every allocated handle, for moving interrupts off a CPU.
+pub fn for_each(mut f: impl FnMut(Handle, &RemapEntry)) {
+    let Some(remapper) = REMAPPER.get() else {
+        return;
+    };
+    // Collected first so that `f` may call `update`
+    let entries: Vec<_> = remapper
+        .allocations
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(index, allocation)| allocation.map(|allocation| (Handle(index as u16), allocation.entry)))
+        .collect();
+    for (handle, entry) in entries {
+        f(handle, &entry);
+    }
+}
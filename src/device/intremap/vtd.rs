@@ -0,0 +1,294 @@
+//! Intel VT-d interrupt remapping, programmed through the queued invalidation interface.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+
+use crate::{
+    acpi::{
+        dmar::{dmar, DeviceScope},
+        madt::{madt, MadtEntry},
+    },
+    device::delay,
+    memory::{allocate_p2frame, map_device_memory, Frame},
+    paging::{PhysicalAddress, RmmA, RmmArch, PAGE_SIZE},
+};
+
+use super::{
+    super::ioapic::{DestinationMode, TriggerMode},
+    write_entry128, Handle, MsiMessage, RemapEntry, Source, Table, TABLE_ORDER,
+};
+
+/// Size of an interrupt remapping table entry.
+pub const ENTRY_SIZE: usize = 16;
+
+const REG_ECAP: usize = 0x10;
+const REG_GCMD: usize = 0x18;
+const REG_GSTS: usize = 0x1C;
+const REG_IQT: usize = 0x88;
+const REG_IQA: usize = 0x90;
+const REG_IRTA: usize = 0xB8;
+
+const ECAP_QI: u64 = 1 << 1;
+const ECAP_IR: u64 = 1 << 3;
+const ECAP_EIM: u64 = 1 << 4;
+
+const GCMD_QIE: u32 = 1 << 26;
+const GCMD_IRE: u32 = 1 << 25;
+const GCMD_SIRTP: u32 = 1 << 24;
+const GCMD_CFI: u32 = 1 << 23;
+/// Status bits that report one-shot commands rather than enabled features.
+const GSTS_ONE_SHOT: u32 = 1 << 30 | 1 << 29 | 1 << 27 | 1 << 24;
+
+const IRTA_EIME: u64 = 1 << 11;
+
+const IRTE_PRESENT: u64 = 1 << 0;
+const IRTE_DEST_LOGICAL: u64 = 1 << 2;
+const IRTE_TRIGGER_LEVEL: u64 = 1 << 4;
+/// Verify all 16 bits of the requester ID.
+const IRTE_SVT_FULL: u64 = 0b01 << 18;
+
+const DESC_IEC: u64 = 0x4;
+const DESC_IEC_INDEXED: u64 = 1 << 4;
+const DESC_WAIT: u64 = 0x5;
+const DESC_WAIT_STATUS_WRITE: u64 = 1 << 5;
+
+const QUEUE_ENTRIES: usize = PAGE_SIZE / 16;
+const COMMAND_TIMEOUT_US: u64 = 1_000_000;
+
+/// Invalidation queue of one unit: a page of descriptors followed by a page holding
+/// the completion status written by wait descriptors.
+struct InvalidationQueue {
+    frame: Frame,
+    tail: usize,
+}
+
+impl InvalidationQueue {
+    #[inline(always)]
+    fn virt(&self) -> usize {
+        RmmA::phys_to_virt(PhysicalAddress::new(self.frame.base().data())).data()
+    }
+
+    #[inline(always)]
+    fn status_phys(&self) -> u64 {
+        (self.frame.base().data() + PAGE_SIZE) as u64
+    }
+
+    #[inline(always)]
+    fn status_ptr(&self) -> *mut u32 {
+        (self.virt() + PAGE_SIZE) as *mut u32
+    }
+}
+
+/// A DMA remapping hardware unit.
+struct Unit {
+    regs: usize,
+    scopes: Vec<DeviceScope>,
+    queue: Mutex<InvalidationQueue>,
+}
+
+impl Unit {
+    #[inline(always)]
+    unsafe fn read32(&self, reg: usize) -> u32 {
+        read_volatile((self.regs + reg) as *const u32)
+    }
+
+    #[inline(always)]
+    unsafe fn write32(&self, reg: usize, value: u32) {
+        write_volatile((self.regs + reg) as *mut u32, value)
+    }
+
+    #[inline(always)]
+    unsafe fn write64(&self, reg: usize, value: u64) {
+        write_volatile((self.regs + reg) as *mut u64, value)
+    }
+
+    /// Sets or clears one global command bit and waits for the status to follow.
+    unsafe fn set_command(&self, bit: u32, enable: bool) -> Result<(), &'static str> {
+        let status = self.read32(REG_GSTS) & !GSTS_ONE_SHOT;
+        self.write32(REG_GCMD, if enable { status | bit } else { status & !bit });
+
+        let mut waited = 0;
+        while (self.read32(REG_GSTS) & bit != 0) != enable {
+            if waited >= COMMAND_TIMEOUT_US {
+                return Err("remapping unit did not acknowledge a command");
+            }
+            delay::udelay(1);
+            waited += 1;
+        }
+        Ok(())
+    }
+
+    /// Queues `descriptors` followed by a wait descriptor and spins until it completes.
+    unsafe fn submit(&self, descriptors: &[[u64; 2]]) {
+        let mut queue = self.queue.lock();
+        let status = queue.status_ptr();
+        write_volatile(status, 0);
+
+        let wait = [DESC_WAIT | DESC_WAIT_STATUS_WRITE | 1 << 32, queue.status_phys()];
+        for descriptor in descriptors.iter().chain(Some(&wait)) {
+            let slot = (queue.virt() + queue.tail * 16) as *mut u64;
+            write_volatile(slot, descriptor[0]);
+            write_volatile(slot.add(1), descriptor[1]);
+            queue.tail = (queue.tail + 1) % QUEUE_ENTRIES;
+        }
+        self.write64(REG_IQT, (queue.tail as u64) << 4);
+
+        while read_volatile(status) == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn enable(&self, table: &Table, x2apic: bool, compat: bool) -> Result<(), &'static str> {
+        // Queued invalidation is the only way to flush the interrupt entry cache
+        self.set_command(GCMD_QIE, false)?;
+        self.queue.lock().tail = 0;
+        self.write64(REG_IQT, 0);
+        self.write64(REG_IQA, self.queue.lock().frame.base().data() as u64);
+        self.set_command(GCMD_QIE, true)?;
+
+        let mut irta = table.phys() as u64 | u64::from(TABLE_ORDER - 1);
+        if x2apic {
+            irta |= IRTA_EIME;
+        }
+        self.write64(REG_IRTA, irta);
+        self.set_command(GCMD_SIRTP, true)?;
+        self.submit(&[[DESC_IEC, 0]]);
+
+        // Interrupts in the compatibility format bypass the table, so they are only let
+        // through for I/O APICs that cannot send remapped ones
+        self.set_command(GCMD_CFI, compat)?;
+        self.set_command(GCMD_IRE, true)
+    }
+}
+
+pub struct Vtd {
+    units: Vec<Unit>,
+    x2apic: bool,
+}
+
+impl Vtd {
+    /// Maps every unit from the DMAR, returning `None` unless all of them can remap
+    /// interrupts (with 32-bit destinations if `x2apic` is set).
+    pub unsafe fn probe(x2apic: bool) -> Option<Self> {
+        let dmar = dmar().filter(|dmar| dmar.intr_remap())?;
+        let mut units = Vec::new();
+
+        for drhd in dmar.drhds() {
+            let regs = map_device_memory(PhysicalAddress::new(drhd.register_base as usize), PAGE_SIZE).data();
+            let ecap = read_volatile((regs + REG_ECAP) as *const u64);
+            if ecap & ECAP_IR == 0 || ecap & ECAP_QI == 0 || (x2apic && ecap & ECAP_EIM == 0) {
+                log::warn!("  IR: VT-d unit at {:#x} cannot remap interrupts (ecap {:#x})", { drhd.register_base }, ecap);
+                return None;
+            }
+
+            let frame = allocate_p2frame(1)?;
+            let queue = InvalidationQueue { frame, tail: 0 };
+            core::ptr::write_bytes(queue.virt() as *mut u8, 0, 2 * PAGE_SIZE);
+
+            units.push(Unit {
+                regs,
+                scopes: drhd.special_scopes(),
+                queue: Mutex::new(queue),
+            });
+        }
+
+        (!units.is_empty()).then_some(Self { units, x2apic })
+    }
+
+    #[inline(always)]
+    pub fn extended_ids(&self) -> bool {
+        self.x2apic
+    }
+
+    pub fn requester_id(&self, source: Source) -> Option<u16> {
+        let scopes = self.units.iter().flat_map(|unit| unit.scopes.iter());
+        match source {
+            Source::Pci(requester_id) => Some(requester_id),
+            Source::IoApic(id) => scopes
+                .filter_map(|scope| match *scope {
+                    DeviceScope::IoApic { id: scope_id, source_id } if scope_id == id => Some(source_id),
+                    _ => None,
+                })
+                .next(),
+            Source::Hpet(id) => scopes
+                .filter_map(|scope| match *scope {
+                    DeviceScope::Hpet { id: scope_id, source_id } if scope_id == id => Some(source_id),
+                    _ => None,
+                })
+                .next(),
+        }
+    }
+
+    pub unsafe fn enable(&self, table: &Table) -> Result<(), &'static str> {
+        // An I/O APIC outside every unit's scope has no requester ID to remap with
+        let unscoped: Vec<u8> = madt()
+            .into_iter()
+            .flat_map(|madt| madt.iter())
+            .filter_map(|entry| match entry {
+                MadtEntry::IoApic(ioapic) => Some(ioapic.id),
+                _ => None,
+            })
+            .filter(|&id| self.requester_id(Source::IoApic(id)).is_none())
+            .collect();
+        let compat = !unscoped.is_empty();
+        if compat {
+            log::warn!(
+                "  IR: I/O APIC(s) {:?} in no DMAR device scope, compatibility-format interrupts stay allowed",
+                unscoped
+            );
+        }
+
+        for unit in &self.units {
+            unit.enable(table, self.x2apic, compat)?;
+        }
+        Ok(())
+    }
+
+    pub unsafe fn write_entry(&self, table: &Table, handle: Handle, requester_id: u16, entry: Option<&RemapEntry>) {
+        let irte = table.entry_ptr(handle.index()) as *mut u64;
+        match entry {
+            Some(entry) => {
+                let dest = if self.x2apic {
+                    u64::from(entry.dest) << 32
+                } else {
+                    u64::from(entry.dest & 0xFF) << 40
+                };
+                let mut low = IRTE_PRESENT | ((entry.delivery_mode as u64) << 5) | u64::from(entry.vector) << 16 | dest;
+                if entry.dest_mode == DestinationMode::Logical {
+                    low |= IRTE_DEST_LOGICAL;
+                }
+                if entry.trigger_mode == TriggerMode::Level {
+                    low |= IRTE_TRIGGER_LEVEL;
+                }
+                write_entry128(irte, low, u64::from(requester_id) | IRTE_SVT_FULL, IRTE_PRESENT);
+            }
+            None => {
+                write_volatile(irte, 0);
+                write_volatile(irte.add(1), 0);
+            }
+        }
+
+        let invalidate = [DESC_IEC | DESC_IEC_INDEXED | (handle.index() as u64) << 32, 0];
+        for unit in &self.units {
+            unit.submit(&[invalidate]);
+        }
+    }
+}
+
+/// Remappable-format I/O APIC redirection entry; the vector and trigger mode have to
+/// match the table entry.
+pub fn ioapic_rte(handle: Handle, entry: &RemapEntry) -> u64 {
+    let index = handle.index() as u64;
+    (index & 0x7FFF) << 49 | 1 << 48 | (index >> 15 & 1) << 11 | u64::from(entry.vector)
+}
+
+/// Remappable-format MSI address; the data word is unused without a subhandle.
+pub fn msi_message(handle: Handle) -> MsiMessage {
+    let index = handle.index() as u64;
+    MsiMessage {
+        address: 0xFEE0_0000 | (index & 0x7FFF) << 5 | 1 << 4 | (index >> 15 & 1) << 2,
+        data: 0,
+    }
+}
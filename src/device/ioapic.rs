@@ -0,0 +1,634 @@
+use alloc::{vec, vec::Vec};
+use core::{fmt, ptr};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    acpi::madt::{self, MadtEntry, MadtIntSrcOverride, MadtIoApic},
+    affinity::{AffinityError, IrqRoute},
+    ipi,
+    memory::{map_device_memory, PhysicalAddress, PAGE_SIZE},
+};
+
+use super::{
+    intremap::{self, Handle, RemapEntry, Source},
+    pic, vector,
+};
+
+/// First IDT vector used for legacy ISA IRQs.
+pub const IRQ_VECTOR_BASE: u8 = 32;
+
+/// Number of legacy ISA IRQ lines.
+pub const ISA_IRQ_COUNT: u8 = 16;
+
+const IOAPICID: u8 = 0x00;
+const IOAPICVER: u8 = 0x01;
+const IOREDTBL: u8 = 0x10;
+
+/// Redirection entries reachable through the 8-bit register index, two registers each.
+pub const MAX_REDIRECTION_ENTRIES: u8 = ((0x100 - IOREDTBL as u32) / 2) as u8;
+
+const REDIR_MASK: u64 = 1 << 16;
+const REDIR_TRIGGER_LEVEL: u64 = 1 << 15;
+const REDIR_POLARITY_LOW: u64 = 1 << 13;
+const REDIR_DEST_LOGICAL: u64 = 1 << 11;
+
+/// Register window of a single I/O APIC.
+pub struct IoApicRegs {
+    pointer: *mut u32,
+}
+
+unsafe impl Send for IoApicRegs {}
+
+impl IoApicRegs {
+    #[inline(always)]
+    fn ioregsel(&self) -> *mut u32 {
+        self.pointer
+    }
+
+    #[inline(always)]
+    fn iowin(&self) -> *mut u32 {
+        // IOWIN is 0x10 bytes after IOREGSEL.
+        unsafe { self.pointer.add(4) }
+    }
+
+    pub fn read_reg(&mut self, reg: u8) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.ioregsel(), reg.into());
+            ptr::read_volatile(self.iowin())
+        }
+    }
+
+    pub fn write_reg(&mut self, reg: u8, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.ioregsel(), reg.into());
+            ptr::write_volatile(self.iowin(), value);
+        }
+    }
+
+    pub fn read_ioapicid(&mut self) -> u32 {
+        self.read_reg(IOAPICID)
+    }
+
+    pub fn read_ioapicver(&mut self) -> u32 {
+        self.read_reg(IOAPICVER)
+    }
+
+    /// Returns the low register of redirection entry `idx`.
+    fn ioredtbl_reg(idx: u8) -> u8 {
+        assert!(idx < MAX_REDIRECTION_ENTRIES, "I/O APIC redirection entry {} out of range", idx);
+        (u32::from(IOREDTBL) + u32::from(idx) * 2) as u8
+    }
+
+    pub fn read_ioredtbl(&mut self, idx: u8) -> u64 {
+        let reg = Self::ioredtbl_reg(idx);
+        let lo = self.read_reg(reg);
+        let hi = self.read_reg(reg + 1);
+        u64::from(lo) | (u64::from(hi) << 32)
+    }
+
+    pub fn write_ioredtbl(&mut self, idx: u8, value: u64) {
+        let reg = Self::ioredtbl_reg(idx);
+        // Mask the entry while the two halves disagree.
+        self.write_reg(reg, (value as u32) | REDIR_MASK as u32);
+        self.write_reg(reg + 1, (value >> 32) as u32);
+        self.write_reg(reg, value as u32);
+    }
+
+    /// Returns the number of redirection entries implemented by this I/O APIC, at most
+    /// [`MAX_REDIRECTION_ENTRIES`].
+    pub fn redirection_entry_count(&mut self) -> u8 {
+        let count = ((self.read_ioapicver() >> 16) & 0xFF) + 1;
+        if count > u32::from(MAX_REDIRECTION_ENTRIES) {
+            log::warn!(
+                "I/O APIC reports {} redirection entries, only {} are addressable",
+                count,
+                MAX_REDIRECTION_ENTRIES
+            );
+        }
+        count.min(u32::from(MAX_REDIRECTION_ENTRIES)) as u8
+    }
+}
+
+/// A single I/O APIC discovered through the MADT.
+pub struct IoApic {
+    regs: Mutex<IoApicRegs>,
+    /// Remapping table entry of each pin, when interrupt remapping is enabled.
+    remapped: Mutex<Vec<Option<Handle>>>,
+    pub id: u8,
+    pub gsi_start: u32,
+    pub count: u8,
+}
+
+impl IoApic {
+    pub fn new(regs_base: *mut u32, madt_ioapic: &MadtIoApic) -> Self {
+        let mut regs = IoApicRegs { pointer: regs_base };
+        let id = ((regs.read_ioapicid() >> 24) & 0xFF) as u8;
+        let count = regs.redirection_entry_count();
+
+        if id != madt_ioapic.id {
+            log::warn!("I/O APIC ID {} does not match MADT ID {}", id, { madt_ioapic.id });
+        }
+
+        Self {
+            regs: Mutex::new(regs),
+            remapped: Mutex::new(vec![None; usize::from(count)]),
+            id: madt_ioapic.id,
+            gsi_start: madt_ioapic.gsi_base,
+            count,
+        }
+    }
+
+    /// Returns the pin of `gsi` on this I/O APIC, if it handles it.
+    #[inline(always)]
+    pub fn pin_of(&self, gsi: u32) -> Option<u8> {
+        gsi.checked_sub(self.gsi_start)
+            .filter(|&pin| pin < u32::from(self.count))
+            .map(|pin| pin as u8)
+    }
+
+    pub fn read_entry(&self, pin: u8) -> u64 {
+        assert!(pin < self.count, "I/O APIC pin {} out of range", pin);
+        self.regs.lock().read_ioredtbl(pin)
+    }
+
+    /// Programs `pin`, through the interrupt remapping table when it is enabled. The
+    /// pin is left as it was if its destination cannot be named.
+    pub fn map(&self, pin: u8, info: MapInfo) -> Result<(), UnreachableDestination> {
+        assert!(pin < self.count, "I/O APIC pin {} out of range", pin);
+
+        if intremap::is_enabled() {
+            let entry = info.remap_entry();
+            let mut remapped = self.remapped.lock();
+            let handle = match remapped[usize::from(pin)] {
+                Some(handle) => intremap::update(handle, |current| *current = entry).map(|_| handle),
+                None => intremap::allocate(Source::IoApic(self.id), entry),
+            };
+            match handle.and_then(|handle| Ok((handle, intremap::ioapic_rte(handle, info.polarity, info.mask)?))) {
+                Ok((handle, rte)) => {
+                    remapped[usize::from(pin)] = Some(handle);
+                    self.regs.lock().write_ioredtbl(pin, rte);
+                    return Ok(());
+                }
+                Err(err) => log::warn!("I/O APIC {} pin {} not remapped: {:?}", self.id, pin, err),
+            }
+        }
+
+        self.regs.lock().write_ioredtbl(pin, info.as_raw()?);
+        Ok(())
+    }
+
+    /// Returns the remapping table entry behind `pin`, if it is remapped.
+    #[inline(always)]
+    pub fn remap_handle(&self, pin: u8) -> Option<Handle> {
+        self.remapped.lock().get(usize::from(pin)).copied().flatten()
+    }
+
+    /// Applies `f` to the remapping table entry of `pin` and rewrites the redirection
+    /// entry to match. Returns false if `pin` is not remapped.
+    fn update_remapped(&self, pin: u8, f: impl FnOnce(&mut RemapEntry)) -> bool {
+        let Some(handle) = self.remap_handle(pin) else {
+            return false;
+        };
+        if intremap::update(handle, f).is_ok() {
+            self.update(pin, |rte| {
+                let polarity = if rte & REDIR_POLARITY_LOW != 0 { Polarity::ActiveLow } else { Polarity::ActiveHigh };
+                intremap::ioapic_rte(handle, polarity, rte & REDIR_MASK != 0).unwrap_or(rte)
+            });
+        }
+        true
+    }
+
+    /// Applies `f` to the raw redirection entry of `pin`.
+    fn update(&self, pin: u8, f: impl FnOnce(u64) -> u64) {
+        assert!(pin < self.count, "I/O APIC pin {} out of range", pin);
+        let mut regs = self.regs.lock();
+        let entry = regs.read_ioredtbl(pin);
+        regs.write_ioredtbl(pin, f(entry));
+    }
+
+    pub fn set_mask(&self, pin: u8, masked: bool) {
+        self.update(pin, |entry| if masked { entry | REDIR_MASK } else { entry & !REDIR_MASK });
+    }
+
+    pub fn is_masked(&self, pin: u8) -> bool {
+        self.read_entry(pin) & REDIR_MASK != 0
+    }
+}
+
+impl fmt::Debug for IoApic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoApic")
+            .field("id", &self.id)
+            .field("gsi_start", &self.gsi_start)
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DestinationMode {
+    Physical,
+    Logical,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    ExtInt = 0b111,
+}
+
+/// An APIC ID above 255, which only remapped entries can deliver to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnreachableDestination(pub u32);
+
+/// Contents of a redirection table entry.
+#[derive(Clone, Copy, Debug)]
+pub struct MapInfo {
+    pub dest: u32,
+    pub mask: bool,
+    pub trigger_mode: TriggerMode,
+    pub polarity: Polarity,
+    pub dest_mode: DestinationMode,
+    pub delivery_mode: DeliveryMode,
+    pub vector: u8,
+}
+
+impl MapInfo {
+    /// Compatibility-format entry, which can only name 8-bit destinations.
+    pub fn as_raw(&self) -> Result<u64, UnreachableDestination> {
+        assert!(self.vector >= 0x20, "I/O APIC vector {:#x} collides with exceptions", self.vector);
+        if self.dest > 0xFF {
+            return Err(UnreachableDestination(self.dest));
+        }
+
+        let mut raw = (u64::from(self.dest) << 56)
+            | ((self.delivery_mode as u64) << 8)
+            | u64::from(self.vector);
+        if self.mask {
+            raw |= REDIR_MASK;
+        }
+        if self.trigger_mode == TriggerMode::Level {
+            raw |= REDIR_TRIGGER_LEVEL;
+        }
+        if self.polarity == Polarity::ActiveLow {
+            raw |= REDIR_POLARITY_LOW;
+        }
+        if self.dest_mode == DestinationMode::Logical {
+            raw |= REDIR_DEST_LOGICAL;
+        }
+        Ok(raw)
+    }
+}
+
+impl MapInfo {
+    #[inline(always)]
+    pub fn remap_entry(&self) -> RemapEntry {
+        RemapEntry {
+            vector: self.vector,
+            dest: self.dest,
+            trigger_mode: self.trigger_mode,
+            dest_mode: self.dest_mode,
+            delivery_mode: self.delivery_mode,
+        }
+    }
+}
+
+/// An ISA IRQ redirected to a different GSI, as reported by the MADT.
+#[derive(Clone, Copy, Debug)]
+pub struct Override {
+    pub bus_irq: u8,
+    pub gsi: u32,
+    pub trigger_mode: Option<TriggerMode>,
+    pub polarity: Option<Polarity>,
+}
+
+impl From<&MadtIntSrcOverride> for Override {
+    fn from(src: &MadtIntSrcOverride) -> Self {
+        let flags = src.flags;
+        // Bits 0-1: polarity, bits 2-3: trigger mode. Zero means "conforms to the bus".
+        let polarity = match flags & 0b11 {
+            0b01 => Some(Polarity::ActiveHigh),
+            0b11 => Some(Polarity::ActiveLow),
+            _ => None,
+        };
+        let trigger_mode = match (flags >> 2) & 0b11 {
+            0b01 => Some(TriggerMode::Edge),
+            0b11 => Some(TriggerMode::Level),
+            _ => None,
+        };
+        Self {
+            bus_irq: src.irq_source,
+            gsi: src.gsi_base,
+            trigger_mode,
+            polarity,
+        }
+    }
+}
+
+static IOAPICS: Once<Vec<IoApic>> = Once::new();
+static SRC_OVERRIDES: Once<Vec<Override>> = Once::new();
+
+pub fn ioapics() -> &'static [IoApic] {
+    IOAPICS.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn src_overrides() -> &'static [Override] {
+    SRC_OVERRIDES.get().map_or(&[], Vec::as_slice)
+}
+
+/// Translates a GSI into the I/O APIC and pin that receive it.
+pub fn find_ioapic(gsi: u32) -> Option<(&'static IoApic, u8)> {
+    ioapics()
+        .iter()
+        .find_map(|ioapic| ioapic.pin_of(gsi).map(|pin| (ioapic, pin)))
+}
+
+/// Returns the GSI, trigger mode and polarity of a legacy ISA IRQ.
+pub fn isa_irq_route(irq: u8) -> (u32, TriggerMode, Polarity) {
+    match src_overrides().iter().find(|over| over.bus_irq == irq) {
+        Some(over) => (
+            over.gsi,
+            over.trigger_mode.unwrap_or(TriggerMode::Edge),
+            over.polarity.unwrap_or(Polarity::ActiveHigh),
+        ),
+        None => (irq.into(), TriggerMode::Edge, Polarity::ActiveHigh),
+    }
+}
+
+/// Maps and collects every I/O APIC and interrupt source override, then routes the
+/// legacy ISA IRQs to the bootstrap processor with all lines masked.
+pub unsafe fn init(bsp_apic_id: u32) {
+    let Some(madt) = madt::madt() else {
+        log::warn!("No MADT, cannot initialize I/O APICs");
+        return;
+    };
+
+    let mut ioapics = Vec::new();
+    let mut overrides = Vec::new();
+
+    for madt_entry in madt.iter() {
+        match madt_entry {
+            MadtEntry::IoApic(madt_ioapic) => {
+                let virt = map_device_memory(PhysicalAddress::new(madt_ioapic.address as usize), PAGE_SIZE);
+                let ioapic = IoApic::new(virt.data() as *mut u32, madt_ioapic);
+                log::info!(
+                    "  I/O APIC {}: GSI {}..{}",
+                    ioapic.id,
+                    ioapic.gsi_start,
+                    ioapic.gsi_start + u32::from(ioapic.count)
+                );
+                ioapics.push(ioapic);
+            }
+            MadtEntry::IntSrcOverride(src) if src.bus_source == 0 => overrides.push(Override::from(src)),
+            MadtEntry::IntSrcOverride(src) => {
+                log::warn!("Ignoring interrupt source override for unknown bus {}", { src.bus_source })
+            }
+            _ => (),
+        }
+    }
+
+    if ioapics.is_empty() {
+        log::warn!("No I/O APIC found in MADT");
+        return;
+    }
+
+    // Mask everything before anything can be routed.
+    for ioapic in &ioapics {
+        for pin in 0..ioapic.count {
+            ioapic.set_mask(pin, true);
+        }
+    }
+
+    IOAPICS.call_once(|| ioapics);
+    SRC_OVERRIDES.call_once(|| overrides);
+
+    let mut unreachable = None;
+    for irq in 0..ISA_IRQ_COUNT {
+        // IRQ 2 is the cascade from the slave PIC and never fires.
+        if irq == 2 {
+            continue;
+        }
+
+        let (gsi, trigger_mode, polarity) = isa_irq_route(irq);
+        let Some((ioapic, pin)) = find_ioapic(gsi) else {
+            log::warn!("No I/O APIC handles GSI {} for ISA IRQ {}", gsi, irq);
+            continue;
+        };
+
+        let mapped = ioapic.map(pin, MapInfo {
+            dest: bsp_apic_id,
+            mask: true,
+            trigger_mode,
+            polarity,
+            dest_mode: DestinationMode::Physical,
+            delivery_mode: DeliveryMode::Fixed,
+            vector: IRQ_VECTOR_BASE + irq,
+        });
+        if let Err(err) = mapped {
+            unreachable = Some(err);
+        }
+    }
+    if let Some(UnreachableDestination(apic_id)) = unreachable {
+        log::warn!(
+            "BSP APIC ID {} needs interrupt remapping, ISA IRQs stay masked until routed to another CPU",
+            apic_id
+        );
+    }
+}
+
+fn with_gsi<R>(gsi: u32, f: impl FnOnce(&IoApic, u8) -> R) -> Option<R> {
+    match find_ioapic(gsi) {
+        Some((ioapic, pin)) => Some(f(ioapic, pin)),
+        None => {
+            log::warn!("No I/O APIC handles GSI {}", gsi);
+            None
+        }
+    }
+}
+
+pub fn mask_gsi(gsi: u32) {
+    with_gsi(gsi, |ioapic, pin| ioapic.set_mask(pin, true));
+}
+
+pub fn unmask_gsi(gsi: u32) {
+    with_gsi(gsi, |ioapic, pin| ioapic.set_mask(pin, false));
+}
+
+/// Returns whether `gsi` is masked, as it is if no I/O APIC handles it.
+pub fn is_gsi_masked(gsi: u32) -> bool {
+    with_gsi(gsi, |ioapic, pin| ioapic.is_masked(pin)).unwrap_or(true)
+}
+
+/// Directs `gsi` to the local APIC with the given ID in physical destination mode.
+/// The route is left alone if the entry is not remapped and cannot name `apic_id`.
+pub fn route_gsi_to_cpu(gsi: u32, apic_id: u32) -> Result<(), UnreachableDestination> {
+    with_gsi(gsi, |ioapic, pin| {
+        let remapped = ioapic.update_remapped(pin, |entry| {
+            entry.dest = apic_id;
+            entry.dest_mode = DestinationMode::Physical;
+        });
+        if !remapped {
+            if apic_id > 0xFF {
+                return Err(UnreachableDestination(apic_id));
+            }
+            ioapic.update(pin, |entry| (entry & !(0xFF << 56) & !REDIR_DEST_LOGICAL) | (u64::from(apic_id) << 56))
+        }
+        Ok(())
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Moves every physically addressed entry delivered to `from` over to `to`, for
+/// taking a CPU offline. Entries that are not remapped stay if they cannot name `to`.
+/// Returns the GSIs of the entries moved.
+pub fn retarget_from_cpu(from: u32, to: u32) -> Vec<u32> {
+    let mut moved = Vec::new();
+    for ioapic in ioapics() {
+        for pin in 0..ioapic.count {
+            let gsi = ioapic.gsi_start + u32::from(pin);
+            if let Some(entry) = ioapic.remap_handle(pin).and_then(intremap::entry) {
+                if entry.dest_mode == DestinationMode::Physical && entry.dest == from {
+                    ioapic.update_remapped(pin, |entry| entry.dest = to);
+                    moved.push(gsi);
+                }
+                continue;
+            }
+            if to > 0xFF {
+                continue;
+            }
+            ioapic.update(pin, |entry| {
+                if entry & REDIR_DEST_LOGICAL == 0 && (entry >> 56) as u32 == from {
+                    moved.push(gsi);
+                    (entry & !(0xFF << 56)) | (u64::from(to) << 56)
+                } else {
+                    entry
+                }
+            });
+        }
+    }
+    moved
+}
+
+/// Masks every physically addressed entry delivering `vector` to `apic_id`, for a
+/// vector firing unhandled. Returns how many entries were masked.
+pub fn mask_vector(apic_id: u32, vector: u8) -> usize {
+    let mut masked = 0;
+    for ioapic in ioapics() {
+        for pin in 0..ioapic.count {
+            let matches = match ioapic.remap_handle(pin).and_then(intremap::entry) {
+                Some(entry) => {
+                    entry.dest_mode == DestinationMode::Physical && entry.dest == apic_id && entry.vector == vector
+                }
+                None => {
+                    let entry = ioapic.read_entry(pin);
+                    entry & REDIR_DEST_LOGICAL == 0 && (entry >> 56) as u32 == apic_id && entry as u8 == vector
+                }
+            };
+            if matches {
+                ioapic.set_mask(pin, true);
+                masked += 1;
+            }
+        }
+    }
+    masked
+}
+
+pub fn set_gsi_vector(gsi: u32, vector: u8) {
+    assert!(vector >= 0x20, "I/O APIC vector {:#x} collides with exceptions", vector);
+    with_gsi(gsi, |ioapic, pin| {
+        if !ioapic.update_remapped(pin, |entry| entry.vector = vector) {
+            ioapic.update(pin, |entry| (entry & !0xFF) | u64::from(vector))
+        }
+    });
+}
+
+/// Masks a legacy ISA IRQ, following any interrupt source override.
+pub fn mask(irq: u8) {
+    if pic::is_enabled() {
+        pic::mask(irq);
+    } else {
+        mask_gsi(isa_irq_route(irq).0);
+    }
+}
+
+/// Unmasks a legacy ISA IRQ, following any interrupt source override.
+pub fn unmask(irq: u8) {
+    if pic::is_enabled() {
+        pic::unmask(irq);
+    } else {
+        unmask_gsi(isa_irq_route(irq).0);
+    }
+}
+
+/// Routes a PCI INTx line, which is always level triggered and active low.
+pub fn map_pci_intx(gsi: u32, vector: u8, apic_id: u32) -> Result<(), UnreachableDestination> {
+    with_gsi(gsi, |ioapic, pin| {
+        ioapic.map(pin, MapInfo {
+            dest: apic_id,
+            mask: false,
+            trigger_mode: TriggerMode::Level,
+            polarity: Polarity::ActiveLow,
+            dest_mode: DestinationMode::Physical,
+            delivery_mode: DeliveryMode::Fixed,
+            vector,
+        })
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Routes a GSI for [`affinity`](crate::affinity), moving its vector along if it
+/// was allocated per CPU rather than being a legacy ISA vector.
+pub struct GsiRoute {
+    pub gsi: u32,
+    pub vector: u8,
+    pub cpu_id: usize,
+}
+
+impl IrqRoute for GsiRoute {
+    /// Leaves the mask as it was, so lines masked by their driver or for firing
+    /// unhandled stay masked.
+    fn set_target(&mut self, cpu_id: usize) -> Result<(), AffinityError> {
+        let apic_id = ipi::hw_id(cpu_id).ok_or(AffinityError::UnknownIrq)? as u32;
+        if self.vector < vector::DEVICE_VECTOR_BASE {
+            route_gsi_to_cpu(self.gsi, apic_id).map_err(|_| AffinityError::Unreachable)?;
+        } else {
+            let new = vector::reassign(self.cpu_id, self.vector, cpu_id).map_err(|_| AffinityError::NoResources)?;
+            let masked = is_gsi_masked(self.gsi);
+            mask_gsi(self.gsi);
+            set_gsi_vector(self.gsi, new);
+            if route_gsi_to_cpu(self.gsi, apic_id).is_err() {
+                set_gsi_vector(self.gsi, self.vector);
+                if !masked {
+                    unmask_gsi(self.gsi);
+                }
+                vector::free(cpu_id, new, 1);
+                return Err(AffinityError::Unreachable);
+            }
+            if !masked {
+                unmask_gsi(self.gsi);
+            }
+            vector::free(self.cpu_id, self.vector, 1);
+            self.vector = new;
+        }
+        self.cpu_id = cpu_id;
+        Ok(())
+    }
+}
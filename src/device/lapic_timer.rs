@@ -0,0 +1,190 @@
+//! # Local APIC timer
+//! Every local APIC has a timer, used as its CPU's clock-event device. With
+//! TSC-deadline mode it fires when the TSC reaches a programmed value; otherwise it
+//! counts the bus clock down in one-shot mode, at a rate measured once at boot.
+//!
+//! The timer raises [`TIMER_VECTOR`], whose IDT stub calls [`handle`]. Unless the CPU
+//! reports an always running APIC timer, it stops in deep C-states and ranks below
+//! an HPET comparator of the CPU's own.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::_mm_mfence;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::_mm_mfence;
+
+use alloc::boxed::Box;
+
+use spin::Once;
+
+use super::{
+    delay,
+    local_apic::the_local_apic,
+    msr::wrmsr,
+    tsc,
+    vector::SYSTEM_VECTOR_BASE,
+};
+use crate::{
+    cpu::{self, Feature},
+    percpu::{self, irq_restore, irq_save},
+    time::{self, ClockEvent, TimeError},
+};
+
+/// Vector of the timer interrupt on every CPU.
+pub const TIMER_VECTOR: u8 = SYSTEM_VECTOR_BASE;
+
+/// Absolute TSC value at which the timer fires in TSC-deadline mode; zero disarms it.
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const LVT_MASKED: u32 = 1 << 16;
+/// Timer modes, in bits 18:17.
+const LVT_ONESHOT: u32 = 0;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// Divide configuration value dividing the bus clock by 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// Length of the one-shot rate measurement.
+const CALIBRATION_US: u64 = 10_000;
+
+/// Shortest delta worth programming; anything shorter is over before the write lands.
+const MIN_DELTA_NS: u64 = 1_000;
+
+/// Count rate in one-shot mode, after the divider.
+static ONESHOT_FREQUENCY: Once<Option<u64>> = Once::new();
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    TscDeadline { tsc_frequency: u64 },
+    OneShot { frequency: u64 },
+}
+
+/// The local APIC timer of one CPU.
+pub struct LapicTimer {
+    mode: Mode,
+}
+
+/// Measures the one-shot count rate against [`delay::udelay`], with the timer masked.
+fn measure_oneshot() -> Option<u64> {
+    let flags = irq_save();
+    let elapsed = unsafe {
+        let local_apic = the_local_apic();
+        local_apic.set_lvt_timer(LVT_MASKED | LVT_ONESHOT | u32::from(TIMER_VECTOR));
+        local_apic.set_div_conf(DIVIDE_BY_16);
+        local_apic.set_init_count(u32::MAX);
+        delay::udelay(CALIBRATION_US);
+        let elapsed = u32::MAX - local_apic.cur_count();
+        local_apic.set_init_count(0);
+        elapsed
+    };
+    irq_restore(flags);
+
+    (elapsed != 0).then(|| u64::from(elapsed) * 1_000_000 / CALIBRATION_US)
+}
+
+impl LapicTimer {
+    /// Sets the calling CPU's timer up, disarmed, preferring TSC-deadline mode.
+    fn new() -> Option<Self> {
+        let mode = match tsc::frequency() {
+            Some(tsc_frequency) if cpu::has(Feature::TscDeadline) => Mode::TscDeadline { tsc_frequency },
+            _ => Mode::OneShot {
+                frequency: (*ONESHOT_FREQUENCY.call_once(|| {
+                    let frequency = measure_oneshot();
+                    match frequency {
+                        Some(frequency) => log::info!("Local APIC timer: {} Hz", frequency),
+                        None => log::warn!("Local APIC timer does not count"),
+                    }
+                    frequency
+                }))?,
+            },
+        };
+
+        unsafe {
+            let local_apic = the_local_apic();
+            match mode {
+                Mode::TscDeadline { .. } => {
+                    local_apic.set_lvt_timer(LVT_TSC_DEADLINE | u32::from(TIMER_VECTOR));
+                    // The LVT write has to land before the deadline MSR is written
+                    if !local_apic.x2 {
+                        _mm_mfence();
+                    }
+                    wrmsr(IA32_TSC_DEADLINE, 0);
+                }
+                Mode::OneShot { .. } => {
+                    local_apic.set_div_conf(DIVIDE_BY_16);
+                    local_apic.set_lvt_timer(LVT_ONESHOT | u32::from(TIMER_VECTOR));
+                    local_apic.set_init_count(0);
+                }
+            }
+        }
+        Some(Self { mode })
+    }
+}
+
+impl ClockEvent for LapicTimer {
+    fn name(&self) -> &'static str {
+        match self.mode {
+            Mode::TscDeadline { .. } => "lapic-deadline",
+            Mode::OneShot { .. } => "lapic",
+        }
+    }
+
+    fn rating(&self) -> u32 {
+        let rating = match self.mode {
+            Mode::TscDeadline { .. } => 150,
+            Mode::OneShot { .. } => 140,
+        };
+        if cpu::has(Feature::Arat) { rating } else { rating - 50 }
+    }
+
+    fn min_delta_ns(&self) -> u64 {
+        MIN_DELTA_NS
+    }
+
+    fn max_delta_ns(&self) -> u64 {
+        match self.mode {
+            // Far enough that the deadline cannot wrap
+            Mode::TscDeadline { tsc_frequency } => time::cycles_to_ns(u64::MAX >> 1, tsc_frequency),
+            Mode::OneShot { frequency } => time::cycles_to_ns(u64::from(u32::MAX), frequency),
+        }
+    }
+
+    fn set_next_event(&mut self, delta_ns: u64) -> Result<(), TimeError> {
+        match self.mode {
+            Mode::TscDeadline { tsc_frequency } => {
+                let deadline = tsc::rdtsc().saturating_add(time::ns_to_cycles(delta_ns, tsc_frequency));
+                unsafe { wrmsr(IA32_TSC_DEADLINE, deadline) };
+            }
+            Mode::OneShot { frequency } => {
+                let count = time::ns_to_cycles(delta_ns, frequency).clamp(1, u64::from(u32::MAX));
+                unsafe { the_local_apic().set_init_count(count as u32) };
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        match self.mode {
+            Mode::TscDeadline { .. } => unsafe { wrmsr(IA32_TSC_DEADLINE, 0) },
+            Mode::OneShot { .. } => unsafe { the_local_apic().set_init_count(0) },
+        }
+    }
+}
+
+/// Makes the local APIC timer a clock-event device of the calling CPU. Called by
+/// every CPU once its local APIC is enabled and, on the first one, after the TSC was
+/// calibrated. Returns whether the timer is in use.
+pub fn setup() -> bool {
+    match LapicTimer::new() {
+        Some(timer) => time::register_clock_event(Box::new(timer)),
+        None => {
+            log::warn!("CPU {}: no usable local APIC timer", percpu::cpu_id());
+            false
+        }
+    }
+}
+
+/// Handles [`TIMER_VECTOR`], called by its IDT stub with interrupts disabled.
+pub fn handle() {
+    time::handle_event();
+    unsafe { the_local_apic().eoi() };
+}
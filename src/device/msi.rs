@@ -0,0 +1,622 @@
+//! # MSI and MSI-X
+//! Message-signalled interrupts are memory writes from a PCI function into the
+//! local APIC address window. The address names the destination APIC and the data
+//! the vector, so every function gets vectors of its own instead of sharing an INTx
+//! line routed through an I/O APIC.
+//!
+//! Only 8 bits of destination fit in the address. Higher x2APIC IDs go through the
+//! interrupt remapping table, or use the extended destination ID some hypervisors
+//! offer when there is no IOMMU.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+
+use alloc::{boxed::Box, vec::Vec};
+
+use spin::Once;
+
+use super::{
+    intremap::{self, Handle, MsiMessage, RemapEntry, RemapError, Source},
+    ioapic::{DeliveryMode, DestinationMode, TriggerMode},
+    pio::{inl, outl},
+    vector::{self, MaskSource, VectorError},
+};
+use crate::{
+    affinity::{AffinityError, IrqRoute},
+    cpu::{self, Feature},
+    dtb::irqchip::InterruptHandler,
+    ipi,
+    memory::{map_device_memory, PhysicalAddress, PAGE_SIZE},
+};
+
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+const MSI_ADDRESS_DEST_SHIFT: u32 = 12;
+/// Bits 14:8 of the destination, in what is otherwise the reserved part of the address.
+const MSI_ADDRESS_EXT_DEST_SHIFT: u32 = 5;
+
+const PCI_COMMAND: u16 = 0x04;
+const PCI_COMMAND_INTX_DISABLE: u32 = 1 << 10;
+const PCI_STATUS_CAP_LIST: u32 = 1 << (16 + 4);
+const PCI_BAR0: u16 = 0x10;
+const PCI_CAP_POINTER: u16 = 0x34;
+
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_MSIX: u8 = 0x11;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_MASKABLE: u16 = 1 << 8;
+
+const MSIX_CONTROL_MASK_ALL: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// CPUID leaf of KVM features and the bit saying MSIs may carry 15-bit destinations.
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+const KVM_FEATURE_MSI_EXT_DEST_ID: u32 = 1 << 15;
+
+/// Access to the configuration space of one PCI function.
+pub trait PciConfig {
+    /// Bus, device and function, as sent with the function's requests.
+    fn requester_id(&self) -> u16;
+    /// Reads the aligned dword at `offset`.
+    unsafe fn read(&self, offset: u16) -> u32;
+    /// Writes the aligned dword at `offset`.
+    unsafe fn write(&self, offset: u16, value: u32);
+
+    unsafe fn read_u16(&self, offset: u16) -> u16 {
+        (self.read(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    unsafe fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read(offset & !3) & !(0xFFFF << shift);
+        self.write(offset & !3, dword | u32::from(value) << shift);
+    }
+}
+
+/// Configuration space through the `0xCF8`/`0xCFC` ports, which reach the first 256
+/// bytes of functions on segment 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LegacyConfig {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+}
+
+impl LegacyConfig {
+    const ADDRESS: u16 = 0xCF8;
+    const DATA: u16 = 0xCFC;
+
+    #[inline(always)]
+    fn address(&self, offset: u16) -> u32 {
+        assert!(offset < 0x100, "config offset {:#x} needs ECAM", offset);
+        1 << 31 | u32::from(self.requester_id()) << 8 | u32::from(offset & 0xFC)
+    }
+}
+
+impl PciConfig for LegacyConfig {
+    fn requester_id(&self) -> u16 {
+        u16::from(self.bus) << 8 | u16::from(self.dev & 0x1F) << 3 | u16::from(self.func & 0x7)
+    }
+
+    unsafe fn read(&self, offset: u16) -> u32 {
+        outl(Self::ADDRESS, self.address(offset));
+        inl(Self::DATA)
+    }
+
+    unsafe fn write(&self, offset: u16, value: u32) {
+        outl(Self::ADDRESS, self.address(offset));
+        outl(Self::DATA, value);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has no MSI or MSI-X capability.
+    NotCapable,
+    /// The CPU is not registered, so its APIC ID is unknown.
+    UnknownCpu,
+    /// The APIC ID does not fit in the message and interrupt remapping is off.
+    NeedsRemapping,
+    /// The BAR holding the MSI-X table is not a memory BAR.
+    InvalidBar,
+    /// More vectors were asked for than the function supports.
+    TooMany,
+    /// Multiple MSI vectors were asked for with interrupt remapping on.
+    MultipleRemapped,
+    Vector(VectorError),
+    Remap(RemapError),
+}
+
+impl From<VectorError> for MsiError {
+    fn from(err: VectorError) -> Self {
+        Self::Vector(err)
+    }
+}
+
+impl From<RemapError> for MsiError {
+    fn from(err: RemapError) -> Self {
+        Self::Remap(err)
+    }
+}
+
+/// Whether the hypervisor accepts destination bits 14:8 in the address, checked once.
+static EXT_DEST_ID: Once<bool> = Once::new();
+
+fn has_ext_dest_id() -> bool {
+    *EXT_DEST_ID.call_once(|| {
+        if !cpu::has(Feature::Hypervisor) {
+            return false;
+        }
+        let leaf = unsafe { __cpuid(0x4000_0000) };
+        let signature = [leaf.ebx.to_le_bytes(), leaf.ecx.to_le_bytes(), leaf.edx.to_le_bytes()];
+        signature.as_flattened() == b"KVMKVMKVM\0\0\0"
+            && leaf.eax >= KVM_CPUID_FEATURES
+            && unsafe { __cpuid(KVM_CPUID_FEATURES) }.eax & KVM_FEATURE_MSI_EXT_DEST_ID != 0
+    })
+}
+
+/// Builds the compatibility-format message delivering `vector` to the local APIC
+/// `apic_id`, fixed and edge triggered.
+pub fn compose(apic_id: u32, vector: u8) -> Result<MsiMessage, MsiError> {
+    let dest = if apic_id <= 0xFF {
+        u64::from(apic_id) << MSI_ADDRESS_DEST_SHIFT
+    } else if apic_id <= 0x7FFF && has_ext_dest_id() {
+        u64::from(apic_id & 0xFF) << MSI_ADDRESS_DEST_SHIFT | u64::from(apic_id >> 8) << MSI_ADDRESS_EXT_DEST_SHIFT
+    } else {
+        return Err(MsiError::NeedsRemapping);
+    };
+    Ok(MsiMessage {
+        address: MSI_ADDRESS_BASE | dest,
+        data: (DeliveryMode::Fixed as u32) << 8 | u32::from(vector),
+    })
+}
+
+/// A vector allocated to a message sender, with its remapping entry if any.
+#[derive(Debug)]
+pub struct MsiIrq {
+    pub cpu_id: usize,
+    pub vector: u8,
+    remap: Option<Handle>,
+}
+
+impl MsiIrq {
+    /// Returns the message raising this interrupt.
+    pub fn message(&self) -> Result<MsiMessage, MsiError> {
+        match self.remap {
+            Some(handle) => Ok(intremap::msi_message(handle)?),
+            None => compose(apic_id(self.cpu_id)?, self.vector),
+        }
+    }
+
+    /// Installs the handler run on `cpu_id` when the message arrives.
+    pub fn set_handler(&self, handler: impl InterruptHandler + 'static) -> Result<(), MsiError> {
+        vector::set_handler(self.cpu_id, self.vector, Box::new(handler))?;
+        Ok(())
+    }
+
+    /// Moves the interrupt to a new vector on `cpu_id`. With remapping only the
+    /// remapping entry changes; otherwise `write` must program the new message.
+    pub fn retarget(&mut self, cpu_id: usize, write: impl FnOnce(MsiMessage)) -> Result<(), MsiError> {
+        let apic_id = apic_id(cpu_id)?;
+        if self.remap.is_none() {
+            compose(apic_id, 0)?;
+        }
+        let vector = vector::reassign(self.cpu_id, self.vector, cpu_id)?;
+        match self.remap {
+            Some(handle) => {
+                if let Err(err) = intremap::update(handle, |entry| {
+                    entry.vector = vector;
+                    entry.dest = apic_id;
+                }) {
+                    // Hand the handler back to the vector still in use
+                    vector::move_vector(cpu_id, vector, self.cpu_id, self.vector);
+                    vector::free(cpu_id, vector, 1);
+                    return Err(err.into());
+                }
+            }
+            None => write(compose(apic_id, vector)?),
+        }
+        vector::free(self.cpu_id, self.vector, 1);
+        self.cpu_id = cpu_id;
+        self.vector = vector;
+        Ok(())
+    }
+
+    /// Releases the vector and the remapping entry. The function must not send the
+    /// message anymore.
+    pub fn free(self) {
+        if let Some(handle) = self.remap {
+            intremap::free(handle);
+        }
+        vector::free(self.cpu_id, self.vector, 1);
+    }
+}
+
+fn apic_id(cpu_id: usize) -> Result<u32, MsiError> {
+    ipi::hw_id(cpu_id).map(|id| id as u32).ok_or(MsiError::UnknownCpu)
+}
+
+/// Sets up the remapping entry of `vector` on `cpu_id` for `source`, if remapping is on.
+fn remap(source: Source, cpu_id: usize, vector: u8) -> Result<Option<Handle>, MsiError> {
+    if !intremap::is_enabled() {
+        return Ok(None);
+    }
+    let handle = intremap::allocate(source, RemapEntry {
+        vector,
+        dest: apic_id(cpu_id)?,
+        trigger_mode: TriggerMode::Edge,
+        dest_mode: DestinationMode::Physical,
+        delivery_mode: DeliveryMode::Fixed,
+    })?;
+    Ok(Some(handle))
+}
+
+/// Allocates one vector on `cpu_id` for messages sent by `source` and checks it can
+/// be reached. PCI functions go through [`enable_msi`] and [`enable_msix`]; this is
+/// for other message senders, such as HPET comparators.
+pub fn allocate_irq(source: Source, cpu_id: usize) -> Result<MsiIrq, MsiError> {
+    let vector = vector::allocate(cpu_id, 1)?;
+    let irq = match remap(source, cpu_id, vector) {
+        Ok(remap) => MsiIrq { cpu_id, vector, remap },
+        Err(err) => {
+            vector::free(cpu_id, vector, 1);
+            return Err(err);
+        }
+    };
+    if let Err(err) = irq.message() {
+        irq.free();
+        return Err(err);
+    }
+    Ok(irq)
+}
+
+/// Walks the capability list for `id`, returning its offset.
+unsafe fn find_capability(config: &impl PciConfig, id: u8) -> Option<u16> {
+    if config.read(PCI_COMMAND) & PCI_STATUS_CAP_LIST == 0 {
+        return None;
+    }
+    let mut offset = config.read(PCI_CAP_POINTER) as u16 & 0xFC;
+    // The list is at most 48 entries long, which also ends malformed loops
+    for _ in 0..48 {
+        if offset == 0 {
+            return None;
+        }
+        let header = config.read(offset);
+        if header as u8 == id {
+            return Some(offset);
+        }
+        offset = (header >> 8) as u16 & 0xFC;
+    }
+    None
+}
+
+/// Stops the function from asserting INTx, which it may still do next to messages.
+unsafe fn disable_intx(config: &impl PciConfig) {
+    let command = config.read(PCI_COMMAND) & 0xFFFF;
+    config.write(PCI_COMMAND, command | PCI_COMMAND_INTX_DISABLE);
+}
+
+/// The MSI capability of a function.
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    cap: u16,
+    control: u16,
+}
+
+impl Msi {
+    pub unsafe fn find(config: &impl PciConfig) -> Option<Self> {
+        let cap = find_capability(config, CAP_ID_MSI)?;
+        Some(Self {
+            cap,
+            control: config.read_u16(cap + 2),
+        })
+    }
+
+    /// Number of vectors the function can use, a power of two up to 32.
+    pub fn max_vectors(&self) -> usize {
+        1 << ((self.control >> 1) & 0x7).min(5)
+    }
+
+    pub fn is_maskable(&self) -> bool {
+        self.control & MSI_CONTROL_MASKABLE != 0
+    }
+
+    fn data_offset(&self) -> u16 {
+        if self.control & MSI_CONTROL_64BIT != 0 { self.cap + 0xC } else { self.cap + 0x8 }
+    }
+
+    fn mask_offset(&self) -> u16 {
+        self.data_offset() + 4
+    }
+
+    /// Programs the message, which the function varies in the low data bits with
+    /// multiple vectors. The capability must be disabled.
+    pub unsafe fn write_message(&self, config: &impl PciConfig, message: MsiMessage) {
+        config.write(self.cap + 4, message.address as u32);
+        if self.control & MSI_CONTROL_64BIT != 0 {
+            config.write(self.cap + 8, (message.address >> 32) as u32);
+        }
+        config.write_u16(self.data_offset(), message.data as u16);
+    }
+
+    /// Enables `count` vectors, a power of two, or disables the capability with zero.
+    pub unsafe fn set_enabled(&self, config: &impl PciConfig, count: usize) {
+        let mut control = config.read_u16(self.cap + 2) & !(MSI_CONTROL_ENABLE | 0x7 << 4);
+        if count > 0 {
+            control |= (count.trailing_zeros() as u16) << 4 | MSI_CONTROL_ENABLE;
+        }
+        config.write_u16(self.cap + 2, control);
+    }
+
+    /// Masks vector `index`, if the function supports per-vector masking.
+    pub unsafe fn set_mask(&self, config: &impl PciConfig, index: usize, masked: bool) {
+        if !self.is_maskable() {
+            return;
+        }
+        let mask = config.read(self.mask_offset());
+        let bit = 1 << index;
+        config.write(self.mask_offset(), if masked { mask | bit } else { mask & !bit });
+    }
+
+    /// Returns whether vector `index` is masked, never the case without per-vector masking.
+    pub unsafe fn is_masked(&self, config: &impl PciConfig, index: usize) -> bool {
+        self.is_maskable() && config.read(self.mask_offset()) & (1 << index) != 0
+    }
+}
+
+/// The MSI-X capability of a function, with its vector table mapped.
+#[derive(Clone, Copy, Debug)]
+pub struct MsiX {
+    cap: u16,
+    table: usize,
+    size: usize,
+}
+
+impl MsiX {
+    /// Finds the capability and maps the table from the BAR it lives in.
+    pub unsafe fn find(config: &impl PciConfig) -> Result<Self, MsiError> {
+        let cap = find_capability(config, CAP_ID_MSIX).ok_or(MsiError::NotCapable)?;
+        let size = usize::from(config.read_u16(cap + 2) & 0x7FF) + 1;
+        let table = config.read(cap + 4);
+        let bar = bar_address(config, (table & 0x7) as u16).ok_or(MsiError::InvalidBar)?;
+
+        let phys = bar + u64::from(table & !0x7);
+        let page = phys & !(PAGE_SIZE as u64 - 1);
+        let len = (phys - page) as usize + size * MSIX_ENTRY_SIZE;
+        let virt = map_device_memory(PhysicalAddress::new(page as usize), len.next_multiple_of(PAGE_SIZE)).data();
+        Ok(Self {
+            cap,
+            table: virt + (phys - page) as usize,
+            size,
+        })
+    }
+
+    /// Number of entries in the vector table.
+    pub fn table_size(&self) -> usize {
+        self.size
+    }
+
+    #[inline(always)]
+    fn entry(&self, index: usize) -> *mut u32 {
+        assert!(index < self.size, "MSI-X entry {} out of {}", index, self.size);
+        (self.table + index * MSIX_ENTRY_SIZE) as *mut u32
+    }
+
+    /// Programs entry `index`, which should be masked meanwhile.
+    pub unsafe fn write_entry(&self, index: usize, message: MsiMessage) {
+        let entry = self.entry(index);
+        ptr::write_volatile(entry, message.address as u32);
+        ptr::write_volatile(entry.add(1), (message.address >> 32) as u32);
+        ptr::write_volatile(entry.add(2), message.data);
+    }
+
+    pub unsafe fn set_mask(&self, index: usize, masked: bool) {
+        let control = self.entry(index).add(3);
+        let value = ptr::read_volatile(control);
+        ptr::write_volatile(control, if masked { value | MSIX_VECTOR_MASKED } else { value & !MSIX_VECTOR_MASKED });
+        // Reading back flushes the posted write before the caller relies on it
+        ptr::read_volatile(control);
+    }
+
+    pub unsafe fn is_masked(&self, index: usize) -> bool {
+        ptr::read_volatile(self.entry(index).add(3)) & MSIX_VECTOR_MASKED != 0
+    }
+
+    /// Enables the capability, or disables it. Entries stay masked until unmasked.
+    pub unsafe fn set_enabled(&self, config: &impl PciConfig, enabled: bool) {
+        let control = config.read_u16(self.cap + 2) & !MSIX_CONTROL_MASK_ALL;
+        let control = if enabled { control | MSIX_CONTROL_ENABLE } else { control & !MSIX_CONTROL_ENABLE };
+        config.write_u16(self.cap + 2, control);
+    }
+}
+
+/// Returns the physical address of memory BAR `index`.
+unsafe fn bar_address(config: &impl PciConfig, index: u16) -> Option<u64> {
+    if index > 5 {
+        return None;
+    }
+    let low = config.read(PCI_BAR0 + index * 4);
+    if low & 1 != 0 {
+        return None;
+    }
+    let high = match (low >> 1) & 0x3 {
+        0b00 => 0,
+        0b10 if index < 5 => config.read(PCI_BAR0 + (index + 1) * 4),
+        _ => return None,
+    };
+    Some(u64::from(high) << 32 | u64::from(low & !0xF))
+}
+
+/// Enables MSI with `count` vectors delivered to `cpu_id`, which must be a power of
+/// two within [`Msi::max_vectors`]. Vector `i` of the function arrives as the
+/// returned vector plus `i`.
+///
+/// Multiple vectors cannot be remapped, as the remapping entries would have to be
+/// consecutive; with remapping on, asking for more than one fails with
+/// [`MsiError::MultipleRemapped`], and MSI-X should be used instead.
+pub unsafe fn enable_msi<C: PciConfig + Clone + Send + 'static>(
+    config: &C,
+    cpu_id: usize,
+    count: usize,
+) -> Result<Vec<MsiIrq>, MsiError> {
+    let msi = Msi::find(config).ok_or(MsiError::NotCapable)?;
+    if !count.is_power_of_two() || count > msi.max_vectors() {
+        return Err(MsiError::TooMany);
+    }
+    if count > 1 && intremap::is_enabled() {
+        return Err(MsiError::MultipleRemapped);
+    }
+
+    let irqs: Vec<_> = if count == 1 {
+        Vec::from([allocate_irq(Source::Pci(config.requester_id()), cpu_id)?])
+    } else {
+        let base = vector::allocate(cpu_id, count)?;
+        (0..count as u8)
+            .map(|i| MsiIrq {
+                cpu_id,
+                vector: base + i,
+                remap: None,
+            })
+            .collect()
+    };
+    let message = match irqs[0].message() {
+        Ok(message) => message,
+        Err(err) => {
+            irqs.into_iter().for_each(MsiIrq::free);
+            return Err(err);
+        }
+    };
+
+    msi.set_enabled(config, 0);
+    msi.write_message(config, message);
+    for (index, irq) in irqs.iter().enumerate() {
+        msi.set_mask(config, index, false);
+        let source = MsiSource {
+            config: config.clone(),
+            msi,
+            index,
+        };
+        let _ = vector::set_source(irq.cpu_id, irq.vector, Box::new(source));
+    }
+    disable_intx(config);
+    msi.set_enabled(config, count);
+    Ok(irqs)
+}
+
+/// Enables MSI-X with one entry for each CPU in `cpus`, in order. The entries start
+/// masked; unmask them once their handlers are installed.
+pub unsafe fn enable_msix(config: &impl PciConfig, cpus: &[usize]) -> Result<(MsiX, Vec<MsiIrq>), MsiError> {
+    let msix = MsiX::find(config)?;
+    if cpus.len() > msix.table_size() {
+        return Err(MsiError::TooMany);
+    }
+
+    let mut irqs = Vec::with_capacity(cpus.len());
+    for &cpu_id in cpus {
+        match allocate_irq(Source::Pci(config.requester_id()), cpu_id) {
+            Ok(irq) => irqs.push(irq),
+            Err(err) => {
+                irqs.into_iter().for_each(MsiIrq::free);
+                return Err(err);
+            }
+        }
+    }
+
+    msix.set_enabled(config, false);
+    for (index, irq) in irqs.iter().enumerate() {
+        msix.set_mask(index, true);
+        // Checked by `allocate_irq`
+        if let Ok(message) = irq.message() {
+            msix.write_entry(index, message);
+        }
+        let _ = vector::set_source(irq.cpu_id, irq.vector, Box::new(MsiXSource { msix, index }));
+    }
+    disable_intx(config);
+    msix.set_enabled(config, true);
+    Ok((msix, irqs))
+}
+
+/// Vector `index` of an MSI function, for masking it when it storms.
+struct MsiSource<C> {
+    config: C,
+    msi: Msi,
+    index: usize,
+}
+
+impl<C: PciConfig + Send> MaskSource for MsiSource<C> {
+    fn mask(&self) -> bool {
+        unsafe { self.msi.set_mask(&self.config, self.index, true) };
+        self.msi.is_maskable()
+    }
+}
+
+/// Entry `index` of an MSI-X table, for masking it when it storms.
+struct MsiXSource {
+    msix: MsiX,
+    index: usize,
+}
+
+impl MaskSource for MsiXSource {
+    fn mask(&self) -> bool {
+        unsafe { self.msix.set_mask(self.index, true) };
+        true
+    }
+}
+
+fn affinity_error(err: MsiError) -> AffinityError {
+    match err {
+        MsiError::UnknownCpu => AffinityError::UnknownIrq,
+        _ => AffinityError::NoResources,
+    }
+}
+
+/// Routes the single vector of an MSI function for [`affinity`](crate::affinity).
+pub struct MsiRoute<C> {
+    pub config: C,
+    pub msi: Msi,
+    pub irq: MsiIrq,
+}
+
+impl<C: PciConfig + Send> IrqRoute for MsiRoute<C> {
+    /// Leaves the mask as it was, so vectors masked by their driver or for firing
+    /// unhandled stay masked.
+    fn set_target(&mut self, cpu_id: usize) -> Result<(), AffinityError> {
+        let (config, msi) = (&self.config, self.msi);
+        self.irq
+            .retarget(cpu_id, |message| unsafe {
+                let masked = msi.is_masked(config, 0);
+                msi.set_mask(config, 0, true);
+                msi.write_message(config, message);
+                msi.set_mask(config, 0, masked);
+            })
+            .map_err(affinity_error)
+    }
+}
+
+/// Routes one MSI-X entry for [`affinity`](crate::affinity).
+pub struct MsiXRoute {
+    pub msix: MsiX,
+    pub index: usize,
+    pub irq: MsiIrq,
+}
+
+impl IrqRoute for MsiXRoute {
+    /// Leaves the mask as it was, like [`MsiRoute`].
+    fn set_target(&mut self, cpu_id: usize) -> Result<(), AffinityError> {
+        let (msix, index) = (self.msix, self.index);
+        self.irq
+            .retarget(cpu_id, |message| unsafe {
+                let masked = msix.is_masked(index);
+                msix.set_mask(index, true);
+                msix.write_entry(index, message);
+                msix.set_mask(index, masked);
+            })
+            .map_err(affinity_error)
+    }
+}
@@ -0,0 +1,30 @@
+use core::arch::asm;
+
+/// Local APIC base address and mode.
+pub const IA32_APIC_BASE: u32 = 0x1B;
+/// Extended feature enables, including long mode and no-execute.
+pub const IA32_EFER: u32 = 0xC000_0080;
+/// GS base used by the kernel, swapped in by `swapgs` on entry from user mode.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+/// GS base in effect.
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// Reads a model-specific register.
+#[inline(always)]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// Writes a model-specific register.
+#[inline(always)]
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags)
+    );
+}
@@ -0,0 +1,157 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::{
+    ioapic::IRQ_VECTOR_BASE,
+    pio::{inb, io_wait, outb},
+};
+
+const MASTER_CMD: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_CMD: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// Interrupt mode configuration register, present on some MP-spec boards.
+const IMCR_SELECT: u16 = 0x22;
+const IMCR_DATA: u16 = 0x23;
+
+const ICW1_ICW4: u8 = 1 << 0;
+const ICW1_INIT: u8 = 1 << 4;
+const ICW4_8086: u8 = 1 << 0;
+const OCW3_READ_ISR: u8 = 0x0B;
+const CMD_EOI: u8 = 0x20;
+
+/// IRQ line on the master that the slave cascades through.
+const CASCADE_IRQ: u8 = 2;
+
+/// One half of the cascaded 8259 pair.
+pub struct Pic {
+    cmd: u16,
+    data: u16,
+}
+
+impl Pic {
+    pub const fn new(cmd: u16, data: u16) -> Self {
+        Self { cmd, data }
+    }
+
+    pub fn ack(&mut self) {
+        unsafe { outb(self.cmd, CMD_EOI) };
+    }
+
+    pub fn mask(&mut self) -> u8 {
+        unsafe { inb(self.data) }
+    }
+
+    pub fn set_mask(&mut self, mask: u8) {
+        unsafe { outb(self.data, mask) };
+    }
+
+    /// Reads the in-service register.
+    pub fn isr(&mut self) -> u8 {
+        unsafe {
+            outb(self.cmd, OCW3_READ_ISR);
+            inb(self.cmd)
+        }
+    }
+
+    unsafe fn remap(&mut self, vector_base: u8, cascade: u8) {
+        outb(self.cmd, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        outb(self.data, vector_base);
+        io_wait();
+        outb(self.data, cascade);
+        io_wait();
+        outb(self.data, ICW4_8086);
+        io_wait();
+    }
+}
+
+pub static MASTER: Mutex<Pic> = Mutex::new(Pic::new(MASTER_CMD, MASTER_DATA));
+pub static SLAVE: Mutex<Pic> = Mutex::new(Pic::new(SLAVE_CMD, SLAVE_DATA));
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the PICs are delivering interrupts instead of the I/O APIC.
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Remaps the PICs to vectors `IRQ_VECTOR_BASE..IRQ_VECTOR_BASE + 16` so that stray
+/// interrupts no longer land on exception vectors, and masks every line.
+pub unsafe fn init() {
+    let mut master = MASTER.lock();
+    let mut slave = SLAVE.lock();
+
+    master.remap(IRQ_VECTOR_BASE, 1 << CASCADE_IRQ);
+    slave.remap(IRQ_VECTOR_BASE + 8, CASCADE_IRQ);
+
+    master.set_mask(0xFF);
+    slave.set_mask(0xFF);
+
+    // A request latched during the remap would otherwise stay in service forever.
+    slave.ack();
+    master.ack();
+}
+
+/// Masks both PICs and routes the INTR line to the local APIC, for use before
+/// enabling the local APIC on systems with `PCAT_COMPAT` set.
+pub unsafe fn disable() {
+    init();
+    ENABLED.store(false, Ordering::Release);
+
+    // Leave PIC mode on boards that implement the IMCR; writes are ignored elsewhere.
+    outb(IMCR_SELECT, 0x70);
+    outb(IMCR_DATA, inb(IMCR_DATA) | 1);
+}
+
+/// Uses the PICs as the interrupt controller on machines without an APIC.
+/// Lines stay masked until drivers unmask them, except the cascade.
+pub unsafe fn enable_fallback() {
+    init();
+    MASTER.lock().set_mask(!(1 << CASCADE_IRQ));
+    ENABLED.store(true, Ordering::Release);
+    log::info!("  PIC: using 8259 as fallback interrupt controller");
+}
+
+pub fn mask(irq: u8) {
+    assert!(irq < 16, "PIC IRQ {} out of range", irq);
+    let (pic, bit) = if irq < 8 { (&MASTER, irq) } else { (&SLAVE, irq - 8) };
+    let mut pic = pic.lock();
+    let current = pic.mask();
+    pic.set_mask(current | (1 << bit));
+}
+
+pub fn unmask(irq: u8) {
+    assert!(irq < 16, "PIC IRQ {} out of range", irq);
+    let (pic, bit) = if irq < 8 { (&MASTER, irq) } else { (&SLAVE, irq - 8) };
+    let mut pic = pic.lock();
+    let current = pic.mask();
+    pic.set_mask(current & !(1 << bit));
+}
+
+/// Returns true if `irq` is a spurious IRQ 7 or 15 that must not be acknowledged
+/// (except for the cascade on the master, in the IRQ 15 case).
+pub fn is_spurious(irq: u8) -> bool {
+    match irq {
+        7 => MASTER.lock().isr() & (1 << 7) == 0,
+        15 => {
+            let spurious = SLAVE.lock().isr() & (1 << 7) == 0;
+            if spurious {
+                MASTER.lock().ack();
+            }
+            spurious
+        }
+        _ => false,
+    }
+}
+
+/// Signals end of interrupt for `irq`.
+pub fn eoi(irq: u8) {
+    if irq >= 8 {
+        SLAVE.lock().ack();
+    }
+    MASTER.lock().ack();
+}
@@ -0,0 +1,35 @@
+use core::arch::asm;
+
+/// Writes a byte to an I/O port.
+#[inline(always)]
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a byte from an I/O port.
+#[inline(always)]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Writes a dword to an I/O port.
+#[inline(always)]
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Reads a dword from an I/O port.
+#[inline(always)]
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", in("dx") port, out("eax") value, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Gives slow devices time to settle between accesses.
+#[inline(always)]
+pub unsafe fn io_wait() {
+    outb(0x80, 0);
+}
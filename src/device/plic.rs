@@ -0,0 +1,210 @@
+//! # RISC-V PLIC
+//! The platform-level interrupt controller gathers wired interrupts and signals
+//! them to hart contexts, one per hart and privilege level. Each context has its own
+//! enable bits, priority threshold and claim register; the kernel uses the
+//! supervisor context of every hart, found in `interrupts-extended`.
+//!
+//! A source is enabled in the context of a single hart at a time, so it is claimed
+//! where it was routed by [`IrqChip::set_affinity`], the boot hart by default.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::asm,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    dtb::{
+        fdt::{Fdt, Node},
+        irqchip::{IrqChip, IrqError, IrqSpec, Polarity, Trigger},
+    },
+    ipi,
+    memory::{map_device_memory, PhysicalAddress},
+    percpu::{self, irq_restore, irq_save},
+};
+
+pub const COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+
+/// Priority of source `i` at `4 * i`.
+const PRIORITY: usize = 0x0;
+/// Enable bits of context `c` at `ENABLE + c * ENABLE_STRIDE`.
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// Threshold of context `c` at `CONTEXT + c * CONTEXT_STRIDE`, its claim and
+/// complete register 4 bytes further.
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// Cause of the supervisor external interrupt, naming supervisor contexts.
+const IRQ_S_EXT: u32 = 9;
+const SIE_SEIE: usize = 1 << 9;
+
+/// No CPU was picked for a source yet.
+const NO_CPU: usize = usize::MAX;
+
+pub struct Plic {
+    /// Mapped registers.
+    base: usize,
+    /// Highest source; zero means no interrupt.
+    ndev: u32,
+    /// Supervisor context of each CPU, by CPU ID.
+    contexts: Vec<Option<usize>>,
+    /// CPU each source is enabled on, or [`NO_CPU`].
+    targets: Vec<AtomicUsize>,
+    /// Serializes updates of the enable bits, which are shared by 32 sources.
+    enable_lock: Mutex<()>,
+}
+
+/// Creates the driver of a PLIC node, mapping its registers. The CPUs must be
+/// registered with [`ipi`] by their hart ID already.
+pub fn probe(fdt: &Fdt, node: &Node) -> Option<Box<dyn IrqChip>> {
+    let (base, size) = fdt.reg(node).next()?;
+    let ndev = node.property("riscv,ndev").and_then(|prop| prop.as_u32())?;
+
+    let mut contexts = Vec::new();
+    for (context, irq) in fdt.interrupts(node).enumerate() {
+        if irq.specifier.get(0) != Some(IRQ_S_EXT) {
+            continue;
+        }
+        let Some(hart) = fdt.parent(&irq.controller).and_then(|cpu| cpu.property("reg")?.as_u32()) else {
+            continue;
+        };
+        let Some(cpu_id) = percpu::cpus().into_iter().find(|&cpu_id| ipi::hw_id(cpu_id) == Some(u64::from(hart)))
+        else {
+            continue;
+        };
+        if contexts.len() <= cpu_id {
+            contexts.resize(cpu_id + 1, None);
+        }
+        contexts[cpu_id] = Some(context);
+    }
+    if contexts.iter().all(Option::is_none) {
+        log::warn!("PLIC {} has no supervisor context", node.name);
+        return None;
+    }
+
+    let base = unsafe { map_device_memory(PhysicalAddress::new(base as usize), size as usize) }.data();
+    let plic = Plic {
+        base,
+        ndev,
+        contexts,
+        targets: (0..=ndev).map(|_| AtomicUsize::new(NO_CPU)).collect(),
+        enable_lock: Mutex::new(()),
+    };
+    for hwirq in 1..=ndev {
+        plic.disable(hwirq);
+    }
+    plic.init_cpu();
+    log::info!("  PLIC {} with {} sources", node.name, ndev);
+    Some(Box::new(plic))
+}
+
+impl Plic {
+    fn context(&self, cpu_id: usize) -> Option<usize> {
+        self.contexts.get(cpu_id).copied().flatten()
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    /// Sets or clears the enable bit of `hwirq` in `context`.
+    fn set_enabled(&self, context: usize, hwirq: u32, enabled: bool) {
+        let reg = self.reg(ENABLE + context * ENABLE_STRIDE + hwirq as usize / 32 * 4);
+        let bit = 1 << (hwirq % 32);
+
+        let flags = irq_save();
+        let guard = self.enable_lock.lock();
+        unsafe {
+            let value = ptr::read_volatile(reg);
+            ptr::write_volatile(reg, if enabled { value | bit } else { value & !bit });
+        }
+        drop(guard);
+        irq_restore(flags);
+    }
+}
+
+impl IrqChip for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn hwirq_count(&self) -> u32 {
+        self.ndev + 1
+    }
+
+    /// Sources are level triggered, with the source number as the only cell.
+    fn xlate(&self, spec: &[u32]) -> Result<IrqSpec, IrqError> {
+        match spec.first() {
+            Some(&hwirq) if hwirq != 0 && hwirq <= self.ndev => Ok(IrqSpec::new(hwirq, Trigger::Level, Polarity::High)),
+            _ => Err(IrqError::InvalidSpecifier),
+        }
+    }
+
+    fn enable(&self, hwirq: u32) {
+        let Some(target) = self.targets.get(hwirq as usize) else {
+            return;
+        };
+        let mut cpu_id = target.load(Ordering::Relaxed);
+        if self.context(cpu_id).is_none() {
+            cpu_id = percpu::cpu_id();
+            target.store(cpu_id, Ordering::Relaxed);
+        }
+        let Some(context) = self.context(cpu_id) else {
+            return;
+        };
+        unsafe { ptr::write_volatile(self.reg(PRIORITY + hwirq as usize * 4), 1) };
+        self.set_enabled(context, hwirq, true);
+    }
+
+    fn disable(&self, hwirq: u32) {
+        for context in self.contexts.iter().flatten() {
+            self.set_enabled(*context, hwirq, false);
+        }
+    }
+
+    fn ack(&self) -> Option<u32> {
+        let context = self.context(percpu::cpu_id())?;
+        match unsafe { ptr::read_volatile(self.reg(CONTEXT + context * CONTEXT_STRIDE + CONTEXT_CLAIM)) } {
+            0 => None,
+            hwirq => Some(hwirq),
+        }
+    }
+
+    fn eoi(&self, hwirq: u32) {
+        if let Some(context) = self.context(percpu::cpu_id()) {
+            unsafe { ptr::write_volatile(self.reg(CONTEXT + context * CONTEXT_STRIDE + CONTEXT_CLAIM), hwirq) };
+        }
+    }
+
+    /// Lets every priority through the calling hart's context and enables
+    /// supervisor external interrupts.
+    fn init_cpu(&self) {
+        let Some(context) = self.context(percpu::cpu_id()) else {
+            return;
+        };
+        unsafe {
+            ptr::write_volatile(self.reg(CONTEXT + context * CONTEXT_STRIDE), 0);
+            asm!("csrs sie, {}", in(reg) SIE_SEIE, options(nostack));
+        }
+    }
+
+    /// Moves the enable bit of `hwirq` to the context of `cpu_id`.
+    fn set_affinity(&self, hwirq: u32, cpu_id: usize) -> Result<(), IrqError> {
+        let target = self.targets.get(hwirq as usize).ok_or(IrqError::OutOfRange)?;
+        let context = self.context(cpu_id).ok_or(IrqError::OutOfRange)?;
+        let previous = target.swap(cpu_id, Ordering::Relaxed);
+        if let Some(old) = self.context(previous).filter(|&old| old != context) {
+            let enable = self.reg(ENABLE + old * ENABLE_STRIDE + hwirq as usize / 32 * 4);
+            if unsafe { ptr::read_volatile(enable) } & (1 << (hwirq % 32)) != 0 {
+                self.set_enabled(old, hwirq, false);
+                self.set_enabled(context, hwirq, true);
+            }
+        }
+        Ok(())
+    }
+}
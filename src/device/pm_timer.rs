@@ -0,0 +1,110 @@
+//! # ACPI PM timer
+//! A free-running counter at 3.579545 MHz, 24 or 32 bits wide, found through the
+//! FADT in I/O port or memory space. Slow to read, but present on every PC that is
+//! not hardware-reduced.
+
+use alloc::sync::Arc;
+use core::ptr::read_volatile;
+
+use spin::Once;
+
+use super::pio::inl;
+use crate::{
+    acpi::fadt::{fadt, Fadt, FLAG_TMR_VAL_EXT},
+    memory::{map_device_memory, PhysicalAddress, PAGE_SIZE},
+    time::{self, Clocksource},
+};
+
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+const ADDRESS_SPACE_IO: u8 = 1;
+
+#[derive(Clone, Copy, Debug)]
+enum Access {
+    Port(u16),
+    Mmio(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PmTimer {
+    access: Access,
+    mask: u32,
+}
+
+impl PmTimer {
+    /// Locates the timer from the FADT, preferring the extended block.
+    pub fn new(fadt: &Fadt) -> Option<Self> {
+        let access = match fadt.x_pm_timer_block() {
+            Some(block) => match block.address_space {
+                ADDRESS_SPACE_IO => Access::Port(block.address as u16),
+                ADDRESS_SPACE_MEMORY => {
+                    let phys = block.address as usize;
+                    let page = phys & !(PAGE_SIZE - 1);
+                    let virt = map_device_memory(PhysicalAddress::new(page), PAGE_SIZE).data();
+                    Access::Mmio(virt + (phys - page))
+                }
+                space => {
+                    log::warn!("PM timer in unsupported address space {}", space);
+                    return None;
+                }
+            },
+            None if fadt.pm_timer_block != 0 && fadt.pm_timer_length >= 4 => Access::Port(fadt.pm_timer_block as u16),
+            None => return None,
+        };
+        let mask = if fadt.flags & FLAG_TMR_VAL_EXT != 0 { u32::MAX } else { 0xFF_FFFF };
+        Some(Self { access, mask })
+    }
+
+    pub fn read(&self) -> u32 {
+        let value = match self.access {
+            Access::Port(port) => unsafe { inl(port) },
+            Access::Mmio(addr) => unsafe { read_volatile(addr as *const u32) },
+        };
+        value & self.mask
+    }
+
+    /// Mask of the valid counter bits, at which it wraps.
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+}
+
+impl Clocksource for PmTimer {
+    fn name(&self) -> &'static str {
+        "acpi_pm"
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn frequency(&self) -> u64 {
+        PM_TIMER_FREQUENCY
+    }
+
+    fn mask(&self) -> u64 {
+        u64::from(self.mask)
+    }
+
+    fn read(&self) -> u64 {
+        u64::from(PmTimer::read(self))
+    }
+}
+
+static PM_TIMER: Once<PmTimer> = Once::new();
+
+/// Returns the PM timer, once found.
+pub fn get() -> Option<&'static PmTimer> {
+    PM_TIMER.get()
+}
+
+/// Registers the PM timer described by the FADT as a clocksource.
+pub fn init() {
+    let Some(timer) = fadt().and_then(PmTimer::new) else {
+        return;
+    };
+    log::info!("PM timer: {}-bit", if timer.mask == u32::MAX { 32 } else { 24 });
+    PM_TIMER.call_once(|| timer);
+    time::register_clocksource(Arc::new(timer));
+}
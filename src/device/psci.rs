@@ -0,0 +1,155 @@
+//! # PSCI
+//! Power State Coordination Interface calls, used to start and stop CPUs on aarch64.
+//! ACPI platforms say in the FADT whether PSCI is there and which conduit reaches it,
+//! device trees in the `/psci` node.
+
+use core::arch::asm;
+
+use spin::Once;
+
+use crate::acpi::fadt::{fadt, ARM_PSCI_COMPLIANT, ARM_PSCI_USE_HVC};
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_OFF: u32 = 0x8400_0002;
+const CPU_ON_64: u32 = 0xC400_0003;
+const AFFINITY_INFO_64: u32 = 0xC400_0004;
+const PSCI_FEATURES: u32 = 0x8400_000A;
+
+/// Instruction that traps to the PSCI implementation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conduit {
+    /// Secure monitor, the usual case on hardware.
+    Smc,
+    /// Hypervisor, the usual case in a virtual machine.
+    Hvc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i64),
+}
+
+impl PsciError {
+    fn from_code(code: i64) -> Result<i64, Self> {
+        Err(match code {
+            0.. => return Ok(code),
+            -1 => Self::NotSupported,
+            -2 => Self::InvalidParameters,
+            -3 => Self::Denied,
+            -4 => Self::AlreadyOn,
+            -5 => Self::OnPending,
+            -6 => Self::InternalFailure,
+            -7 => Self::NotPresent,
+            -8 => Self::Disabled,
+            -9 => Self::InvalidAddress,
+            _ => Self::Unknown(code),
+        })
+    }
+}
+
+/// Power state of an affinity instance, from `AFFINITY_INFO`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AffinityState {
+    On,
+    Off,
+    OnPending,
+}
+
+static CONDUIT: Once<Conduit> = Once::new();
+
+/// Picks the conduit from the FADT ARM boot flags. Returns false, leaving PSCI
+/// unused, if the platform does not claim to implement it.
+pub fn init() -> bool {
+    let flags = fadt().map_or(0, |fadt| fadt.arm_boot_arch());
+    if flags & ARM_PSCI_COMPLIANT == 0 {
+        return false;
+    }
+
+    init_conduit(if flags & ARM_PSCI_USE_HVC != 0 { Conduit::Hvc } else { Conduit::Smc });
+    true
+}
+
+/// Uses PSCI through `conduit`, as given by the `method` of a device tree `/psci` node.
+pub fn init_conduit(conduit: Conduit) {
+    CONDUIT.call_once(|| conduit);
+
+    let (major, minor) = version();
+    log::info!("  PSCI: version {}.{} through {:?}", major, minor, conduit);
+}
+
+#[inline(always)]
+pub fn is_available() -> bool {
+    CONDUIT.get().is_some()
+}
+
+/// Makes an SMC32/SMC64 call with up to three arguments and returns x0.
+unsafe fn call(function: u32, arg1: u64, arg2: u64, arg3: u64) -> i64 {
+    let conduit = *CONDUIT.get().expect("PSCI not initialized");
+    let ret: i64;
+    // SMCCC 1.0 allows x4 to x17 to be clobbered as well
+    macro_rules! trap {
+        ($insn:literal) => {
+            asm!(
+                $insn,
+                inlateout("x0") u64::from(function) => ret,
+                inlateout("x1") arg1 => _,
+                inlateout("x2") arg2 => _,
+                inlateout("x3") arg3 => _,
+                lateout("x4") _, lateout("x5") _, lateout("x6") _, lateout("x7") _,
+                lateout("x8") _, lateout("x9") _, lateout("x10") _, lateout("x11") _,
+                lateout("x12") _, lateout("x13") _, lateout("x14") _, lateout("x15") _,
+                lateout("x16") _, lateout("x17") _,
+                options(nostack)
+            )
+        };
+    }
+    match conduit {
+        Conduit::Smc => trap!("smc #0"),
+        Conduit::Hvc => trap!("hvc #0"),
+    }
+    ret
+}
+
+/// Returns the major and minor PSCI version.
+pub fn version() -> (u16, u16) {
+    let version = unsafe { call(PSCI_VERSION, 0, 0, 0) } as u32;
+    ((version >> 16) as u16, version as u16)
+}
+
+/// Returns whether the implementation has `function`, PSCI 1.0 and later.
+pub fn has_function(function: u32) -> bool {
+    version().0 >= 1 && unsafe { call(PSCI_FEATURES, u64::from(function), 0, 0) } >= 0
+}
+
+/// Powers on the CPU with affinity `mpidr`, which starts at the physical address
+/// `entry` with the MMU off and `context` in x0.
+pub fn cpu_on(mpidr: u64, entry: u64, context: u64) -> Result<(), PsciError> {
+    PsciError::from_code(unsafe { call(CPU_ON_64, mpidr, entry, context) }).map(|_| ())
+}
+
+/// Returns the power state of the CPU with affinity `mpidr`.
+pub fn affinity_info(mpidr: u64) -> Result<AffinityState, PsciError> {
+    match PsciError::from_code(unsafe { call(AFFINITY_INFO_64, mpidr, 0, 0) })? {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        code => Err(PsciError::Unknown(code)),
+    }
+}
+
+/// Powers the calling CPU off. Only returns if that was refused.
+pub fn cpu_off() -> PsciError {
+    match PsciError::from_code(unsafe { call(CPU_OFF, 0, 0, 0) }) {
+        Ok(code) => PsciError::Unknown(code),
+        Err(err) => err,
+    }
+}
@@ -0,0 +1,260 @@
+//! # TSC
+//! The time stamp counter increments at a fixed rate on CPUs with an invariant TSC,
+//! and with the core clock on older ones, which makes it unusable as a clocksource
+//! there. Its rate comes from the crystal ratio in CPUID when the CPU reports one,
+//! and is otherwise measured against the HPET or the PM timer.
+//!
+//! Firmware is expected to start every TSC in step; [`check_sync`] verifies it once
+//! the APs run, and drops the TSC as a clocksource if they disagree.
+
+use alloc::sync::Arc;
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, _rdtsc};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, Once};
+
+use super::{hpet, pm_timer};
+use crate::{
+    cpu::{self, Feature},
+    ipi,
+    percpu::{self, irq_restore, irq_save},
+    time::{self, Clocksource},
+};
+
+/// Length of one calibration round.
+const CALIBRATION_NS: u64 = 10_000_000;
+/// Calibration rounds, of which the median is kept.
+const CALIBRATION_ROUNDS: usize = 3;
+/// TSC cycles after which a reference counter that has not moved is given up on.
+const CALIBRATION_TIMEOUT: u64 = 10_000_000_000;
+
+/// Reads per CPU in the synchronization check.
+const SYNC_LOOPS: usize = 10_000;
+/// Spins waiting for the other CPUs to join the synchronization check.
+const SYNC_WAIT_SPINS: usize = 100_000_000;
+
+static FREQUENCY: Once<u64> = Once::new();
+
+/// Cleared when the TSCs of two CPUs were found to disagree.
+static SYNCED: AtomicBool = AtomicBool::new(true);
+
+/// Reads the counter. Not ordered against surrounding loads and stores.
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Reads the counter once every earlier instruction completed, with `rdtscp` where
+/// the CPU has it, as it orders the read without a separate fence.
+#[inline(always)]
+pub fn rdtsc_ordered() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+            crate::alternative!(feature: "rdtscp", then: ["rdtscp"], default: ["lfence", "rdtsc"]),
+            out("eax") low,
+            out("edx") high,
+            out("ecx") _,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    u64::from(high) << 32 | u64::from(low)
+}
+
+/// Returns the TSC frequency in Hz, once known.
+pub fn frequency() -> Option<u64> {
+    FREQUENCY.get().copied()
+}
+
+/// Returns whether the TSC counts at a constant rate in every P- and C-state.
+pub fn is_invariant() -> bool {
+    cpu::has(Feature::InvariantTsc)
+}
+
+/// Returns whether the TSCs of all CPUs were found to agree, or not checked yet.
+pub fn is_synced() -> bool {
+    SYNCED.load(Ordering::Relaxed)
+}
+
+/// Returns the frequency from the crystal ratio of CPUID leaf 0x15.
+fn crystal_frequency() -> Option<u64> {
+    if unsafe { __cpuid(0) }.eax < 0x15 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(0x15) };
+    // Crystal frequency in ecx, TSC to crystal ratio in ebx / eax
+    (leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0)
+        .then(|| u64::from(leaf.ecx) * u64::from(leaf.ebx) / u64::from(leaf.eax))
+}
+
+/// Returns the nominal base frequency of CPUID leaf 0x16, which is only approximate.
+fn base_frequency() -> Option<u64> {
+    if unsafe { __cpuid(0) }.eax < 0x16 {
+        return None;
+    }
+    let mhz = unsafe { __cpuid(0x16) }.eax & 0xFFFF;
+    (mhz != 0).then(|| u64::from(mhz) * 1_000_000)
+}
+
+/// Measures the TSC over one round against a reference counter.
+fn measure(read: &dyn Fn() -> u64, frequency: u64, mask: u64) -> Option<u64> {
+    let ticks = time::ns_to_cycles(CALIBRATION_NS, frequency);
+
+    let flags = irq_save();
+    // Start on a fresh tick of the reference, which may be much slower than the TSC
+    let first = read();
+    let mut start = first;
+    let mut tsc_start = rdtsc_ordered();
+    let deadline = tsc_start + CALIBRATION_TIMEOUT;
+    while start == first && tsc_start < deadline {
+        start = read();
+        tsc_start = rdtsc_ordered();
+    }
+    let mut elapsed = 0;
+    let mut tsc_end = tsc_start;
+    while elapsed < ticks && tsc_end < deadline {
+        elapsed = read().wrapping_sub(start) & mask;
+        tsc_end = rdtsc_ordered();
+    }
+    irq_restore(flags);
+
+    (elapsed >= ticks).then(|| {
+        (u128::from(tsc_end - tsc_start) * u128::from(frequency) / u128::from(elapsed)) as u64
+    })
+}
+
+/// Measures the TSC against the HPET, or else the PM timer.
+fn calibrate() -> Option<(u64, &'static str)> {
+    let (read, frequency, mask, name): (&dyn Fn() -> u64, u64, u64, &'static str) =
+        if let Some(hpet) = hpet::get() {
+            (&|| hpet.counter(), hpet.caps.frequency(), hpet.counter_mask(), "HPET")
+        } else if let Some(pm_timer) = pm_timer::get() {
+            (
+                &|| u64::from(pm_timer.read()),
+                pm_timer::PM_TIMER_FREQUENCY,
+                u64::from(pm_timer.mask()),
+                "PM timer",
+            )
+        } else {
+            return None;
+        };
+
+    let mut rounds = [0; CALIBRATION_ROUNDS];
+    for round in &mut rounds {
+        *round = measure(read, frequency, mask)?;
+    }
+    rounds.sort_unstable();
+    Some((rounds[CALIBRATION_ROUNDS / 2], name))
+}
+
+struct Tsc {
+    frequency: u64,
+}
+
+impl Clocksource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc_ordered()
+    }
+
+    fn is_stable(&self) -> bool {
+        is_invariant() && is_synced()
+    }
+}
+
+/// Finds the TSC frequency and registers the TSC as a clocksource. Called after the
+/// HPET and the PM timer were set up, which serve as references.
+pub fn init() {
+    if !cpu::has(Feature::Tsc) {
+        return;
+    }
+    let found = crystal_frequency()
+        .map(|frequency| (frequency, "CPUID"))
+        .or_else(calibrate)
+        .or_else(|| base_frequency().map(|frequency| (frequency, "nominal base frequency")));
+    let Some((frequency, source)) = found else {
+        log::warn!("TSC: unknown frequency");
+        return;
+    };
+
+    FREQUENCY.call_once(|| frequency);
+    log::info!(
+        "TSC: {}.{:03} MHz from {}{}",
+        frequency / 1_000_000,
+        frequency / 1_000 % 1_000,
+        source,
+        if is_invariant() { ", invariant" } else { "" }
+    );
+    time::register_clocksource(Arc::new(Tsc { frequency }));
+}
+
+/// Checks that the TSCs of all online CPUs agree, by having them read it in turns
+/// under a lock: a value below the one read last, on another CPU, means the two
+/// counters are apart. Unsynchronized TSCs stop being a clocksource.
+pub fn check_sync() -> bool {
+    let Some(frequency) = frequency() else {
+        return true;
+    };
+    let mut cpus = 0;
+    percpu::for_each_cpu(|cpu_id| cpus += usize::from(ipi::is_online(cpu_id)));
+    if cpus < 2 {
+        return true;
+    }
+
+    let last = Mutex::new(0u64);
+    let warp = AtomicU64::new(0);
+    let arrived = AtomicUsize::new(0);
+    ipi::call_on_all(|| {
+        // Overlap as much as possible with the others
+        arrived.fetch_add(1, Ordering::AcqRel);
+        for _ in 0..SYNC_WAIT_SPINS {
+            if arrived.load(Ordering::Acquire) >= cpus {
+                break;
+            }
+            spin_loop();
+        }
+        for _ in 0..SYNC_LOOPS {
+            let mut last = last.lock();
+            let now = rdtsc_ordered();
+            if now < *last {
+                warp.fetch_max(*last - now, Ordering::Relaxed);
+            }
+            *last = now;
+        }
+    });
+
+    let warp = warp.load(Ordering::Relaxed);
+    if warp == 0 {
+        log::info!("TSC: synchronized across {} CPUs", cpus);
+        return true;
+    }
+    log::warn!(
+        "TSC: CPUs are up to {} ns apart, not using it as a clocksource",
+        time::cycles_to_ns(warp, frequency)
+    );
+    SYNCED.store(false, Ordering::Relaxed);
+    time::unregister_clocksource("tsc");
+    false
+}
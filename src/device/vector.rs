@@ -0,0 +1,267 @@
+//! # Interrupt vectors
+//! Every local APIC has its own 256 IDT vectors, so an MSI is addressed to a CPU and
+//! a vector on that CPU. Exceptions take the first 32, legacy ISA IRQs the next 16,
+//! the IPI has [`IPI_VECTOR`] and the local APIC keeps everything from
+//! [`SYSTEM_VECTOR_BASE`] for its timer, errors and spurious interrupts.
+//!
+//! The IDT stubs of the remaining vectors call [`handle`] after saving registers;
+//! the local APIC is acknowledged by the stub once it returns. A vector firing
+//! without a handler, or with one not claiming it, [`UNHANDLED_LIMIT`] times in a
+//! row has the I/O APIC entries delivering it masked, and its [`MaskSource`] if
+//! messages raise it.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+
+use super::ioapic::{self, ISA_IRQ_COUNT, IRQ_VECTOR_BASE};
+use crate::{
+    dtb::irqchip::{InterruptHandler, IrqReturn, UNHANDLED_LIMIT},
+    ipi::{self, IPI_VECTOR},
+    percpu::{self, irq_restore, irq_save},
+};
+
+/// First vector handed out to devices.
+pub const DEVICE_VECTOR_BASE: u8 = IRQ_VECTOR_BASE + ISA_IRQ_COUNT;
+
+/// First vector kept for local APIC interrupts.
+pub const SYSTEM_VECTOR_BASE: u8 = 0xF0;
+
+const DEVICE_VECTORS: usize = (SYSTEM_VECTOR_BASE - DEVICE_VECTOR_BASE) as usize;
+
+/// A message sender raising a device vector, such as an MSI vector or MSI-X entry,
+/// which is masked when the vector fires unhandled. I/O APIC entries are found by
+/// their vector instead.
+pub trait MaskSource: Send {
+    /// Masks the sender, returning false if it cannot be masked.
+    fn mask(&self) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VectorError {
+    /// The CPU has no per-CPU area yet.
+    UnknownCpu,
+    /// No free, suitably aligned block of vectors is left on the CPU.
+    Exhausted,
+    /// The vector is not a device vector, or it is not allocated.
+    InvalidVector,
+}
+
+crate::percpu! {
+    /// Allocated vectors of the CPU, one bit each, starting with the reserved ones.
+    static USED: Mutex<[u64; 4]> = Mutex::new(reserved_mask());
+    /// Handler of each device vector.
+    static HANDLERS: [Mutex<Option<Box<dyn InterruptHandler>>>; DEVICE_VECTORS] =
+        [const { Mutex::new(None) }; DEVICE_VECTORS];
+    /// Unhandled interrupts in a row on each device vector.
+    static UNHANDLED: [AtomicU32; DEVICE_VECTORS] = [const { AtomicU32::new(0) }; DEVICE_VECTORS];
+    /// Message sender of each device vector, if it has one.
+    static SOURCES: [Mutex<Option<Box<dyn MaskSource>>>; DEVICE_VECTORS] =
+        [const { Mutex::new(None) }; DEVICE_VECTORS];
+}
+
+const fn reserved_mask() -> [u64; 4] {
+    let mut mask = [0; 4];
+    let mut vector = 0;
+    while vector < 256 {
+        if vector < DEVICE_VECTOR_BASE as usize || vector >= SYSTEM_VECTOR_BASE as usize || vector == IPI_VECTOR as usize {
+            mask[vector / 64] |= 1 << (vector % 64);
+        }
+        vector += 1;
+    }
+    mask
+}
+
+#[inline(always)]
+fn is_used(used: &[u64; 4], vector: usize) -> bool {
+    used[vector / 64] & (1 << (vector % 64)) != 0
+}
+
+#[inline(always)]
+fn handler_slot(cpu_id: usize, vector: u8) -> Result<&'static Mutex<Option<Box<dyn InterruptHandler>>>, VectorError> {
+    let index = vector.checked_sub(DEVICE_VECTOR_BASE).ok_or(VectorError::InvalidVector)?;
+    HANDLERS
+        .get_for(cpu_id)
+        .ok_or(VectorError::UnknownCpu)?
+        .get(usize::from(index))
+        .ok_or(VectorError::InvalidVector)
+}
+
+#[inline(always)]
+fn source_slot(cpu_id: usize, vector: u8) -> Result<&'static Mutex<Option<Box<dyn MaskSource>>>, VectorError> {
+    let index = vector.checked_sub(DEVICE_VECTOR_BASE).ok_or(VectorError::InvalidVector)?;
+    SOURCES
+        .get_for(cpu_id)
+        .ok_or(VectorError::UnknownCpu)?
+        .get(usize::from(index))
+        .ok_or(VectorError::InvalidVector)
+}
+
+/// Allocates `count` consecutive vectors on `cpu_id`, aligned to `count`, which must
+/// be a power of two, as multiple-message MSI only varies the low bits of the
+/// vector. Returns the first one.
+///
+/// Higher vectors are tried first: the local APIC prioritizes by the upper four
+/// bits, and device interrupts should not starve the legacy ones below them.
+pub fn allocate(cpu_id: usize, count: usize) -> Result<u8, VectorError> {
+    assert!(count.is_power_of_two() && count <= 32, "invalid vector count {}", count);
+    let used = USED.get_for(cpu_id).ok_or(VectorError::UnknownCpu)?;
+
+    let flags = irq_save();
+    let mut used = used.lock();
+    let first = (usize::from(DEVICE_VECTOR_BASE)..usize::from(SYSTEM_VECTOR_BASE))
+        .rev()
+        .filter(|base| base % count == 0 && base + count <= usize::from(SYSTEM_VECTOR_BASE))
+        .find(|&base| (base..base + count).all(|vector| !is_used(&used, vector)));
+    if let Some(base) = first {
+        for vector in base..base + count {
+            used[vector / 64] |= 1 << (vector % 64);
+        }
+    }
+    drop(used);
+    irq_restore(flags);
+
+    first.map(|base| base as u8).ok_or(VectorError::Exhausted)
+}
+
+/// Releases `count` vectors from `vector` on `cpu_id`, dropping their handlers and
+/// message senders.
+pub fn free(cpu_id: usize, vector: u8, count: usize) {
+    for vector in vector..vector.saturating_add(count as u8) {
+        drop(take_handler(cpu_id, vector));
+        drop(take_source(cpu_id, vector));
+    }
+
+    let Some(used) = USED.get_for(cpu_id) else {
+        return;
+    };
+    let flags = irq_save();
+    let mut used = used.lock();
+    for vector in usize::from(vector)..usize::from(vector) + count {
+        if (usize::from(DEVICE_VECTOR_BASE)..usize::from(SYSTEM_VECTOR_BASE)).contains(&vector)
+            && vector != usize::from(IPI_VECTOR)
+        {
+            used[vector / 64] &= !(1 << (vector % 64));
+        }
+    }
+    drop(used);
+    irq_restore(flags);
+}
+
+/// Installs the handler of an allocated `vector` on `cpu_id`, returning the previous one.
+pub fn set_handler(
+    cpu_id: usize,
+    vector: u8,
+    handler: Box<dyn InterruptHandler>,
+) -> Result<Option<Box<dyn InterruptHandler>>, VectorError> {
+    let used = USED.get_for(cpu_id).ok_or(VectorError::UnknownCpu)?;
+    if !is_used(&used.lock(), usize::from(vector)) {
+        return Err(VectorError::InvalidVector);
+    }
+    let slot = handler_slot(cpu_id, vector)?;
+
+    let flags = irq_save();
+    let previous = slot.lock().replace(handler);
+    irq_restore(flags);
+    if let Some(unhandled) = UNHANDLED.get_for(cpu_id) {
+        unhandled[usize::from(vector - DEVICE_VECTOR_BASE)].store(0, Ordering::Relaxed);
+    }
+    Ok(previous)
+}
+
+/// Removes the handler of `vector` on `cpu_id`, keeping the vector allocated.
+pub fn take_handler(cpu_id: usize, vector: u8) -> Option<Box<dyn InterruptHandler>> {
+    let slot = handler_slot(cpu_id, vector).ok()?;
+    let flags = irq_save();
+    let handler = slot.lock().take();
+    irq_restore(flags);
+    handler
+}
+
+/// Records the message sender of an allocated `vector` on `cpu_id`.
+pub fn set_source(cpu_id: usize, vector: u8, source: Box<dyn MaskSource>) -> Result<(), VectorError> {
+    let used = USED.get_for(cpu_id).ok_or(VectorError::UnknownCpu)?;
+    if !is_used(&used.lock(), usize::from(vector)) {
+        return Err(VectorError::InvalidVector);
+    }
+    let slot = source_slot(cpu_id, vector)?;
+
+    let flags = irq_save();
+    *slot.lock() = Some(source);
+    irq_restore(flags);
+    Ok(())
+}
+
+fn take_source(cpu_id: usize, vector: u8) -> Option<Box<dyn MaskSource>> {
+    let slot = source_slot(cpu_id, vector).ok()?;
+    let flags = irq_save();
+    let source = slot.lock().take();
+    irq_restore(flags);
+    source
+}
+
+/// Moves the handler and message sender of `vector` on `from` to the allocated
+/// vector `new` on `to`.
+pub fn move_vector(from: usize, vector: u8, to: usize, new: u8) {
+    if let Some(handler) = take_handler(from, vector) {
+        let _ = set_handler(to, new, handler);
+    }
+    if let Some(source) = take_source(from, vector) {
+        let _ = set_source(to, new, source);
+    }
+}
+
+/// Allocates a vector on `to` for an interrupt leaving `vector` on `from`, moving
+/// its handler and message sender over. The caller points the source at the new
+/// vector, then frees the old one; a message arriving on the old vector meanwhile
+/// is lost.
+pub fn reassign(from: usize, vector: u8, to: usize) -> Result<u8, VectorError> {
+    let new = allocate(to, 1)?;
+    move_vector(from, vector, to, new);
+    Ok(new)
+}
+
+/// Runs the handler of a device `vector` on the calling CPU, with interrupts
+/// disabled. Returns whether it handled the interrupt.
+pub fn handle(vector: u8) -> bool {
+    let cpu_id = percpu::cpu_id();
+    let Ok(slot) = handler_slot(cpu_id, vector) else {
+        return false;
+    };
+    let handled = match slot.lock().as_mut() {
+        Some(handler) => handler.irq_handler(usize::from(vector)) != IrqReturn::None,
+        None => false,
+    };
+    if let Some(unhandled) = UNHANDLED.get_for(cpu_id) {
+        count(cpu_id, vector, &unhandled[usize::from(vector - DEVICE_VECTOR_BASE)], handled);
+    }
+    handled
+}
+
+/// Tracks the unhandled streak of `vector`, masking its I/O APIC entries and its
+/// message sender once it reaches [`UNHANDLED_LIMIT`].
+fn count(cpu_id: usize, vector: u8, unhandled: &AtomicU32, handled: bool) {
+    if handled {
+        unhandled.store(0, Ordering::Relaxed);
+        return;
+    }
+    let streak = unhandled.fetch_add(1, Ordering::Relaxed) + 1;
+    if streak == 1 {
+        log::warn!("Unhandled interrupt on vector {:#x}", vector);
+    }
+    if streak == UNHANDLED_LIMIT {
+        let masked = ipi::hw_id(cpu_id).map_or(0, |apic_id| ioapic::mask_vector(apic_id as u32, vector));
+        let message = source_slot(cpu_id, vector)
+            .ok()
+            .and_then(|slot| slot.lock().as_ref().map(|source| source.mask()));
+        match message {
+            Some(true) => log::error!("Vector {:#x} fired {} times unhandled, masked its message sender", vector, streak),
+            Some(false) => log::error!("Vector {:#x} fired {} times unhandled, its sender cannot be masked", vector, streak),
+            None if masked > 0 => {
+                log::error!("Vector {:#x} fired {} times unhandled, masked {} I/O APIC entries", vector, streak, masked)
+            }
+            None => log::error!("Vector {:#x} fired {} times unhandled, from no known source", vector, streak),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! # x2APIC
+//! Decides whether the local APICs run in x2APIC mode, which is needed for APIC IDs
+//! of 255 and above, and sets up interrupt remapping so devices can reach them.
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::__cpuid;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::acpi::dmar::dmar;
+
+use super::{
+    intremap,
+    msr::{rdmsr, wrmsr, IA32_APIC_BASE},
+};
+
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Highest APIC ID a device can target without remapping; 0xFF is the xAPIC broadcast.
+pub const MAX_XAPIC_ID: u32 = 0xFE;
+
+/// Set once the BSP has switched, so every AP follows.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Checks CPUID.01H:ECX[21].
+pub fn cpu_supports_x2apic() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 21) != 0
+}
+
+/// Returns whether the calling CPU's local APIC is in x2APIC mode.
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_X2APIC != 0
+}
+
+/// Picks the local APIC mode on the BSP, before any AP is started.
+///
+/// x2APIC is used when the firmware already enabled it, when some APIC ID cannot be
+/// named in xAPIC mode, or otherwise when supported unless the DMAR opts out.
+/// Interrupt remapping is then set up with 32-bit destinations. Returns true if the
+/// BSP is in x2APIC mode afterwards.
+pub unsafe fn init(max_apic_id: u32) -> bool {
+    let firmware_enabled = is_enabled();
+    let opt_out = dmar().is_some_and(|dmar| dmar.x2apic_opt_out());
+    let needed = max_apic_id > MAX_XAPIC_ID;
+
+    let use_x2apic = firmware_enabled || (cpu_supports_x2apic() && (needed || !opt_out));
+    if !use_x2apic {
+        if needed {
+            log::warn!("  x2APIC: not supported, CPUs with APIC IDs above {} cannot be used", MAX_XAPIC_ID);
+        }
+        return false;
+    }
+
+    // Without remapping only the low 8 bits of a destination reach the I/O APICs and MSIs
+    if !intremap::init(true) {
+        if needed {
+            log::warn!(
+                "  x2APIC: no interrupt remapping, device interrupts can only target APIC IDs up to {}",
+                MAX_XAPIC_ID
+            );
+        } else if opt_out && !firmware_enabled {
+            return false;
+        }
+    }
+
+    enable_this_cpu();
+    log::info!(
+        "  x2APIC: {}{}",
+        if firmware_enabled { "enabled by firmware" } else { "enabled" },
+        if intremap::supports_x2apic() { ", with interrupt remapping" } else { "" }
+    );
+    true
+}
+
+/// Switches the calling CPU to x2APIC mode if the BSP did. APs call this before
+/// touching their local APIC.
+pub unsafe fn init_ap() {
+    if ENABLED.load(Ordering::Acquire) && !is_enabled() {
+        enable_this_cpu();
+    }
+}
+
+/// Goes from xAPIC to x2APIC mode; going back requires disabling the APIC first.
+unsafe fn enable_this_cpu() {
+    let base = rdmsr(IA32_APIC_BASE);
+    // Enabling x2APIC from the disabled state is invalid, so enable the APIC first
+    if base & APIC_BASE_ENABLE == 0 {
+        wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+    }
+    wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+    ENABLED.store(true, Ordering::Release);
+}
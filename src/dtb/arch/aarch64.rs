@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+
+use super::{fdt::Fdt, irq_of, DtCpu};
+use crate::{
+    acpi::madt::{self, ApMethod, ApTarget, Redistributors},
+    device::{
+        generic_timer::{self, TimerPpi, TimerPpis},
+        psci::{self, Conduit},
+    },
+    ipi,
+};
+
+const GIC_V2_COMPATIBLE: &[&str] = &[
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+    "arm,cortex-a7-gic",
+    "arm,arm11mp-gic",
+    "arm,pl390",
+];
+const GIC_V3_COMPATIBLE: &[&str] = &["arm,gic-v3"];
+const TIMER_COMPATIBLE: &[&str] = &["arm,armv8-timer", "arm,armv7-timer"];
+
+/// Indices of the timers in the timer node's `interrupts`, after the secure one.
+const TIMER_NON_SECURE_EL1: usize = 1;
+const TIMER_VIRTUAL_EL1: usize = 2;
+const TIMER_EL2: usize = 3;
+
+pub(super) fn init(fdt: &Fdt<'static>, cpus: &[DtCpu]) {
+    let bsp = cpus.iter().find(|cpu| madt::is_this_cpu(cpu.hw_id)).map_or(0, |cpu| cpu.cpu_id);
+    unsafe { crate::percpu::init(bsp) };
+
+    if !init_gic(fdt, cpus, bsp) {
+        log::warn!("No GIC found in the device tree");
+        return;
+    }
+    init_timer(fdt);
+
+    if cfg!(feature = "multi_core") {
+        start_aps(fdt, cpus, bsp);
+    }
+}
+
+fn init_gic(fdt: &Fdt<'static>, cpus: &[DtCpu], bsp: usize) -> bool {
+    let (node, version) = match fdt.find_compatible(GIC_V3_COMPATIBLE).next() {
+        Some(node) => (node, 3),
+        None => match fdt.find_compatible(GIC_V2_COMPATIBLE).next() {
+            Some(node) => (node, 2),
+            None => return false,
+        },
+    };
+    let regs: Vec<_> = fdt.reg(&node).collect();
+    let Some(&(dist, _)) = regs.first() else {
+        log::warn!("GIC {} has no distributor address", node.name);
+        return false;
+    };
+
+    // Before GICv3, SGIs target CPU interface numbers, which the device tree lacks.
+    // Aff0 of the CPU's `reg` matches on single-cluster systems, and every CPU
+    // replaces it with the number its GIC reports once it runs
+    for cpu in cpus {
+        let hw_id = if version < 3 { cpu.hw_id & 0xFF } else { cpu.hw_id };
+        ipi::register_cpu(cpu.cpu_id, hw_id);
+    }
+    ipi::set_online(bsp, true);
+
+    if version < 3 {
+        let Some(&(cpu_if, _)) = regs.get(1) else {
+            log::warn!("GIC {} has no CPU interface address", node.name);
+            return false;
+        };
+        madt::init_gic_v1_v2(dist, cpu_if, node.phandle());
+    } else {
+        let count = node.property("#redistributor-regions").and_then(|prop| prop.as_u32()).unwrap_or(1) as usize;
+        let regions: Vec<_> = regs.iter().skip(1).take(count).copied().collect();
+        let affinities: Vec<_> = cpus.iter().map(|cpu| (cpu.cpu_id, cpu.hw_id)).collect();
+        madt::init_gic_v3(dist, Redistributors::Regions(&regions, &affinities), bsp, node.phandle());
+    }
+    true
+}
+
+fn init_timer(fdt: &Fdt<'static>) {
+    let Some(node) = fdt.find_compatible(TIMER_COMPATIBLE).next() else {
+        log::warn!("No architected timer found in the device tree");
+        return;
+    };
+    if generic_timer::frequency() == 0 {
+        match node.property("clock-frequency").and_then(|prop| prop.as_u32()) {
+            Some(frequency) => generic_timer::set_fallback_frequency(u64::from(frequency)),
+            None => log::warn!("generic_timer: CNTFRQ_EL0 is not set and there is no clock-frequency"),
+        }
+    }
+
+    let always_on = node.property("always-on").is_some();
+    let ppi = |index| match irq_of(fdt, &node, index) {
+        Ok(virq) => Some(TimerPpi { virq, always_on }),
+        Err(err) => {
+            log::debug!("generic_timer interrupt {} not mapped: {:?}", index, err);
+            None
+        }
+    };
+    let psci_hvc = fdt
+        .find_node("/psci")
+        .and_then(|psci| psci.property("method"))
+        .and_then(|prop| prop.as_str())
+        == Some("hvc");
+    generic_timer::init(TimerPpis {
+        physical: ppi(TIMER_NON_SECURE_EL1),
+        virt: ppi(TIMER_VIRTUAL_EL1),
+        hyp: ppi(TIMER_EL2),
+        hypervisor: psci_hvc || fdt.find_node("/hypervisor").is_some(),
+    });
+}
+
+fn start_aps(fdt: &Fdt<'static>, cpus: &[DtCpu], bsp: usize) {
+    if let Some(node) = fdt.find_node("/psci").filter(|node| node.is_enabled()) {
+        match node.property("method").and_then(|prop| prop.as_str()) {
+            Some("hvc") => psci::init_conduit(Conduit::Hvc),
+            Some("smc") => psci::init_conduit(Conduit::Smc),
+            method => log::warn!("Unknown PSCI method {:?}", method),
+        }
+    }
+
+    let targets: Vec<_> = cpus
+        .iter()
+        .filter(|cpu| cpu.cpu_id != bsp)
+        .filter_map(|cpu| {
+            let method = match cpu.node.property("enable-method").and_then(|prop| prop.as_str()) {
+                Some("psci") if psci::is_available() => ApMethod::Psci,
+                Some("spin-table") => ApMethod::SpinTable {
+                    release_addr: cpu.node.property("cpu-release-addr")?.as_u64()?,
+                },
+                method => {
+                    log::error!("AP startup failed: CPU {}: unsupported enable-method {:?}", cpu.cpu_id, method);
+                    return None;
+                }
+            };
+            Some(ApTarget {
+                cpu_id: cpu.cpu_id,
+                mpidr: cpu.hw_id,
+                method,
+            })
+        })
+        .collect();
+    madt::start_aps(&targets);
+}
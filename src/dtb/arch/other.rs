@@ -0,0 +1,5 @@
+use super::{fdt::Fdt, DtCpu};
+
+pub(super) fn init(_fdt: &Fdt<'static>, cpus: &[DtCpu]) {
+    log::warn!("Device tree boot not handled on this platform, {} CPUs ignored", cpus.len());
+}
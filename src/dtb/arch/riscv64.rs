@@ -0,0 +1,276 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use spin::Once;
+
+use super::{
+    fdt::Fdt,
+    irqchip::{IrqChip, IrqChipItem, IrqError, IrqSpec, Parent, Polarity, Trigger, IRQ_CHIP},
+    register_irq_chip_driver, DtCpu,
+};
+use crate::{
+    device::{aplic, plic},
+    ipi,
+    memory::{map_device_memory, PhysicalAddress, PAGE_SIZE},
+    percpu::{irq_restore, irq_save},
+};
+
+/// Indirectly accessed registers of the supervisor interrupt file, selected through
+/// `siselect` (CSR 0x150) and accessed through `sireg` (CSR 0x151).
+const ISELECT_EIDELIVERY: usize = 0x70;
+const ISELECT_EITHRESHOLD: usize = 0x72;
+/// First of the enable registers; only even ones exist on RV64, 64 identities each.
+const ISELECT_EIE0: usize = 0xC0;
+
+const SIE_SSIE: usize = 1 << 1;
+const SIE_SEIE: usize = 1 << 9;
+const SIP_SSIP: usize = 1 << 1;
+
+static TIMEBASE_FREQUENCY: Once<u64> = Once::new();
+
+/// Interrupt file of each CPU in the IMSIC, by CPU ID, which APLICs name as the hart index.
+static HART_INDEX: Once<Vec<Option<u32>>> = Once::new();
+/// Highest IMSIC identity, once an IMSIC is found.
+static IMSIC_IDS: Once<u32> = Once::new();
+/// Next IMSIC identity to hand out; those up to the IPI's are taken.
+static NEXT_IDENTITY: AtomicU32 = AtomicU32::new(ipi::IPI_IDENTITY + 1);
+
+/// Returns the frequency of the `time` CSR in Hz, from `/cpus/timebase-frequency`.
+pub fn timebase_frequency() -> Option<u64> {
+    TIMEBASE_FREQUENCY.get().copied()
+}
+
+/// Returns the IMSIC interrupt file of `cpu_id`.
+pub fn imsic_hart_index(cpu_id: usize) -> Option<u32> {
+    HART_INDEX.get()?.get(cpu_id).copied().flatten()
+}
+
+/// Takes an IMSIC identity for a stacked domain. Identities are never given back.
+pub fn allocate_imsic_identity() -> Option<u32> {
+    let &ids = IMSIC_IDS.get()?;
+    NEXT_IDENTITY
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| (id <= ids).then_some(id + 1))
+        .ok()
+}
+
+pub(super) fn init(fdt: &Fdt<'static>, cpus: &[DtCpu]) {
+    let timebase = fdt
+        .find_node("/cpus")
+        .and_then(|node| node.property("timebase-frequency"))
+        .and_then(|prop| prop.as_u64());
+    match timebase {
+        Some(frequency) => {
+            TIMEBASE_FREQUENCY.call_once(|| frequency);
+            log::info!("  timebase {} Hz", frequency);
+        }
+        None => log::warn!("No timebase-frequency in the device tree"),
+    }
+
+    // OpenSBI passes the boot hart in the header as well as in a0
+    let boot_hart = u64::from(fdt.boot_cpuid());
+    for cpu in cpus {
+        ipi::register_cpu(cpu.cpu_id, cpu.hw_id);
+        if cpu.hw_id == boot_hart {
+            unsafe { crate::percpu::init(cpu.cpu_id) };
+            ipi::set_online(cpu.cpu_id, true);
+        }
+    }
+
+    init_ipi(fdt, cpus);
+
+    // Registered by `register_irq_chips` once the IMSIC is known
+    for &compatible in plic::COMPATIBLE {
+        register_irq_chip_driver(compatible, plic::probe);
+    }
+    register_irq_chip_driver(aplic::COMPATIBLE, aplic::probe);
+
+    if cfg!(feature = "multi_core") && cpus.len() > 1 {
+        log::warn!("Secondary harts are not started on riscv64 yet");
+    }
+}
+
+/// Registers the root domain IPIs arrive through: the IMSIC if there is one, and
+/// supervisor software interrupts raised by SBI otherwise.
+fn init_ipi(fdt: &Fdt<'static>, cpus: &[DtCpu]) {
+    let (item, hwirq) = match init_imsic(fdt, cpus) {
+        Some(item) => (item, ipi::IPI_IDENTITY),
+        None => (
+            IrqChipItem {
+                phandle: None,
+                gsi_base: None,
+                parent: Parent::Cpu,
+                ic: Box::new(SbiIpi),
+            },
+            0,
+        ),
+    };
+    match IRQ_CHIP.register(item) {
+        Ok(id) => ipi::init_irq(id, hwirq),
+        Err(err) => log::error!("Failed to register the IPI domain: {:?}", err),
+    }
+}
+
+/// Maps the supervisor IMSIC interrupt file of every hart, so IPIs can be sent
+/// without going through SBI, and returns the IMSIC as a root domain.
+fn init_imsic(fdt: &Fdt<'static>, cpus: &[DtCpu]) -> Option<IrqChipItem> {
+    let node = fdt.find_compatible(&["riscv,imsics"]).next()?;
+    let Some((base, size)) = fdt.reg(&node).next() else {
+        log::warn!("IMSIC {} has no address", node.name);
+        return None;
+    };
+    let virt = unsafe { map_device_memory(PhysicalAddress::new(base as usize), size as usize) }.data();
+
+    // Files follow the order of `interrupts-extended`, one page each, which points
+    // at the local interrupt controller of each hart
+    let mut hart_index = Vec::new();
+    for (i, irq) in fdt.interrupts(&node).enumerate() {
+        if (i + 1) * PAGE_SIZE > size as usize {
+            break;
+        }
+        let Some(hart) = fdt.parent(&irq.controller) else {
+            continue;
+        };
+        if let Some(cpu) = cpus.iter().find(|cpu| cpu.node.name == hart.name) {
+            ipi::set_imsic_file(cpu.cpu_id, virt + i * PAGE_SIZE);
+            if hart_index.len() <= cpu.cpu_id {
+                hart_index.resize(cpu.cpu_id + 1, None);
+            }
+            hart_index[cpu.cpu_id] = Some(i as u32);
+        }
+    }
+    HART_INDEX.call_once(|| hart_index);
+
+    let ids = node.property("riscv,num-ids").and_then(|prop| prop.as_u32()).unwrap_or(63);
+    IMSIC_IDS.call_once(|| ids);
+    let imsic = Imsic {
+        ids,
+        enabled: (0..ids as usize / 64 + 1).map(|_| AtomicU64::new(0)).collect(),
+    };
+    unsafe { imsic.init_cpu_file() };
+    Some(IrqChipItem {
+        phandle: node.phandle(),
+        gsi_base: None,
+        parent: Parent::Cpu,
+        ic: Box::new(imsic),
+    })
+}
+
+/// Writes `value` to the interrupt file register `select` of the calling hart.
+unsafe fn write_ireg(select: usize, value: usize) {
+    let flags = irq_save();
+    asm!("csrw 0x150, {}", "csrw 0x151, {}", in(reg) select, in(reg) value, options(nostack));
+    irq_restore(flags);
+}
+
+/// The supervisor IMSIC interrupt files, driven through the CSRs of the calling hart.
+///
+/// Identities are enabled per hart, so the ones enabled so far are kept and every
+/// hart enables them as well in `init_cpu`.
+struct Imsic {
+    /// Highest identity; zero means no interrupt.
+    ids: u32,
+    /// Enabled identities, one bit each.
+    enabled: Vec<AtomicU64>,
+}
+
+impl Imsic {
+    /// Turns interrupt delivery from the calling hart's file on and enables the
+    /// identities enabled so far.
+    unsafe fn init_cpu_file(&self) {
+        write_ireg(ISELECT_EIDELIVERY, 1);
+        write_ireg(ISELECT_EITHRESHOLD, 0);
+        for (i, enabled) in self.enabled.iter().enumerate() {
+            write_ireg(ISELECT_EIE0 + i * 2, enabled.load(Ordering::Relaxed) as usize);
+        }
+        asm!("csrs sie, {}", in(reg) SIE_SEIE, options(nostack));
+    }
+
+    fn set_enabled(&self, hwirq: u32, enable: bool) {
+        let Some(enabled) = self.enabled.get(hwirq as usize / 64) else {
+            return;
+        };
+        let bit = 1 << (hwirq % 64);
+        let mask = if enable {
+            enabled.fetch_or(bit, Ordering::Relaxed) | bit
+        } else {
+            enabled.fetch_and(!bit, Ordering::Relaxed) & !bit
+        };
+        unsafe { write_ireg(ISELECT_EIE0 + hwirq as usize / 64 * 2, mask as usize) };
+    }
+}
+
+impl IrqChip for Imsic {
+    fn name(&self) -> &str {
+        "imsic"
+    }
+
+    fn hwirq_count(&self) -> u32 {
+        self.ids + 1
+    }
+
+    fn xlate(&self, spec: &[u32]) -> Result<IrqSpec, IrqError> {
+        let &hwirq = spec.first().ok_or(IrqError::InvalidSpecifier)?;
+        Ok(IrqSpec::new(hwirq, Trigger::Edge, Polarity::High))
+    }
+
+    fn enable(&self, hwirq: u32) {
+        self.set_enabled(hwirq, true);
+    }
+
+    fn disable(&self, hwirq: u32) {
+        self.set_enabled(hwirq, false);
+    }
+
+    /// Claims the top pending identity through `stopei` (CSR 0x15C).
+    fn ack(&self) -> Option<u32> {
+        let topei: usize;
+        unsafe { asm!("csrrw {}, 0x15C, zero", out(reg) topei, options(nostack)) };
+        match (topei >> 16) as u32 {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    fn eoi(&self, _hwirq: u32) {}
+
+    fn init_cpu(&self) {
+        unsafe { self.init_cpu_file() };
+    }
+}
+
+/// Supervisor software interrupts, which SBI IPIs raise on harts without an IMSIC.
+struct SbiIpi;
+
+impl IrqChip for SbiIpi {
+    fn name(&self) -> &str {
+        "sbi-ipi"
+    }
+
+    fn hwirq_count(&self) -> u32 {
+        1
+    }
+
+    fn xlate(&self, _spec: &[u32]) -> Result<IrqSpec, IrqError> {
+        Err(IrqError::InvalidSpecifier)
+    }
+
+    fn enable(&self, _hwirq: u32) {
+        unsafe { asm!("csrs sie, {}", in(reg) SIE_SSIE, options(nostack)) };
+    }
+
+    fn disable(&self, _hwirq: u32) {
+        unsafe { asm!("csrc sie, {}", in(reg) SIE_SSIE, options(nostack)) };
+    }
+
+    /// Clears `sip.SSIP` before the IPI is handled, so a later one raises it again.
+    fn ack(&self) -> Option<u32> {
+        let sip: usize;
+        unsafe { asm!("csrrc {}, sip, {}", out(reg) sip, in(reg) SIP_SSIP, options(nostack)) };
+        (sip & SIP_SSIP != 0).then_some(0)
+    }
+
+    fn eoi(&self, _hwirq: u32) {}
+}
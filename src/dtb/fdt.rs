@@ -0,0 +1,764 @@
+//! # Flattened device tree
+//! Zero-copy parser of a DTB as handed over by firmware. Nodes and properties borrow
+//! from the blob; nothing is allocated.
+
+use core::{fmt, slice, str};
+
+pub const FDT_MAGIC: u32 = 0xD00D_FEED;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Version 17 is the last incompatible one, and the only one written today.
+const FDT_COMPAT_VERSION: u32 = 17;
+const HEADER_SIZE: usize = 40;
+
+/// Deepest node handled when walking up to the root.
+const MAX_DEPTH: usize = 16;
+/// Most cells of an interrupt specifier or unit address handled.
+pub const MAX_CELLS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    BadVersion(u32),
+    /// A block lies outside of `totalsize`.
+    Truncated,
+}
+
+/// A validated device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    rsvmap: usize,
+    boot_cpuid: u32,
+}
+
+#[inline(always)]
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[inline(always)]
+fn be64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from(be32(bytes, offset)?) << 32 | u64::from(be32(bytes, offset + 4)?))
+}
+
+/// Reads a value of `cells` cells, as addresses and sizes are stored.
+fn read_cells(bytes: &[u8], offset: usize, cells: u32) -> Option<u64> {
+    match cells {
+        0 => Some(0),
+        1 => be32(bytes, offset).map(u64::from),
+        2 => be64(bytes, offset),
+        // Wider values are only used for PCI, whose top cell holds flags
+        _ => be64(bytes, offset + (cells as usize - 2) * 4),
+    }
+}
+
+#[inline(always)]
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn cstr(bytes: &[u8], offset: usize) -> Option<&str> {
+    let bytes = bytes.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    str::from_utf8(&bytes[..len]).ok()
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        let header = |i: usize| be32(data, i * 4).ok_or(FdtError::Truncated);
+        if header(0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = header(1)? as usize;
+        let data = data.get(..total_size).ok_or(FdtError::Truncated)?;
+        if total_size < HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        let (off_struct, off_strings, off_rsvmap) = (header(2)? as usize, header(3)? as usize, header(4)? as usize);
+        let (version, last_comp_version) = (header(5)?, header(6)?);
+        if version < FDT_COMPAT_VERSION || last_comp_version > FDT_COMPAT_VERSION {
+            return Err(FdtError::BadVersion(version));
+        }
+        let (size_strings, size_struct) = (header(8)? as usize, header(9)? as usize);
+
+        Ok(Self {
+            data,
+            structs: data.get(off_struct..off_struct + size_struct).ok_or(FdtError::Truncated)?,
+            strings: data.get(off_strings..off_strings + size_strings).ok_or(FdtError::Truncated)?,
+            rsvmap: off_rsvmap,
+            boot_cpuid: header(7)?,
+        })
+    }
+
+    /// Validates the blob at `ptr`, whose length is read from its header.
+    ///
+    /// # Safety
+    /// `ptr` must point to readable memory holding a device tree blob that outlives `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        let header = slice::from_raw_parts(ptr, 8);
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(header, 4).unwrap_or(0) as usize;
+        Self::new(slice::from_raw_parts(ptr, total_size))
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Physical ID of the boot CPU, as given by some bootloaders.
+    pub fn boot_cpuid(&self) -> u32 {
+        self.boot_cpuid
+    }
+
+    /// Returns the memory reservation block: regions the kernel must not use.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let data = self.data;
+        let mut offset = self.rsvmap;
+        core::iter::from_fn(move || {
+            let (address, size) = (be64(data, offset)?, be64(data, offset + 8)?);
+            offset += 16;
+            (address != 0 || size != 0).then_some((address, size))
+        })
+    }
+
+    fn token(&self, offset: usize) -> Option<u32> {
+        be32(self.structs, offset)
+    }
+
+    /// Decodes the node whose `FDT_BEGIN_NODE` token is at `offset`.
+    fn node_at(&self, offset: usize) -> Option<Node<'a>> {
+        if self.token(offset)? != FDT_BEGIN_NODE {
+            return None;
+        }
+        let name = cstr(self.structs, offset + 4)?;
+        Some(Node {
+            fdt: *self,
+            offset,
+            name,
+            body: align4(offset + 4 + name.len() + 1),
+        })
+    }
+
+    /// Returns the offset after the token at `offset` and its payload, with its kind.
+    fn skip(&self, offset: usize) -> Option<(u32, usize)> {
+        let token = self.token(offset)?;
+        let next = match token {
+            FDT_BEGIN_NODE => align4(offset + 4 + cstr(self.structs, offset + 4)?.len() + 1),
+            FDT_PROP => align4(offset + 12 + self.token(offset + 4)? as usize),
+            FDT_END_NODE | FDT_NOP | FDT_END => offset + 4,
+            _ => return None,
+        };
+        Some((token, next))
+    }
+
+    pub fn root(&self) -> Option<Node<'a>> {
+        let mut offset = 0;
+        while self.token(offset)? == FDT_NOP {
+            offset += 4;
+        }
+        self.node_at(offset)
+    }
+
+    /// Iterates over every node, depth first.
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let fdt = *self;
+        let mut offset = Some(0);
+        core::iter::from_fn(move || {
+            loop {
+                let (token, next) = fdt.skip(offset?)?;
+                let current = offset.replace(next)?;
+                match token {
+                    FDT_BEGIN_NODE => return fdt.node_at(current),
+                    FDT_END => offset = None,
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    /// Finds a node by its absolute path. Components without a unit address also
+    /// match nodes that have one, so `/cpus/cpu` finds `/cpus/cpu@0`.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut node = self.root()?;
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            node = node.children().find(|child| {
+                child.name == component
+                    || (!component.contains('@') && child.name.split('@').next() == Some(component))
+            })?;
+        }
+        Some(node)
+    }
+
+    /// Follows an alias from `/aliases`, or returns the node if given a path.
+    pub fn resolve_path(&self, path: &str) -> Option<Node<'a>> {
+        if path.starts_with('/') {
+            return self.find_node(path);
+        }
+        let alias = self.find_node("/aliases")?.property(path)?.as_str()?;
+        self.find_node(alias)
+    }
+
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// Iterates over the enabled nodes compatible with any of `compatibles`.
+    pub fn find_compatible<'c>(&self, compatibles: &'c [&'c str]) -> impl Iterator<Item = Node<'a>> + 'c
+    where
+        'a: 'c,
+    {
+        self.nodes()
+            .filter(move |node| node.is_enabled() && compatibles.iter().any(|compatible| node.is_compatible(compatible)))
+    }
+
+    pub fn chosen(&self) -> Option<Node<'a>> {
+        self.find_node("/chosen")
+    }
+
+    /// Returns the parent of `node`, or `None` for the root.
+    pub fn parent(&self, node: &Node<'a>) -> Option<Node<'a>> {
+        let ancestors = self.ancestors(node)?;
+        ancestors.last().copied()
+    }
+
+    /// Returns the path from the root to the parent of `node`.
+    fn ancestors(&self, node: &Node<'a>) -> Option<Ancestors<'a>> {
+        let mut stack = Ancestors { nodes: [None; MAX_DEPTH], len: 0 };
+        let mut offset = 0;
+        loop {
+            if offset == node.offset {
+                return Some(stack);
+            }
+            let (token, next) = self.skip(offset)?;
+            match token {
+                FDT_BEGIN_NODE => {
+                    if stack.len == MAX_DEPTH {
+                        return None;
+                    }
+                    stack.nodes[stack.len] = self.node_at(offset);
+                    stack.len += 1;
+                }
+                FDT_END_NODE => stack.len = stack.len.checked_sub(1)?,
+                FDT_END => return None,
+                _ => {}
+            }
+            offset = next;
+        }
+    }
+
+    /// Returns the `reg` entries of `node` as CPU physical addresses, translated
+    /// through the `ranges` of every bus above it. Entries outside of any range are
+    /// skipped.
+    pub fn reg(&self, node: &Node<'a>) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let fdt = *self;
+        let ancestors = self.ancestors(node);
+        let (address_cells, size_cells) = ancestors
+            .and_then(|ancestors| ancestors.last().copied())
+            .map_or((2, 1), |parent| (parent.address_cells(), parent.size_cells()));
+        let value = node.property("reg").map_or(&[][..], |reg| reg.value);
+        let stride = (address_cells + size_cells) as usize * 4;
+
+        (0..value.len().checked_div(stride).unwrap_or(0)).filter_map(move |i| {
+            let address = read_cells(value, i * stride, address_cells)?;
+            let size = read_cells(value, i * stride + address_cells as usize * 4, size_cells)?;
+            Some((fdt.translate(&ancestors?, address)?, size))
+        })
+    }
+
+    /// Translates a bus address of a child of the innermost ancestor to the root.
+    fn translate(&self, ancestors: &Ancestors<'a>, mut address: u64) -> Option<u64> {
+        // The root has no `ranges`; its children already use CPU addresses
+        for level in (1..ancestors.len).rev() {
+            let bus = ancestors.nodes[level]?;
+            let parent = ancestors.nodes[level - 1]?;
+            let ranges = bus.property("ranges")?.value;
+            if ranges.is_empty() {
+                continue;
+            }
+
+            let (child_cells, parent_cells, size_cells) = (bus.address_cells(), parent.address_cells(), bus.size_cells());
+            let stride = (child_cells + parent_cells + size_cells) as usize * 4;
+            address = (0..ranges.len() / stride).find_map(|i| {
+                let child = read_cells(ranges, i * stride, child_cells)?;
+                let parent = read_cells(ranges, i * stride + child_cells as usize * 4, parent_cells)?;
+                let size = read_cells(ranges, i * stride + (child_cells + parent_cells) as usize * 4, size_cells)?;
+                (address >= child && address - child < size).then(|| parent + (address - child))
+            })?;
+        }
+        Some(address)
+    }
+
+    /// Returns the interrupt parent of `node`: the node its `interrupt-parent` points
+    /// to, or else its tree parent, repeated until reaching a node with
+    /// `#interrupt-cells`.
+    pub fn interrupt_parent(&self, node: &Node<'a>) -> Option<Node<'a>> {
+        let mut node = *node;
+        for _ in 0..MAX_DEPTH {
+            node = match node.property("interrupt-parent").and_then(|prop| prop.as_u32()) {
+                Some(phandle) => self.find_phandle(phandle)?,
+                None => self.parent(&node)?,
+            };
+            if node.interrupt_cells().is_some() {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// Returns the interrupts of `node`, from `interrupts-extended` or `interrupts`,
+    /// routed through any `interrupt-map` on the way to their controller.
+    pub fn interrupts(&self, node: &Node<'a>) -> impl Iterator<Item = Interrupt<'a>> + 'a {
+        let fdt = *self;
+        let unit_address = node.property("reg").map_or(&[][..], |reg| reg.value);
+        let (extended, value, parent) = match node.property("interrupts-extended") {
+            Some(prop) => (true, prop.value, None),
+            None => (false, node.property("interrupts").map_or(&[][..], |prop| prop.value), self.interrupt_parent(node)),
+        };
+
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            let controller = if extended {
+                let controller = fdt.find_phandle(be32(value, offset)?)?;
+                offset += 4;
+                controller
+            } else {
+                parent?
+            };
+            let cells = controller.interrupt_cells()? as usize * 4;
+            let specifier = value.get(offset..offset + cells)?;
+            offset += cells;
+            fdt.route(controller, unit_address, specifier)
+        })
+    }
+
+    /// Follows `interrupt-map`s from `nexus` until reaching an interrupt controller.
+    fn route(&self, mut nexus: Node<'a>, mut unit_address: &'a [u8], mut specifier: &'a [u8]) -> Option<Interrupt<'a>> {
+        for _ in 0..MAX_DEPTH {
+            if nexus.property("interrupt-controller").is_some() {
+                return Some(Interrupt { controller: nexus, specifier: Cells(specifier) });
+            }
+            (nexus, unit_address, specifier) = self.map_interrupt(&nexus, unit_address, specifier)?;
+        }
+        None
+    }
+
+    /// Looks up a child interrupt in the `interrupt-map` of `nexus`, returning the
+    /// parent, its unit address and the specifier in its domain.
+    fn map_interrupt(
+        &self,
+        nexus: &Node<'a>,
+        unit_address: &[u8],
+        specifier: &[u8],
+    ) -> Option<(Node<'a>, &'a [u8], &'a [u8])> {
+        let map = nexus.property("interrupt-map")?.value;
+        let mask = nexus.property("interrupt-map-mask").map(|prop| prop.value);
+        let address_len = nexus.address_cells() as usize * 4;
+        let specifier_len = nexus.interrupt_cells()? as usize * 4;
+        let child_len = address_len + specifier_len;
+
+        let mut child = [0u32; 2 * MAX_CELLS];
+        if child_len > child.len() * 4 {
+            return None;
+        }
+        for (i, cell) in child.iter_mut().enumerate().take(child_len / 4) {
+            let (bytes, offset) = if i * 4 < address_len { (unit_address, i * 4) } else { (specifier, i * 4 - address_len) };
+            *cell = be32(bytes, offset).unwrap_or(0) & mask.and_then(|mask| be32(mask, i * 4)).unwrap_or(!0);
+        }
+
+        let mut offset = 0;
+        while offset + child_len < map.len() {
+            let matches = (0..child_len / 4).all(|i| be32(map, offset + i * 4) == Some(child[i]));
+            let parent = self.find_phandle(be32(map, offset + child_len)?)?;
+            let parent_address_len = parent.property("#address-cells").and_then(|prop| prop.as_u32()).unwrap_or(0) as usize * 4;
+            let parent_specifier_len = parent.interrupt_cells()? as usize * 4;
+
+            let parent_address = offset + child_len + 4;
+            let parent_specifier = parent_address + parent_address_len;
+            let next = parent_specifier + parent_specifier_len;
+            if matches {
+                return Some((
+                    parent,
+                    map.get(parent_address..parent_specifier)?,
+                    map.get(parent_specifier..next)?,
+                ));
+            }
+            offset = next;
+        }
+        None
+    }
+}
+
+impl fmt::Debug for Fdt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fdt")
+            .field("total_size", &self.data.len())
+            .field("boot_cpuid", &self.boot_cpuid)
+            .finish()
+    }
+}
+
+/// Nodes from the root down to the parent of a node.
+#[derive(Clone, Copy)]
+struct Ancestors<'a> {
+    nodes: [Option<Node<'a>>; MAX_DEPTH],
+    len: usize,
+}
+
+impl<'a> Ancestors<'a> {
+    fn last(&self) -> Option<&Node<'a>> {
+        self.nodes[..self.len].last()?.as_ref()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// Offset of the `FDT_BEGIN_NODE` token.
+    offset: usize,
+    /// Offset of the first property or child.
+    body: usize,
+    pub name: &'a str,
+}
+
+impl<'a> Node<'a> {
+    pub fn properties(&self) -> impl Iterator<Item = Property<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut offset = self.body;
+        core::iter::from_fn(move || {
+            loop {
+                match fdt.token(offset)? {
+                    FDT_PROP => {
+                        let len = fdt.token(offset + 4)? as usize;
+                        let name = cstr(fdt.strings, fdt.token(offset + 8)? as usize)?;
+                        let value = fdt.structs.get(offset + 12..offset + 12 + len)?;
+                        offset = align4(offset + 12 + len);
+                        return Some(Property { name, value });
+                    }
+                    FDT_NOP => offset += 4,
+                    _ => return None,
+                }
+            }
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let fdt = self.fdt;
+        let mut offset = Some(self.body);
+        let mut depth = 0usize;
+        core::iter::from_fn(move || {
+            loop {
+                let current = offset?;
+                let (token, next) = fdt.skip(current)?;
+                offset = Some(next);
+                match token {
+                    FDT_BEGIN_NODE => {
+                        depth += 1;
+                        if depth == 1 {
+                            return fdt.node_at(current);
+                        }
+                    }
+                    FDT_END_NODE if depth == 0 => offset = None,
+                    FDT_END_NODE => depth -= 1,
+                    FDT_END => offset = None,
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    /// Returns the part of the name after `@`.
+    pub fn unit_address(&self) -> Option<&'a str> {
+        self.name.split_once('@').map(|(_, unit)| unit)
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.property("compatible").into_iter().flat_map(|prop| prop.str_list())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// Nodes without `status` are enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.property("status")
+            .and_then(|prop| prop.as_str())
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))
+            .and_then(|prop| prop.as_u32())
+    }
+
+    /// Cells of an address in the `reg` of this node's children.
+    pub fn address_cells(&self) -> u32 {
+        self.property("#address-cells").and_then(|prop| prop.as_u32()).unwrap_or(2)
+    }
+
+    /// Cells of a size in the `reg` of this node's children.
+    pub fn size_cells(&self) -> u32 {
+        self.property("#size-cells").and_then(|prop| prop.as_u32()).unwrap_or(1)
+    }
+
+    /// Cells of an interrupt specifier, for interrupt controllers and nexuses.
+    pub fn interrupt_cells(&self) -> Option<u32> {
+        self.property("#interrupt-cells").and_then(|prop| prop.as_u32())
+    }
+}
+
+impl fmt::Debug for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node").field("name", &self.name).field("offset", &self.offset).finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        (self.value.len() == 4).then(|| be32(self.value, 0)).flatten()
+    }
+
+    /// Reads a one or two cell value.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => be32(self.value, 0).map(u64::from),
+            8 => be64(self.value, 0),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        cstr(self.value, 0)
+    }
+
+    pub fn str_list(self) -> impl Iterator<Item = &'a str> + 'a {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| str::from_utf8(s).ok())
+    }
+
+    pub fn cells(&self) -> Cells<'a> {
+        Cells(self.value)
+    }
+}
+
+/// Big endian cells borrowed from a property.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Cells<'a>(&'a [u8]);
+
+impl Cells<'_> {
+    pub fn len(&self) -> usize {
+        self.0.len() / 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len() < 4
+    }
+
+    pub fn get(&self, i: usize) -> Option<u32> {
+        be32(self.0, i * 4)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len()).filter_map(|i| self.get(i))
+    }
+
+    /// Copies up to [`MAX_CELLS`] cells, returning how many.
+    pub fn to_array(&self) -> ([u32; MAX_CELLS], usize) {
+        let mut cells = [0; MAX_CELLS];
+        let len = self.len().min(MAX_CELLS);
+        for (i, cell) in cells.iter_mut().enumerate().take(len) {
+            *cell = self.get(i).unwrap_or(0);
+        }
+        (cells, len)
+    }
+}
+
+impl fmt::Debug for Cells<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// An interrupt of a device, as a specifier in the domain of its controller.
+#[derive(Clone, Copy, Debug)]
+pub struct Interrupt<'a> {
+    pub controller: Node<'a>,
+    pub specifier: Cells<'a>,
+}
+
+// ---------- TESTS ----------
+
+#[cfg(test)]
+mod builder {
+    use alloc::vec::Vec;
+
+    /// Assembles a blob from structure tokens, for tests.
+    pub struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        pub fn new() -> Self {
+            Self { structs: Vec::new(), strings: Vec::new() }
+        }
+
+        fn word(&mut self, value: u32) {
+            self.structs.extend_from_slice(&value.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            while !self.structs.len().is_multiple_of(4) {
+                self.structs.push(0);
+            }
+        }
+
+        pub fn begin(&mut self, name: &str) -> &mut Self {
+            self.word(super::FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        pub fn end(&mut self) -> &mut Self {
+            self.word(super::FDT_END_NODE);
+            self
+        }
+
+        pub fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let nameoff = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.word(super::FDT_PROP);
+            self.word(value.len() as u32);
+            self.word(nameoff);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        pub fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        pub fn finish(&mut self) -> Vec<u8> {
+            self.word(super::FDT_END);
+            let rsvmap = [0x8000_0000u64, 0x1000, 0, 0];
+            let off_rsvmap = super::HEADER_SIZE;
+            let off_struct = off_rsvmap + rsvmap.len() * 8;
+            let off_strings = off_struct + self.structs.len();
+            let total = off_strings + self.strings.len();
+
+            let mut blob = Vec::new();
+            for word in [
+                super::FDT_MAGIC,
+                total as u32,
+                off_struct as u32,
+                off_strings as u32,
+                off_rsvmap as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ] {
+                blob.extend_from_slice(&word.to_be_bytes());
+            }
+            for value in rsvmap {
+                blob.extend_from_slice(&value.to_be_bytes());
+            }
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+}
+
+#[test]
+fn fdt_translation_and_interrupt_map() {
+    let blob = builder::Builder::new()
+        .begin("")
+        .cells("#address-cells", &[2])
+        .cells("#size-cells", &[2])
+        .prop("compatible", b"linux,dummy-virt\0")
+        .begin("intc@8000000")
+        .prop("compatible", b"arm,gic-v3\0")
+        .prop("interrupt-controller", &[])
+        .cells("#interrupt-cells", &[3])
+        .cells("#address-cells", &[0])
+        .cells("phandle", &[1])
+        .cells("reg", &[0, 0x0800_0000, 0, 0x1_0000])
+        .end()
+        .begin("soc")
+        .cells("#address-cells", &[1])
+        .cells("#size-cells", &[1])
+        .cells("ranges", &[0x0, 0x0, 0x0900_0000, 0x10_0000])
+        .cells("interrupt-parent", &[1])
+        .begin("serial@1000")
+        .prop("compatible", b"vendor,uart\0arm,pl011\0")
+        .cells("reg", &[0x1000, 0x1000])
+        .cells("interrupts", &[0, 1, 4])
+        .end()
+        .begin("pci")
+        .cells("#address-cells", &[3])
+        .cells("#interrupt-cells", &[1])
+        .cells("interrupt-map-mask", &[0x1800, 0, 0, 7])
+        .cells("interrupt-map", &[0x0000, 0, 0, 1, 1, 0, 3, 4, 0x0800, 0, 0, 1, 1, 0, 4, 4])
+        .begin("dev@1")
+        .cells("reg", &[0x0800, 0, 0])
+        .cells("interrupts", &[1])
+        .prop("status", b"disabled\0")
+        .end()
+        .end()
+        .end()
+        .end()
+        .finish();
+
+    let fdt = Fdt::new(&blob).unwrap();
+    assert_eq!(fdt.memory_reservations().collect::<alloc::vec::Vec<_>>(), [(0x8000_0000, 0x1000)]);
+    assert_eq!(fdt.root().unwrap().children().count(), 2);
+
+    let serial = fdt.find_node("/soc/serial").unwrap();
+    assert!(serial.is_compatible("arm,pl011"));
+    assert_eq!(fdt.parent(&serial).unwrap().name, "soc");
+    assert_eq!(fdt.reg(&serial).collect::<alloc::vec::Vec<_>>(), [(0x0900_1000, 0x1000)]);
+
+    let irq = fdt.interrupts(&serial).next().unwrap();
+    assert_eq!(irq.controller.phandle(), Some(1));
+    assert_eq!(irq.specifier.iter().collect::<alloc::vec::Vec<_>>(), [0, 1, 4]);
+
+    let dev = fdt.find_node("/soc/pci/dev@1").unwrap();
+    assert!(!dev.is_enabled());
+    let irq = fdt.interrupts(&dev).next().unwrap();
+    assert_eq!(irq.controller.name, "intc@8000000");
+    assert_eq!(irq.specifier.iter().collect::<alloc::vec::Vec<_>>(), [0, 4, 4]);
+
+    assert_eq!(fdt.find_compatible(&["arm,pl011"]).count(), 1);
+    assert_eq!(Fdt::new(&blob[..20]).unwrap_err(), FdtError::Truncated);
+}
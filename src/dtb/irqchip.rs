@@ -0,0 +1,677 @@
+//! # Interrupt domains
+//! Every interrupt controller is registered as a domain owning the hardware IRQs
+//! `0..hwirq_count`. Kernel-wide IRQ numbers (virqs) index [`IrqDesc`]s and are
+//! handed out to domains in contiguous ranges.
+//!
+//! A domain sits below its parent in one of three ways, whether it was described by
+//! ACPI or a device tree:
+//! - [`Parent::Cpu`]: wired to the CPU's interrupt input, like a GIC, PLIC or IMSIC.
+//! - [`Parent::Chained`]: its outputs are IRQs of the parent, and it is asked for its
+//!   own pending IRQs when they fire, like a GPIO controller on a GIC.
+//! - [`Parent::Stacked`]: each hardware IRQ is forwarded to one of the parent's and
+//!   shares its virq, like an APLIC delivering MSIs to an IMSIC.
+//!
+//! ACPI GSIs are translated through the domain whose `gsi_base` range holds them,
+//! and device tree specifiers through the domain with the `interrupt-parent` phandle.
+//!
+//! Every virq counts its interrupts per CPU, which [`IrqChipCore::snapshot`] reports
+//! along with how it is configured. A line firing without a handler, or with one
+//! returning [`IrqReturn::None`], [`UNHANDLED_LIMIT`] times in a row is disabled.
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, Once, RwLock};
+
+use crate::{
+    affinity::{AffinityError, IrqRoute},
+    percpu::{self, irq_restore, irq_save},
+    softirq,
+};
+
+/// Unhandled interrupts in a row after which a line is disabled.
+pub const UNHANDLED_LIMIT: u32 = 1000;
+
+/// Target CPU of a virq whose affinity was never set.
+const NO_TARGET: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Trigger {
+    Edge,
+    #[default]
+    Level,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Polarity {
+    #[default]
+    High,
+    Low,
+}
+
+/// A hardware IRQ of one domain, with how it is signalled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrqSpec {
+    pub hwirq: u32,
+    pub trigger: Trigger,
+    pub polarity: Polarity,
+}
+
+impl IrqSpec {
+    pub fn new(hwirq: u32, trigger: Trigger, polarity: Polarity) -> Self {
+        Self { hwirq, trigger, polarity }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqError {
+    /// No domain has the phandle, GSI or ID given.
+    UnknownDomain,
+    /// The device tree specifier cannot be decoded by the domain.
+    InvalidSpecifier,
+    /// The hardware IRQ or virq is outside of its domain or table.
+    OutOfRange,
+    /// A stacked domain found no free hardware IRQ in its parent.
+    NoParentIrq,
+    /// The virq already has a handler.
+    Busy,
+    /// The controller cannot do this for the IRQ, like routing a per-CPU interrupt.
+    Unsupported,
+}
+
+/// Index of a registered domain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DomainId(pub usize);
+
+/// What a handler did with an interrupt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The device did not raise it, on a shared line.
+    None,
+    /// Nothing more to do.
+    Handled,
+    /// The bottom half has to run.
+    WakeThread,
+}
+
+/// Handles a virq; called in interrupt context with the line acknowledged.
+pub trait InterruptHandler: Send {
+    fn irq_handler(&mut self, irq: usize) -> IrqReturn;
+}
+
+/// Driver of an interrupt controller. Every hardware IRQ number is relative to the
+/// controller, numbered from zero.
+pub trait IrqChip: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Number of hardware IRQs, fixed at registration.
+    fn hwirq_count(&self) -> u32;
+
+    /// Decodes an interrupt specifier of `#interrupt-cells` cells from a device tree.
+    fn xlate(&self, spec: &[u32]) -> Result<IrqSpec, IrqError>;
+
+    /// Programs the trigger mode and polarity of a hardware IRQ.
+    fn configure(&self, _spec: IrqSpec) -> Result<(), IrqError> {
+        Ok(())
+    }
+
+    fn enable(&self, hwirq: u32);
+    fn disable(&self, hwirq: u32);
+
+    /// Claims the highest priority pending hardware IRQ, if any.
+    fn ack(&self) -> Option<u32>;
+    /// Ends a hardware IRQ once its handler ran, on stacked domains as well.
+    fn eoi(&self, hwirq: u32);
+
+    /// For stacked domains, reserves the parent hardware IRQ that `hwirq` is
+    /// forwarded to and points the hardware at it.
+    fn parent_hwirq(&self, _hwirq: u32) -> Option<u32> {
+        None
+    }
+
+    /// Sets up the calling CPU's interface of a [`Parent::Cpu`] controller.
+    fn init_cpu(&self) {}
+
+    /// Delivers a hardware IRQ to `cpu_id` only.
+    fn set_affinity(&self, _hwirq: u32, _cpu_id: usize) -> Result<(), IrqError> {
+        Err(IrqError::Unsupported)
+    }
+}
+
+/// Where a domain's interrupts go.
+pub enum Parent {
+    Cpu,
+    /// Outputs wired to these virqs of other domains.
+    Chained(Vec<usize>),
+    Stacked(DomainId),
+}
+
+/// A controller to register, as found by ACPI or in a device tree.
+pub struct IrqChipItem {
+    /// Device tree phandle, for controllers found in a DTB.
+    pub phandle: Option<u32>,
+    /// First GSI handled, for controllers described by ACPI.
+    pub gsi_base: Option<u32>,
+    pub parent: Parent,
+    pub ic: Box<dyn IrqChip>,
+}
+
+struct Domain {
+    item: IrqChipItem,
+    /// First virq for `Cpu` and `Chained` domains; stacked ones borrow their parent's.
+    virq_base: Option<usize>,
+    /// Acknowledges that returned no valid hardware IRQ.
+    spurious: AtomicU64,
+}
+
+/// Interrupt counts of a virq on one CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IrqCounts {
+    /// Interrupts that a handler or a chained domain took.
+    pub delivered: u64,
+    /// Interrupts of a chained domain's line with nothing pending in that domain.
+    pub spurious: u64,
+    /// Interrupts with no handler to take them.
+    pub unhandled: u64,
+}
+
+#[derive(Debug, Default)]
+struct CpuStats {
+    delivered: AtomicU64,
+    spurious: AtomicU64,
+    unhandled: AtomicU64,
+}
+
+impl CpuStats {
+    fn counts(&self) -> IrqCounts {
+        IrqCounts {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            spurious: self.spurious.load(Ordering::Relaxed),
+            unhandled: self.unhandled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A virq as reported by [`IrqChipCore::snapshot`].
+#[derive(Clone, Debug)]
+pub struct IrqStats {
+    pub virq: usize,
+    /// Name of the chip owning the virq.
+    pub chip: String,
+    pub hwirq: u32,
+    /// Signalling the virq was mapped with, unknown until mapped.
+    pub trigger: Option<Trigger>,
+    pub polarity: Option<Polarity>,
+    /// CPU the virq was last routed to.
+    pub target: Option<usize>,
+    pub has_handler: bool,
+    /// Disabled for firing unhandled too often.
+    pub storm_disabled: bool,
+    /// Counts by CPU ID.
+    pub per_cpu: Vec<IrqCounts>,
+}
+
+impl IrqStats {
+    /// Sums the counts of every CPU.
+    pub fn total(&self) -> IrqCounts {
+        self.per_cpu.iter().fold(IrqCounts::default(), |total, counts| IrqCounts {
+            delivered: total.delivered + counts.delivered,
+            spurious: total.spurious + counts.spurious,
+            unhandled: total.unhandled + counts.unhandled,
+        })
+    }
+}
+
+/// Number of CPU IDs counted for, covering every CPU with a per-CPU area.
+fn cpu_slots() -> usize {
+    percpu::cpus().into_iter().max().map_or(1, |max| max + 1)
+}
+
+/// State of one virq.
+pub struct IrqDesc {
+    domain: DomainId,
+    hwirq: u32,
+    /// Stacked domain and hardware IRQ forwarded to this virq, once mapped.
+    stacked: Once<(DomainId, u32)>,
+    /// Domains chained to this virq, polled when it fires.
+    cascade: Vec<DomainId>,
+    handler: Mutex<Option<Box<dyn InterruptHandler>>>,
+    /// Trigger and polarity from the last mapping.
+    signal: Mutex<Option<(Trigger, Polarity)>>,
+    /// CPU from the last [`IrqChipCore::set_affinity`], or [`NO_TARGET`].
+    target: AtomicUsize,
+    /// Counts by CPU ID, grown when CPUs are added.
+    stats: Vec<CpuStats>,
+    unhandled_streak: AtomicU32,
+    storm_disabled: AtomicBool,
+}
+
+impl IrqDesc {
+    fn new(domain: DomainId, hwirq: u32, cpus: usize) -> Self {
+        Self {
+            domain,
+            hwirq,
+            stacked: Once::new(),
+            cascade: Vec::new(),
+            handler: Mutex::new(None),
+            signal: Mutex::new(None),
+            target: AtomicUsize::new(NO_TARGET),
+            stats: (0..cpus).map(|_| CpuStats::default()).collect(),
+            unhandled_streak: AtomicU32::new(0),
+            storm_disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Bumps a counter of the calling CPU, unless it was added after the last resize.
+    fn count(&self, counter: fn(&CpuStats) -> &AtomicU64) {
+        if let Some(stats) = self.stats.get(percpu::cpu_id()) {
+            counter(stats).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Debug for IrqDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrqDesc")
+            .field("domain", &self.domain)
+            .field("hwirq", &self.hwirq)
+            .field("stacked", &self.stacked.get())
+            .field("cascade", &self.cascade)
+            .finish()
+    }
+}
+
+/// Every interrupt domain and virq of the system.
+pub struct IrqChipCore {
+    domains: RwLock<Vec<Domain>>,
+    descs: RwLock<Vec<IrqDesc>>,
+}
+
+pub static IRQ_CHIP: IrqChipCore = IrqChipCore::new();
+
+impl IrqChipCore {
+    pub const fn new() -> Self {
+        Self {
+            domains: RwLock::new(Vec::new()),
+            descs: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Registers a controller, allocating virqs for its hardware IRQs unless stacked.
+    /// Parents must be registered first.
+    pub fn register(&self, item: IrqChipItem) -> Result<DomainId, IrqError> {
+        // Both tables are read in interrupt context on this CPU
+        let flags = irq_save();
+        let result = Self::register_locked(&mut self.domains.write(), &mut self.descs.write(), item);
+        irq_restore(flags);
+        result
+    }
+
+    fn register_locked(
+        domains: &mut Vec<Domain>,
+        descs: &mut Vec<IrqDesc>,
+        item: IrqChipItem,
+    ) -> Result<DomainId, IrqError> {
+        let id = DomainId(domains.len());
+
+        match &item.parent {
+            Parent::Cpu => {}
+            Parent::Chained(virqs) => {
+                if virqs.iter().any(|&virq| virq >= descs.len()) {
+                    return Err(IrqError::OutOfRange);
+                }
+                for &virq in virqs {
+                    descs[virq].cascade.push(id);
+                }
+            }
+            Parent::Stacked(parent) => {
+                let parent = domains.get(parent.0).ok_or(IrqError::UnknownDomain)?;
+                if parent.virq_base.is_none() {
+                    // Stacking is resolved in one step, from a stacked domain to the one owning the virqs
+                    return Err(IrqError::UnknownDomain);
+                }
+            }
+        }
+
+        let virq_base = match item.parent {
+            Parent::Stacked(_) => None,
+            _ => {
+                let base = descs.len();
+                let cpus = cpu_slots();
+                descs.extend((0..item.ic.hwirq_count()).map(|hwirq| IrqDesc::new(id, hwirq, cpus)));
+                Some(base)
+            }
+        };
+
+        log::info!(
+            "IRQ domain {} ({}): {} hwirqs, virqs {:?}, phandle {:?}, GSI base {:?}",
+            id.0,
+            item.ic.name(),
+            item.ic.hwirq_count(),
+            virq_base.map(|base| base..base + item.ic.hwirq_count() as usize),
+            item.phandle,
+            item.gsi_base,
+        );
+        domains.push(Domain {
+            item,
+            virq_base,
+            spurious: AtomicU64::new(0),
+        });
+        Ok(id)
+    }
+
+    /// Returns the domain registered with a device tree phandle.
+    pub fn find_phandle(&self, phandle: u32) -> Option<DomainId> {
+        let domains = self.domains.read();
+        domains.iter().position(|domain| domain.item.phandle == Some(phandle)).map(DomainId)
+    }
+
+    /// Returns the domain whose GSI range holds `gsi`.
+    pub fn find_gsi(&self, gsi: u32) -> Option<DomainId> {
+        let domains = self.domains.read();
+        domains
+            .iter()
+            .position(|domain| {
+                let count = domain.item.ic.hwirq_count();
+                domain.item.gsi_base.is_some_and(|base| gsi >= base && gsi - base < count)
+            })
+            .map(DomainId)
+    }
+
+    /// Maps a hardware IRQ of a domain, configuring it, and returns its virq.
+    pub fn map(&self, id: DomainId, spec: IrqSpec) -> Result<usize, IrqError> {
+        let domains = self.domains.read();
+        let domain = domains.get(id.0).ok_or(IrqError::UnknownDomain)?;
+        if spec.hwirq >= domain.item.ic.hwirq_count() {
+            return Err(IrqError::OutOfRange);
+        }
+        domain.item.ic.configure(spec)?;
+
+        let virq = match (domain.virq_base, &domain.item.parent) {
+            (Some(base), _) => base + spec.hwirq as usize,
+            (None, &Parent::Stacked(parent_id)) => {
+                let parent = &domains[parent_id.0];
+                let parent_hwirq = domain.item.ic.parent_hwirq(spec.hwirq).ok_or(IrqError::NoParentIrq)?;
+                // The parent sees an MSI-like message, always edge triggered
+                let parent_spec = IrqSpec::new(parent_hwirq, Trigger::Edge, Polarity::High);
+                parent.item.ic.configure(parent_spec)?;
+
+                let virq = parent.virq_base.ok_or(IrqError::UnknownDomain)? + parent_hwirq as usize;
+                let descs = self.descs.read();
+                let desc = descs.get(virq).ok_or(IrqError::OutOfRange)?;
+                if *desc.stacked.call_once(|| (id, spec.hwirq)) != (id, spec.hwirq) {
+                    return Err(IrqError::NoParentIrq);
+                }
+                virq
+            }
+            (None, _) => return Err(IrqError::UnknownDomain),
+        };
+
+        if let Some(desc) = self.descs.read().get(virq) {
+            *desc.signal.lock() = Some((spec.trigger, spec.polarity));
+        }
+        Ok(virq)
+    }
+
+    /// Translates an ACPI GSI to a virq.
+    pub fn gsi_to_virq(&self, gsi: u32, trigger: Trigger, polarity: Polarity) -> Result<usize, IrqError> {
+        let id = self.find_gsi(gsi).ok_or(IrqError::UnknownDomain)?;
+        let base = self.domains.read()[id.0].item.gsi_base.unwrap_or(0);
+        self.map(id, IrqSpec::new(gsi - base, trigger, polarity))
+    }
+
+    /// Translates a device tree interrupt specifier of the controller with `phandle`
+    /// to a virq.
+    pub fn of_to_virq(&self, phandle: u32, spec: &[u32]) -> Result<usize, IrqError> {
+        let id = self.find_phandle(phandle).ok_or(IrqError::UnknownDomain)?;
+        let spec = self.domains.read()[id.0].item.ic.xlate(spec)?;
+        self.map(id, spec)
+    }
+
+    /// Calls `f` with every chip a virq goes through, from the stacked one down.
+    fn with_chips(&self, virq: usize, mut f: impl FnMut(&dyn IrqChip, u32)) -> Result<(), IrqError> {
+        let domains = self.domains.read();
+        let descs = self.descs.read();
+        let desc = descs.get(virq).ok_or(IrqError::OutOfRange)?;
+        if let Some(&(stacked, hwirq)) = desc.stacked.get() {
+            f(&*domains[stacked.0].item.ic, hwirq);
+        }
+        f(&*domains[desc.domain.0].item.ic, desc.hwirq);
+        Ok(())
+    }
+
+    /// Enables a virq, also after it was disabled for firing unhandled.
+    pub fn irq_enable(&self, virq: usize) -> Result<(), IrqError> {
+        if let Some(desc) = self.descs.read().get(virq) {
+            desc.unhandled_streak.store(0, Ordering::Relaxed);
+            desc.storm_disabled.store(false, Ordering::Relaxed);
+        }
+        self.with_chips(virq, |chip, hwirq| chip.enable(hwirq))
+    }
+
+    pub fn irq_disable(&self, virq: usize) -> Result<(), IrqError> {
+        self.with_chips(virq, |chip, hwirq| chip.disable(hwirq))
+    }
+
+    /// Delivers a virq to `cpu_id`, through the stacked domain if it can route it and
+    /// the owner of the virq otherwise.
+    pub fn set_affinity(&self, virq: usize, cpu_id: usize) -> Result<(), IrqError> {
+        let domains = self.domains.read();
+        let descs = self.descs.read();
+        let desc = descs.get(virq).ok_or(IrqError::OutOfRange)?;
+        let mut result = Err(IrqError::Unsupported);
+        if let Some(&(stacked, hwirq)) = desc.stacked.get() {
+            result = domains[stacked.0].item.ic.set_affinity(hwirq, cpu_id);
+        }
+        if result == Err(IrqError::Unsupported) {
+            result = domains[desc.domain.0].item.ic.set_affinity(desc.hwirq, cpu_id);
+        }
+        if result.is_ok() {
+            desc.target.store(cpu_id, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Returns the domain and hardware IRQ behind a virq, through any stacked domain.
+    pub fn irq_to_hwirq(&self, virq: usize) -> Option<(DomainId, u32)> {
+        let descs = self.descs.read();
+        let desc = descs.get(virq)?;
+        Some(desc.stacked.get().copied().unwrap_or((desc.domain, desc.hwirq)))
+    }
+
+    /// Sets up the calling CPU's interface of every root controller, and makes room
+    /// for its counters. Called on APs.
+    pub fn init_cpu(&self) {
+        let cpus = cpu_slots().max(percpu::cpu_id() + 1);
+        let flags = irq_save();
+        for desc in self.descs.write().iter_mut() {
+            if desc.stats.len() < cpus {
+                desc.stats.resize_with(cpus, CpuStats::default);
+            }
+        }
+        irq_restore(flags);
+
+        for domain in self.domains.read().iter() {
+            if let Parent::Cpu = domain.item.parent {
+                domain.item.ic.init_cpu();
+            }
+        }
+    }
+
+    /// Installs the handler of a virq.
+    pub fn set_handler(&self, virq: usize, handler: Box<dyn InterruptHandler>) -> Result<(), IrqError> {
+        let descs = self.descs.read();
+        let desc = descs.get(virq).ok_or(IrqError::OutOfRange)?;
+        // The handler lock is also taken in interrupt context on this CPU
+        let flags = irq_save();
+        let mut slot = desc.handler.lock();
+        let result = match *slot {
+            Some(_) => Err(IrqError::Busy),
+            None => {
+                *slot = Some(handler);
+                Ok(())
+            }
+        };
+        drop(slot);
+        irq_restore(flags);
+        result
+    }
+
+    /// Removes the handler of a virq, returning it.
+    pub fn take_handler(&self, virq: usize) -> Option<Box<dyn InterruptHandler>> {
+        let descs = self.descs.read();
+        let desc = descs.get(virq)?;
+        let flags = irq_save();
+        let handler = desc.handler.lock().take();
+        irq_restore(flags);
+        handler
+    }
+
+    /// Handles every IRQ pending at the root controllers, then runs the softirqs
+    /// they queued. Called by the arch interrupt vector with interrupts disabled.
+    pub fn handle_irq(&self) {
+        softirq::irq_enter();
+        {
+            let domains = self.domains.read();
+            for domain in domains.iter() {
+                if let (Parent::Cpu, Some(base)) = (&domain.item.parent, domain.virq_base) {
+                    self.handle_domain(&domains, domain, base);
+                }
+            }
+        }
+        softirq::irq_exit();
+    }
+
+    /// Handles the pending IRQs of a domain, returning whether there were any.
+    fn handle_domain(&self, domains: &[Domain], domain: &Domain, base: usize) -> bool {
+        let chip = &*domain.item.ic;
+        let mut any = false;
+        while let Some(hwirq) = chip.ack() {
+            if hwirq >= chip.hwirq_count() {
+                // Spurious, as reported by the controller
+                domain.spurious.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+            any = true;
+            self.dispatch(domains, base + hwirq as usize);
+            chip.eoi(hwirq);
+        }
+        any
+    }
+
+    fn dispatch(&self, domains: &[Domain], virq: usize) {
+        let descs = self.descs.read();
+        let Some(desc) = descs.get(virq) else {
+            return;
+        };
+
+        let handled = match desc.handler.lock().as_mut() {
+            Some(handler) => handler.irq_handler(virq) != IrqReturn::None,
+            None => false,
+        };
+        if let Some(&(stacked, hwirq)) = desc.stacked.get() {
+            domains[stacked.0].item.ic.eoi(hwirq);
+        }
+        let mut cascaded = false;
+        for &child in &desc.cascade {
+            let child = &domains[child.0];
+            if let Some(base) = child.virq_base {
+                cascaded |= self.handle_domain(domains, child, base);
+            }
+        }
+
+        if handled || cascaded {
+            desc.count(|stats| &stats.delivered);
+            desc.unhandled_streak.store(0, Ordering::Relaxed);
+        } else if !desc.cascade.is_empty() {
+            desc.count(|stats| &stats.spurious);
+        } else {
+            desc.count(|stats| &stats.unhandled);
+            let streak = desc.unhandled_streak.fetch_add(1, Ordering::Relaxed) + 1;
+            if streak == 1 {
+                log::warn!("Unhandled IRQ {} ({:?})", virq, desc);
+            }
+            if streak >= UNHANDLED_LIMIT && !desc.storm_disabled.swap(true, Ordering::Relaxed) {
+                log::error!("IRQ {} fired {} times unhandled, disabling it", virq, streak);
+                if let Some(&(stacked, hwirq)) = desc.stacked.get() {
+                    domains[stacked.0].item.ic.disable(hwirq);
+                }
+                domains[desc.domain.0].item.ic.disable(desc.hwirq);
+            }
+        }
+    }
+
+    /// Reports the configuration and counts of a virq.
+    pub fn stats(&self, virq: usize) -> Option<IrqStats> {
+        let domains = self.domains.read();
+        let descs = self.descs.read();
+        let desc = descs.get(virq)?;
+        let signal = *desc.signal.lock();
+        let target = desc.target.load(Ordering::Relaxed);
+        // The handler lock is also taken in interrupt context on this CPU
+        let flags = irq_save();
+        let has_handler = desc.handler.lock().is_some();
+        irq_restore(flags);
+        Some(IrqStats {
+            virq,
+            chip: domains[desc.domain.0].item.ic.name().to_string(),
+            hwirq: desc.hwirq,
+            trigger: signal.map(|(trigger, _)| trigger),
+            polarity: signal.map(|(_, polarity)| polarity),
+            target: (target != NO_TARGET).then_some(target),
+            has_handler,
+            storm_disabled: desc.storm_disabled.load(Ordering::Relaxed),
+            per_cpu: desc.stats.iter().map(CpuStats::counts).collect(),
+        })
+    }
+
+    /// Reports every virq that was mapped, has a handler or has fired.
+    pub fn snapshot(&self) -> Vec<IrqStats> {
+        let count = self.descs.read().len();
+        (0..count)
+            .filter_map(|virq| self.stats(virq))
+            .filter(|stats| stats.trigger.is_some() || stats.has_handler || stats.total() != IrqCounts::default())
+            .collect()
+    }
+
+    /// Returns the name of every chip with how many spurious acknowledges it had.
+    pub fn spurious_by_chip(&self) -> Vec<(String, u64)> {
+        let domains = self.domains.read();
+        domains
+            .iter()
+            .map(|domain| (domain.item.ic.name().to_string(), domain.spurious.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl Default for IrqChipCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Routes a virq for [`affinity`](crate::affinity).
+pub struct VirqRoute(pub usize);
+
+impl IrqRoute for VirqRoute {
+    fn set_target(&mut self, cpu_id: usize) -> Result<(), AffinityError> {
+        IRQ_CHIP.set_affinity(self.0, cpu_id).map_err(|err| match err {
+            IrqError::Unsupported => AffinityError::Unsupported,
+            _ => AffinityError::UnknownIrq,
+        })
+    }
+}
+
+/// Installs `handler` for a virq, as translated by [`IrqChipCore::gsi_to_virq`] or
+/// [`IrqChipCore::of_to_virq`].
+pub fn register_irq(virq: usize, handler: impl InterruptHandler + 'static) -> Result<(), IrqError> {
+    IRQ_CHIP.set_handler(virq, Box::new(handler))
+}
+
@@ -0,0 +1,325 @@
+//! # Device tree
+//! Discovery of CPUs, memory, the console UART, interrupt controllers and timers from
+//! a flattened device tree, for platforms booted without ACPI tables, like QEMU
+//! `virt` without UEFI or riscv64 with OpenSBI.
+//!
+//! Interrupt controllers are registered in [`irqchip`] whether they come from a
+//! device tree or from ACPI.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use spin::{Once, RwLock};
+
+use self::{
+    fdt::{Fdt, Node},
+    irqchip::{IrqChip, IrqChipItem, IrqError, Parent, IRQ_CHIP},
+};
+use crate::{
+    device::serial::{SerialKind, COM1},
+    memory::{allocate_frame_at, areas, map_device_memory, Frame, KernelMapper, PhysicalAddress, PAGE_SIZE},
+    paging::VirtualAddress,
+};
+
+pub mod fdt;
+pub mod irqchip;
+
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64.rs"]
+mod arch;
+
+#[cfg(target_arch = "riscv64")]
+#[path = "arch/riscv64.rs"]
+mod arch;
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "riscv64")))]
+#[path = "arch/other.rs"]
+mod arch;
+
+#[cfg(target_arch = "riscv64")]
+pub use self::arch::{allocate_imsic_identity, imsic_hart_index, timebase_frequency};
+
+static DTB: Once<Fdt<'static>> = Once::new();
+
+pub fn dtb() -> Option<&'static Fdt<'static>> {
+    DTB.get()
+}
+
+/// A CPU under `/cpus`, numbered in the order found.
+#[derive(Clone, Copy, Debug)]
+pub struct DtCpu {
+    pub cpu_id: usize,
+    /// Its `reg`: the MPIDR affinity on aarch64, the hart ID on riscv64.
+    pub hw_id: u64,
+    pub node: Node<'static>,
+}
+
+/// RAM from `/memory` nodes, as physical base and size.
+static MEMORY: Once<Vec<(u64, u64)>> = Once::new();
+/// Memory reservations and `/reserved-memory` children, which must not be allocated.
+static RESERVED: Once<Vec<(u64, u64)>> = Once::new();
+
+pub fn memory_regions() -> &'static [(u64, u64)] {
+    MEMORY.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn reserved_regions() -> &'static [(u64, u64)] {
+    RESERVED.get().map_or(&[], Vec::as_slice)
+}
+
+/// Discovers the platform from the device tree at `dtb`, unless ACPI tables were
+/// found already. Returns whether the device tree is in use. Called by
+/// [`acpi::init`](crate::acpi::init) when the pointer it was handed is no RSDP.
+///
+/// # Safety
+/// `dtb` must be the mapped device tree blob, left in place for the kernel's lifetime.
+/// Its frames are taken out of the frame allocator here; it is not in
+/// [`reserved_regions`].
+pub unsafe fn init(dtb: *const u8) -> bool {
+    if crate::acpi::RXSDT_ENUM.get().is_some() {
+        log::info!("ACPI tables found, ignoring the device tree");
+        return false;
+    }
+
+    let fdt = match Fdt::from_ptr(dtb) {
+        Ok(fdt) => *DTB.call_once(|| fdt),
+        Err(err) => {
+            log::error!("Invalid device tree at {:p}: {:?}", dtb, err);
+            return false;
+        }
+    };
+    let model = fdt.root().and_then(|root| root.property("model")).and_then(|prop| prop.as_str());
+    log::info!("DTB: {} bytes, model {:?}", fdt.total_size(), model);
+    reserve_blob(dtb, fdt.total_size());
+
+    init_memory(&fdt);
+    init_serial(&fdt);
+
+    let cpus = cpus(&fdt);
+    arch::init(&fdt, &cpus);
+    register_irq_chips(&fdt);
+    true
+}
+
+fn init_memory(fdt: &Fdt<'static>) {
+    let memory = MEMORY.call_once(|| {
+        fdt.nodes()
+            .filter(|node| node.is_enabled() && node.property("device_type").and_then(|prop| prop.as_str()) == Some("memory"))
+            .flat_map(|node| fdt.reg(&node))
+            .filter(|&(_, size)| size != 0)
+            .collect()
+    });
+    let reserved = RESERVED.call_once(|| {
+        let mut reserved: Vec<_> = fdt.memory_reservations().collect();
+        if let Some(node) = fdt.find_node("/reserved-memory") {
+            reserved.extend(node.children().filter(|child| child.is_enabled()).flat_map(|child| fdt.reg(&child)));
+        }
+        reserved
+    });
+
+    for &(base, size) in memory {
+        log::info!("  memory: {:#x}..{:#x}", base, base.saturating_add(size));
+    }
+    for &(base, size) in reserved {
+        log::info!("  reserved: {:#x}..{:#x}", base, base.saturating_add(size));
+    }
+    reserve_memory(memory, reserved);
+}
+
+/// Takes the reserved regions out of the frame allocator, which was set up from the
+/// bootloader's memory map before the device tree could be read, and reports RAM
+/// that map left out.
+fn reserve_memory(memory: &[(u64, u64)], reserved: &[(u64, u64)]) {
+    let areas: Vec<_> = areas()
+        .iter()
+        .map(|area| (area.base.data() as u64, (area.base.data() + area.size) as u64))
+        .collect();
+
+    for &(base, size) in memory {
+        let end = base.saturating_add(size);
+        if !areas.iter().any(|&(start, limit)| start < end && base < limit) {
+            log::warn!("  memory {:#x}..{:#x} is missing from the boot memory map", base, end);
+        }
+    }
+
+    let mut taken = 0;
+    for &(base, size) in reserved {
+        let end = base.saturating_add(size);
+        for &(start, limit) in &areas {
+            let first = base.max(start) / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+            let last = end.min(limit);
+            for page in (first..last).step_by(PAGE_SIZE) {
+                // Frames in use already cannot be handed out anyway
+                taken += usize::from(allocate_frame_at(Frame::containing(PhysicalAddress::new(page as usize))).is_some());
+            }
+        }
+    }
+    if taken > 0 {
+        log::info!("  {} reserved frames taken from the allocator", taken);
+    }
+}
+
+/// Takes the frames holding the blob at `dtb` out of the frame allocator, as [`DTB`]
+/// borrows it for the kernel's lifetime.
+fn reserve_blob(dtb: *const u8, size: usize) {
+    let start = dtb as usize & !(PAGE_SIZE - 1);
+    let end = dtb as usize + size;
+    let mapper = KernelMapper::lock();
+    for page in (start..end).step_by(PAGE_SIZE) {
+        match mapper.translate(VirtualAddress::new(page)) {
+            // Frames outside the allocator's areas are not handed out anyway
+            Some((phys, _)) => {
+                let _ = allocate_frame_at(Frame::containing(phys));
+            }
+            None => log::warn!("  DTB page {:#x} is not mapped", page),
+        }
+    }
+}
+
+/// Sets up the console named by `/chosen/stdout-path`.
+fn init_serial(fdt: &Fdt<'static>) {
+    let Some(path) = fdt.chosen().and_then(|chosen| chosen.property("stdout-path")).and_then(|prop| prop.as_str())
+    else {
+        return;
+    };
+    // Options such as the baud rate follow a colon
+    let path = path.split(':').next().unwrap_or(path);
+    let Some(node) = fdt.resolve_path(path) else {
+        log::warn!("stdout-path {:?} not found", path);
+        return;
+    };
+    let Some((base, _)) = fdt.reg(&node).next() else {
+        log::warn!("Console {} has no address", node.name);
+        return;
+    };
+    let virt = unsafe { map_device_memory(PhysicalAddress::new(base as usize), PAGE_SIZE) }.data();
+
+    let serial = if node.is_compatible("arm,pl011") {
+        SerialKind::Pl011(crate::device::uart_pl011::SerialPort::new(virt, false))
+    } else if node.is_compatible("ns16550a") || node.is_compatible("ns16550") {
+        use crate::{device::uart_16550::SerialPort, syscall::io::Mmio};
+
+        let width = node.property("reg-io-width").and_then(|prop| prop.as_u32()).unwrap_or(1);
+        if width == 4 {
+            let port = SerialPort::<Mmio<u32>>::new(virt);
+            port.init();
+            SerialKind::Ns16550u32(port)
+        } else {
+            let port = SerialPort::<Mmio<u8>>::new(virt);
+            port.init();
+            SerialKind::Ns16550u8(port)
+        }
+    } else {
+        log::warn!("Unsupported console {} ({:?})", node.name, node.compatible().next());
+        return;
+    };
+    *COM1.lock() = Some(serial);
+    log::info!("  console: {} at {:#x}", node.name, base);
+}
+
+/// Returns the enabled CPUs under `/cpus`.
+fn cpus(fdt: &Fdt<'static>) -> Vec<DtCpu> {
+    let Some(cpus) = fdt.find_node("/cpus") else {
+        return Vec::new();
+    };
+    let address_cells = cpus.address_cells();
+    cpus.children()
+        .filter(|node| node.is_enabled() && node.property("device_type").and_then(|prop| prop.as_str()) == Some("cpu"))
+        .filter_map(|node| {
+            let reg = node.property("reg")?.cells();
+            let hw_id = (0..address_cells as usize).try_fold(0u64, |id, i| Some(id << 32 | u64::from(reg.get(i)?)))?;
+            Some((node, hw_id))
+        })
+        .enumerate()
+        .map(|(cpu_id, (node, hw_id))| DtCpu { cpu_id, hw_id, node })
+        .collect()
+}
+
+/// Translates interrupt `index` of `node` to a virq. Its controller must be registered.
+pub fn irq_of(fdt: &Fdt, node: &Node, index: usize) -> Result<usize, IrqError> {
+    let irq = fdt.interrupts(node).nth(index).ok_or(IrqError::InvalidSpecifier)?;
+    let phandle = irq.controller.phandle().ok_or(IrqError::UnknownDomain)?;
+    let (cells, len) = irq.specifier.to_array();
+    IRQ_CHIP.of_to_virq(phandle, &cells[..len])
+}
+
+/// Creates the driver of an interrupt controller node.
+pub type IrqChipProbe = fn(&Fdt, &Node) -> Option<Box<dyn IrqChip>>;
+
+/// Drivers of controllers found by [`register_irq_chips`], by compatible string.
+static IRQ_CHIP_DRIVERS: RwLock<Vec<(&'static str, IrqChipProbe)>> = RwLock::new(Vec::new());
+
+/// Makes a driver of an interrupt controller known to device tree boot, like the
+/// riscv64 PLIC and APLIC. Controllers that IPIs go through are set up by the
+/// architecture code instead, before any of these.
+pub fn register_irq_chip_driver(compatible: &'static str, probe: IrqChipProbe) {
+    IRQ_CHIP_DRIVERS.write().push((compatible, probe));
+}
+
+/// Returns how an interrupt controller node reaches its parent, once that parent
+/// is registered.
+fn irq_chip_parent(fdt: &Fdt, node: &Node) -> Option<Parent> {
+    // MSI producers forward each of their interrupts to one of the parent's
+    if let Some(msi_parent) = node.property("msi-parent").and_then(|prop| prop.cells().get(0)) {
+        return IRQ_CHIP.find_phandle(msi_parent).map(Parent::Stacked);
+    }
+
+    let mut interrupts = fdt.interrupts(node).peekable();
+    if interrupts.peek().is_none() {
+        return Some(Parent::Cpu);
+    }
+    let mut virqs = Vec::new();
+    for irq in interrupts {
+        // Per-hart local interrupt controllers stand for the CPU itself
+        if irq.controller.is_compatible("riscv,cpu-intc") {
+            return Some(Parent::Cpu);
+        }
+        let (cells, len) = irq.specifier.to_array();
+        virqs.push(IRQ_CHIP.of_to_virq(irq.controller.phandle()?, &cells[..len]).ok()?);
+    }
+    Some(Parent::Chained(virqs))
+}
+
+/// Registers every other interrupt controller with a known driver, parents first.
+fn register_irq_chips(fdt: &Fdt<'static>) {
+    let drivers = IRQ_CHIP_DRIVERS.read();
+    let mut pending: Vec<(Node, IrqChipProbe)> = fdt
+        .nodes()
+        .filter(|node| node.is_enabled() && node.property("interrupt-controller").is_some())
+        .filter(|node| node.phandle().is_none_or(|phandle| IRQ_CHIP.find_phandle(phandle).is_none()))
+        .filter_map(|node| {
+            let &(_, probe) = drivers.iter().find(|(compatible, _)| node.is_compatible(compatible))?;
+            Some((node, probe))
+        })
+        .collect();
+
+    // Each pass registers the controllers whose parent is known by now
+    while !pending.is_empty() {
+        let before = pending.len();
+        pending.retain(|(node, probe)| {
+            let Some(parent) = irq_chip_parent(fdt, node) else {
+                return true;
+            };
+            match probe(fdt, node) {
+                Some(ic) => {
+                    let item = IrqChipItem {
+                        phandle: node.phandle(),
+                        gsi_base: None,
+                        parent,
+                        ic,
+                    };
+                    if let Err(err) = IRQ_CHIP.register(item) {
+                        log::error!("Failed to register {}: {:?}", node.name, err);
+                    }
+                }
+                None => log::warn!("Failed to probe interrupt controller {}", node.name),
+            }
+            false
+        });
+        if pending.len() == before {
+            for (node, _) in &pending {
+                log::warn!("Interrupt controller {} has no registered parent", node.name);
+            }
+            break;
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! IPIs as SGIs, through the GICv3 system registers or the GICv2 distributor.
+//!
+//! TLB maintenance is broadcast to the inner shareable domain by hardware, so TLB
+//! shootdowns never interrupt other CPUs.
+
+use core::{
+    arch::asm,
+    ptr::write_volatile,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::paging::VirtualAddress;
+
+/// SGI used for every IPI, handled through the GIC domain by [`super::handle`].
+pub const IPI_SGI: u64 = 0;
+
+pub const BROADCAST_TLB_FLUSH: bool = true;
+
+const GICD_SGIR: usize = 0xF00;
+
+/// Mapped GICv2 distributor; zero with GICv3, where SGIs go through `ICC_SGI1R_EL1`.
+static GICV2_DISTRIBUTOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Sends SGIs through the distributor at `base` from now on. With GICv2 the
+/// hardware IDs registered are CPU interface numbers rather than MPIDRs.
+pub fn set_gicv2_distributor(base: usize) {
+    GICV2_DISTRIBUTOR.store(base, Ordering::Release);
+}
+
+pub unsafe fn send(_cpu_id: usize, hw_id: u64) {
+    let distributor = GICV2_DISTRIBUTOR.load(Ordering::Acquire);
+    if distributor != 0 {
+        assert!(hw_id < 8, "GICv2 CPU interface {} cannot receive SGIs", hw_id);
+        asm!("dsb ishst", options(nostack, preserves_flags));
+        write_volatile((distributor + GICD_SGIR) as *mut u32, (1 << (16 + hw_id)) as u32 | IPI_SGI as u32);
+        return;
+    }
+
+    // Aff3, Aff2 and Aff1 name a cluster, Aff0 a bit in a target list of 16 per range
+    let aff0 = hw_id & 0xFF;
+    let value = (hw_id >> 32 & 0xFF) << 48
+        | (hw_id >> 16 & 0xFF) << 32
+        | (aff0 / 16) << 44
+        | IPI_SGI << 24
+        | (hw_id >> 8 & 0xFF) << 16
+        | 1 << (aff0 % 16);
+    asm!(
+        "dsb ishst",
+        "msr icc_sgi1r_el1, {}",
+        "isb",
+        in(reg) value,
+        options(nostack, preserves_flags)
+    );
+}
+
+#[inline(always)]
+pub unsafe fn flush_page(addr: VirtualAddress) {
+    asm!(
+        "dsb ishst",
+        "tlbi vaae1is, {}",
+        "dsb ish",
+        "isb",
+        in(reg) addr.data() >> 12,
+        options(nostack, preserves_flags)
+    );
+}
+
+pub unsafe fn flush_all() {
+    asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb", options(nostack, preserves_flags));
+}
@@ -0,0 +1,68 @@
+//! IPIs through the target hart's IMSIC interrupt file when it has one, otherwise
+//! through the SBI IPI extension.
+//!
+//! Either way the IPI is a root domain of [`irqchip`](crate::dtb::irqchip), and its
+//! handler calls [`super::handle`]: the IMSIC claims [`IPI_IDENTITY`] like any other
+//! external interrupt, and SBI IPIs arrive as supervisor software interrupts, which
+//! the `sbi-ipi` domain takes by clearing `sip.SSIP`.
+
+use core::{
+    arch::asm,
+    ptr::write_volatile,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::paging::{RmmA, RmmArch, VirtualAddress};
+
+/// IMSIC interrupt identity used for every IPI.
+pub const IPI_IDENTITY: u32 = 1;
+
+pub const BROADCAST_TLB_FLUSH: bool = false;
+
+/// SBI "sPI" extension and its `sbi_send_ipi` function.
+const SBI_EXT_IPI: usize = 0x735049;
+const SBI_SEND_IPI: usize = 0;
+
+crate::percpu! {
+    /// Mapped supervisor interrupt file of the hart, zero if it has no IMSIC.
+    static IMSIC_FILE: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Sends IPIs to `cpu_id` through its mapped supervisor-level IMSIC interrupt file.
+pub fn set_imsic_file(cpu_id: usize, base: usize) {
+    if let Some(file) = IMSIC_FILE.get_for(cpu_id) {
+        file.store(base, Ordering::Release);
+    }
+}
+
+pub unsafe fn send(cpu_id: usize, hw_id: u64) {
+    let file = IMSIC_FILE.get_for(cpu_id).map_or(0, |file| file.load(Ordering::Acquire));
+    if file != 0 {
+        // `seteipnum_le` is at the start of the interrupt file
+        asm!("fence w, o", options(nostack, preserves_flags));
+        write_volatile(file as *mut u32, IPI_IDENTITY);
+        return;
+    }
+
+    let error: isize;
+    asm!(
+        "ecall",
+        inlateout("a0") 1usize => error,
+        inlateout("a1") hw_id as usize => _,
+        in("a6") SBI_SEND_IPI,
+        in("a7") SBI_EXT_IPI,
+        options(nostack)
+    );
+    if error != 0 {
+        log::error!("SBI IPI to hart {} failed: {}", hw_id, error);
+    }
+}
+
+#[inline(always)]
+pub unsafe fn flush_page(addr: VirtualAddress) {
+    RmmA::invalidate(addr);
+}
+
+pub unsafe fn flush_all() {
+    RmmA::invalidate_all();
+}
@@ -0,0 +1,45 @@
+//! IPIs through the local APIC ICR.
+
+use core::arch::asm;
+
+use crate::{
+    device::local_apic::the_local_apic,
+    paging::{RmmA, RmmArch, VirtualAddress},
+};
+
+/// IDT vector of the IPI; its handler acknowledges the local APIC and calls [`super::handle`].
+pub const IPI_VECTOR: u8 = 0x40;
+
+/// Kernel mappings are only ever flushed on the CPU executing `invlpg`.
+pub const BROADCAST_TLB_FLUSH: bool = false;
+
+const CR4_PGE: usize = 1 << 7;
+
+/// Sends a fixed, edge-triggered IPI to the APIC ID `hw_id`.
+pub unsafe fn send(_cpu_id: usize, hw_id: u64) {
+    let local_apic = the_local_apic();
+    let low = 0x4000 | u64::from(IPI_VECTOR);
+    let icr = if local_apic.x2 {
+        low | hw_id << 32
+    } else {
+        assert!(hw_id <= 0xFF, "APIC ID {} needs x2APIC mode", hw_id);
+        low | hw_id << 56
+    };
+    local_apic.set_icr(icr);
+}
+
+#[inline(always)]
+pub unsafe fn flush_page(addr: VirtualAddress) {
+    RmmA::invalidate(addr);
+}
+
+/// Flushes global pages as well, which reloading CR3 leaves alone.
+pub unsafe fn flush_all() {
+    let cr4: usize;
+    asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    if cr4 & CR4_PGE != 0 {
+        asm!("mov cr4, {}", "mov cr4, {}", in(reg) cr4 & !CR4_PGE, in(reg) cr4, options(nostack, preserves_flags));
+    } else {
+        RmmA::invalidate_all();
+    }
+}
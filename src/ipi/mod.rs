@@ -0,0 +1,458 @@
+//! # Inter-processor interrupts
+//! Other CPUs are interrupted through the local APIC ICR on x86, an SGI on aarch64
+//! and an IMSIC or SBI IPI on riscv64. Every architecture uses a single interrupt;
+//! what it asks for is kept in a pending mask in the target's per-CPU area.
+//!
+//! A sender that has to wait, for a function call or a TLB shootdown, keeps
+//! handling its own pending requests so two CPUs waiting on each other cannot
+//! deadlock, even with interrupts disabled. Neither path allocates, so the heap
+//! can shoot down its own mappings.
+
+use core::{
+    cell::SyncUnsafeCell,
+    hint, mem,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use spin::Once;
+use spin::{Mutex, MutexGuard};
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use crate::dtb::irqchip::{register_irq, DomainId, InterruptHandler, IrqReturn, IrqSpec, Polarity, Trigger, IRQ_CHIP};
+use crate::{
+    paging::{Page, VirtualAddress, PAGE_SIZE},
+    percpu::{self, irq_restore, irq_save},
+};
+
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64.rs"]
+mod arch;
+
+#[cfg(target_arch = "riscv64")]
+#[path = "arch/riscv64.rs"]
+mod arch;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[path = "arch/x86.rs"]
+mod arch;
+
+#[cfg(target_arch = "aarch64")]
+pub use self::arch::{set_gicv2_distributor, IPI_SGI};
+#[cfg(target_arch = "riscv64")]
+pub use self::arch::{set_imsic_file, IPI_IDENTITY};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use self::arch::IPI_VECTOR;
+
+/// Requests a CPU can have pending, as bits of its pending mask.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum IpiKind {
+    /// Run the function published by `call_on_cpu` or `call_on_all`.
+    Call = 1 << 0,
+    /// Flush the ranges published by [`TlbShootdown::finish`].
+    TlbShootdown = 1 << 1,
+    /// Nothing to do; only leaves `hlt` or `wfi`.
+    Wakeup = 1 << 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpiError {
+    /// No CPU was registered with this ID.
+    UnknownCpu,
+    /// The CPU is not running, so it would never answer.
+    Offline,
+}
+
+crate::percpu! {
+    /// Hardware ID the IPI is addressed to: APIC ID, MPIDR affinity (GIC CPU
+    /// interface number with GICv2) or hart ID.
+    static HW_ID: AtomicU64 = AtomicU64::new(0);
+    static ONLINE: AtomicBool = AtomicBool::new(false);
+    /// Mask of pending [`IpiKind`]s.
+    static PENDING: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Makes `cpu_id` a possible IPI target. Called while enumerating CPUs.
+pub fn register_cpu(cpu_id: usize, hw_id: u64) {
+    percpu::allocate(cpu_id);
+    if let Some(id) = HW_ID.get_for(cpu_id) {
+        id.store(hw_id, Ordering::Relaxed);
+    }
+}
+
+/// Marks `cpu_id` as answering IPIs, or not. A CPU is set online once it runs the
+/// kernel, and offline before it stops for good, after which it calls [`handle`]
+/// one last time.
+pub fn set_online(cpu_id: usize, online: bool) {
+    if let Some(flag) = ONLINE.get_for(cpu_id) {
+        flag.store(online, Ordering::SeqCst);
+    }
+}
+
+pub fn is_online(cpu_id: usize) -> bool {
+    ONLINE.get_for(cpu_id).is_some_and(|flag| flag.load(Ordering::SeqCst))
+}
+
+/// Returns the hardware ID `cpu_id` was registered with.
+pub fn hw_id(cpu_id: usize) -> Option<u64> {
+    HW_ID.get_for(cpu_id).map(|id| id.load(Ordering::Relaxed))
+}
+
+/// Calls `f` for every online CPU other than the calling one.
+fn for_each_other(mut f: impl FnMut(usize)) {
+    let me = percpu::cpu_id();
+    percpu::for_each_cpu(|cpu_id| {
+        if cpu_id != me && is_online(cpu_id) {
+            f(cpu_id);
+        }
+    });
+}
+
+/// Publishes a request acknowledged through `acks` and sends `kind` to `target`, or
+/// to every other online CPU. Each CPU is counted right before it is sent to, in a
+/// single pass, so one coming online meanwhile is either both or neither; an extra
+/// count held until the end keeps `acks` from reaching zero early.
+fn send_request(target: Option<usize>, kind: IpiKind, acks: &AtomicUsize) {
+    acks.store(1, Ordering::Release);
+
+    let send_one = |cpu_id| {
+        acks.fetch_add(1, Ordering::AcqRel);
+        if send(cpu_id, kind).is_err() {
+            // Went offline since it was checked, so nobody will answer for it
+            acks.fetch_sub(1, Ordering::AcqRel);
+        }
+    };
+    match target {
+        Some(cpu_id) => send_one(cpu_id),
+        None => for_each_other(send_one),
+    }
+    acks.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Marks `kind` pending on `cpu_id` and interrupts it.
+pub fn send(cpu_id: usize, kind: IpiKind) -> Result<(), IpiError> {
+    let pending = PENDING.get_for(cpu_id).ok_or(IpiError::UnknownCpu)?;
+    let hw_id = HW_ID.get_for(cpu_id).ok_or(IpiError::UnknownCpu)?.load(Ordering::Relaxed);
+
+    // Marked before checking, so a CPU going offline either sees the request when it
+    // handles its last ones, or is seen offline here
+    let previous = pending.fetch_or(kind as usize, Ordering::SeqCst);
+    if !is_online(cpu_id) {
+        // Retract it, unless the CPU already took it
+        return match pending.fetch_and(!(kind as usize), Ordering::SeqCst) & kind as usize {
+            0 => Ok(()),
+            _ => Err(IpiError::Offline),
+        };
+    }
+
+    // Only interrupt if nothing was pending, otherwise the handler has yet to run
+    if previous == 0 {
+        unsafe { arch::send(cpu_id, hw_id) };
+    }
+    Ok(())
+}
+
+/// Interrupts `cpu_id` even if it is not online yet, to wake a CPU waiting for an
+/// interrupt before it runs the kernel. Nothing is marked pending.
+pub fn wake(cpu_id: usize) -> Result<(), IpiError> {
+    let hw_id = HW_ID.get_for(cpu_id).ok_or(IpiError::UnknownCpu)?.load(Ordering::Relaxed);
+    unsafe { arch::send(cpu_id, hw_id) };
+    Ok(())
+}
+
+/// Handles every request pending on the calling CPU. Called by the IPI interrupt
+/// handler, once the interrupt controller has been acknowledged, and by waiting senders.
+pub fn handle() {
+    let pending = PENDING.with(|pending| pending.swap(0, Ordering::AcqRel));
+    if pending & IpiKind::TlbShootdown as usize != 0 {
+        handle_shootdown();
+    }
+    if pending & IpiKind::Call as usize != 0 {
+        handle_call();
+    }
+}
+
+/// Virq of the IPI where it goes through [`irqchip`](crate::dtb::irqchip).
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+static IPI_VIRQ: Once<usize> = Once::new();
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+struct IpiHandler;
+
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+impl InterruptHandler for IpiHandler {
+    fn irq_handler(&mut self, _irq: usize) -> IrqReturn {
+        handle();
+        IrqReturn::Handled
+    }
+}
+
+/// Handles `hwirq` of the root domain `domain` as the IPI, and enables it on the
+/// calling CPU. Called once the domain is registered.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub fn init_irq(domain: DomainId, hwirq: u32) {
+    let virq = match IRQ_CHIP.map(domain, IrqSpec::new(hwirq, Trigger::Edge, Polarity::High)) {
+        Ok(virq) => virq,
+        Err(err) => {
+            log::error!("IPI: hwirq {} not mapped: {:?}", hwirq, err);
+            return;
+        }
+    };
+    if let Err(err) = register_irq(virq, IpiHandler) {
+        log::error!("IPI: irq {} unavailable: {:?}", virq, err);
+        return;
+    }
+    IPI_VIRQ.call_once(|| virq);
+    init_cpu();
+}
+
+/// Enables the IPI on the calling CPU, as controllers keep it enabled per CPU.
+/// Called by every AP once its interrupt controller interface is set up.
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+pub fn init_cpu() {
+    if let Some(&virq) = IPI_VIRQ.get() {
+        let _ = IRQ_CHIP.irq_enable(virq);
+    }
+}
+
+/// Takes `lock` while handling requests sent to the calling CPU, which may come
+/// from the current holder.
+fn lock_handling<T>(lock: &Mutex<T>) -> MutexGuard<'_, T> {
+    loop {
+        if let Some(guard) = lock.try_lock() {
+            return guard;
+        }
+        handle();
+        hint::spin_loop();
+    }
+}
+
+/// Spins until every target has acknowledged, handling requests meanwhile.
+fn wait_acks(acks: &AtomicUsize) {
+    while acks.load(Ordering::Acquire) != 0 {
+        handle();
+        hint::spin_loop();
+    }
+}
+
+// ---------- Function calls ----------
+
+/// The function being called; only valid while `CALL_LOCK` is held by the sender.
+struct CallRequest {
+    func: Option<*const (dyn Fn() + Sync)>,
+    acks: AtomicUsize,
+}
+
+// SAFETY: `func` is only set while the sender waits, and points to a `Sync` closure.
+unsafe impl Sync for CallRequest {}
+
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+static CALL: SyncUnsafeCell<CallRequest> = SyncUnsafeCell::new(CallRequest {
+    func: None,
+    acks: AtomicUsize::new(0),
+});
+
+fn handle_call() {
+    // SAFETY: The sender keeps the request alive until this CPU has acknowledged it.
+    let request = unsafe { &*CALL.get() };
+    if let Some(func) = request.func {
+        unsafe { (*func)() };
+    }
+    request.acks.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Runs `f` on `target`, or on every other online CPU, and on the calling CPU if
+/// `include_self`, returning once every CPU is done.
+fn call_on(target: Option<usize>, include_self: bool, f: &(dyn Fn() + Sync)) {
+    let _guard = lock_handling(&CALL_LOCK);
+    let request = CALL.get();
+
+    // SAFETY: `f` outlives the request, as every target acknowledges before it is cleared.
+    let func: *const (dyn Fn() + Sync + 'static) = unsafe { mem::transmute(f) };
+    unsafe {
+        (*request).func = Some(func);
+        send_request(target, IpiKind::Call, &(*request).acks);
+    }
+    if include_self {
+        let flags = irq_save();
+        f();
+        irq_restore(flags);
+    }
+
+    wait_acks(unsafe { &(*request).acks });
+    unsafe { (*request).func = None };
+}
+
+/// Runs `f` on `cpu_id` in interrupt context and waits for it to return.
+///
+/// `f` runs directly, with interrupts disabled, if `cpu_id` is the calling CPU. It
+/// must not take locks the caller holds.
+pub fn call_on_cpu(cpu_id: usize, f: impl Fn() + Sync) -> Result<(), IpiError> {
+    if PENDING.get_for(cpu_id).is_none() {
+        return Err(IpiError::UnknownCpu);
+    }
+    let me = percpu::cpu_id();
+    if cpu_id != me && !is_online(cpu_id) {
+        return Err(IpiError::Offline);
+    }
+
+    if cpu_id == me {
+        let flags = irq_save();
+        f();
+        irq_restore(flags);
+    } else {
+        call_on(Some(cpu_id), false, &f);
+    }
+    Ok(())
+}
+
+/// Runs `f` on every online CPU, the calling one included, and waits for all of them.
+pub fn call_on_all(f: impl Fn() + Sync) {
+    call_on(None, true, &f);
+}
+
+// ---------- TLB shootdown ----------
+
+/// Ranges a batch can hold before it turns into a full flush.
+const SHOOTDOWN_RANGES: usize = 16;
+/// Above this many pages, flushing everything is cheaper than page by page.
+const SHOOTDOWN_MAX_PAGES: usize = 64;
+
+/// A batch of kernel address ranges whose stale translations have to be flushed on
+/// every CPU. Collect ranges while changing the page tables, then call [`finish`](Self::finish).
+#[derive(Clone, Copy, Debug)]
+pub struct TlbShootdown {
+    ranges: [(usize, usize); SHOOTDOWN_RANGES],
+    count: usize,
+    pages: usize,
+    full: bool,
+}
+
+impl TlbShootdown {
+    pub const fn new() -> Self {
+        Self {
+            ranges: [(0, 0); SHOOTDOWN_RANGES],
+            count: 0,
+            pages: 0,
+            full: false,
+        }
+    }
+
+    /// Flushes the whole TLB, global pages included.
+    pub const fn all() -> Self {
+        let mut shootdown = Self::new();
+        shootdown.full = true;
+        shootdown
+    }
+
+    /// Adds the pages overlapping `start..end`, merging with the previous range if adjacent.
+    pub fn add_range(&mut self, start: VirtualAddress, end: VirtualAddress) {
+        let start = start.data() & !(PAGE_SIZE - 1);
+        let end = end.data().next_multiple_of(PAGE_SIZE);
+        if self.full || start >= end {
+            return;
+        }
+
+        self.pages += (end - start) / PAGE_SIZE;
+        if self.pages > SHOOTDOWN_MAX_PAGES {
+            self.full = true;
+        } else if let Some(last) = self.ranges[..self.count].last_mut().filter(|last| last.1 == start) {
+            last.1 = end;
+        } else if self.count < SHOOTDOWN_RANGES {
+            self.ranges[self.count] = (start, end);
+            self.count += 1;
+        } else {
+            self.full = true;
+        }
+    }
+
+    pub fn add_page(&mut self, page: Page) {
+        let start = page.start_address();
+        self.add_range(start, VirtualAddress::new(start.data() + PAGE_SIZE));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.count == 0
+    }
+
+    /// Flushes the batch on the calling CPU.
+    fn flush_local(&self) {
+        if self.full {
+            unsafe { arch::flush_all() };
+        } else {
+            for &(start, end) in &self.ranges[..self.count] {
+                for addr in (start..end).step_by(PAGE_SIZE) {
+                    unsafe { arch::flush_page(VirtualAddress::new(addr)) };
+                }
+            }
+        }
+    }
+
+    /// Flushes the batch here and on every other online CPU, returning once all of
+    /// them have acknowledged.
+    pub fn finish(self) {
+        if self.is_empty() {
+            return;
+        }
+        if arch::BROADCAST_TLB_FLUSH {
+            self.flush_local();
+            return;
+        }
+
+        let _guard = lock_handling(&SHOOTDOWN_LOCK);
+        let request = SHOOTDOWN.get();
+        unsafe {
+            (*request).batch = self;
+            send_request(None, IpiKind::TlbShootdown, &(*request).acks);
+        }
+        self.flush_local();
+
+        wait_acks(unsafe { &(*request).acks });
+    }
+}
+
+impl Default for TlbShootdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ShootdownRequest {
+    batch: TlbShootdown,
+    acks: AtomicUsize,
+}
+
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static SHOOTDOWN: SyncUnsafeCell<ShootdownRequest> = SyncUnsafeCell::new(ShootdownRequest {
+    batch: TlbShootdown::new(),
+    acks: AtomicUsize::new(0),
+});
+
+fn handle_shootdown() {
+    // SAFETY: The sender keeps the batch unchanged until this CPU has acknowledged it.
+    let request = unsafe { &*SHOOTDOWN.get() };
+    request.batch.flush_local();
+    request.acks.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Flushes every CPU's TLB entirely.
+pub fn tlb_shootdown_all() {
+    TlbShootdown::all().finish();
+}
+
+// ---------- TESTS ----------
+
+#[test]
+fn shootdown_merges_adjacent_ranges() {
+    let mut batch = TlbShootdown::new();
+    batch.add_range(VirtualAddress::new(0x1000), VirtualAddress::new(0x3000));
+    batch.add_range(VirtualAddress::new(0x3000), VirtualAddress::new(0x4800));
+    assert_eq!(batch.count, 1);
+    assert_eq!(batch.ranges[0], (0x1000, 0x5000));
+    assert_eq!(batch.pages, 4);
+    assert!(!batch.full);
+
+    batch.add_range(VirtualAddress::new(0x10_0000), VirtualAddress::new(0x10_0000 + 64 * PAGE_SIZE));
+    assert!(batch.full);
+}
@@ -0,0 +1,393 @@
+//! # Five-level paging
+//! Support for 57-bit linear addresses (LA57) on x86_64.
+//!
+//! The kernel page tables are always built as a 4-level hierarchy. With LA57 enabled
+//! every CPU has a PML5 of its own whose first and last entries both point at the
+//! PML4 it runs on, so every existing mapping keeps its address. [`La57Arch`], the
+//! rmm architecture of the paging layer on x86_64, hides that level: it hands rmm the
+//! PML4 under the loaded PML5, and loading a PML4 repoints the CPU's PML5 at it.
+//! Physical memory beyond the reach of the 4-level direct map is mapped through the
+//! 5-level-only direct map, which every PML5 shares.
+
+use core::{
+    arch::{asm, x86_64::__cpuid_count},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use rmm::{MemoryArea, TableKind, X8664Arch};
+use spin::Once;
+
+use crate::{
+    acpi::madt::{switch_paging_entry, with_trampoline},
+    memory::{allocate_frame, areas},
+    paging::{PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+};
+
+/// CR4 bit enabling 57-bit linear addresses.
+pub const CR4_LA57: usize = 1 << 12;
+
+/// Start of the direct physical map with 4-level paging (PML4 entry 256).
+pub const PHYS_OFFSET_4LEVEL: usize = 0xFFFF_8000_0000_0000;
+/// Start of the direct physical map that only exists with 5-level paging (PML5 entry 256).
+pub const PHYS_OFFSET_5LEVEL: usize = 0xFF00_0000_0000_0000;
+
+/// Physical memory covered by the 4-level direct map (PML4 entries 256..384).
+pub const DIRECT_MAP_4LEVEL_SIZE: usize = 1 << 46;
+/// Physical memory covered by the 5-level direct map (PML5 entries 256..510).
+pub const DIRECT_MAP_5LEVEL_SIZE: usize = 254 << 48;
+
+/// Environment key of the boot option forcing 4-level paging.
+const BOOT_OPTION: &[u8] = b"PAGING_LEVELS";
+
+const ENTRY_PRESENT: u64 = 1 << 0;
+const ENTRY_WRITABLE: u64 = 1 << 1;
+const ENTRY_HUGE: u64 = 1 << 7;
+const ENTRY_GLOBAL: u64 = 1 << 8;
+const ENTRY_NO_EXECUTE: u64 = 1 << 63;
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const HUGE_PAGE_SIZE: usize = 1 << 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PagingLevels {
+    Four = 4,
+    Five = 5,
+}
+
+impl PagingLevels {
+    /// Number of implemented linear address bits.
+    #[inline(always)]
+    pub fn virt_bits(self) -> u32 {
+        match self {
+            Self::Four => 48,
+            Self::Five => 57,
+        }
+    }
+
+    /// Checks that bits above the implemented width are a sign extension.
+    #[inline(always)]
+    pub fn is_canonical(self, addr: usize) -> bool {
+        let shift = usize::BITS - self.virt_bits();
+        (((addr << shift) as isize) >> shift) as usize == addr
+    }
+
+    /// Amount of physical memory that can be direct mapped.
+    #[inline(always)]
+    pub fn direct_map_size(self) -> usize {
+        match self {
+            Self::Four => DIRECT_MAP_4LEVEL_SIZE,
+            Self::Five => DIRECT_MAP_5LEVEL_SIZE,
+        }
+    }
+}
+
+static LEVELS: AtomicU8 = AtomicU8::new(PagingLevels::Four as u8);
+/// Physical address of the BSP's PML5, zero with 4-level paging.
+static PML5: AtomicUsize = AtomicUsize::new(0);
+
+/// Boot environment, handed over by `kstart` before [`init`].
+static BOOT_ENV: Once<&'static [u8]> = Once::new();
+
+/// Returns the paging depth in use.
+#[inline(always)]
+pub fn levels() -> PagingLevels {
+    match LEVELS.load(Ordering::Relaxed) {
+        5 => PagingLevels::Five,
+        _ => PagingLevels::Four,
+    }
+}
+
+/// Returns the table to load into CR3 for a kernel PML4 at `pml4`.
+#[inline(always)]
+pub fn root_table_phys(pml4: usize) -> usize {
+    match PML5.load(Ordering::Acquire) {
+        0 => pml4,
+        pml5 => pml5,
+    }
+}
+
+/// Checks CPUID.(EAX=07H,ECX=0):ECX[16].
+pub fn cpu_supports_la57() -> bool {
+    let max_leaf = unsafe { __cpuid_count(0, 0) }.eax;
+    max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ecx & (1 << 16) != 0
+}
+
+/// Checks CPUID.80000001H:EDX[26], needed for the 5-level direct map.
+fn cpu_supports_1g_pages() -> bool {
+    let max_leaf = unsafe { __cpuid_count(0x8000_0000, 0) }.eax;
+    max_leaf >= 0x8000_0001 && unsafe { __cpuid_count(0x8000_0001, 0) }.edx & (1 << 26) != 0
+}
+
+/// Returns true if the boot environment contains `PAGING_LEVELS=4`.
+pub fn forced_4level(env: &[u8]) -> bool {
+    env.split(|&b| b == b'\n' || b == b'\0')
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, |&b| b == b'=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .any(|(key, value)| key.trim_ascii() == BOOT_OPTION && value.trim_ascii() == b"4")
+}
+
+#[inline(always)]
+fn read_cr4() -> usize {
+    let value: usize;
+    unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+#[inline(always)]
+fn read_cr3() -> usize {
+    let value: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Records the boot environment, where `PAGING_LEVELS=4` keeps 4-level paging.
+pub fn set_boot_env(env: &'static [u8]) {
+    BOOT_ENV.call_once(|| env);
+}
+
+/// Selects the paging depth for the BSP and maps the physical memory only the 5-level
+/// direct map reaches. Must run with interrupts disabled, before any AP is started
+/// and before physical memory above the 4-level direct map is used.
+pub unsafe fn init() {
+    if read_cr4() & CR4_LA57 != 0 {
+        // The bootloader already enabled it; there is no way back without its tables.
+        PML5.store(read_cr3() & ENTRY_ADDRESS_MASK as usize, Ordering::Release);
+        LEVELS.store(PagingLevels::Five as u8, Ordering::Relaxed);
+        log::info!("Paging: 5-level, enabled by bootloader");
+        map_high_memory();
+        return;
+    }
+
+    if BOOT_ENV.get().is_some_and(|env| forced_4level(env)) {
+        log::info!("Paging: 4-level, forced by {}=4", core::str::from_utf8(BOOT_OPTION).unwrap_or(""));
+        return;
+    }
+
+    if !cpu_supports_la57() || !cpu_supports_1g_pages() {
+        log::info!("Paging: 4-level");
+        return;
+    }
+
+    match enable() {
+        Ok(()) => {
+            log::info!("Paging: 5-level");
+            map_high_memory();
+        }
+        Err(err) => log::warn!("Paging: staying 4-level, could not enable LA57: {}", err),
+    }
+}
+
+/// Maps the memory areas that end above the 4-level direct map.
+unsafe fn map_high_memory() {
+    for area in areas() {
+        let base = area.base.data().max(DIRECT_MAP_4LEVEL_SIZE);
+        let end = area.base.data() + area.size;
+        if end <= base {
+            continue;
+        }
+        if let Err(err) = map_high_physical(base, end - base) {
+            log::warn!("Paging: memory {:#x}..{:#x} stays unmapped: {}", base, end, err);
+        }
+    }
+}
+
+/// Gives the calling AP a PML5 of its own, a copy of the BSP's, so it can load
+/// address spaces independently. Called once per AP, before it loads any other table.
+pub unsafe fn init_ap() {
+    let bsp_pml5 = PML5.load(Ordering::Acquire);
+    if bsp_pml5 == 0 {
+        return;
+    }
+    let Some(frame) = allocate_frame() else {
+        log::warn!("Paging: out of memory for an AP's PML5, sharing the BSP's");
+        return;
+    };
+    let pml5 = frame.base().data();
+    core::ptr::copy_nonoverlapping(table_entry(bsp_pml5, 0), table_entry(pml5, 0), PAGE_SIZE / 8);
+    asm!("mov cr3, {}", in(reg) pml5, options(nostack, preserves_flags));
+}
+
+/// Returns the PML5 of the calling CPU, which stays loaded in CR3 with LA57.
+#[inline(always)]
+fn cpu_pml5() -> usize {
+    read_cr3() & ENTRY_ADDRESS_MASK as usize
+}
+
+/// Puts a PML5 on top of the current PML4 and switches the CPU to 5-level paging.
+unsafe fn enable() -> Result<(), &'static str> {
+    let pml4 = read_cr3() & ENTRY_ADDRESS_MASK as usize;
+
+    let pml5_frame = allocate_frame().ok_or("out of memory for PML5")?;
+    let pml5 = pml5_frame.base().data();
+    // CR3 is loaded from a 32-bit register while paging is off
+    if pml5 + PAGE_SIZE > 1 << 32 {
+        return Err("PML5 frame is above 4 GiB");
+    }
+
+    let table = RmmA::phys_to_virt(PhysicalAddress::new(pml5)).data() as *mut u64;
+    core::ptr::write_bytes(table, 0, PAGE_SIZE / 8);
+    // Lower half (identity mapped trampoline) and upper half (kernel, 4-level direct map)
+    let entry = pml4 as u64 | ENTRY_PRESENT | ENTRY_WRITABLE;
+    table.write(entry);
+    table.add(511).write(entry);
+
+    with_trampoline(|_| {
+        let switch_paging: extern "sysv64" fn(usize, usize) = core::mem::transmute(switch_paging_entry());
+        switch_paging(pml5, 1);
+    })
+    .ok_or("no AP trampoline to switch paging from")?;
+
+    PML5.store(pml5, Ordering::Release);
+    LEVELS.store(PagingLevels::Five as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The rmm architecture of the x86_64 paging layer: [`X8664Arch`], which walks
+/// 4 levels, with the PML5 of each CPU kept out of its sight.
+#[derive(Clone, Copy, Debug)]
+pub struct La57Arch;
+
+impl RmmArch for La57Arch {
+    const PAGE_SHIFT: usize = X8664Arch::PAGE_SHIFT;
+    const PAGE_ENTRY_SHIFT: usize = X8664Arch::PAGE_ENTRY_SHIFT;
+    const PAGE_LEVELS: usize = X8664Arch::PAGE_LEVELS;
+
+    const ENTRY_ADDRESS_WIDTH: usize = X8664Arch::ENTRY_ADDRESS_WIDTH;
+    const ENTRY_FLAG_DEFAULT_PAGE: usize = X8664Arch::ENTRY_FLAG_DEFAULT_PAGE;
+    const ENTRY_FLAG_DEFAULT_TABLE: usize = X8664Arch::ENTRY_FLAG_DEFAULT_TABLE;
+    const ENTRY_FLAG_PRESENT: usize = X8664Arch::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_READONLY: usize = X8664Arch::ENTRY_FLAG_READONLY;
+    const ENTRY_FLAG_READWRITE: usize = X8664Arch::ENTRY_FLAG_READWRITE;
+    const ENTRY_FLAG_PAGE_USER: usize = X8664Arch::ENTRY_FLAG_PAGE_USER;
+    const ENTRY_FLAG_NO_EXEC: usize = X8664Arch::ENTRY_FLAG_NO_EXEC;
+    const ENTRY_FLAG_EXEC: usize = X8664Arch::ENTRY_FLAG_EXEC;
+    const ENTRY_FLAG_GLOBAL: usize = X8664Arch::ENTRY_FLAG_GLOBAL;
+    const ENTRY_FLAG_NO_GLOBAL: usize = X8664Arch::ENTRY_FLAG_NO_GLOBAL;
+    const ENTRY_FLAG_WRITE_COMBINING: usize = X8664Arch::ENTRY_FLAG_WRITE_COMBINING;
+
+    const PHYS_OFFSET: usize = X8664Arch::PHYS_OFFSET;
+
+    unsafe fn init() -> &'static [MemoryArea] {
+        X8664Arch::init()
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate(address: VirtualAddress) {
+        X8664Arch::invalidate(address);
+    }
+
+    /// Returns the PML4 in use, the one under entry 511 of the PML5 with LA57.
+    #[inline(always)]
+    unsafe fn table(table_kind: TableKind) -> PhysicalAddress {
+        let root = X8664Arch::table(table_kind);
+        match levels() {
+            PagingLevels::Four => root,
+            PagingLevels::Five => PhysicalAddress::new((table_entry(root.data(), 511).read() & ENTRY_ADDRESS_MASK) as usize),
+        }
+    }
+
+    /// Loads the PML4 at `address`, through the calling CPU's PML5 with LA57. Only
+    /// entries 0 and 511 are rewritten; the kernel half of every PML4 is the same, so
+    /// the kernel stays mapped until CR3 is reloaded.
+    #[inline(always)]
+    unsafe fn set_table(table_kind: TableKind, address: PhysicalAddress) {
+        match levels() {
+            PagingLevels::Four => X8664Arch::set_table(table_kind, address),
+            PagingLevels::Five => {
+                let pml5 = cpu_pml5();
+                let entry = address.data() as u64 | ENTRY_PRESENT | ENTRY_WRITABLE;
+                table_entry(pml5, 0).write_volatile(entry);
+                table_entry(pml5, 511).write_volatile(entry);
+                X8664Arch::set_table(table_kind, PhysicalAddress::new(pml5));
+            }
+        }
+    }
+
+    /// Uses the 5-level direct map for memory the 4-level one cannot reach.
+    #[inline(always)]
+    unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
+        match phys_to_virt(phys.data()) {
+            Some(virt) => VirtualAddress::new(virt),
+            None => X8664Arch::phys_to_virt(phys),
+        }
+    }
+
+    #[inline(always)]
+    fn virt_is_valid(address: VirtualAddress) -> bool {
+        X8664Arch::virt_is_valid(address)
+    }
+}
+
+/// Returns the virtual address of `phys` in whichever direct map covers it.
+#[inline(always)]
+pub fn phys_to_virt(phys: usize) -> Option<usize> {
+    if phys < DIRECT_MAP_4LEVEL_SIZE {
+        Some(phys + PHYS_OFFSET_4LEVEL)
+    } else if levels() == PagingLevels::Five && phys < DIRECT_MAP_5LEVEL_SIZE {
+        Some(phys + PHYS_OFFSET_5LEVEL)
+    } else {
+        None
+    }
+}
+
+/// Maps physical memory that the 4-level direct map cannot reach into the 5-level
+/// direct map with 1 GiB pages. Both `base` and `size` are rounded out to 1 GiB.
+pub unsafe fn map_high_physical(base: usize, size: usize) -> Result<(), &'static str> {
+    let pml5 = PML5.load(Ordering::Acquire);
+    if pml5 == 0 {
+        return Err("5-level paging is not enabled");
+    }
+
+    let start = base - base % HUGE_PAGE_SIZE;
+    let end = (base + size).next_multiple_of(HUGE_PAGE_SIZE);
+    if end > DIRECT_MAP_5LEVEL_SIZE {
+        return Err("physical range exceeds the 5-level direct map");
+    }
+
+    for phys in (start..end).step_by(HUGE_PAGE_SIZE) {
+        let virt = phys + PHYS_OFFSET_5LEVEL;
+        let pml4 = next_table(pml5, (virt >> 48) & 0x1FF)?;
+        let pdpt = next_table(pml4, (virt >> 39) & 0x1FF)?;
+        let entry = table_entry(pdpt, (virt >> 30) & 0x1FF);
+        entry.write(
+            phys as u64 | ENTRY_PRESENT | ENTRY_WRITABLE | ENTRY_HUGE | ENTRY_GLOBAL | ENTRY_NO_EXECUTE,
+        );
+    }
+
+    Ok(())
+}
+
+#[inline(always)]
+unsafe fn table_entry(table: usize, index: usize) -> *mut u64 {
+    (RmmA::phys_to_virt(PhysicalAddress::new(table)).data() as *mut u64).add(index)
+}
+
+/// Returns the table referenced by `table[index]`, allocating it if absent.
+unsafe fn next_table(table: usize, index: usize) -> Result<usize, &'static str> {
+    let entry = table_entry(table, index);
+    let value = entry.read();
+    if value & ENTRY_PRESENT != 0 {
+        return Ok((value & ENTRY_ADDRESS_MASK) as usize);
+    }
+
+    let frame = allocate_frame().ok_or("out of memory for page tables")?;
+    let next = frame.base().data();
+    core::ptr::write_bytes(RmmA::phys_to_virt(PhysicalAddress::new(next)).data() as *mut u8, 0, PAGE_SIZE);
+    entry.write(next as u64 | ENTRY_PRESENT | ENTRY_WRITABLE);
+    Ok(next)
+}
+
+// ---------- TESTS ----------
+#[test]
+fn test_paging_levels() {
+    assert!(PagingLevels::Four.is_canonical(0xFFFF_8000_0000_0000));
+    assert!(!PagingLevels::Four.is_canonical(0xFF00_0000_0000_0000));
+    assert!(PagingLevels::Five.is_canonical(0xFF00_0000_0000_0000));
+    assert!(PagingLevels::Five.is_canonical(0xFFFF_FFFF_8000_0000));
+
+    assert!(forced_4level(b"REDOXFS_UUID=x\nPAGING_LEVELS=4\n"));
+    assert!(!forced_4level(b"PAGING_LEVELS=5"));
+}
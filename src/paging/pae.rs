@@ -0,0 +1,369 @@
+//! # PAE paging
+//! Physical Address Extension paging for the i686 kernel.
+//!
+//! PAE uses 64-bit entries in a 3-level hierarchy (PDPT, page directories, page
+//! tables), which gives access to physical memory above 4 GiB and, with EFER.NXE,
+//! a no-execute bit. The switch rebuilds the current 2-level tables in PAE format,
+//! marking everything outside the kernel text non-executable; from then on the
+//! paging layer maps through [`PaeArch`], so nothing is left in the 2-level tables.
+
+use core::{
+    arch::{asm, x86::__cpuid},
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use rmm::{MemoryArea, TableKind, X86Arch};
+
+use crate::{
+    acpi::madt::{switch_paging_entry, trampoline_frame, with_trampoline},
+    memory::allocate_frame,
+    paging::{PhysicalAddress, RmmA, RmmArch, VirtualAddress, PAGE_SIZE},
+};
+
+/// CR4 bit enabling PAE paging.
+pub const CR4_PAE: usize = 1 << 5;
+/// EFER bit enabling the no-execute page bit.
+pub const EFER_NXE: usize = 1 << 11;
+
+pub const ENTRY_PRESENT: u64 = 1 << 0;
+pub const ENTRY_WRITABLE: u64 = 1 << 1;
+pub const ENTRY_USER: u64 = 1 << 2;
+pub const ENTRY_HUGE: u64 = 1 << 7;
+pub const ENTRY_GLOBAL: u64 = 1 << 8;
+pub const ENTRY_NO_EXECUTE: u64 = 1 << 63;
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// The PDPT is 32-byte aligned.
+const PDPT_ADDRESS_MASK: usize = !0x1F;
+/// Available bit standing for [`ENTRY_NO_EXECUTE`] in the `usize` view of an entry.
+pub const ENTRY_SOFT_NO_EXECUTE: usize = 1 << 11;
+/// Low flag bits that mean the same thing in 32-bit and PAE entries.
+const ENTRY_LOW_FLAGS: u64 = 0xFFF;
+
+const ENTRY_COUNT: usize = 512;
+const LEGACY_ENTRY_COUNT: usize = 1024;
+const LARGE_PAGE_SIZE: usize = 2 << 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NX: AtomicBool = AtomicBool::new(false);
+/// Physical address of the PDPT, zero without PAE.
+static PDPT: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+}
+
+/// Returns whether the kernel runs with PAE paging.
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns whether the no-execute bit is honoured.
+#[inline(always)]
+pub fn nx_enabled() -> bool {
+    NX.load(Ordering::Relaxed)
+}
+
+/// Returns the table to load into CR3 for a legacy page directory at `page_directory`.
+#[inline(always)]
+pub fn root_table_phys(page_directory: usize) -> usize {
+    match PDPT.load(Ordering::Acquire) {
+        0 => page_directory,
+        pdpt => pdpt,
+    }
+}
+
+/// Checks CPUID.01H:EDX[6].
+pub fn cpu_supports_pae() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 6) != 0
+}
+
+/// Checks CPUID.80000001H:EDX[20].
+pub fn cpu_supports_nx() -> bool {
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0
+}
+
+/// A 64-bit PAE page table entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PaeEntry(pub u64);
+
+impl PaeEntry {
+    #[inline(always)]
+    pub fn new(phys: usize, flags: u64) -> Self {
+        Self(phys as u64 & ENTRY_ADDRESS_MASK | flags)
+    }
+
+    #[inline(always)]
+    pub fn is_present(self) -> bool {
+        self.0 & ENTRY_PRESENT != 0
+    }
+
+    #[inline(always)]
+    pub fn address(self) -> usize {
+        (self.0 & ENTRY_ADDRESS_MASK) as usize
+    }
+
+    #[inline(always)]
+    pub fn flags(self) -> u64 {
+        self.0 & !ENTRY_ADDRESS_MASK
+    }
+
+    /// Returns the entry as rmm sees it.
+    #[inline(always)]
+    fn to_rmm(self) -> usize {
+        let low = self.0 as u32 as usize & !ENTRY_SOFT_NO_EXECUTE;
+        if self.0 & ENTRY_NO_EXECUTE != 0 { low | ENTRY_SOFT_NO_EXECUTE } else { low }
+    }
+
+    /// Builds the entry rmm wants written.
+    #[inline(always)]
+    fn from_rmm(data: usize) -> Self {
+        let entry = (data & !ENTRY_SOFT_NO_EXECUTE) as u64;
+        if data & ENTRY_SOFT_NO_EXECUTE != 0 && nx_enabled() {
+            Self(entry | ENTRY_NO_EXECUTE)
+        } else {
+            Self(entry)
+        }
+    }
+}
+
+#[inline(always)]
+unsafe fn table<T>(phys: usize) -> *mut T {
+    RmmA::phys_to_virt(PhysicalAddress::new(phys)).data() as *mut T
+}
+
+/// Allocates a zeroed frame for a page table.
+unsafe fn allocate_table() -> Result<usize, &'static str> {
+    let frame = allocate_frame().ok_or("out of memory for page tables")?;
+    let phys = frame.base().data();
+    ptr::write_bytes(table::<u8>(phys), 0, PAGE_SIZE);
+    Ok(phys)
+}
+
+/// Allocates an empty hierarchy with all four page directories present, as PDPT
+/// entries are only reloaded on a CR3 write, returning its PDPT.
+unsafe fn allocate_hierarchy() -> Result<usize, &'static str> {
+    let pdpt = allocate_table()?;
+    // CR3 holds a 32-bit physical address with PAE on i686
+    if pdpt > u32::MAX as usize - PAGE_SIZE {
+        return Err("PDPT frame is above 4 GiB");
+    }
+    for i in 0..4 {
+        let directory = allocate_table()?;
+        // PDPT entries only have the present and caching bits
+        table::<PaeEntry>(pdpt).add(i).write(PaeEntry::new(directory, ENTRY_PRESENT));
+    }
+    Ok(pdpt)
+}
+
+/// Returns the page directory entry covering `virt`.
+unsafe fn directory_entry(pdpt: usize, virt: usize) -> *mut PaeEntry {
+    let directory = table::<PaeEntry>(pdpt).add(virt >> 30).read().address();
+    table::<PaeEntry>(directory).add((virt >> 21) & (ENTRY_COUNT - 1))
+}
+
+/// Returns the page table entry for `virt`, allocating the page table if needed.
+unsafe fn page_entry(pdpt: usize, virt: usize) -> Result<*mut PaeEntry, &'static str> {
+    let pde = directory_entry(pdpt, virt);
+    let directory_entry = pde.read();
+    if directory_entry.0 & ENTRY_HUGE != 0 {
+        return Err("address is covered by a 2 MiB page");
+    }
+
+    let page_table = if directory_entry.is_present() {
+        directory_entry.address()
+    } else {
+        let page_table = allocate_table()?;
+        // Permissions are enforced on the last level
+        pde.write(PaeEntry::new(page_table, ENTRY_PRESENT | ENTRY_WRITABLE | ENTRY_USER));
+        page_table
+    };
+
+    Ok(table::<PaeEntry>(page_table).add((virt >> 12) & (ENTRY_COUNT - 1)))
+}
+
+/// The rmm architecture of the i686 paging layer once PAE is enabled.
+///
+/// rmm handles entries as `usize`, the low half of a PAE entry, so reads and writes
+/// of that size carry the whole entry: the no-execute bit is folded into
+/// [`ENTRY_SOFT_NO_EXECUTE`], an available bit, and dropped where NX is
+/// unsupported, since bit 63 is reserved there. Frames mapped through rmm lie below
+/// 4 GiB. PDPTs must be created with all four page directories present, as the
+/// kernel one is.
+#[derive(Clone, Copy, Debug)]
+pub struct PaeArch;
+
+impl RmmArch for PaeArch {
+    const PAGE_SHIFT: usize = 12;
+    const PAGE_ENTRY_SHIFT: usize = 9;
+    const PAGE_LEVELS: usize = 3;
+
+    const ENTRY_ADDRESS_WIDTH: usize = 20;
+    const ENTRY_FLAG_DEFAULT_PAGE: usize = Self::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_DEFAULT_TABLE: usize =
+        Self::ENTRY_FLAG_PRESENT | Self::ENTRY_FLAG_READWRITE | Self::ENTRY_FLAG_PAGE_USER;
+    const ENTRY_FLAG_PRESENT: usize = ENTRY_PRESENT as usize;
+    const ENTRY_FLAG_READONLY: usize = 0;
+    const ENTRY_FLAG_READWRITE: usize = ENTRY_WRITABLE as usize;
+    const ENTRY_FLAG_PAGE_USER: usize = ENTRY_USER as usize;
+    const ENTRY_FLAG_NO_EXEC: usize = ENTRY_SOFT_NO_EXECUTE;
+    const ENTRY_FLAG_EXEC: usize = 0;
+    const ENTRY_FLAG_GLOBAL: usize = ENTRY_GLOBAL as usize;
+    const ENTRY_FLAG_NO_GLOBAL: usize = 0;
+    const ENTRY_FLAG_WRITE_COMBINING: usize = X86Arch::ENTRY_FLAG_WRITE_COMBINING;
+
+    const PHYS_OFFSET: usize = X86Arch::PHYS_OFFSET;
+
+    unsafe fn init() -> &'static [MemoryArea] {
+        X86Arch::init()
+    }
+
+    #[inline(always)]
+    unsafe fn read<T>(address: VirtualAddress) -> T {
+        if mem::size_of::<T>() != mem::size_of::<usize>() {
+            return ptr::read(address.data() as *const T);
+        }
+        let entry = ptr::read(address.data() as *const PaeEntry).to_rmm();
+        mem::transmute_copy(&entry)
+    }
+
+    #[inline(always)]
+    unsafe fn write<T>(address: VirtualAddress, value: T) {
+        if mem::size_of::<T>() != mem::size_of::<usize>() {
+            return ptr::write(address.data() as *mut T, value);
+        }
+        let entry = PaeEntry::from_rmm(mem::transmute_copy(&value));
+        ptr::write(address.data() as *mut PaeEntry, entry);
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate(address: VirtualAddress) {
+        invalidate(address.data());
+    }
+
+    #[inline(always)]
+    unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
+        PhysicalAddress::new(read_cr3() & PDPT_ADDRESS_MASK)
+    }
+
+    #[inline(always)]
+    unsafe fn set_table(_table_kind: TableKind, address: PhysicalAddress) {
+        asm!("mov cr3, {}", in(reg) address.data(), options(nostack, preserves_flags));
+    }
+
+    #[inline(always)]
+    unsafe fn phys_to_virt(phys: PhysicalAddress) -> VirtualAddress {
+        X86Arch::phys_to_virt(phys)
+    }
+
+    #[inline(always)]
+    fn virt_is_valid(_address: VirtualAddress) -> bool {
+        true
+    }
+}
+
+#[inline(always)]
+unsafe fn invalidate(virt: usize) {
+    asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags));
+}
+
+#[inline(always)]
+fn read_cr3() -> usize {
+    let value: usize;
+    unsafe { asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Returns whether the page at `virt` may stay executable: the kernel text and the
+/// identity-mapped trampoline, which the switch itself runs from.
+fn is_executable(virt: usize) -> bool {
+    let text = unsafe { (&raw const __text_start as usize)..(&raw const __text_end as usize) };
+    let trampoline = trampoline_frame().map(|frame| frame.base().data());
+    text.contains(&virt) || trampoline == Some(virt & !(PAGE_SIZE - 1))
+}
+
+/// Copies every mapping of the 32-bit page directory at `page_directory` into the
+/// hierarchy under `pdpt`.
+unsafe fn mirror_legacy_tables(pdpt: usize, page_directory: usize) -> Result<(), &'static str> {
+    let nx = if nx_enabled() { ENTRY_NO_EXECUTE } else { 0 };
+
+    for pd_index in 0..LEGACY_ENTRY_COUNT {
+        let pde = u64::from(table::<u32>(page_directory).add(pd_index).read());
+        if pde & ENTRY_PRESENT == 0 {
+            continue;
+        }
+        let virt_base = pd_index << 22;
+
+        if pde & ENTRY_HUGE != 0 {
+            // A 4 MiB page becomes two 2 MiB pages
+            let phys = (pde & 0xFFC0_0000) as usize;
+            for half in 0..2 {
+                let virt = virt_base + half * LARGE_PAGE_SIZE;
+                let exec = (0..LARGE_PAGE_SIZE).step_by(PAGE_SIZE).any(|off| is_executable(virt + off));
+                let flags = (pde & ENTRY_LOW_FLAGS) | if exec { 0 } else { nx };
+                directory_entry(pdpt, virt).write(PaeEntry::new(phys + half * LARGE_PAGE_SIZE, flags));
+            }
+            continue;
+        }
+
+        let page_table = (pde & 0xFFFF_F000) as usize;
+        for pt_index in 0..LEGACY_ENTRY_COUNT {
+            let pte = u64::from(table::<u32>(page_table).add(pt_index).read());
+            if pte & ENTRY_PRESENT == 0 {
+                continue;
+            }
+            let virt = virt_base + (pt_index << 12);
+            let flags = (pte & ENTRY_LOW_FLAGS) | if is_executable(virt) { 0 } else { nx };
+            page_entry(pdpt, virt)?.write(PaeEntry::new((pte & 0xFFFF_F000) as usize, flags));
+        }
+    }
+
+    Ok(())
+}
+
+/// Switches the BSP from 32-bit to PAE paging, enabling NX where supported.
+///
+/// Must run with interrupts disabled and before any AP is started. Returns an error,
+/// leaving 32-bit paging in place, if the CPU lacks PAE or memory runs out.
+pub unsafe fn init() -> Result<(), &'static str> {
+    if !cpu_supports_pae() {
+        return Err("CPU does not support PAE");
+    }
+    NX.store(cpu_supports_nx(), Ordering::Relaxed);
+
+    let efer = if nx_enabled() { EFER_NXE } else { 0 };
+
+    with_trampoline(|_| -> Result<(), &'static str> {
+        // Built while the trampoline is identity mapped, so the switch can return
+        let pdpt = allocate_hierarchy()?;
+        mirror_legacy_tables(pdpt, read_cr3() & 0xFFFF_F000)?;
+
+        let switch_paging: extern "C" fn(usize, usize, usize) = core::mem::transmute(switch_paging_entry());
+        switch_paging(pdpt, CR4_PAE, efer);
+        // Set before returning, so the identity mapping, which was copied along with
+        // everything else, is removed from the PAE tables
+        PDPT.store(pdpt, Ordering::Release);
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+    .ok_or("no AP trampoline to switch paging from")??;
+
+    log::info!("Paging: PAE, NX {}", if nx_enabled() { "enabled" } else { "unsupported" });
+    Ok(())
+}
+
+// ---------- TESTS ----------
+#[test]
+fn test_pae_entry() {
+    let entry = PaeEntry::new(0x1_2345_6000, ENTRY_PRESENT | ENTRY_WRITABLE | ENTRY_NO_EXECUTE);
+    assert!(entry.is_present());
+    assert_eq!(entry.address(), 0x1_2345_6000);
+    assert_eq!(entry.flags(), ENTRY_PRESENT | ENTRY_WRITABLE | ENTRY_NO_EXECUTE);
+
+    let entry = PaeEntry::new(0x1234_5000, ENTRY_PRESENT | ENTRY_NO_EXECUTE);
+    assert_eq!(entry.to_rmm(), 0x1234_5000 | ENTRY_PRESENT as usize | ENTRY_SOFT_NO_EXECUTE);
+}
@@ -0,0 +1,39 @@
+//! The area base lives in `TPIDR_EL1`, which user mode cannot read.
+
+use core::arch::asm;
+
+/// DAIF bit masking IRQs.
+const DAIF_I: usize = 1 << 7;
+
+pub unsafe fn set_base(base: usize) {
+    asm!("msr tpidr_el1, {}", in(reg) base, options(nostack, preserves_flags));
+}
+
+#[inline(always)]
+pub fn base() -> usize {
+    let base: usize;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) base, options(nomem, nostack, preserves_flags)) };
+    base
+}
+
+/// Masks IRQs, returning DAIF for [`irq_restore`].
+#[inline(always)]
+pub fn irq_save() -> usize {
+    let flags: usize;
+    unsafe { asm!("mrs {}, daif", "msr daifset, #2", out(reg) flags, options(nomem, nostack)) };
+    flags
+}
+
+/// Unmasks IRQs.
+#[inline(always)]
+pub fn irq_enable() {
+    unsafe { asm!("msr daifclr, #2", options(nomem, nostack)) };
+}
+
+/// Unmasks IRQs again if they were unmasked when `flags` was saved.
+#[inline(always)]
+pub fn irq_restore(flags: usize) {
+    if flags & DAIF_I == 0 {
+        unsafe { asm!("msr daifclr, #2", options(nomem, nostack)) };
+    }
+}
@@ -0,0 +1,40 @@
+//! The area base lives in `tp`. Trap entry from user mode has to swap in the
+//! kernel's value, kept in `sscratch`, before touching per-CPU data.
+
+use core::arch::asm;
+
+/// `sstatus.SIE`
+const SSTATUS_SIE: usize = 1 << 1;
+
+pub unsafe fn set_base(base: usize) {
+    asm!("mv tp, {}", in(reg) base, options(nomem, nostack, preserves_flags));
+}
+
+#[inline(always)]
+pub fn base() -> usize {
+    let base: usize;
+    unsafe { asm!("mv {}, tp", out(reg) base, options(nomem, nostack, preserves_flags)) };
+    base
+}
+
+/// Disables supervisor interrupts, returning `sstatus` for [`irq_restore`].
+#[inline(always)]
+pub fn irq_save() -> usize {
+    let flags: usize;
+    unsafe { asm!("csrrci {}, sstatus, 2", out(reg) flags, options(nomem, nostack)) };
+    flags
+}
+
+/// Enables supervisor interrupts.
+#[inline(always)]
+pub fn irq_enable() {
+    unsafe { asm!("csrsi sstatus, 2", options(nomem, nostack)) };
+}
+
+/// Enables supervisor interrupts again if they were enabled when `flags` was saved.
+#[inline(always)]
+pub fn irq_restore(flags: usize) {
+    if flags & SSTATUS_SIE != 0 {
+        unsafe { asm!("csrsi sstatus, 2", options(nomem, nostack)) };
+    }
+}
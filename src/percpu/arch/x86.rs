@@ -0,0 +1,122 @@
+//! The area base lives in FS. Protected mode has no base MSR, so the base is set
+//! through a data descriptor in a slot appended to a private copy of the GDT the
+//! calling CPU has loaded.
+//!
+//! APs set their base while still on the trampoline's GDT, and `kstart_ap` loads
+//! the kernel's afterwards, so the slot is put back on interrupt entry whenever the
+//! loaded GDT lacks it.
+
+use alloc::vec;
+use core::{
+    arch::asm,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{device::local_apic::the_local_apic, ipi};
+
+/// GDT index of the per-CPU descriptor, right after the entries of the kernel's GDT,
+/// which the BSP has loaded when it first sets its base. Zero until then.
+static GDT_PERCPU: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u32,
+}
+
+#[inline(always)]
+fn loaded_gdt() -> DescriptorTablePointer {
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack, preserves_flags)) };
+    gdtr
+}
+
+/// Loads FS with the per-CPU selector, on interrupt entry. A CPU whose loaded GDT
+/// has no per-CPU slot gets one first, its area found through its local APIC ID.
+pub unsafe fn load_selector() {
+    let index = GDT_PERCPU.load(Ordering::Relaxed);
+    if (usize::from(loaded_gdt().limit) + 1) / 8 <= index {
+        let apic_id = u64::from(the_local_apic().id());
+        if let Some(cpu_id) = super::cpus().into_iter().find(|&cpu_id| ipi::hw_id(cpu_id) == Some(apic_id)) {
+            // Loads FS as well
+            set_base(super::allocate(cpu_id));
+            return;
+        }
+    }
+    let selector = (index * 8) as u16;
+    asm!("mov fs, {:x}", in(reg) selector, options(nostack, preserves_flags));
+}
+
+/// Writes a flat, writable, ring 0 data descriptor starting at `base` into the
+/// per-CPU slot of the calling CPU's GDT and loads FS with it. Unless the loaded GDT
+/// has the slot already, the CPU moves to a private copy of it with the slot added;
+/// entries the copied GDT lacks are left empty, as the trampoline's GDT only has
+/// the ones needed until the kernel's is loaded.
+pub unsafe fn set_base(base: usize) {
+    let gdtr = loaded_gdt();
+    let entries = (usize::from(gdtr.limit) + 1) / 8;
+
+    let index = match GDT_PERCPU.compare_exchange(0, entries, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => entries,
+        Err(index) => index,
+    };
+    let gdt = if entries > index {
+        // Already the private copy
+        gdtr.base as *mut u64
+    } else {
+        let copy = vec![0u64; index + 1].leak();
+        copy[..entries].copy_from_slice(slice::from_raw_parts(gdtr.base as *const u64, entries));
+        copy.as_mut_ptr()
+    };
+
+    let base = base as u64;
+    // Limit 0xFFFFF in 4 KiB pages, present, DPL 0, read/write data, 32-bit
+    let descriptor = 0xFFFF
+        | (base & 0xFF_FFFF) << 16
+        | 0x92 << 40
+        | 0xF << 48
+        | 0xC << 52
+        | (base >> 24 & 0xFF) << 56;
+    core::ptr::write_volatile(gdt.add(index), descriptor);
+
+    // Loaded segments, TR included, keep their cached descriptors
+    let gdtr = DescriptorTablePointer {
+        limit: ((index + 1) * 8 - 1) as u16,
+        base: gdt as u32,
+    };
+    asm!("lgdt [{}]", in(reg) &gdtr, options(nostack, preserves_flags));
+
+    let selector = (index * 8) as u16;
+    asm!("mov fs, {:x}", in(reg) selector, options(nostack, preserves_flags));
+}
+
+/// Reads the base back from the header's self pointer.
+#[inline(always)]
+pub fn base() -> usize {
+    let base: usize;
+    unsafe { asm!("mov {}, fs:[0]", out(reg) base, options(nostack, readonly, preserves_flags)) };
+    base
+}
+
+/// Disables interrupts, returning EFLAGS for [`irq_restore`].
+#[inline(always)]
+pub fn irq_save() -> usize {
+    let flags: usize;
+    unsafe { asm!("pushfd", "pop {}", "cli", out(reg) flags, options(nomem)) };
+    flags
+}
+
+/// Enables interrupts.
+#[inline(always)]
+pub fn irq_enable() {
+    unsafe { asm!("sti", options(nomem, nostack)) };
+}
+
+/// Enables interrupts again if they were enabled when `flags` was saved.
+#[inline(always)]
+pub fn irq_restore(flags: usize) {
+    if flags & (1 << 9) != 0 {
+        unsafe { asm!("sti", options(nomem, nostack)) };
+    }
+}
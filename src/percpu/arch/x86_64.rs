@@ -0,0 +1,43 @@
+//! The area base lives in GS. Kernel code always runs with the kernel's GS base
+//! loaded, so entry from user mode has to `swapgs` before touching per-CPU data.
+
+use core::arch::asm;
+
+use crate::device::msr::{wrmsr, IA32_GS_BASE, IA32_KERNEL_GS_BASE};
+
+/// Loads `base` as the GS base of the calling CPU. The inactive base is cleared so
+/// the first `swapgs` from user mode does not leak the area.
+pub unsafe fn set_base(base: usize) {
+    wrmsr(IA32_GS_BASE, base as u64);
+    wrmsr(IA32_KERNEL_GS_BASE, 0);
+}
+
+/// Reads the base back from the header's self pointer, which avoids `rdmsr`.
+#[inline(always)]
+pub fn base() -> usize {
+    let base: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) base, options(nostack, readonly, preserves_flags)) };
+    base
+}
+
+/// Disables interrupts, returning RFLAGS for [`irq_restore`].
+#[inline(always)]
+pub fn irq_save() -> usize {
+    let flags: usize;
+    unsafe { asm!("pushfq", "pop {}", "cli", out(reg) flags, options(nomem)) };
+    flags
+}
+
+/// Enables interrupts.
+#[inline(always)]
+pub fn irq_enable() {
+    unsafe { asm!("sti", options(nomem, nostack)) };
+}
+
+/// Enables interrupts again if they were enabled when `flags` was saved.
+#[inline(always)]
+pub fn irq_restore(flags: usize) {
+    if flags & (1 << 9) != 0 {
+        unsafe { asm!("sti", options(nomem, nostack)) };
+    }
+}
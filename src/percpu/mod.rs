@@ -0,0 +1,258 @@
+//! # Per-CPU data
+//! Every CPU gets its own copy of the `.percpu` section, filled from the template in
+//! the kernel image. The running CPU finds its copy through a base register: the
+//! kernel GS base on x86_64, FS on i686, `TPIDR_EL1` on aarch64 and `tp` on riscv64.
+//!
+//! Variables are declared with [`percpu!`](crate::percpu) and read through guards
+//! that keep preemption disabled, so a reference never outlives a migration.
+
+use alloc::{
+    alloc::{alloc_zeroed, Layout},
+    vec::Vec,
+};
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem,
+    ops::Deref,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::RwLock;
+
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64.rs"]
+mod arch;
+
+#[cfg(target_arch = "riscv64")]
+#[path = "arch/riscv64.rs"]
+mod arch;
+
+#[cfg(target_arch = "x86")]
+#[path = "arch/x86.rs"]
+mod arch;
+
+#[cfg(target_arch = "x86_64")]
+#[path = "arch/x86_64.rs"]
+mod arch;
+
+pub use self::arch::{irq_enable, irq_restore, irq_save};
+#[cfg(target_arch = "x86")]
+pub use self::arch::load_selector;
+
+unsafe extern "C" {
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+/// Declares per-CPU statics, each of type [`PerCpu<T>`](crate::percpu::PerCpu).
+///
+/// ```ignore
+/// percpu! {
+///     /// Ticks seen by this CPU
+///     pub static TICKS: Cell<u64> = Cell::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            #[unsafe(link_section = ".percpu")]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )+
+    };
+}
+
+/// Start of every per-CPU area, in front of the copy of the template.
+#[repr(C, align(64))]
+struct Header {
+    /// Address of the header itself, so the base can be read through the base register.
+    this: usize,
+    cpu_id: usize,
+    preempt_count: AtomicUsize,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+/// Base address of each CPU's area, indexed by CPU ID; zero if not allocated.
+static AREAS: RwLock<Vec<usize>> = RwLock::new(Vec::new());
+
+#[inline(always)]
+fn template() -> (usize, usize) {
+    unsafe { (&raw const __percpu_start as usize, &raw const __percpu_end as usize) }
+}
+
+#[inline(always)]
+fn header() -> &'static Header {
+    unsafe { &*(arch::base() as *const Header) }
+}
+
+/// Allocates and fills the area of `cpu_id`, returning its base. Called by the CPU
+/// starting an AP, or by a CPU for itself; an existing area is reused.
+pub fn allocate(cpu_id: usize) -> usize {
+    if let Some(&base) = AREAS.read().get(cpu_id).filter(|&&base| base != 0) {
+        return base;
+    }
+
+    let (start, end) = template();
+    let layout = Layout::from_size_align(HEADER_SIZE + (end - start), mem::align_of::<Header>())
+        .expect("invalid per-CPU area layout");
+    let base = unsafe { alloc_zeroed(layout) } as usize;
+    assert!(base != 0, "out of memory for the per-CPU area of CPU {}", cpu_id);
+
+    unsafe {
+        ptr::write(
+            base as *mut Header,
+            Header {
+                this: base,
+                cpu_id,
+                preempt_count: AtomicUsize::new(0),
+            },
+        );
+        ptr::copy_nonoverlapping(start as *const u8, (base + HEADER_SIZE) as *mut u8, end - start);
+    }
+
+    let mut areas = AREAS.write();
+    if areas.len() <= cpu_id {
+        areas.resize(cpu_id + 1, 0);
+    }
+    areas[cpu_id] = base;
+    base
+}
+
+/// Points the calling CPU's base register at the area of `cpu_id`, allocating it
+/// if needed. Must run on every CPU before it touches per-CPU data.
+pub unsafe fn init(cpu_id: usize) {
+    arch::set_base(allocate(cpu_id));
+}
+
+/// Returns the ID of the calling CPU.
+#[inline(always)]
+pub fn cpu_id() -> usize {
+    header().cpu_id
+}
+
+/// Returns the IDs of every CPU with an area.
+pub fn cpus() -> Vec<usize> {
+    let areas = AREAS.read();
+    (0..areas.len()).filter(|&cpu_id| areas[cpu_id] != 0).collect()
+}
+
+/// Calls `f` with the ID of every CPU with an area, without allocating. `f` must not
+/// allocate areas itself.
+pub fn for_each_cpu(mut f: impl FnMut(usize)) {
+    let areas = AREAS.read();
+    for (cpu_id, &base) in areas.iter().enumerate() {
+        if base != 0 {
+            f(cpu_id);
+        }
+    }
+}
+
+/// Returns how deeply preemption is disabled on the calling CPU; the scheduler only
+/// switches contexts at zero.
+#[inline(always)]
+pub fn preempt_count() -> usize {
+    header().preempt_count.load(Ordering::Relaxed)
+}
+
+pub fn preempt_disable() {
+    // Interrupts are off so that the counter of the CPU we were on is the one changed
+    let flags = irq_save();
+    header().preempt_count.fetch_add(1, Ordering::Relaxed);
+    irq_restore(flags);
+}
+
+pub fn preempt_enable() {
+    let flags = irq_save();
+    let previous = header().preempt_count.fetch_sub(1, Ordering::Relaxed);
+    irq_restore(flags);
+    debug_assert!(previous != 0, "unbalanced preempt_enable");
+}
+
+/// Keeps preemption disabled while alive; not `Send`, as it belongs to one CPU.
+pub struct PreemptGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Disables preemption until the returned guard is dropped.
+pub fn preempt_guard() -> PreemptGuard {
+    preempt_disable();
+    PreemptGuard { _not_send: PhantomData }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// A variable with one instance per CPU, declared with [`percpu!`](crate::percpu).
+///
+/// The static itself is only the template; every access goes to the running CPU's
+/// copy. Use `Cell`, `RefCell` or atomics inside for mutation.
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>,
+}
+
+// SAFETY: The template is never accessed, and each copy is only borrowed by its own
+// CPU with preemption disabled, or through `get_for` which requires `T: Sync`.
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            template: UnsafeCell::new(value),
+        }
+    }
+
+    /// Offset of this variable from the base of an area.
+    #[inline(always)]
+    fn offset(&'static self) -> usize {
+        let (start, end) = template();
+        let addr = self.template.get() as usize;
+        debug_assert!((start..end).contains(&addr), "per-CPU static declared outside percpu!");
+        HEADER_SIZE + (addr - start)
+    }
+
+    /// Borrows the calling CPU's instance, disabling preemption until the guard is dropped.
+    #[inline(always)]
+    pub fn get(&'static self) -> PerCpuRef<T> {
+        let preempt = preempt_guard();
+        let value = unsafe { &*((arch::base() + self.offset()) as *const T) };
+        PerCpuRef { value, _preempt: preempt }
+    }
+
+    /// Runs `f` on the calling CPU's instance with preemption disabled.
+    #[inline(always)]
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.get())
+    }
+
+    /// Returns the instance of another CPU, if it has an area.
+    pub fn get_for(&'static self, cpu_id: usize) -> Option<&'static T>
+    where
+        T: Sync,
+    {
+        let base = *AREAS.read().get(cpu_id).filter(|&&base| base != 0)?;
+        Some(unsafe { &*((base + self.offset()) as *const T) })
+    }
+}
+
+/// Borrow of the running CPU's instance of a [`PerCpu`] variable.
+pub struct PerCpuRef<T: 'static> {
+    value: &'static T,
+    _preempt: PreemptGuard,
+}
+
+impl<T> Deref for PerCpuRef<T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
@@ -0,0 +1,286 @@
+//! # Deferred interrupt work
+//! Interrupt handlers can be split in two. The top half runs in interrupt context
+//! with the line acknowledged; it only quiets the device and asks for the bottom
+//! half, which runs later on the same CPU with interrupts enabled.
+//!
+//! Bottom halves are [`Work`] items queued per CPU, either as softirqs, run when the
+//! outermost interrupt returns, or for the CPU's IRQ thread, which the scheduler runs
+//! like any other thread. Softirq processing is bounded: work still pending after
+//! [`MAX_SOFTIRQ_RESTART`] rounds or [`MAX_SOFTIRQ_WORK`] items is handed to the IRQ
+//! thread, so a storm cannot keep the interrupted thread from running.
+//!
+//! Queueing never allocates, so top halves may queue work freely.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use spin::Once;
+
+pub use crate::dtb::irqchip::IrqReturn;
+
+use crate::{
+    dtb::irqchip::InterruptHandler,
+    percpu::{self, irq_enable, irq_restore, irq_save},
+};
+
+/// Rounds of newly queued softirqs run before the rest goes to the IRQ thread.
+pub const MAX_SOFTIRQ_RESTART: usize = 10;
+
+/// Softirq work items run on one interrupt return before the rest goes to the IRQ thread.
+pub const MAX_SOFTIRQ_WORK: usize = 64;
+
+/// Where a bottom half runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deferral {
+    /// On the return from the outermost interrupt, in interrupt context but with
+    /// interrupts enabled. Must not block.
+    Softirq,
+    /// In the CPU's IRQ thread, which may block and is scheduled normally.
+    Thread,
+}
+
+/// A bottom half, queued at most once at a time.
+pub struct Work {
+    /// Next item of the per-CPU list this one is queued on.
+    next: AtomicPtr<Work>,
+    queued: AtomicBool,
+    deferral: Deferral,
+    func: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Work {
+    pub fn new(deferral: Deferral, func: impl Fn() + Send + Sync + 'static) -> Arc<Self> {
+        Arc::new(Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(false),
+            deferral,
+            func: Box::new(func),
+        })
+    }
+
+    pub fn is_queued(&self) -> bool {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+crate::percpu! {
+    /// Softirq work queued on the CPU, most recent first.
+    static SOFTIRQ_LIST: AtomicPtr<Work> = AtomicPtr::new(ptr::null_mut());
+    /// Work for the CPU's IRQ thread, most recent first.
+    static THREAD_LIST: AtomicPtr<Work> = AtomicPtr::new(ptr::null_mut());
+    /// Nesting of interrupt handlers on the CPU.
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+    /// Set while the CPU runs softirqs, which interrupts on top must not restart.
+    static IN_SOFTIRQ: AtomicBool = AtomicBool::new(false);
+}
+
+/// Wakes the IRQ thread of a CPU, installed by the scheduler.
+static THREAD_WAKER: Once<fn(usize)> = Once::new();
+
+/// Installs the function waking the IRQ thread of a CPU, which then calls
+/// [`run_thread`]. Without one, idle CPUs have to poll [`has_thread_work`].
+pub fn set_thread_waker(waker: fn(usize)) {
+    THREAD_WAKER.call_once(|| waker);
+}
+
+fn wake_thread(cpu_id: usize) {
+    if let Some(waker) = THREAD_WAKER.get() {
+        waker(cpu_id);
+    }
+}
+
+/// Pushes a queued item, owning one reference, onto `list`.
+fn push(list: &AtomicPtr<Work>, work: *mut Work) {
+    let mut head = list.load(Ordering::Relaxed);
+    loop {
+        unsafe { (*work).next.store(head, Ordering::Relaxed) };
+        match list.compare_exchange_weak(head, work, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(current) => head = current,
+        }
+    }
+}
+
+/// Takes every item of `list`, oldest first.
+fn take_all(list: &AtomicPtr<Work>) -> *mut Work {
+    let mut head = list.swap(ptr::null_mut(), Ordering::Acquire);
+    let mut reversed = ptr::null_mut();
+    while !head.is_null() {
+        let next = unsafe { (*head).next.load(Ordering::Relaxed) };
+        unsafe { (*head).next.store(reversed, Ordering::Relaxed) };
+        reversed = head;
+        head = next;
+    }
+    reversed
+}
+
+/// Pushes the items taken from a list onto `list`, keeping their order.
+fn push_all(mut next: *mut Work, list: &AtomicPtr<Work>) {
+    while !next.is_null() {
+        let work = next;
+        next = unsafe { (*work).next.load(Ordering::Relaxed) };
+        push(list, work);
+    }
+}
+
+/// Queues `work` on the calling CPU. Returns false if it was queued already, in
+/// which case it runs once for both requests.
+pub fn schedule(work: &Arc<Work>) -> bool {
+    if work.queued.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    let raw = Arc::into_raw(work.clone()) as *mut Work;
+
+    let flags = irq_save();
+    match work.deferral {
+        Deferral::Softirq => SOFTIRQ_LIST.with(|list| push(list, raw)),
+        Deferral::Thread => {
+            THREAD_LIST.with(|list| push(list, raw));
+            wake_thread(percpu::cpu_id());
+        }
+    }
+    irq_restore(flags);
+    true
+}
+
+/// Runs and releases a taken item. Its flag is cleared first so it can queue itself again.
+unsafe fn run(raw: *mut Work) {
+    let work = Arc::from_raw(raw);
+    work.queued.store(false, Ordering::Release);
+    (work.func)();
+}
+
+/// Marks the start of an interrupt handler on the calling CPU. Called by the arch
+/// interrupt entry with interrupts disabled.
+pub fn irq_enter() {
+    IRQ_DEPTH.with(|depth| depth.fetch_add(1, Ordering::Relaxed));
+}
+
+/// Marks the end of an interrupt handler, running pending softirqs when leaving the
+/// outermost one. Called by the arch interrupt entry with interrupts disabled, once
+/// the interrupt controller was sent its EOI.
+pub fn irq_exit() {
+    let depth = IRQ_DEPTH.with(|depth| depth.fetch_sub(1, Ordering::Relaxed) - 1);
+    if depth == 0 && !IN_SOFTIRQ.with(|flag| flag.load(Ordering::Relaxed)) {
+        run_softirqs();
+    }
+}
+
+/// Returns whether the calling CPU is in an interrupt handler or a softirq.
+pub fn in_interrupt() -> bool {
+    IRQ_DEPTH.with(|depth| depth.load(Ordering::Relaxed)) != 0 || IN_SOFTIRQ.with(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Runs the softirqs of the calling CPU within the bounds, with interrupts enabled
+/// meanwhile. Entered and left with interrupts disabled.
+fn run_softirqs() {
+    let _preempt = percpu::preempt_guard();
+    IN_SOFTIRQ.with(|flag| flag.store(true, Ordering::Relaxed));
+
+    let mut budget = MAX_SOFTIRQ_WORK;
+    for _ in 0..MAX_SOFTIRQ_RESTART {
+        let mut next = SOFTIRQ_LIST.with(take_all);
+        if next.is_null() {
+            break;
+        }
+        irq_enable();
+        while !next.is_null() && budget > 0 {
+            let work = next;
+            next = unsafe { (*work).next.load(Ordering::Relaxed) };
+            unsafe { run(work) };
+            budget -= 1;
+        }
+        irq_save();
+        // Out of budget: the rest goes to the thread
+        THREAD_LIST.with(|list| push_all(next, list));
+        if budget == 0 {
+            break;
+        }
+    }
+
+    let overflow = SOFTIRQ_LIST.with(take_all);
+    let deferred = !overflow.is_null() || THREAD_LIST.with(|list| !list.load(Ordering::Relaxed).is_null());
+    THREAD_LIST.with(|list| push_all(overflow, list));
+    IN_SOFTIRQ.with(|flag| flag.store(false, Ordering::Relaxed));
+    if deferred {
+        wake_thread(percpu::cpu_id());
+    }
+}
+
+/// Returns whether the calling CPU's IRQ thread has work.
+pub fn has_thread_work() -> bool {
+    THREAD_LIST.with(|list| !list.load(Ordering::Relaxed).is_null())
+}
+
+/// Runs the work queued for the calling CPU's IRQ thread until there is none left,
+/// including softirqs deferred past their bounds. Called from the IRQ thread with
+/// interrupts enabled; it must stay on this CPU meanwhile.
+pub fn run_thread() {
+    loop {
+        let mut next = THREAD_LIST.with(take_all);
+        if next.is_null() {
+            return;
+        }
+        while !next.is_null() {
+            let work = next;
+            next = unsafe { (*work).next.load(Ordering::Relaxed) };
+            unsafe { run(work) };
+        }
+    }
+}
+
+/// Moves the work queued on `cpu_id`, which went offline, to the calling CPU.
+pub fn migrate_from(cpu_id: usize) {
+    let flags = irq_save();
+    for list in [&SOFTIRQ_LIST, &THREAD_LIST] {
+        let Some(from) = list.get_for(cpu_id) else {
+            continue;
+        };
+        let taken = take_all(from);
+        list.with(|to| push_all(taken, to));
+    }
+    irq_restore(flags);
+    wake_thread(percpu::cpu_id());
+}
+
+/// A handler split into halves.
+pub trait ThreadedHandler: Send + Sync {
+    /// Runs in interrupt context. Before asking for the bottom half it must stop the
+    /// device from raising the interrupt again, as the line is unmasked on return.
+    fn top_half(&self, irq: usize) -> IrqReturn;
+
+    /// Runs later on the same CPU with interrupts enabled, and unmasks the device.
+    fn bottom_half(&self, irq: usize);
+}
+
+/// The top half of a [`ThreadedHandler`], installed like any interrupt handler.
+pub struct Threaded<T> {
+    handler: Arc<T>,
+    irq: Arc<AtomicUsize>,
+    work: Arc<Work>,
+}
+
+/// Wraps `handler` for [`register_irq`](crate::dtb::irqchip::register_irq) or a
+/// device vector, deferring its bottom half as asked.
+pub fn threaded<T: ThreadedHandler + 'static>(handler: Arc<T>, deferral: Deferral) -> Threaded<T> {
+    let irq = Arc::new(AtomicUsize::new(0));
+    let work = {
+        let (handler, irq) = (handler.clone(), irq.clone());
+        Work::new(deferral, move || handler.bottom_half(irq.load(Ordering::Relaxed)))
+    };
+    Threaded { handler, irq, work }
+}
+
+impl<T: ThreadedHandler> InterruptHandler for Threaded<T> {
+    fn irq_handler(&mut self, irq: usize) -> IrqReturn {
+        let ret = self.handler.top_half(irq);
+        if ret == IrqReturn::WakeThread {
+            self.irq.store(irq, Ordering::Relaxed);
+            schedule(&self.work);
+        }
+        ret
+    }
+}
@@ -0,0 +1,49 @@
+//! The arm generic counter, read through the virtual count register so it agrees
+//! with the timer compare values under a hypervisor.
+
+use alloc::sync::Arc;
+use core::arch::asm;
+
+use super::{register_clocksource, Clocksource};
+use crate::device::generic_timer;
+
+/// The architecture guarantees at least 56 valid bits.
+const COUNTER_MASK: u64 = (1 << 56) - 1;
+
+struct GenericCounter {
+    frequency: u64,
+}
+
+impl Clocksource for GenericCounter {
+    fn name(&self) -> &'static str {
+        "arch_sys_counter"
+    }
+
+    fn rating(&self) -> u32 {
+        400
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        COUNTER_MASK
+    }
+
+    fn read(&self) -> u64 {
+        let count: u64;
+        // Without the barrier the read may happen ahead of earlier instructions
+        unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack, preserves_flags)) };
+        count
+    }
+}
+
+pub(super) fn init() {
+    let frequency = generic_timer::frequency();
+    if frequency == 0 {
+        log::error!("arch_sys_counter: unknown frequency");
+        return;
+    }
+    register_clocksource(Arc::new(GenericCounter { frequency }));
+}
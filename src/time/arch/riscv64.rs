@@ -0,0 +1,47 @@
+//! The `time` CSR, which counts at the platform's timebase frequency on every hart.
+
+use alloc::sync::Arc;
+use core::arch::asm;
+
+use super::{register_clocksource, Clocksource};
+use crate::{acpi::rhct::rhct, dtb};
+
+struct TimeCsr {
+    frequency: u64,
+}
+
+impl Clocksource for TimeCsr {
+    fn name(&self) -> &'static str {
+        "riscv_time"
+    }
+
+    fn rating(&self) -> u32 {
+        300
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn read(&self) -> u64 {
+        let time: u64;
+        unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack, preserves_flags)) };
+        time
+    }
+}
+
+/// Returns the timebase frequency, from the device tree or the RHCT.
+pub fn frequency() -> Option<u64> {
+    dtb::timebase_frequency().or_else(|| rhct().map(|rhct| rhct.time_base_frequency))
+}
+
+pub(super) fn init() {
+    match frequency() {
+        Some(frequency) => register_clocksource(Arc::new(TimeCsr { frequency })),
+        None => log::warn!("Unknown timebase frequency, the time CSR is unused"),
+    }
+}
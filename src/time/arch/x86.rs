@@ -0,0 +1,8 @@
+//! The TSC is the built-in counter; the HPET and the PM timer register themselves
+//! when their tables are parsed.
+
+use crate::device::tsc;
+
+pub(super) fn init() {
+    tsc::init();
+}
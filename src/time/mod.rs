@@ -0,0 +1,469 @@
+//! # Clocks and timers
+//! Clocksources are free-running counters: the HPET main counter, the ACPI PM timer,
+//! the TSC, the arm generic counter and the RISC-V `time` CSR. Each registers with a
+//! rating and a frequency, and the best rated stable one drives monotonic time.
+//!
+//! Clock-event devices raise an interrupt once, some time from now. Every CPU has at
+//! most one in use, the best rated it registered, and its handler calls
+//! [`handle_event`]. The timer core keeps the CPU's pending timers and programs the
+//! device for the earliest one, or sooner when the clocksource would otherwise wrap
+//! unnoticed.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    cmp::Reverse,
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::{Mutex, RwLock};
+
+use crate::{
+    dtb::irqchip::{InterruptHandler, IrqReturn},
+    percpu::{self, irq_restore, irq_save},
+};
+
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/aarch64.rs"]
+mod arch;
+
+#[cfg(target_arch = "riscv64")]
+#[path = "arch/riscv64.rs"]
+mod arch;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[path = "arch/x86.rs"]
+mod arch;
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeError {
+    /// The event would have been in the past by the time the device was armed.
+    Expired,
+    /// The calling CPU has no clock-event device.
+    NoDevice,
+}
+
+/// A free-running counter.
+pub trait Clocksource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Higher is better. Sources rated zero are only used when there is nothing else.
+    fn rating(&self) -> u32;
+
+    /// Counter frequency in Hz.
+    fn frequency(&self) -> u64;
+
+    /// Mask of the valid counter bits, at which it wraps.
+    fn mask(&self) -> u64;
+
+    fn read(&self) -> u64;
+
+    /// Whether the counter runs at the same constant rate on every CPU and in every
+    /// power state. Unstable sources are only used when there is nothing else.
+    fn is_stable(&self) -> bool {
+        true
+    }
+}
+
+/// A per-CPU device raising one interrupt at a programmed time.
+pub trait ClockEvent: Send {
+    fn name(&self) -> &'static str;
+
+    /// Higher is better.
+    fn rating(&self) -> u32;
+
+    /// Shortest delta the device can be programmed with.
+    fn min_delta_ns(&self) -> u64;
+
+    /// Longest delta the device can be programmed with.
+    fn max_delta_ns(&self) -> u64;
+
+    /// Raises the interrupt once, `delta_ns` from now, cancelling any pending event.
+    fn set_next_event(&mut self, delta_ns: u64) -> Result<(), TimeError>;
+
+    /// Cancels any pending event.
+    fn shutdown(&mut self);
+}
+
+/// Converts `cycles` of a counter running at `frequency` Hz to nanoseconds,
+/// saturating at `u64::MAX`.
+#[inline(always)]
+pub fn cycles_to_ns(cycles: u64, frequency: u64) -> u64 {
+    u64::try_from(u128::from(cycles) * u128::from(NSEC_PER_SEC) / u128::from(frequency)).unwrap_or(u64::MAX)
+}
+
+/// Converts `ns` to cycles of a counter running at `frequency` Hz, rounding up and
+/// saturating at `u64::MAX`.
+#[inline(always)]
+pub fn ns_to_cycles(ns: u64, frequency: u64) -> u64 {
+    u64::try_from((u128::from(ns) * u128::from(frequency)).div_ceil(u128::from(NSEC_PER_SEC))).unwrap_or(u64::MAX)
+}
+
+/// The clocksource in use, and the time at its last update.
+struct Clock {
+    source: Arc<dyn Clocksource>,
+    frequency: u64,
+    mask: u64,
+    cycles: u64,
+    ns: u64,
+    /// Part of a nanosecond left over from the last update, in units of
+    /// 1 / `frequency` ns, so that updates do not lose time to rounding.
+    remainder: u64,
+}
+
+impl Clock {
+    fn new(source: Arc<dyn Clocksource>, ns: u64) -> Self {
+        Self {
+            frequency: source.frequency(),
+            mask: source.mask(),
+            cycles: source.read(),
+            source,
+            ns,
+            remainder: 0,
+        }
+    }
+
+    /// Returns the nanoseconds `cycles` past the last update add up to, and what is
+    /// left over.
+    fn elapsed(&self, cycles: u64) -> (u64, u64) {
+        let delta = cycles.wrapping_sub(self.cycles) & self.mask;
+        let scaled = u128::from(delta) * u128::from(NSEC_PER_SEC) + u128::from(self.remainder);
+        let frequency = u128::from(self.frequency);
+        ((scaled / frequency) as u64, (scaled % frequency) as u64)
+    }
+
+    fn now(&self) -> u64 {
+        self.ns + self.elapsed(self.source.read()).0
+    }
+
+    /// Folds the cycles elapsed since the last update into the time.
+    fn update(&mut self) {
+        let cycles = self.source.read();
+        let (ns, remainder) = self.elapsed(cycles);
+        self.ns += ns;
+        self.remainder = remainder;
+        self.cycles = cycles;
+    }
+
+    /// Longest time without an update before the counter may wrap past the last one.
+    fn max_idle_ns(&self) -> u64 {
+        cycles_to_ns(self.mask >> 1, self.frequency)
+    }
+}
+
+static CLOCKSOURCES: Mutex<Vec<Arc<dyn Clocksource>>> = Mutex::new(Vec::new());
+static CLOCK: RwLock<Option<Clock>> = RwLock::new(None);
+
+/// Time at the last clock update, so it never goes backwards across a switch of
+/// clocksource or between CPUs reading an unstable one.
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the built-in counter of the architecture. Called once firmware tables
+/// or the device tree were parsed.
+pub fn init() {
+    arch::init();
+}
+
+/// Returns the better of two clocksources: stable ones first, then by rating.
+fn better(a: &dyn Clocksource, b: &dyn Clocksource) -> bool {
+    (a.is_stable(), a.rating()) > (b.is_stable(), b.rating())
+}
+
+/// Switches to the best registered clocksource, if it is not in use already.
+fn select() {
+    let sources = CLOCKSOURCES.lock();
+    let Some(best) = sources.iter().reduce(|best, source| if better(&**source, &**best) { source } else { best }) else {
+        return;
+    };
+
+    let flags = irq_save();
+    let mut clock = CLOCK.write();
+    let ns = match clock.as_ref() {
+        Some(current) if Arc::ptr_eq(&current.source, best) => None,
+        Some(current) => Some(current.now()),
+        None => Some(LAST_NS.load(Ordering::Relaxed)),
+    };
+    if let Some(ns) = ns {
+        log::info!("Clocksource: {} at {} Hz", best.name(), best.frequency());
+        *clock = Some(Clock::new(best.clone(), ns));
+    }
+    drop(clock);
+    irq_restore(flags);
+}
+
+/// Adds a clocksource, switching to it if it is the best one.
+pub fn register_clocksource(source: Arc<dyn Clocksource>) {
+    if source.frequency() == 0 {
+        log::warn!("Clocksource {} has no frequency", source.name());
+        return;
+    }
+    CLOCKSOURCES.lock().push(source);
+    select();
+}
+
+/// Removes the clocksource called `name`, for instance when it turned out unstable,
+/// switching to the next best one if it was in use.
+pub fn unregister_clocksource(name: &str) {
+    CLOCKSOURCES.lock().retain(|source| source.name() != name);
+
+    let flags = irq_save();
+    let mut clock = CLOCK.write();
+    let ns = clock.take_if(|clock| clock.source.name() == name).map(|clock| clock.now());
+    drop(clock);
+    irq_restore(flags);
+
+    if let Some(ns) = ns {
+        LAST_NS.fetch_max(ns, Ordering::Relaxed);
+        select();
+    }
+}
+
+/// Returns the clocksource in use.
+pub fn clocksource() -> Option<Arc<dyn Clocksource>> {
+    // The clock is updated from interrupts
+    let flags = irq_save();
+    let source = CLOCK.read().as_ref().map(|clock| clock.source.clone());
+    irq_restore(flags);
+    source
+}
+
+/// Returns the monotonic time in nanoseconds since the first clocksource registered,
+/// or zero before then.
+pub fn now_ns() -> u64 {
+    let flags = irq_save();
+    let now = CLOCK.read().as_ref().map_or(0, Clock::now);
+    irq_restore(flags);
+    now.max(LAST_NS.load(Ordering::Relaxed))
+}
+
+/// Folds the elapsed cycles into the time, which has to happen before the counter
+/// wraps. Each clock event does it.
+fn update_clock() -> u64 {
+    let mut clock = CLOCK.write();
+    clock.as_mut().map_or(u64::MAX, |clock| {
+        clock.update();
+        LAST_NS.fetch_max(clock.ns, Ordering::Relaxed);
+        clock.max_idle_ns()
+    })
+}
+
+/// Identifies a pending timer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    cpu_id: usize,
+    seq: u64,
+}
+
+struct Timer {
+    deadline: u64,
+    seq: u64,
+    func: Box<dyn FnOnce() + Send>,
+}
+
+crate::percpu! {
+    /// Clock-event device of the CPU.
+    static EVENT: Mutex<Option<Box<dyn ClockEvent>>> = Mutex::new(None);
+    /// Pending timers of the CPU, latest deadline first.
+    static TIMERS: Mutex<Vec<Timer>> = Mutex::new(Vec::new());
+}
+
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Makes `event` the clock-event device of the calling CPU, unless the one it has is
+/// rated higher. Returns whether it is in use.
+pub fn register_clock_event(mut event: Box<dyn ClockEvent>) -> bool {
+    let flags = irq_save();
+    let used = EVENT.with(|slot| {
+        let mut slot = slot.lock();
+        if slot.as_ref().is_some_and(|current| current.rating() >= event.rating()) {
+            return false;
+        }
+        log::info!("CPU {}: clock events from {}", percpu::cpu_id(), event.name());
+        if let Some(mut old) = slot.take() {
+            old.shutdown();
+        }
+        event.shutdown();
+        *slot = Some(event);
+        true
+    });
+    if used {
+        reprogram();
+    }
+    irq_restore(flags);
+    used
+}
+
+/// Returns whether the calling CPU has a clock-event device.
+pub fn has_clock_event() -> bool {
+    EVENT.with(|slot| slot.lock().is_some())
+}
+
+/// Programs the calling CPU's device for its earliest timer, or for the next clock
+/// update. Called with interrupts disabled.
+fn reprogram() {
+    let max_idle = update_clock();
+    let now = now_ns();
+    let next = TIMERS.with(|timers| timers.lock().last().map(|timer| timer.deadline));
+
+    EVENT.with(|slot| {
+        let mut slot = slot.lock();
+        let Some(event) = slot.as_mut() else {
+            return;
+        };
+        let delta = next
+            .map_or(u64::MAX, |deadline| deadline.saturating_sub(now))
+            .min(max_idle)
+            .clamp(event.min_delta_ns(), event.max_delta_ns());
+        // Retry at the shortest delta if the time moved past the first one
+        if event.set_next_event(delta) == Err(TimeError::Expired) {
+            let _ = event.set_next_event(event.min_delta_ns());
+        }
+    });
+}
+
+/// Runs `func` on the calling CPU once the monotonic time reaches `deadline_ns`, in
+/// interrupt context. Work that may take long belongs in a
+/// [`softirq`](crate::softirq) queued from there.
+pub fn add_timer(deadline_ns: u64, func: impl FnOnce() + Send + 'static) -> Result<TimerId, TimeError> {
+    let flags = irq_save();
+    if !has_clock_event() {
+        irq_restore(flags);
+        return Err(TimeError::NoDevice);
+    }
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    let earliest = TIMERS.with(|timers| {
+        let mut timers = timers.lock();
+        let index = timers.partition_point(|timer| timer.deadline > deadline_ns);
+        timers.insert(index, Timer {
+            deadline: deadline_ns,
+            seq,
+            func: Box::new(func),
+        });
+        index == timers.len() - 1
+    });
+    if earliest {
+        reprogram();
+    }
+    irq_restore(flags);
+    Ok(TimerId { cpu_id: percpu::cpu_id(), seq })
+}
+
+/// Runs `func` on the calling CPU `delay_ns` from now.
+pub fn add_timer_after(delay_ns: u64, func: impl FnOnce() + Send + 'static) -> Result<TimerId, TimeError> {
+    add_timer(now_ns().saturating_add(delay_ns), func)
+}
+
+/// Cancels a timer that has not run yet. Returns false if it ran or is running.
+pub fn cancel_timer(id: TimerId) -> bool {
+    let Some(timers) = TIMERS.get_for(id.cpu_id) else {
+        return false;
+    };
+    let flags = irq_save();
+    let mut timers = timers.lock();
+    let timer = timers.iter().position(|timer| timer.seq == id.seq).map(|index| timers.remove(index));
+    drop(timers);
+    irq_restore(flags);
+    // The device may still fire for it, which finds nothing due
+    timer.is_some()
+}
+
+/// Runs the expired timers of the calling CPU and programs its device again. Called by
+/// the handler of every clock-event device, with interrupts disabled.
+pub fn handle_event() {
+    loop {
+        let now = now_ns();
+        let timer = TIMERS.with(|timers| {
+            let mut timers = timers.lock();
+            timers.last().is_some_and(|timer| timer.deadline <= now).then(|| timers.pop()).flatten()
+        });
+        match timer {
+            Some(timer) => (timer.func)(),
+            None => break,
+        }
+    }
+    reprogram();
+}
+
+/// Interrupt handler of a clock-event device that needs no acknowledgement.
+pub struct EventHandler;
+
+impl InterruptHandler for EventHandler {
+    fn irq_handler(&mut self, _irq: usize) -> IrqReturn {
+        handle_event();
+        IrqReturn::Handled
+    }
+}
+
+/// Moves the timers of `cpu_id`, which went offline, to the calling CPU and drops
+/// its clock-event device.
+pub fn migrate_from(cpu_id: usize) {
+    let flags = irq_save();
+    if let Some(event) = EVENT.get_for(cpu_id) {
+        drop(event.lock().take());
+    }
+    let moved = TIMERS.get_for(cpu_id).map(|timers| mem::take(&mut *timers.lock()));
+    if let Some(moved) = moved.filter(|moved| !moved.is_empty()) {
+        TIMERS.with(|timers| {
+            let mut timers = timers.lock();
+            timers.extend(moved);
+            timers.sort_by_key(|timer| Reverse(timer.deadline));
+        });
+        reprogram();
+    }
+    irq_restore(flags);
+}
+
+// ---------- TESTS ----------
+#[test]
+fn test_cycle_conversion() {
+    // PM timer
+    assert_eq!(cycles_to_ns(3_579_545, 3_579_545), NSEC_PER_SEC);
+    assert_eq!(ns_to_cycles(1_000, 3_579_545), 4);
+    assert_eq!(cycles_to_ns(4, 3_579_545), 1_117);
+    // A 24-bit counter wraps after 4.69 s
+    assert_eq!(cycles_to_ns(0xFF_FFFF, 3_579_545) / 10_000_000, 468);
+    // No overflow at 64 bits and GHz rates
+    assert_eq!(cycles_to_ns(u64::MAX, 4_000_000_000), 4_611_686_018_427_387_903);
+    // Saturates below 1 GHz, like a 64-bit HPET at 14.3 MHz
+    assert_eq!(cycles_to_ns(u64::MAX >> 1, 14_318_180), u64::MAX);
+    assert_eq!(ns_to_cycles(u64::MAX, 4_000_000_000), u64::MAX);
+}
+
+#[test]
+fn test_update_keeps_remainder() {
+    struct Fixed(AtomicU64);
+
+    impl Clocksource for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn rating(&self) -> u32 {
+            0
+        }
+
+        fn frequency(&self) -> u64 {
+            3_579_545
+        }
+
+        fn mask(&self) -> u64 {
+            0xFF_FFFF
+        }
+
+        fn read(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    let source = Arc::new(Fixed(AtomicU64::new(0)));
+    let mut clock = Clock::new(source.clone(), 0);
+    // One cycle at a time is 279.36 ns, which truncating each update would round down
+    for cycles in 1..=3_579_545u64 {
+        source.0.store(cycles & 0xFF_FFFF, Ordering::Relaxed);
+        clock.update();
+    }
+    assert_eq!(clock.ns, NSEC_PER_SEC);
+    assert_eq!(clock.now(), NSEC_PER_SEC);
+}